@@ -0,0 +1,81 @@
+//! Allocations and time per message when parsing and serializing a PRIVMSG-heavy workload
+//! Run with `cargo bench --bench message`
+
+use bytes::BytesMut;
+use rirc_server::Message;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const ITERATIONS: usize = 200_000;
+
+const LINES: &[&str] = &[
+    "PRIVMSG #rust :has anyone tried the new borrow checker diagnostics?",
+    ":alice!~alice@192.0.2.10 PRIVMSG #rust :yes, the messages are much clearer now",
+    "@time=2021-05-12T10:00:00.000Z;msgid=abc :bob!~bob@host PRIVMSG #rust :nice",
+    "PRIVMSG bob :hello there",
+    "NOTICE #rust :this channel is logged",
+    "PING :irc.example.org",
+];
+
+fn bench(name: &str, mut f: impl FnMut(&str)) {
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for i in 0..ITERATIONS {
+        f(LINES[i % LINES.len()]);
+    }
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+    println!(
+        "{:<10} {:>6.2} allocations/msg {:>8.1} ns/msg",
+        name,
+        allocations as f64 / ITERATIONS as f64,
+        elapsed.as_nanos() as f64 / ITERATIONS as f64
+    );
+}
+
+fn main() {
+    bench("parse", |line| {
+        black_box(Message::new(line));
+    });
+
+    let msgs: Vec<Message> = LINES.iter().map(|line| Message::new(line)).collect();
+    let mut next = 0;
+    bench("to_line", |_| {
+        black_box(msgs[next % msgs.len()].to_line());
+        next += 1;
+    });
+
+    // Like a connection's send buffer, which is reused between messages
+    let mut buf = BytesMut::new();
+    bench("to_bytes", |_| {
+        buf.clear();
+        msgs[next % msgs.len()].to_bytes(&mut buf);
+        black_box(&buf);
+        next += 1;
+    });
+}
@@ -1,14 +1,15 @@
-use rirc_server::{Server, ServerCallbacks, ServerSettings};
+use rirc_server::{DefaultHooks, Server, ServerSettings};
+use std::sync::Arc;
 
 #[tokio::main]
 async fn main() -> Result<(), std::io::Error> {
     let mut server = Server::new(
         ServerSettings {
-            listen_addr: "0.0.0.0:6667".parse().unwrap(),
+            listen_addrs: vec!["0.0.0.0:6667".parse().unwrap()],
             server_name: "example-server".to_owned(),
             ..Default::default()
         },
-        ServerCallbacks::default(),
+        Arc::new(DefaultHooks),
     );
 
     server.start().await
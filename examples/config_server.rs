@@ -0,0 +1,29 @@
+use rirc_server::{DefaultHooks, Server, ServerSettings};
+use std::io::Result;
+use std::path::PathBuf;
+use std::sync::Arc;
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+struct Options {
+    /// The config file, see examples/rirc.toml
+    #[structopt(parse(from_os_str), default_value = "rirc.toml")]
+    config: PathBuf,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let options = Options::from_args();
+
+    // The error shows where the file is wrong, which the Debug output of an Err from main would hide
+    let settings = match ServerSettings::from_toml_file(&options.config) {
+        Ok(settings) => settings,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    let mut server = Server::new(settings, Arc::new(DefaultHooks));
+
+    server.start().await
+}
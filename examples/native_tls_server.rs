@@ -0,0 +1,39 @@
+use rirc_server::{DefaultHooks, Server, ServerSettings};
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::PathBuf;
+use std::sync::Arc;
+use structopt::StructOpt;
+use tokio_native_tls::native_tls::{Identity, TlsAcceptor};
+
+#[derive(StructOpt)]
+struct Options {
+    /// Your fullchain.pem certificate chain
+    #[structopt(short = "c", long = "cert", parse(from_os_str))]
+    cert: PathBuf,
+
+    /// Your privkey.pem key, in PKCS#8 format
+    #[structopt(short = "k", long = "key", parse(from_os_str))]
+    key: PathBuf,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let options = Options::from_args();
+    let identity = Identity::from_pkcs8(&fs::read(&options.cert)?, &fs::read(&options.key)?)
+        .map_err(|err| Error::new(ErrorKind::InvalidInput, err))?;
+    let acceptor =
+        TlsAcceptor::new(identity).map_err(|err| Error::new(ErrorKind::InvalidInput, err))?;
+
+    let mut server = Server::new(
+        ServerSettings {
+            listen_addrs: vec!["0.0.0.0:6697".parse().unwrap()],
+            server_name: "example-tls-server".to_owned(),
+            ..Default::default()
+        },
+        Arc::new(DefaultHooks),
+    );
+    server.use_native_tls(acceptor);
+
+    server.start().await
+}
@@ -1,10 +1,11 @@
-use rirc_server::{Server, ServerCallbacks, ServerSettings};
+use rirc_server::{DefaultHooks, Server, ServerSettings, SniCertResolver};
+use rustls_pemfile::{certs, pkcs8_private_keys};
 use std::fs::File;
 use std::io::{BufReader, Error, ErrorKind, Result};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use structopt::StructOpt;
-use tokio_rustls::rustls::internal::pemfile::{certs, pkcs8_private_keys};
-use tokio_rustls::rustls::{Certificate, NoClientAuth, PrivateKey, ServerConfig};
+use tokio_rustls::rustls::{Certificate, PrivateKey};
 
 #[derive(StructOpt)]
 struct Options {
@@ -15,42 +16,55 @@ struct Options {
     /// Your privkey.pem key
     #[structopt(short = "k", long = "key", parse(from_os_str))]
     key: PathBuf,
+
+    /// Extra certificates picked by SNI, as "name:fullchain.pem:privkey.pem"
+    #[structopt(long = "sni")]
+    sni: Vec<String>,
 }
 
 fn load_certs(path: &Path) -> Result<Vec<Certificate>> {
-    certs(&mut BufReader::new(File::open(path)?))
-        .map_err(|_| Error::new(ErrorKind::InvalidInput, "invalid cert"))
+    let certs = certs(&mut BufReader::new(File::open(path)?))
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, "invalid cert"))?;
+    Ok(certs.into_iter().map(Certificate).collect())
 }
 
-fn load_keys(path: &Path) -> Result<Vec<PrivateKey>> {
-    pkcs8_private_keys(&mut BufReader::new(File::open(path)?))
-        .map_err(|_| Error::new(ErrorKind::InvalidInput, "invalid key"))
+fn load_key(path: &Path) -> Result<PrivateKey> {
+    let mut keys = pkcs8_private_keys(&mut BufReader::new(File::open(path)?))
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, "invalid key"))?;
+    if keys.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput, "no key found"));
+    }
+    Ok(PrivateKey(keys.remove(0)))
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // This TLS example code happily lifted from tokio-rustls/examples/server/src/main.rs
     let options = Options::from_args();
-    let certs = load_certs(&options.cert)?;
-    let mut keys = load_keys(&options.key)?;
 
-    // NOTE: rustls does NOT like starting a server on an IP, without DNS
-    //       If you get CorruptMessagePayload(Handshake) errors on 127.0.0.1, this is why
-    //       See https://github.com/briansmith/webpki/issues/54
-    let mut tls_config = ServerConfig::new(NoClientAuth::new());
-    tls_config
-        .set_single_cert(certs, keys.remove(0))
-        .expect("Failed to set server certificate");
+    // Clients that don't send SNI, like those connecting to an IP, get the default certificate
+    let resolver = Arc::new(SniCertResolver::new());
+    resolver.set_default(load_certs(&options.cert)?, &load_key(&options.key)?)?;
+    for sni in &options.sni {
+        let (name, cert, key) = match sni.split(':').collect::<Vec<_>>()[..] {
+            [name, cert, key] => (name, cert, key),
+            _ => return Err(Error::new(ErrorKind::InvalidInput, "invalid --sni")),
+        };
+        resolver.add(
+            name,
+            load_certs(Path::new(cert))?,
+            &load_key(Path::new(key))?,
+        )?;
+    }
 
     let mut server = Server::new(
         ServerSettings {
-            listen_addr: "0.0.0.0:6697".parse().unwrap(),
+            listen_addrs: vec!["0.0.0.0:6697".parse().unwrap()],
             server_name: "example-tls-server".to_owned(),
             ..Default::default()
         },
-        ServerCallbacks::default(),
+        Arc::new(DefaultHooks),
     );
-    server.use_tls(tls_config.into());
+    server.use_tls_with_sni(resolver);
 
     server.start().await
 }
@@ -0,0 +1,180 @@
+use crate::casemapping::Casemapping;
+use crate::password::{hash_password_async, verify_password};
+use async_trait::async_trait;
+use chrono::{DateTime, Local};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::error::Error;
+use tokio::sync::RwLock;
+
+pub type AccountResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+/// A user account, like the ones registered with NickServ
+#[derive(Clone, Debug)]
+pub struct Account {
+    /// The account name, with the case it was registered with
+    pub name: String,
+    pub email: Option<String>,
+    pub registered: DateTime<Local>,
+    /// Last time someone logged in to or out of the account
+    pub last_seen: DateTime<Local>,
+}
+
+impl Account {
+    pub fn new(name: &str) -> Self {
+        let now = Local::now();
+        Account {
+            name: name.to_owned(),
+            email: None,
+            registered: now,
+            last_seen: now,
+        }
+    }
+}
+
+/// Where accounts are stored and passwords checked, used by PASS logins and the built-in services
+/// Embedders can implement this to use their own user database, like SQL or LDAP
+/// Errors are reported to users as accounts being temporarily unavailable
+#[async_trait]
+pub trait AccountProvider: Send + Sync {
+    /// Returns the account if the password is right, None if it's wrong or the account doesn't exist
+    async fn verify_credentials(
+        &self,
+        name: &str,
+        password: &str,
+    ) -> AccountResult<Option<Account>>;
+
+    /// Returns an account, or None if it doesn't exist
+    async fn get_account(&self, name: &str) -> AccountResult<Option<Account>>;
+
+    /// Creates an account, returns None if the name is already registered
+    async fn create_account(
+        &self,
+        name: &str,
+        password: &str,
+        email: Option<&str>,
+    ) -> AccountResult<Option<Account>>;
+
+    /// Saves changes to the metadata of an existing account, like its email or last seen time
+    async fn update_account(&self, account: &Account) -> AccountResult<()>;
+}
+
+/// Keeps accounts in memory with Argon2id password hashes, they are lost when the server stops
+pub struct MemoryAccountProvider {
+    casemapping: Casemapping,
+    /// Casemapped account name -> Account and password hash
+    accounts: RwLock<HashMap<String, (Account, String)>>,
+}
+
+impl MemoryAccountProvider {
+    /// Account names are compared with this casemapping, which should be the server's
+    pub fn new(casemapping: Casemapping) -> Self {
+        MemoryAccountProvider {
+            casemapping,
+            accounts: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Starts with existing accounts and their password hashes
+    pub(crate) fn with_accounts(
+        casemapping: Casemapping,
+        accounts: Vec<(Account, String)>,
+    ) -> Self {
+        let accounts = accounts
+            .into_iter()
+            .map(|(account, hash)| (casemapping.to_upper(&account.name), (account, hash)))
+            .collect();
+        MemoryAccountProvider {
+            casemapping,
+            accounts: RwLock::new(accounts),
+        }
+    }
+
+    /// Returns every account with its password hash
+    pub(crate) async fn accounts(&self) -> Vec<(Account, String)> {
+        self.accounts.read().await.values().cloned().collect()
+    }
+}
+
+#[async_trait]
+impl AccountProvider for MemoryAccountProvider {
+    async fn verify_credentials(
+        &self,
+        name: &str,
+        password: &str,
+    ) -> AccountResult<Option<Account>> {
+        let accounts = self.accounts.read().await;
+        let (account, hash) = match accounts.get(&self.casemapping.to_upper(name)) {
+            Some((account, hash)) => (account.clone(), hash.clone()),
+            None => return Ok(None),
+        };
+        drop(accounts);
+        let is_valid = verify_password(password, &hash).await;
+        Ok(Some(account).filter(|_| is_valid))
+    }
+
+    async fn get_account(&self, name: &str) -> AccountResult<Option<Account>> {
+        let accounts = self.accounts.read().await;
+        let account = accounts.get(&self.casemapping.to_upper(name));
+        Ok(account.map(|(account, _)| account.clone()))
+    }
+
+    async fn create_account(
+        &self,
+        name: &str,
+        password: &str,
+        email: Option<&str>,
+    ) -> AccountResult<Option<Account>> {
+        let key = self.casemapping.to_upper(name);
+        if self.accounts.read().await.contains_key(&key) {
+            return Ok(None);
+        }
+        let hash = hash_password_async(password).await;
+        let mut account = Account::new(name);
+        account.email = email.map(str::to_owned);
+
+        match self.accounts.write().await.entry(key) {
+            Entry::Occupied(_) => Ok(None),
+            Entry::Vacant(entry) => {
+                entry.insert((account.clone(), hash));
+                Ok(Some(account))
+            }
+        }
+    }
+
+    async fn update_account(&self, account: &Account) -> AccountResult<()> {
+        let mut accounts = self.accounts.write().await;
+        if let Some((stored, _)) = accounts.get_mut(&self.casemapping.to_upper(&account.name)) {
+            *stored = account.clone();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn memory_accounts() {
+        let provider = MemoryAccountProvider::new(Casemapping::Ascii);
+        let account = provider.create_account("Alice", "hunter2", None).await;
+        assert_eq!(account.unwrap().unwrap().name, "Alice");
+        let account = provider.create_account("alice", "other", None).await;
+        assert!(account.unwrap().is_none());
+
+        let account = provider.verify_credentials("ALICE", "hunter2").await;
+        let mut account = account.unwrap().unwrap();
+        assert!(provider
+            .verify_credentials("alice", "hunter3")
+            .await
+            .unwrap()
+            .is_none());
+        assert!(provider.get_account("bob").await.unwrap().is_none());
+
+        account.email = Some("alice@example.org".to_owned());
+        provider.update_account(&account).await.unwrap();
+        let account = provider.get_account("alice").await.unwrap().unwrap();
+        assert_eq!(account.email.as_deref(), Some("alice@example.org"));
+    }
+}
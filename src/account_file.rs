@@ -0,0 +1,172 @@
+use crate::account::{Account, AccountProvider, AccountResult, MemoryAccountProvider};
+use crate::casemapping::Casemapping;
+use async_trait::async_trait;
+use chrono::{Local, TimeZone};
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use tokio::sync::Mutex;
+
+/// Keeps accounts in a text file, with Argon2id password hashes
+/// Each line is an account, with tab-separated name, password hash, email,
+/// registration time and last seen time as Unix timestamps
+/// The whole file is rewritten whenever an account changes
+pub struct FileAccountProvider {
+    path: PathBuf,
+    accounts: MemoryAccountProvider,
+    /// Makes sure two changes don't write the file at the same time
+    save_lock: Mutex<()>,
+}
+
+impl FileAccountProvider {
+    /// Loads the accounts from a file, which is created when the first account is registered
+    /// Account names are compared with this casemapping, which should be the server's
+    pub fn open(path: impl AsRef<Path>, casemapping: Casemapping) -> Result<Self, Error> {
+        let path = path.as_ref().to_owned();
+        let accounts = match std::fs::read_to_string(&path) {
+            Ok(text) => parse_accounts(&text)?,
+            Err(err) if err.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err),
+        };
+        Ok(FileAccountProvider {
+            path,
+            accounts: MemoryAccountProvider::with_accounts(casemapping, accounts),
+            save_lock: Mutex::new(()),
+        })
+    }
+
+    async fn save(&self) -> Result<(), Error> {
+        let _guard = self.save_lock.lock().await;
+        let mut accounts = self.accounts.accounts().await;
+        accounts.sort_by_key(|(account, _)| account.registered);
+        let text: String = accounts
+            .iter()
+            .map(|(account, hash)| format_account(account, hash))
+            .collect();
+
+        // Write to another file first, so a crash can't leave a half-written file behind
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        tokio::fs::write(&tmp_path, text).await?;
+        tokio::fs::rename(&tmp_path, &self.path).await
+    }
+}
+
+#[async_trait]
+impl AccountProvider for FileAccountProvider {
+    async fn verify_credentials(
+        &self,
+        name: &str,
+        password: &str,
+    ) -> AccountResult<Option<Account>> {
+        self.accounts.verify_credentials(name, password).await
+    }
+
+    async fn get_account(&self, name: &str) -> AccountResult<Option<Account>> {
+        self.accounts.get_account(name).await
+    }
+
+    async fn create_account(
+        &self,
+        name: &str,
+        password: &str,
+        email: Option<&str>,
+    ) -> AccountResult<Option<Account>> {
+        let account = self.accounts.create_account(name, password, email).await?;
+        if account.is_some() {
+            self.save().await?;
+        }
+        Ok(account)
+    }
+
+    async fn update_account(&self, account: &Account) -> AccountResult<()> {
+        self.accounts.update_account(account).await?;
+        Ok(self.save().await?)
+    }
+}
+
+fn format_account(account: &Account, hash: &str) -> String {
+    format!(
+        "{}\t{}\t{}\t{}\t{}\n",
+        account.name,
+        hash,
+        account.email.as_deref().unwrap_or_default(),
+        account.registered.timestamp(),
+        account.last_seen.timestamp()
+    )
+}
+
+fn parse_accounts(text: &str) -> Result<Vec<(Account, String)>, Error> {
+    let mut accounts = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        let account = parse_account(line).ok_or_else(|| {
+            let msg = format!("Invalid account on line {} of the accounts file", i + 1);
+            Error::new(ErrorKind::InvalidData, msg)
+        })?;
+        accounts.push(account);
+    }
+    Ok(accounts)
+}
+
+fn parse_account(line: &str) -> Option<(Account, String)> {
+    let mut fields = line.split('\t');
+    let (name, hash, email, registered, last_seen) = (
+        fields.next()?,
+        fields.next()?,
+        fields.next()?,
+        fields.next()?,
+        fields.next()?,
+    );
+    let timestamp = |field: &str| Local.timestamp_opt(field.parse().ok()?, 0).single();
+    let account = Account {
+        name: name.to_owned(),
+        email: Some(email.to_owned()).filter(|email| !email.is_empty()),
+        registered: timestamp(registered)?,
+        last_seen: timestamp(last_seen)?,
+    };
+    Some((account, hash.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_and_parse() {
+        let mut account = Account::new("Alice");
+        account.email = Some("alice@example.org".to_owned());
+        let line = format_account(&account, "$argon2id$hash");
+        let (parsed, hash) = parse_account(line.trim_end()).unwrap();
+        assert_eq!(parsed.name, "Alice");
+        assert_eq!(parsed.email, account.email);
+        assert_eq!(
+            parsed.registered.timestamp(),
+            account.registered.timestamp()
+        );
+        assert_eq!(hash, "$argon2id$hash");
+
+        let line = format_account(&Account::new("bob"), "hash");
+        assert_eq!(parse_account(line.trim_end()).unwrap().0.email, None);
+        assert!(parse_accounts("bob\thash\t\tnot a time\t0\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn persists_accounts() {
+        let path = std::env::temp_dir().join(format!("rirc-accounts-{}", std::process::id()));
+        let provider = FileAccountProvider::open(&path, Casemapping::Ascii).unwrap();
+        provider
+            .create_account("alice", "hunter2", None)
+            .await
+            .unwrap();
+
+        let provider = FileAccountProvider::open(&path, Casemapping::Ascii).unwrap();
+        let account = provider
+            .verify_credentials("ALICE", "hunter2")
+            .await
+            .unwrap();
+        assert_eq!(account.unwrap().name, "alice");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,472 @@
+use crate::commands::{change_topic, check_topic};
+use crate::health::check_health;
+use crate::message::check_text;
+use crate::server::ServerState;
+use crate::snomask::SnoCategory;
+use chrono::Local;
+use serde_json::{json, Value};
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+
+/// Requests must be sent completely within this time
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Limit on the size of the headers and body of a request
+const MAX_REQUEST_LEN: u64 = 64 * 1024;
+/// How long to wait before accepting connections again after an error
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// The health check fails if the event loop lags more than this
+const MAX_HEALTHY_LAG: Duration = Duration::from_secs(1);
+
+/// Settings of the HTTP admin API
+#[derive(Clone, Debug)]
+pub struct AdminApiSettings {
+    /// Address of the HTTP server, which should not be reachable from the internet
+    pub listen_addr: SocketAddr,
+    /// Secret that requests must send in an "Authorization: Bearer <token>" header
+    pub token: String,
+}
+
+struct Request {
+    method: String,
+    path: String,
+    token: Option<String>,
+    content_length: usize,
+    body: Value,
+}
+
+struct Response {
+    status: u16,
+    body: Value,
+}
+
+impl Response {
+    fn ok(body: Value) -> Self {
+        Response { status: 200, body }
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        Response {
+            status,
+            body: json!({ "error": message }),
+        }
+    }
+}
+
+/// Serves the admin API, the listener is bound by Server::start so that it can report bind errors
+/// Failing to accept a connection is reported to operators, and the API keeps running
+pub async fn run_admin_api(state: Arc<ServerState>, listener: TcpListener, token: String) {
+    loop {
+        let socket = match listener.accept().await {
+            Ok((socket, _)) => socket,
+            Err(err) => {
+                let notice = format!("Admin API failed to accept a connection: {}", err);
+                state.snotice(SnoCategory::OperActions, &notice).await;
+                // Errors like running out of file descriptors don't go away right away
+                tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                continue;
+            }
+        };
+        let state = state.clone();
+        let token = token.clone();
+        tokio::spawn(async move {
+            timeout(REQUEST_TIMEOUT, handle_connection(&state, &token, socket))
+                .await
+                .ok();
+        });
+    }
+}
+
+async fn handle_connection(
+    state: &ServerState,
+    token: &str,
+    mut socket: TcpStream,
+) -> Result<(), Error> {
+    let mut reader = BufReader::new((&mut socket).take(MAX_REQUEST_LEN));
+    let response = match read_request(&mut reader).await {
+        // Health checks come from watchdogs and load balancers, which don't know the token
+        Ok(request) if request.method == "GET" && request.path == "/health" => {
+            get_health(state).await
+        }
+        // The body is only read once the token is checked, so unknown clients can't make us buffer it
+        Ok(mut request)
+            if request
+                .token
+                .as_deref()
+                .is_some_and(|t| tokens_match(t, token)) =>
+        {
+            if request.content_length as u64 > MAX_REQUEST_LEN {
+                Response::error(413, "Request body too large")
+            } else {
+                match read_body(&mut reader, request.content_length).await {
+                    Ok(body) => {
+                        request.body = body;
+                        handle_request(state, request).await
+                    }
+                    Err(err) => Response::error(400, &err.to_string()),
+                }
+            }
+        }
+        Ok(_) => Response::error(401, "Missing or invalid token"),
+        Err(err) => Response::error(400, &err.to_string()),
+    };
+    drop(reader);
+
+    let body = response.body.to_string();
+    let reason = match response.status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        413 => "Payload Too Large",
+        503 => "Service Unavailable",
+        _ => "Error",
+    };
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason,
+        body.len()
+    );
+    socket.write_all(head.as_bytes()).await?;
+    socket.write_all(body.as_bytes()).await?;
+    socket.shutdown().await
+}
+
+/// Reads the request line and headers, the body is left for read_body
+async fn read_request(reader: &mut (impl AsyncBufRead + Unpin)) -> Result<Request, Error> {
+    let invalid = |msg: &str| Error::new(ErrorKind::InvalidData, msg.to_owned());
+
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+    let mut words = line.split_whitespace();
+    let (method, path) = match (words.next(), words.next()) {
+        (Some(method), Some(path)) => (method.to_owned(), path.to_owned()),
+        _ => return Err(invalid("Invalid request line")),
+    };
+
+    let mut token = None;
+    let mut content_length = 0;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).await? == 0 {
+            return Err(invalid("Incomplete headers"));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let (name, value) = header
+            .split_once(':')
+            .ok_or_else(|| invalid("Invalid header"))?;
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("Authorization") {
+            token = value.strip_prefix("Bearer ").map(str::to_owned);
+        } else if name.eq_ignore_ascii_case("Content-Length") {
+            content_length = value
+                .parse()
+                .map_err(|_| invalid("Invalid Content-Length"))?;
+        }
+    }
+
+    Ok(Request {
+        method,
+        path,
+        token,
+        content_length,
+        body: Value::Null,
+    })
+}
+
+/// Reads a JSON body, the caller makes sure content_length is within MAX_REQUEST_LEN
+async fn read_body(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    content_length: usize,
+) -> Result<Value, Error> {
+    if content_length == 0 {
+        return Ok(Value::Null);
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).await?;
+    serde_json::from_slice(&body)
+        .map_err(|_| Error::new(ErrorKind::InvalidData, "The body must be JSON"))
+}
+
+/// Compares the tokens in constant time, so their content can't be guessed from the timing
+fn tokens_match(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Decodes %XX escapes in a path segment, like "%23channel"
+fn percent_decode(segment: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(segment.len());
+    let mut iter = segment.bytes();
+    while let Some(byte) = iter.next() {
+        if byte == b'%' {
+            let hex = [iter.next()?, iter.next()?];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(byte);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+async fn handle_request(state: &ServerState, request: Request) -> Response {
+    let segments: Option<Vec<_>> = request
+        .path
+        .trim_matches('/')
+        .split('/')
+        .map(percent_decode)
+        .collect();
+    let segments = match segments {
+        Some(segments) => segments,
+        None => return Response::error(400, "Invalid path"),
+    };
+    let segments: Vec<_> = segments.iter().map(String::as_str).collect();
+    let text_param = |name: &str| request.body.get(name).and_then(Value::as_str);
+
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["stats"]) => get_stats(state).await,
+        ("GET", ["clients"]) => get_clients(state).await,
+        ("POST", ["clients", nick, "kick"]) => {
+            let reason = text_param("reason").unwrap_or("Kicked by an administrator");
+            kick_client(state, nick, reason).await
+        }
+        ("GET", ["channels"]) => get_channels(state).await,
+        ("GET", ["channels", name]) => get_channel(state, name).await,
+        ("PUT", ["channels", name, "topic"]) => match text_param("topic") {
+            Some(topic) => set_topic(state, name, topic).await,
+            None => Response::error(400, "Missing topic"),
+        },
+        ("POST", ["notice"]) => match text_param("text") {
+            Some(text) => broadcast_notice(state, text).await,
+            None => Response::error(400, "Missing text"),
+        },
+        _ => Response::error(404, "Unknown endpoint"),
+    }
+}
+
+async fn get_stats(state: &ServerState) -> Response {
+    let users: Vec<_> = state
+        .users
+        .values()
+        .iter()
+        .filter_map(Weak::upgrade)
+        .collect();
+    let mut num_opers = 0;
+    for user in &users {
+        if user.read().await.mode.is_oper {
+            num_opers += 1;
+        }
+    }
+    let num_clients = state.clients.len();
+    let mut stats = json!({
+        "users": users.len(),
+        "unregistered": num_clients.saturating_sub(users.len()),
+        "operators": num_opers,
+        "channels": state.channels.len(),
+        "uptime": (Local::now() - state.creation_time).num_seconds(),
+    });
+    if let Some(ref dispatcher) = state.dispatcher {
+        let workers: Vec<_> = dispatcher
+            .stats()
+            .iter()
+            .map(|worker| {
+                json!({
+                    "queued": worker.queued,
+                    "processed": worker.processed,
+                    "busy_ms": worker.busy_time.as_millis() as u64,
+                    "queue_full": worker.queue_full,
+                })
+            })
+            .collect();
+        stats["dispatch_workers"] = Value::Array(workers);
+    }
+    Response::ok(stats)
+}
+
+async fn get_health(state: &ServerState) -> Response {
+    let health = check_health(state).await;
+    let status = if health.is_ready(MAX_HEALTHY_LAG) {
+        200
+    } else {
+        503
+    };
+    Response {
+        status,
+        body: json!({
+            "accepting": health.accepting,
+            "clients": health.clients,
+            "users": health.users,
+            "event_loop_lag_ms": health.event_loop_lag.as_secs_f64() * 1000.0,
+        }),
+    }
+}
+
+async fn get_clients(state: &ServerState) -> Response {
+    let clients: Vec<_> = state
+        .clients
+        .values()
+        .iter()
+        .filter_map(Weak::upgrade)
+        .collect();
+    let mut list = Vec::new();
+    for client in clients {
+        let client = client.read().await;
+        let tls = client.connection_info.tls.as_ref();
+        let mut channels = Vec::new();
+        for channel in client
+            .channels
+            .read()
+            .await
+            .values()
+            .filter_map(Weak::upgrade)
+        {
+            channels.push(channel.read().await.name.clone());
+        }
+        list.push(json!({
+            "nick": client.get_nick(),
+            "username": client.get_username(),
+            "host": client.get_real_host(),
+            "addr": client.addr.to_string(),
+            "ip": client.get_ip().to_string(),
+            "account": client.account,
+            "operator": client.mode.is_oper,
+            "secure": client.is_secure,
+            "certfp": client.certfp,
+            "tls_protocol": tls.and_then(|tls| tls.protocol.as_ref()),
+            "tls_cipher": tls.and_then(|tls| tls.cipher.as_ref()),
+            "send_stalled_ms": client.send_stalled_for().as_millis() as u64,
+            "channels": channels,
+        }));
+    }
+    Response::ok(Value::Array(list))
+}
+
+async fn kick_client(state: &ServerState, nick: &str, reason: &str) -> Response {
+    if let Err(err) = check_text(reason) {
+        return Response::error(400, &err.to_string());
+    }
+    match state.find_user(nick) {
+        Some(user) => {
+            user.read().await.disconnect(reason).await.ok();
+            Response::ok(json!({ "kicked": nick }))
+        }
+        None => Response::error(404, "No such nick"),
+    }
+}
+
+async fn get_channels(state: &ServerState) -> Response {
+    let channels = state.channels.values();
+    let mut list = Vec::new();
+    for channel in channels {
+        let channel = channel.read().await;
+        list.push(json!({
+            "name": channel.name,
+            "topic": channel.topic.as_ref().map(|topic| &topic.text),
+            "members": channel.users.read().await.len(),
+        }));
+    }
+    Response::ok(Value::Array(list))
+}
+
+async fn get_channel(state: &ServerState, name: &str) -> Response {
+    let channel = match state.channels.get(&state.casemap(name)) {
+        Some(channel) => channel,
+        None => return Response::error(404, "No such channel"),
+    };
+    let channel = channel.read().await;
+    let mut members = Vec::new();
+    for member in channel.users.read().await.values() {
+        if let Some(user) = member.client.upgrade() {
+            members.push(json!({
+                "nick": user.read().await.get_nick(),
+                "prefix": member.mode.prefix(&state.settings.member_ranks),
+            }));
+        }
+    }
+    Response::ok(json!({
+        "name": channel.name,
+        "topic": channel.topic.as_ref().map(|topic| json!({
+            "text": topic.text,
+            "set_by": topic.set_by_host,
+            "set_at": topic.set_at.timestamp(),
+        })),
+        "modes": channel.mode.to_string(),
+        "members": members,
+    }))
+}
+
+async fn set_topic(state: &ServerState, name: &str, text: &str) -> Response {
+    if let Err(err) = check_topic(state, text) {
+        return Response::error(400, &err.to_string());
+    }
+    let channel = match state.channels.get(&state.casemap(name)) {
+        Some(channel) => channel,
+        None => return Response::error(404, "No such channel"),
+    };
+    let mut channel = channel.write().await;
+    let server_name = state.settings.server_name.clone();
+    change_topic(state, &mut channel, text, server_name)
+        .await
+        .ok();
+    Response::ok(json!({ "channel": channel.name, "topic": text }))
+}
+
+async fn broadcast_notice(state: &ServerState, text: &str) -> Response {
+    if let Err(err) = check_text(text) {
+        return Response::error(400, &err.to_string());
+    }
+    let users: Vec<_> = state
+        .users
+        .values()
+        .iter()
+        .filter_map(Weak::upgrade)
+        .collect();
+    for user in &users {
+        user.read().await.send_notice(text).await.ok();
+    }
+    Response::ok(json!({ "sent": users.len() }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_path_segments() {
+        assert_eq!(percent_decode("%23chan").as_deref(), Some("#chan"));
+        assert_eq!(percent_decode("nick").as_deref(), Some("nick"));
+        assert_eq!(percent_decode("%2"), None);
+        assert_eq!(percent_decode("%zz"), None);
+    }
+
+    #[test]
+    fn compare_tokens() {
+        assert!(tokens_match("secret", "secret"));
+        assert!(!tokens_match("secret", "secreT"));
+        assert!(!tokens_match("secret", "secret2"));
+    }
+
+    #[tokio::test]
+    async fn body_is_read_separately() {
+        let mut reader: &[u8] = b"PUT /x HTTP/1.1\r\nContent-Length: 99999999999\r\n\r\n{}";
+        let request = read_request(&mut reader).await.unwrap();
+        assert_eq!(request.content_length, 99999999999);
+        assert_eq!(request.body, Value::Null);
+
+        let mut reader: &[u8] = b"{\"text\": \"hi\"}";
+        let len = reader.len();
+        let body = read_body(&mut reader, len).await.unwrap();
+        assert_eq!(body["text"], "hi");
+    }
+}
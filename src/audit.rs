@@ -0,0 +1,83 @@
+use chrono::{DateTime, Local};
+use std::collections::VecDeque;
+use std::fmt;
+
+/// A privileged action taken by an operator
+#[derive(Clone, Debug)]
+pub struct AuditEntry {
+    pub time: DateTime<Local>,
+    /// The "nick!user@host" of the operator
+    pub actor: String,
+    /// The command used, like "SAMODE"
+    pub action: String,
+    /// What the action was applied to, like "#channel +o nick"
+    pub target: String,
+}
+
+impl fmt::Display for AuditEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} {}",
+            self.time.format("%Y-%m-%d %H:%M:%S"),
+            self.actor,
+            self.action,
+            self.target
+        )
+    }
+}
+
+/// The most recent operator actions, older entries are dropped once the log is full
+#[derive(Clone, Debug)]
+pub struct AuditLog {
+    entries: VecDeque<AuditEntry>,
+    max_entries: usize,
+}
+
+impl AuditLog {
+    pub fn new(max_entries: usize) -> Self {
+        AuditLog {
+            entries: VecDeque::new(),
+            max_entries,
+        }
+    }
+
+    pub fn record(&mut self, actor: &str, action: &str, target: &str) {
+        if self.max_entries == 0 {
+            return;
+        }
+        if self.entries.len() == self.max_entries {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(AuditEntry {
+            time: Local::now(),
+            actor: actor.to_owned(),
+            action: action.to_owned(),
+            target: target.to_owned(),
+        });
+    }
+
+    /// Returns the entries from oldest to newest
+    pub fn entries(&self) -> impl DoubleEndedIterator<Item = &AuditEntry> {
+        self.entries.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drops_oldest_entries() {
+        let mut log = AuditLog::new(2);
+        log.record("a!u@h", "QLINE", "bad*");
+        log.record("a!u@h", "CBAN", "#bad");
+        log.record("b!u@h", "SAJOIN", "nick #chan");
+        let actions: Vec<_> = log.entries().map(|entry| entry.action.as_str()).collect();
+        assert_eq!(actions, ["CBAN", "SAJOIN"]);
+
+        let mut log = AuditLog::new(0);
+        log.record("a!u@h", "QLINE", "bad*");
+        assert_eq!(log.entries().count(), 0);
+    }
+}
@@ -1,32 +1,194 @@
 use crate::channel::Channel;
 use crate::client::Client;
-use crate::message::Message;
+use crate::connection_info::ConnectionInfo;
+use crate::message::{Message, ReplyCode};
+use crate::snapshot::ChannelInfo;
+use async_trait::async_trait;
 use std::error::Error;
 use std::net::SocketAddr;
 
-type CallbackResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
-
-pub struct ServerCallbacks {
-    // A new client just connected, doesn't have a nick/user yet. Return true to accept it.
-    pub on_client_connect: fn(&SocketAddr) -> CallbackResult<bool>,
-    // A client is trying to register (setting their nick/user). Return true to accept it.
-    pub on_client_registering: fn(&mut Client) -> CallbackResult<bool>,
-    // A client has completed registration, received the MOTD, and can now be sent extra commands.
-    pub on_client_registered: fn(&Client) -> CallbackResult<()>,
-    // A client disconnected. The client may or may not have completed registration.
-    pub on_client_disconnect: fn(&SocketAddr) -> CallbackResult<()>,
-    // A registered client is sending a message on a channel, return true to accept it.
-    pub on_client_channel_message: fn(&Client, &Channel, &Message) -> CallbackResult<bool>,
+pub type CallbackResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+/// Lets embedders react to what clients do, and accept or refuse it
+/// Every hook has a default that accepts everything, so implementations only override those they need
+/// The hooks run on the client's task while it is processed, so a slow hook only holds up that client
+#[async_trait]
+pub trait ServerHooks: Send + Sync {
+    /// A new client just connected, doesn't have a nick/user yet. Return true to accept it.
+    /// The connection info tells whether it uses TLS, to refuse insecure connections for example.
+    async fn on_client_connect(
+        &self,
+        _addr: &SocketAddr,
+        _connection_info: &ConnectionInfo,
+    ) -> CallbackResult<bool> {
+        Ok(true)
+    }
+
+    /// A message was received from a client, before any command handles it. It can be processed as is,
+    /// rewritten, or consumed so the server ignores it. An error drops the message too.
+    /// This sees every message, also those of unregistered clients and those the server doesn't know.
+    async fn on_raw_message(
+        &self,
+        _client: &Client,
+        _msg: &Message,
+    ) -> CallbackResult<RawMessageAction> {
+        Ok(RawMessageAction::Pass)
+    }
+
+    /// A client is trying to register (setting their nick/user). Return true to accept it.
+    async fn on_client_registering(&self, _client: &mut Client) -> CallbackResult<bool> {
+        Ok(true)
+    }
+
+    /// A client has completed registration, received the MOTD, and can now be sent extra commands.
+    async fn on_client_registered(&self, _client: &Client) -> CallbackResult<()> {
+        Ok(())
+    }
+
+    /// A client disconnected. The client may or may not have completed registration.
+    async fn on_client_disconnect(&self, _addr: &SocketAddr) -> CallbackResult<()> {
+        Ok(())
+    }
+
+    /// A registered client is changing nick with NICK, return true to accept it.
+    /// Nick changes forced by the server, like those of NickServ, can't be refused and only notify the hook.
+    async fn on_nick_change(
+        &self,
+        _client: &Client,
+        _old_nick: &str,
+        _new_nick: &str,
+    ) -> CallbackResult<bool> {
+        Ok(true)
+    }
+
+    /// A client changed user or channel modes with MODE or SAMODE, return false to undo the change.
+    /// The target is the channel or nick, and the modestring and its parameters are what was applied.
+    async fn on_mode_change(
+        &self,
+        _client: &Client,
+        _target: &str,
+        _modestring: &str,
+        _params: &[String],
+    ) -> CallbackResult<bool> {
+        Ok(true)
+    }
+
+    /// A registered client is joining a channel and passed its modes, return a veto to refuse it.
+    /// Joins forced by operators skip this hook, like they skip the channel modes.
+    /// The channel isn't locked meanwhile, so the hook can act on it with a ChannelHandle.
+    async fn on_client_channel_join(
+        &self,
+        _client: &Client,
+        _channel: &ChannelInfo,
+    ) -> CallbackResult<Option<JoinVeto>> {
+        Ok(None)
+    }
+
+    /// A client left a channel with PART, or was parted by an operator. Kicks and quits don't call this.
+    async fn on_client_channel_part(
+        &self,
+        _client: &Client,
+        _channel: &ChannelInfo,
+        _reason: Option<&str>,
+    ) -> CallbackResult<()> {
+        Ok(())
+    }
+
+    /// A channel was just created, usually by its first JOIN. Its topic and modes can be set up here.
+    /// Nobody can use the channel until the hook returns, not even the user creating it.
+    async fn on_channel_created(&self, _channel: &mut Channel) -> CallbackResult<()> {
+        Ok(())
+    }
+
+    /// The last user left a channel and it was removed, permanent channels are never removed.
+    /// The channel stays locked while the hook runs.
+    async fn on_channel_destroyed(&self, _channel: &Channel) -> CallbackResult<()> {
+        Ok(())
+    }
+
+    /// A registered client is sending a message on a channel, return true to accept it.
+    async fn on_client_channel_message(
+        &self,
+        _client: &Client,
+        _channel: &Channel,
+        _msg: &Message,
+    ) -> CallbackResult<bool> {
+        Ok(true)
+    }
+
+    /// A registered client is sending a PRIVMSG or NOTICE to a user, possibly itself.
+    /// The message can be delivered, rewritten or dropped. An error is sent to the client as the reason.
+    async fn on_client_private_message(
+        &self,
+        _client: &Client,
+        _target: &Client,
+        _msg: &Message,
+    ) -> CallbackResult<PrivateMessageAction> {
+        Ok(PrivateMessageAction::Deliver)
+    }
 }
 
-impl Default for ServerCallbacks {
-    fn default() -> Self {
-        ServerCallbacks {
-            on_client_connect: |_| Ok(true),
-            on_client_registering: |_| Ok(true),
-            on_client_registered: |_| Ok(()),
-            on_client_disconnect: |_| Ok(()),
-            on_client_channel_message: |_, _, _| Ok(true),
+/// Why on_client_channel_join refused a join, the client gets the numeric of the matching channel mode
+/// Refusals that look like a ban, invite-only or full channel are forwarded like those, if the channel has +f
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum JoinVeto {
+    /// ERR_BANNEDFROMCHAN (474)
+    Banned,
+    /// ERR_INVITEONLYCHAN (473)
+    InviteOnly,
+    /// ERR_CHANNELISFULL (471)
+    Full,
+    /// ERR_BADCHANNELKEY (475)
+    BadKey,
+    /// ERR_NEEDREGGEDNICK (477)
+    NeedAccount,
+    /// ERR_SECUREONLYCHAN (489)
+    SecureOnly,
+    /// ERR_OPERONLY (520)
+    OperOnly,
+    /// Any other reason, sent with the 474 numeric
+    Other(String),
+}
+
+impl JoinVeto {
+    pub(crate) fn into_reply(self, channel: String) -> ReplyCode {
+        match self {
+            JoinVeto::Banned => ReplyCode::ErrBannedFromChan { channel },
+            JoinVeto::InviteOnly => ReplyCode::ErrInviteOnlyChan { channel },
+            JoinVeto::Full => ReplyCode::ErrChannelIsFull { channel },
+            JoinVeto::BadKey => ReplyCode::ErrBadChannelKey { channel },
+            JoinVeto::NeedAccount => ReplyCode::ErrNeedReggedNick { channel },
+            JoinVeto::SecureOnly => ReplyCode::ErrSecureOnlyChan { channel },
+            JoinVeto::OperOnly => ReplyCode::ErrOperOnly { channel },
+            JoinVeto::Other(reason) => ReplyCode::ErrCannotJoinChan { channel, reason },
         }
     }
 }
+
+/// What on_client_private_message does with a message
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PrivateMessageAction {
+    /// Delivers the message as it was sent
+    Deliver,
+    /// Delivers the message with this text instead
+    Rewrite(String),
+    /// Silently drops the message
+    Drop,
+}
+
+/// What on_raw_message does with a message
+#[derive(Clone, Debug)]
+pub enum RawMessageAction {
+    /// Processes the message as it was received
+    Pass,
+    /// Processes this message instead
+    Rewrite(Message),
+    /// Ignores the message, the hook took care of it
+    Consume,
+}
+
+/// Hooks that accept everything, for servers that don't need any
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultHooks;
+
+impl ServerHooks for DefaultHooks {}
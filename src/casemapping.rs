@@ -0,0 +1,94 @@
+use unicode_normalization::UnicodeNormalization;
+
+/// How nicknames and channel names are compared, advertised with the CASEMAPPING ISUPPORT token
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Casemapping {
+    /// Only the letters a-z are equivalent to A-Z
+    #[default]
+    Ascii,
+    /// Like ascii, but {}|^ are also the lowercase versions of []\~
+    Rfc1459,
+    /// Like rfc1459, but ^ and ~ are distinct
+    StrictRfc1459,
+    /// Unicode case mapping after NFC normalization, for UTF-8 nicks (see NickPolicy::Unicode)
+    Rfc8265,
+}
+
+impl Casemapping {
+    /// Value of the CASEMAPPING ISUPPORT token
+    pub fn name(self) -> &'static str {
+        match self {
+            Casemapping::Ascii => "ascii",
+            Casemapping::Rfc1459 => "rfc1459",
+            Casemapping::StrictRfc1459 => "strict-rfc1459",
+            Casemapping::Rfc8265 => "rfc8265",
+        }
+    }
+
+    fn to_upper_char(self, c: char) -> char {
+        match (self, c) {
+            (Casemapping::Rfc1459, '^') => '~',
+            (Casemapping::Rfc1459, '{') | (Casemapping::StrictRfc1459, '{') => '[',
+            (Casemapping::Rfc1459, '}') | (Casemapping::StrictRfc1459, '}') => ']',
+            (Casemapping::Rfc1459, '|') | (Casemapping::StrictRfc1459, '|') => '\\',
+            _ => c.to_ascii_uppercase(),
+        }
+    }
+
+    /// Returns the canonical form of a name, two names are equivalent if their canonical forms are equal
+    pub fn to_upper(self, name: &str) -> String {
+        if self == Casemapping::Rfc8265 {
+            return name.nfc().flat_map(char::to_uppercase).nfc().collect();
+        }
+        name.chars().map(|c| self.to_upper_char(c)).collect()
+    }
+
+    /// Whether two names are equivalent under this casemapping
+    pub fn equals(self, a: &str, b: &str) -> bool {
+        if self == Casemapping::Rfc8265 {
+            return self.to_upper(a) == self.to_upper(b);
+        }
+        a.len() == b.len()
+            && a.chars()
+                .zip(b.chars())
+                .all(|(a, b)| self.to_upper_char(a) == self.to_upper_char(b))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ascii() {
+        let casemapping = Casemapping::Ascii;
+        assert_eq!(casemapping.to_upper("Nick[]{}|^~"), "NICK[]{}|^~");
+        assert!(casemapping.equals("nick", "NICK"));
+        assert!(!casemapping.equals("nick{", "NICK["));
+    }
+
+    #[test]
+    fn rfc1459() {
+        let casemapping = Casemapping::Rfc1459;
+        assert_eq!(casemapping.to_upper("Nick{}|^"), "NICK[]\\~");
+        assert!(casemapping.equals("nick{|}^", "NICK[\\]~"));
+        assert!(!casemapping.equals("nick", "nick_"));
+    }
+
+    #[test]
+    fn strict_rfc1459() {
+        let casemapping = Casemapping::StrictRfc1459;
+        assert_eq!(casemapping.to_upper("Nick{}|^"), "NICK[]\\^");
+        assert!(casemapping.equals("nick{", "NICK["));
+        assert!(!casemapping.equals("nick^", "nick~"));
+    }
+
+    #[test]
+    fn rfc8265() {
+        let casemapping = Casemapping::Rfc8265;
+        assert_eq!(casemapping.to_upper("héllo"), "HÉLLO");
+        assert!(casemapping.equals("Дмитрий", "дмитрий"));
+        assert!(casemapping.equals("he\u{301}llo", "HÉLLO"));
+        assert!(!casemapping.equals("nick{", "nick["));
+    }
+}
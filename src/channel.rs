@@ -1,28 +1,95 @@
-use crate::client::Client;
-use crate::message::{make_reply_msg, Message, ReplyCode};
-use crate::mode::ChannelMode;
+use crate::channel_registration::ChannelRegistration;
+use crate::client::{Client, ClientHandle};
+use crate::commands::{change_topic, check_topic};
+use crate::errors::ChannelNotFoundError;
+use crate::message::{check_text, make_reply_msg, Message, MessageParams, ReplyCode};
+use crate::extban::normalize_ban_mask;
+use crate::mode::{
+    can_set_rank, halfop_rank, operator_rank, parse_modestring, rank_of_mode, BaseMode,
+    ChannelMode, ChannelModeChange, MemberMode, ModeChange, ModeType,
+};
+use crate::sendq::{current_seq, send_in_order};
 use crate::server::ServerState;
+use crate::settings::ServerSettings;
+use crate::snapshot::ChannelMemberInfo;
+use bytes::Bytes;
 use chrono::{DateTime, Local};
-use futures::future;
-use futures::FutureExt;
-use std::collections::HashMap;
-use std::io::Error;
-use std::sync::Weak;
-use std::time::{SystemTime, UNIX_EPOCH};
-use tokio::sync::RwLock;
+use smallvec::smallvec;
+use std::collections::{HashMap, HashSet};
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{broadcast, RwLock};
 
+#[derive(Clone, Debug)]
 pub struct Topic {
     pub text: String,
     pub set_by_host: String,
     pub set_at: DateTime<Local>,
 }
 
+pub struct ChannelMember {
+    pub client: Weak<RwLock<Client>>,
+    /// Used to relay the channel's messages, without locking the member's client
+    pub handle: ClientHandle,
+    pub mode: MemberMode,
+}
+
+impl ChannelMember {
+    pub fn new(client: Weak<RwLock<Client>>, handle: ClientHandle) -> ChannelMember {
+        ChannelMember {
+            client,
+            handle,
+            mode: Default::default(),
+        }
+    }
+}
+
+/// Remembers recently kicked users, to enforce the rejoin delay of +J
+#[derive(Default)]
+pub struct RecentKicks {
+    kicks: HashMap<SocketAddr, Instant>, // Client addr -> time of the kick
+}
+
+impl RecentKicks {
+    pub fn record(&mut self, user_addr: SocketAddr, delay: Duration) {
+        self.kicks.retain(|_, kicked_at| kicked_at.elapsed() < delay);
+        self.kicks.insert(user_addr, Instant::now());
+    }
+
+    /// Returns how long a kicked user still has to wait before rejoining, if at all
+    pub fn remaining_delay(&self, user_addr: SocketAddr, delay: Duration) -> Option<Duration> {
+        let elapsed = self.kicks.get(&user_addr)?.elapsed();
+        delay.checked_sub(elapsed).filter(|remaining| !remaining.is_zero())
+    }
+}
+
+/// A message relayed to all members of a channel, except maybe the one who sent it
+#[derive(Clone)]
+pub struct ChannelMessage {
+    /// Orders the message with the members' other messages, and lets a member's writer stop
+    /// exactly at the messages sent before the member left, see send_in_order
+    pub seq: u64,
+    /// Serialized once, the members' writers share it
+    pub line: Bytes,
+    pub exclude: Option<SocketAddr>,
+}
+
+/// How many messages a member's writer can fall behind the channel, before being disconnected
+const FANOUT_CAPACITY: usize = 256;
+
 pub struct Channel {
     pub name: String, // Includes the # character
     pub topic: Option<Topic>,
-    pub users: RwLock<HashMap<String, Weak<RwLock<Client>>>>, // Client addr -> chan member
+    pub users: RwLock<HashMap<SocketAddr, ChannelMember>>, // Client addr -> chan member
+    pub recent_kicks: RwLock<RecentKicks>,
+    /// Users invited with ChannelHandle::invite, who can join even if the channel is +i
+    pub invites: RwLock<HashSet<SocketAddr>>,
     pub creation_timestamp: u64,
     pub mode: ChannelMode,
+    /// The writers of all members are subscribed, see ClientHandle::subscribe
+    fanout: broadcast::Sender<ChannelMessage>,
 }
 
 impl Channel {
@@ -31,32 +98,68 @@ impl Channel {
             name,
             topic: None,
             users: RwLock::new(HashMap::new()),
+            recent_kicks: RwLock::new(RecentKicks::default()),
+            invites: RwLock::new(HashSet::new()),
             creation_timestamp: SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .unwrap()
                 .as_secs(),
             mode: Default::default(),
+            fanout: broadcast::channel(FANOUT_CAPACITY).0,
         }
     }
 
+    /// Re-creates a registered channel with its last topic, it stays open even when empty
+    pub fn from_registration(registration: &ChannelRegistration, server_name: &str) -> Channel {
+        let mut channel = Channel::new(registration.name.clone());
+        channel.mode.permanent = true;
+        channel.topic = registration.topic.as_ref().map(|text| Topic {
+            text: text.clone(),
+            set_by_host: server_name.to_owned(),
+            set_at: Local::now(),
+        });
+        channel
+    }
+
     pub async fn get_names_msgs(&self, state: &ServerState, client_nick: &str) -> Vec<Message> {
         let mut msgs = Vec::new();
         let users_guard = self.users.read().await;
 
         let mut names = Vec::new();
-        for weak_user in users_guard.values() {
-            if let Some(user) = weak_user.upgrade() {
-                if let Some(nick) = user.read().await.get_nick() {
-                    names.push(nick);
+        let mut found_dead = false;
+        for member in users_guard.values() {
+            let user = match member.client.upgrade() {
+                Some(user) => user,
+                None => {
+                    found_dead = true;
+                    continue;
+                }
+            };
+            let nick = user.read().await.get_nick();
+            if let Some(nick) = nick {
+                match member.mode.prefix(&state.settings.member_ranks) {
+                    Some(prefix) => names.push(prefix.to_string() + &nick),
+                    None => names.push(nick),
                 }
             }
         }
+        drop(users_guard);
+        if found_dead {
+            self.prune_dead_members().await;
+        }
 
+        let symbol = if self.mode.secret {
+            '@'
+        } else if self.mode.private {
+            '*'
+        } else {
+            '='
+        };
         let base_msg = make_reply_msg(
             state,
             client_nick,
             ReplyCode::RplNameReply {
-                symbol: '=',
+                symbol,
                 channel: self.name.clone(),
             },
         );
@@ -100,35 +203,315 @@ impl Channel {
     }
 
     /// Sends a message to all members of a channel
+    /// The members' writers each receive it from the channel, so this doesn't depend on the number of members
     pub async fn send(
         &self,
         message: Message,
-        exclude_user_addr: Option<String>,
+        exclude_user_addr: Option<SocketAddr>,
     ) -> Result<(), Error> {
-        let users_guard = self.users.read().await;
-        let mut futs = Vec::new();
-        for user in users_guard.values() {
-            let user = match user.upgrade() {
-                Some(user) => user,
-                None => continue,
+        // Members joining or leaving hold the write lock, so they see a consistent sequence number
+        let _users_guard = self.users.read().await;
+        let line = message.to_shared_line();
+        send_in_order(|seq| {
+            // This only fails when nobody is subscribed
+            let _ = self.fanout.send(ChannelMessage {
+                seq,
+                line,
+                exclude: exclude_user_addr,
+            });
+        });
+        Ok(())
+    }
+
+    /// Receives the messages sent to the channel from now on, for a new member's writer
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<ChannelMessage> {
+        self.fanout.subscribe()
+    }
+
+    /// Sequence number of the next message sent to the channel
+    pub(crate) fn next_seq(&self) -> u64 {
+        current_seq()
+    }
+
+    /// Removes the members whose client is gone, returns how many were removed
+    pub async fn prune_dead_members(&self) -> usize {
+        let mut users = self.users.write().await;
+        let len = users.len();
+        users.retain(|_, member| member.client.strong_count() > 0);
+        len - users.len()
+    }
+
+    /// Whether a user can see this channel and its members from the outside
+    pub async fn is_visible_to(&self, user_addr: SocketAddr) -> bool {
+        !self.mode.is_hidden() || self.users.read().await.contains_key(&user_addr)
+    }
+
+    /// Returns the membership status of a user, or None if they are not in the channel
+    pub async fn get_member_mode(&self, user_addr: SocketAddr) -> Option<MemberMode> {
+        self.users
+            .read()
+            .await
+            .get(&user_addr)
+            .map(|member| member.mode)
+    }
+
+    /// Applies a modestring whose modes may consume parameters, in order
+    /// set_by is the prefix of the user recorded in new list mode entries,
+    /// and setter_mode their status in the channel, which decides what they may change
+    pub async fn apply_modestring(
+        &mut self,
+        modestring: &str,
+        params: &[String],
+        set_by: &str,
+        setter_mode: MemberMode,
+        settings: &ServerSettings,
+    ) -> ChannelModeChange {
+        let ranks = &settings.member_ranks;
+        let mut change = ChannelModeChange::default();
+        let mut users = self.users.write().await;
+        // Members whose client is gone could still be found by nick below
+        users.retain(|_, member| member.client.strong_count() > 0);
+        let is_operator = setter_mode.is_at_least(operator_rank(ranks));
+        let is_halfop = setter_mode.is_at_least(halfop_rank(ranks));
+        let setter_nick = set_by.split('!').next().unwrap_or(set_by);
+
+        let parsed = parse_modestring(modestring, params, |mode| {
+            if rank_of_mode(ranks, mode).is_some() {
+                Some(ModeType::Membership)
+            } else {
+                self.mode.mode_type(mode)
+            }
+        });
+        change.unknown_modes = parsed.unknown_modes;
+
+        for mode_change in parsed.changes {
+            let ModeChange {
+                mode: c, positive, ..
+            } = mode_change;
+
+            let rank = match rank_of_mode(ranks, c) {
+                Some(rank) => rank,
+                None => {
+                    let mode_type = self.mode.mode_type(c);
+                    if mode_type == Some(ModeType::List) && mode_change.param.is_none() {
+                        if !change.list_queries.contains(&(c as char)) {
+                            change.list_queries.push(c as char);
+                        }
+                        continue;
+                    }
+
+                    let allowed = match mode_type {
+                        Some(ModeType::List) => is_halfop,
+                        _ => is_operator,
+                    };
+                    if !allowed {
+                        change.missing_privileges = true;
+                        continue;
+                    }
+
+                    let mut mode_change = mode_change;
+                    if mode_type == Some(ModeType::List) {
+                        let mask = mode_change.param.as_deref().unwrap_or_default();
+                        match normalize_ban_mask(&settings.extbans, mask) {
+                            Some(mask) => mode_change.param = Some(mask),
+                            None => continue,
+                        }
+                    }
+                    if let Some(applied_param) = self.mode.apply_change(&mode_change, set_by, settings.casemapping) {
+                        change.applied.push(c, positive, applied_param);
+                    }
+                    continue;
+                }
             };
 
-            let exclude_user_addr = exclude_user_addr.clone();
-            let message = message.clone();
-            futs.push(async move {
-                let user_guard = user.read().await;
-                if exclude_user_addr.is_none()
-                    || exclude_user_addr.as_ref().unwrap() != &user_guard.addr.to_string()
-                {
-                    user_guard.send(message).boxed().await?;
+            let param = mode_change.param.unwrap_or_default();
+            let casemapped_param = settings.casemapping.to_upper(&param);
+            let mut target = None;
+            for member in users.values_mut() {
+                let user = match member.client.upgrade() {
+                    Some(user) => user,
+                    None => continue,
+                };
+                let user = user.read().await;
+                if user.get_casemapped_nick() == Some(&casemapped_param) {
+                    target = Some((member, user.get_nick().unwrap()));
+                    break;
+                }
+            }
+
+            match target {
+                Some((member, nick)) => {
+                    // Members can always give up their own ranks
+                    let is_self = settings.casemapping.equals(&nick, setter_nick);
+                    if !(can_set_rank(ranks, setter_mode, rank) || (!positive && is_self))
+                        || (!positive && member.mode.outranks(setter_mode))
+                    {
+                        change.missing_privileges = true;
+                    } else if member.mode.set_rank(rank, positive) {
+                        change.applied.push(c, positive, Some(nick));
+                    }
                 }
-                Result::<(), Error>::Ok(())
-            })
+                None => change.missing_members.push(param),
+            }
         }
-        let results = future::join_all(futs).await;
-        for result in results {
-            result?;
+
+        change
+    }
+}
+
+/// Acts on a channel from the embedder's code, see ServerHandle::find_channel
+/// The channel is only locked during each call, and nothing happens once it is removed
+#[derive(Clone)]
+pub struct ChannelHandle {
+    name: String,
+    channel: Weak<RwLock<Channel>>,
+    server_state: Arc<ServerState>,
+}
+
+impl ChannelHandle {
+    pub(crate) fn new(
+        server_state: Arc<ServerState>,
+        channel: &Arc<RwLock<Channel>>,
+        name: String,
+    ) -> ChannelHandle {
+        ChannelHandle {
+            name,
+            channel: Arc::downgrade(channel),
+            server_state,
         }
-        Ok(())
+    }
+
+    /// The name of the channel, as it was when the handle was created
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn channel(&self) -> Result<Arc<RwLock<Channel>>, Error> {
+        let channel = self.channel.upgrade();
+        channel.ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                ChannelNotFoundError::new(self.name.clone()),
+            )
+        })
+    }
+
+    pub async fn topic(&self) -> Option<Topic> {
+        self.channel().ok()?.read().await.topic.clone()
+    }
+
+    /// Changes the topic as the server, an empty text removes the topic
+    pub async fn set_topic(&self, text: &str) -> Result<(), Error> {
+        check_topic(&self.server_state, text)?;
+        let channel = self.channel()?;
+        let mut channel = channel.write().await;
+        let server_name = self.server_state.settings.server_name.clone();
+        change_topic(&self.server_state, &mut channel, text, server_name).await
+    }
+
+    /// The members of the channel, empty if it was removed
+    pub async fn members(&self) -> Vec<ChannelMemberInfo> {
+        let channel = match self.channel() {
+            Ok(channel) => channel,
+            Err(_) => return Vec::new(),
+        };
+        let members: Vec<_> = {
+            let channel = channel.read().await;
+            let users = channel.users.read().await;
+            users
+                .iter()
+                .map(|(&addr, member)| (addr, member.client.clone(), member.mode))
+                .collect()
+        };
+
+        let ranks = &self.server_state.settings.member_ranks;
+        let mut infos = Vec::with_capacity(members.len());
+        for (addr, client, mode) in members {
+            let nick = match client.upgrade() {
+                Some(client) => client.read().await.get_nick(),
+                None => continue,
+            };
+            infos.push(ChannelMemberInfo {
+                nick: nick.unwrap_or_default(),
+                addr,
+                prefix: mode.prefix(ranks),
+            });
+        }
+        infos
+    }
+
+    /// Changes the channel's modes as the server, which has the highest rank
+    /// Modes that don't apply, like ranks of nicks that aren't members, are ignored
+    pub async fn set_mode(&self, modestring: &str, params: &[String]) -> Result<(), Error> {
+        let channel = self.channel()?;
+        let mut channel = channel.write().await;
+        let settings = &self.server_state.settings;
+        let mut server_mode = MemberMode::default();
+        server_mode.set_rank(0, true);
+        let server_name = &settings.server_name;
+        let change = channel
+            .apply_modestring(modestring, params, server_name, server_mode, settings)
+            .await;
+        if change.applied.is_empty() {
+            return Ok(());
+        }
+
+        let mut msg_params: MessageParams = smallvec![channel.name.clone()];
+        msg_params.push(change.applied.modestring);
+        msg_params.extend(change.applied.params);
+        let msg = Message {
+            tags: Vec::new(),
+            source: Some(server_name.clone()),
+            command: "MODE".into(),
+            params: msg_params,
+        };
+        channel.send(msg, None).await
+    }
+
+    /// Sends a message to all members of the channel
+    pub async fn send(&self, msg: Message) -> Result<(), Error> {
+        let channel = self.channel()?;
+        let channel = channel.read().await;
+        channel.send(msg, None).await
+    }
+
+    /// Sends a NOTICE from the server to the channel
+    pub async fn send_notice(&self, text: &str) -> Result<(), Error> {
+        self.send_text("NOTICE", text).await
+    }
+
+    /// Sends a PRIVMSG from the server to the channel
+    pub async fn send_privmsg(&self, text: &str) -> Result<(), Error> {
+        self.send_text("PRIVMSG", text).await
+    }
+
+    async fn send_text(&self, command: &'static str, text: &str) -> Result<(), Error> {
+        check_text(text)?;
+        let channel = self.channel()?;
+        let channel = channel.read().await;
+        let msg = Message {
+            tags: Vec::new(),
+            source: Some(self.server_state.settings.server_name.clone()),
+            command: command.into(),
+            params: smallvec![channel.name.clone(), text.to_owned()],
+        };
+        channel.send(msg, None).await
+    }
+
+    /// Invites a user, who can then join once even if the channel is +i
+    pub async fn invite(&self, nick: &str) -> Result<(), Error> {
+        let channel = self.channel()?;
+        let user = self.server_state.find_user(nick);
+        let user = user.ok_or_else(|| Error::new(ErrorKind::NotFound, "No such nick"))?;
+        let user = user.read().await;
+        let channel = channel.read().await;
+        channel.invites.write().await.insert(user.addr);
+        user.send(Message {
+            tags: Vec::new(),
+            source: Some(self.server_state.settings.server_name.clone()),
+            command: "INVITE".into(),
+            params: smallvec![user.get_nick().unwrap(), channel.name.clone()],
+        })
+        .await
     }
 }
@@ -0,0 +1,117 @@
+use crate::casemapping::Casemapping;
+use chrono::{DateTime, Local};
+
+/// Rank given to an account when it joins a registered channel
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChannelAccess {
+    Op,
+    Voice,
+}
+
+impl ChannelAccess {
+    /// The member mode given to the account
+    pub fn mode(self) -> char {
+        match self {
+            ChannelAccess::Op => 'o',
+            ChannelAccess::Voice => 'v',
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ChannelAccess::Op => "OP",
+            ChannelAccess::Voice => "VOICE",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<ChannelAccess> {
+        [ChannelAccess::Op, ChannelAccess::Voice]
+            .iter()
+            .copied()
+            .find(|access| access.name().eq_ignore_ascii_case(name))
+    }
+}
+
+/// A channel registered with ChanServ, owned by the account of its founder
+/// Registered channels are re-created with their topic when the server starts
+#[derive(Clone, Debug)]
+pub struct ChannelRegistration {
+    pub name: String,
+    /// Account name of the owner of the channel
+    pub founder: String,
+    /// Accounts that automatically get a rank when they join
+    pub access: Vec<(String, ChannelAccess)>,
+    /// Only the founder and accounts with op access can change the topic
+    pub topic_lock: bool,
+    /// Last topic of the channel
+    pub topic: Option<String>,
+    pub registered: DateTime<Local>,
+}
+
+impl ChannelRegistration {
+    pub fn new(name: &str, founder: &str) -> Self {
+        ChannelRegistration {
+            name: name.to_owned(),
+            founder: founder.to_owned(),
+            access: Vec::new(),
+            topic_lock: false,
+            topic: None,
+            registered: Local::now(),
+        }
+    }
+
+    pub fn is_founder(&self, casemapping: Casemapping, account: &str) -> bool {
+        casemapping.equals(&self.founder, account)
+    }
+
+    /// The access of an account, the founder always has op access
+    pub fn access_of(&self, casemapping: Casemapping, account: &str) -> Option<ChannelAccess> {
+        if self.is_founder(casemapping, account) {
+            return Some(ChannelAccess::Op);
+        }
+        self.access
+            .iter()
+            .find(|(name, _)| casemapping.equals(name, account))
+            .map(|&(_, access)| access)
+    }
+
+    /// Gives access to an account, replacing its previous access if any
+    pub fn set_access(&mut self, casemapping: Casemapping, account: &str, access: ChannelAccess) {
+        self.remove_access(casemapping, account);
+        self.access.push((account.to_owned(), access));
+    }
+
+    /// Returns false if the account had no access
+    pub fn remove_access(&mut self, casemapping: Casemapping, account: &str) -> bool {
+        let len = self.access.len();
+        self.access
+            .retain(|(name, _)| !casemapping.equals(name, account));
+        self.access.len() != len
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn access_list() {
+        let mut registration = ChannelRegistration::new("#chan", "Founder");
+        let casemapping = Casemapping::Ascii;
+        assert_eq!(
+            registration.access_of(casemapping, "founder"),
+            Some(ChannelAccess::Op)
+        );
+        assert_eq!(registration.access_of(casemapping, "friend"), None);
+
+        registration.set_access(casemapping, "Friend", ChannelAccess::Op);
+        registration.set_access(casemapping, "friend", ChannelAccess::Voice);
+        assert_eq!(registration.access.len(), 1);
+        assert_eq!(
+            registration.access_of(casemapping, "FRIEND"),
+            Some(ChannelAccess::Voice)
+        );
+        assert!(registration.remove_access(casemapping, "friend"));
+        assert!(!registration.remove_access(casemapping, "friend"));
+    }
+}
@@ -1,27 +1,61 @@
-use crate::channel::Channel;
+use crate::channel::{Channel, ChannelMember, ChannelMessage};
+use crate::commands::{force_nick_change, is_nick_taken, kick_member};
+use crate::connection_info::{ConnectionInfo, Transport};
 use crate::errors::ChannelNotFoundError;
-use crate::message::{make_reply_msg, Message, MessageSink, MessageStream, ReplyCode};
-use crate::mode::{UserMode, CHANMODES};
+use crate::events::ServerEvent;
+use crate::extban::{extban_isupport, BanTarget};
+use crate::message::{
+    check_text, make_reply_msg, Message, MessageParams, MessageStream, ReplyCode, MAX_LENGTH,
+};
+use crate::mode::{chanmodes_isupport, prefix_isupport, UserMode};
+use crate::motd::wrap_motd;
+use crate::oper::OperPrivileges;
+use crate::sendq::{is_urgent, send_in_order, Outgoing, SendQueue, Writer};
 use crate::server::ServerState;
-use futures::executor::block_on;
-use futures::{Sink, SinkExt, Stream};
+use crate::server_handle::ServerHandle;
+use crate::snapshot::ChannelInfo;
+use crate::snomask::SnoCategory;
+use crate::virtual_client::{
+    next_virtual_addr, VirtualClient, VirtualIdentity, VIRTUAL_BUFFER_SIZE,
+};
+use crate::webirc::WebircInfo;
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use smallvec::smallvec;
 use std::collections::hash_map::Entry;
 use std::collections::{HashMap, HashSet};
 use std::io::{Error, ErrorKind};
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::pin::Pin;
+use std::sync::atomic::Ordering;
 use std::sync::{Arc, Weak};
-use tokio::io::BufReader;
+use std::time::Duration;
+use tokio::io::{AsyncWrite, BufReader};
 use tokio::net::TcpStream;
-use tokio::sync::RwLock;
+use tokio::sync::{broadcast, mpsc, Notify, RwLock};
+use tokio_stream::wrappers::UnboundedReceiverStream;
 
-#[cfg(feature = "tls")]
-use tokio_rustls::server::TlsStream;
+#[cfg(any(feature = "tls", feature = "native-tls"))]
+use crate::connection_info::TlsInfo;
+#[cfg(any(feature = "tls", feature = "native-tls"))]
+use tokio::io::AsyncRead;
+
+/// Most ISUPPORT tokens sent in a single RPL_ISUPPORT, as clients expect
+const ISUPPORT_TOKENS_PER_LINE: usize = 13;
+
+/// The name of an ISUPPORT token, without its value
+fn isupport_name(token: &str) -> &str {
+    token.split('=').next().unwrap()
+}
 
 pub struct ClientUnregisteredState {
     pub nick: Option<String>,
     pub username: Option<String>,
     pub realname: Option<String>,
+    /// Username returned by the client's ident server, used instead of the USER username
+    pub ident: Option<String>,
+    /// Account credentials given with PASS, used to log in when registration completes
+    pub password: Option<String>,
 }
 
 pub struct ClientNormalState {
@@ -36,6 +70,8 @@ impl ClientUnregisteredState {
             nick: None,
             username: None,
             realname: None,
+            ident: None,
+            password: None,
         }
     }
 }
@@ -47,6 +83,244 @@ pub enum ClientStatus {
     Normal(ClientNormalState),
 }
 
+/// How the PRIVMSGs and NOTICEs of a muted client are dropped
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mute {
+    /// The client isn't told, as if their messages were delivered
+    Silent,
+    /// The client gets a notice when their messages are dropped
+    Notify,
+}
+
+/// Lets other tasks close a client's connection
+#[derive(Default)]
+pub struct DisconnectSignal {
+    notify: Notify,
+    reason: std::sync::Mutex<Option<String>>,
+}
+
+impl DisconnectSignal {
+    pub fn trigger(&self, reason: &str) {
+        *self.reason.lock().unwrap() = Some(reason.to_owned());
+        self.notify.notify_one();
+    }
+
+    /// Waits until the signal is triggered, and returns the reason
+    pub async fn wait(&self) -> String {
+        self.notify.notified().await;
+        self.reason.lock().unwrap().clone().unwrap_or_default()
+    }
+}
+
+/// What other tasks need to reach a client, reachable without locking the Client
+struct ClientShared {
+    outgoing: mpsc::UnboundedSender<Outgoing>,
+    /// Messages that go ahead of those already queued, see is_urgent
+    urgent: mpsc::UnboundedSender<Message>,
+    sendq: Arc<SendQueue>,
+    disconnect_signal: Arc<DisconnectSignal>,
+    sendq_limit: usize,
+    /// To find the client from a handle, see ClientHandle::client
+    server_state: Arc<ServerState>,
+}
+
+impl ClientShared {
+    fn send(&self, msg: Message) {
+        let len = msg.line_len() + 2;
+        if is_urgent(&msg) {
+            self.enqueue(len, || self.urgent.send(msg).is_ok());
+        } else {
+            self.enqueue(len, || {
+                send_in_order(|seq| self.outgoing.send(Outgoing::Message(seq, msg)).is_ok())
+            });
+        }
+    }
+
+    fn send_line(&self, line: Bytes) {
+        let len = line.len();
+        self.enqueue(len, || {
+            send_in_order(|seq| self.outgoing.send(Outgoing::Line(seq, line)).is_ok())
+        });
+    }
+
+    /// Counts a message in the SendQ, and hands it to the writer unless the SendQ is full
+    fn enqueue(&self, len: usize, send: impl FnOnce() -> bool) {
+        let queued = self.sendq.bytes.fetch_add(len, Ordering::Relaxed) + len;
+        if queued > self.sendq_limit {
+            self.sendq.bytes.fetch_sub(len, Ordering::Relaxed);
+            // The sender isn't at fault if this client doesn't read its messages, so it doesn't get an error
+            self.disconnect_signal.trigger("SendQ exceeded");
+        } else if !send() {
+            // The writer stopped because writing failed, the client is being disconnected
+            self.sendq.bytes.fetch_sub(len, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Sends messages to a client without locking it, or doing anything once the client is gone
+/// Channels keep one for each member, so relaying a message never waits on the members' locks
+#[derive(Clone)]
+pub struct ClientHandle {
+    pub addr: SocketAddr,
+    shared: Weak<ClientShared>,
+}
+
+impl ClientHandle {
+    /// Queues a message for the client, it is dropped if the client disconnected
+    pub fn send(&self, msg: Message) {
+        if let Some(shared) = self.shared.upgrade() {
+            shared.send(msg);
+        }
+    }
+
+    /// Queues a line from Message::to_shared_line, to relay the same message to many clients without copying it
+    pub fn send_line(&self, line: Bytes) {
+        if let Some(shared) = self.shared.upgrade() {
+            shared.send_line(line);
+        }
+    }
+
+    /// Whether the client is still connected
+    pub fn is_connected(&self) -> bool {
+        self.shared.strong_count() > 0
+    }
+
+    /// The client, unless it disconnected
+    /// Most methods don't need it, this is for what they don't cover
+    pub fn client(&self) -> Option<Arc<RwLock<Client>>> {
+        let shared = self.shared.upgrade()?;
+        shared.server_state.clients.get(&self.addr)?.upgrade()
+    }
+
+    fn connected_client(&self) -> Result<Arc<RwLock<Client>>, Error> {
+        self.client()
+            .ok_or_else(|| Error::new(ErrorKind::NotConnected, "Client disconnected"))
+    }
+
+    /// The client's nick, unset if it disconnected or hasn't sent a NICK yet
+    pub async fn nick(&self) -> Option<String> {
+        self.client()?.read().await.get_nick()
+    }
+
+    /// Name of the account the client is logged in to, if any
+    pub async fn account(&self) -> Option<String> {
+        self.client()?.read().await.account.clone()
+    }
+
+    /// Sends a NOTICE from the server to the client
+    pub async fn send_notice(&self, text: &str) -> Result<(), Error> {
+        check_text(text)?;
+        let client = self.connected_client()?;
+        let client = client.read().await;
+        client.send_notice(text).await
+    }
+
+    /// Kicks the client from a channel, the KICK comes from the server
+    pub async fn kick(&self, channel_name: &str, reason: &str) -> Result<(), Error> {
+        check_text(reason)?;
+        let client = self.connected_client()?;
+        let client = client.read().await;
+        let state = &client.server_state;
+        let channel = client
+            .channels
+            .read()
+            .await
+            .get(&state.casemap(channel_name))
+            .and_then(Weak::upgrade);
+        let channel = channel.ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                ChannelNotFoundError::new(channel_name.to_owned()),
+            )
+        })?;
+
+        let channel_guard = channel.read().await;
+        let source = state.settings.server_name.clone();
+        kick_member(state, &channel_guard, &client, source, reason).await?;
+        if channel_guard.users.read().await.is_empty() && !channel_guard.mode.permanent {
+            state.remove_empty_channel(&channel, &channel_guard).await;
+        }
+        Ok(())
+    }
+
+    /// Changes the client's user modes as the server, like "+B"
+    /// Modes the client isn't allowed to have, like +o for a non-operator, are ignored
+    pub async fn set_mode(&self, modestring: &str, params: &[String]) -> Result<(), Error> {
+        let client = self.connected_client()?;
+        let mut client = client.write().await;
+        let (applied, _) = client.mode.apply_user_modestring(modestring, params);
+        if applied.is_empty() {
+            return Ok(());
+        }
+
+        let nick = client.get_nick().unwrap_or_else(|| "*".to_owned());
+        let mut params: MessageParams = smallvec![nick, applied.modestring];
+        params.extend(applied.params);
+        client
+            .send(Message {
+                tags: Vec::new(),
+                source: Some(client.server_state.settings.server_name.clone()),
+                command: "MODE".into(),
+                params,
+            })
+            .await
+    }
+
+    /// Renames the user as the server, like for nick enforcement or a GHOST, and announces the NICK
+    /// The nick must be valid and free, but forbidden nicks and the nick change hook's refusals don't apply
+    pub async fn change_nick(&self, new_nick: &str) -> Result<(), Error> {
+        let client_lock = self.connected_client()?;
+        let client = client_lock.read().await;
+        let state = client.server_state.clone();
+        if !matches!(client.status, ClientStatus::Normal(_)) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Client hasn't completed registration",
+            ));
+        }
+        let max_len = state.runtime_settings().max_name_length;
+        let new_nick = state
+            .settings
+            .nick_policy
+            .validate(max_len, new_nick)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Erroneous nickname"))?;
+        // Changing the case of the user's own nick is allowed
+        let is_own_nick = client.get_casemapped_nick() == Some(&state.casemap(&new_nick));
+        drop(client);
+        if !is_own_nick && is_nick_taken(&state, &new_nick).await {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                "Nickname is already in use",
+            ));
+        }
+        force_nick_change(&state, &client_lock, &new_nick).await
+    }
+
+    /// Closes the connection with an ERROR, the client quits with the reason
+    pub async fn disconnect(&self, reason: &str) -> Result<(), Error> {
+        check_text(reason)?;
+        let client = self.connected_client()?;
+        let client = client.read().await;
+        client.disconnect(reason).await
+    }
+
+    /// Starts relaying a channel's messages to the client, from when the receiver was created
+    pub(crate) fn subscribe(&self, channel_name: &str, fanout: broadcast::Receiver<ChannelMessage>) {
+        if let Some(shared) = self.shared.upgrade() {
+            let subscribe = Outgoing::Subscribe(channel_name.to_owned(), fanout);
+            shared.outgoing.send(subscribe).ok();
+        }
+    }
+
+    /// Stops relaying a channel's messages, must be called with the channel's users lock held
+    pub(crate) fn unsubscribe(&self, channel: &Channel) {
+        if let Some(shared) = self.shared.upgrade() {
+            let unsubscribe = Outgoing::Unsubscribe(channel.name.clone(), channel.next_seq());
+            shared.outgoing.send(unsubscribe).ok();
+        }
+    }
+}
+
 pub struct ClientDuplex {
     pub stream: Pin<Box<dyn Stream<Item = Result<Message, Error>> + Send>>,
     pub client: Client,
@@ -55,87 +329,169 @@ pub struct ClientDuplex {
 impl ClientDuplex {
     pub fn from_tcp_stream(server_state: Arc<ServerState>, socket: TcpStream) -> ClientDuplex {
         let addr = socket.peer_addr().unwrap();
+        let local_addr = socket.local_addr().unwrap();
         let (socket_r, socket_w) = socket.into_split();
-        let sink = Box::pin(MessageSink::new(socket_w));
         let stream = Box::pin(MessageStream::new(BufReader::new(socket_r)));
-        Self::from_sink_and_stream(server_state, addr, stream, sink)
+        let info = ConnectionInfo::default();
+        Self::from_sink_and_stream(server_state, addr, local_addr, info, stream, Box::new(socket_w))
     }
 
-    #[cfg(feature = "tls")]
-    pub fn from_tls_stream(
+    /// Wraps a connection after its TLS handshake, with the fingerprint of the client's certificate if it sent one
+    #[cfg(any(feature = "tls", feature = "native-tls"))]
+    pub(crate) fn from_secure_stream<S>(
         server_state: Arc<ServerState>,
-        socket: TlsStream<TcpStream>,
-    ) -> ClientDuplex {
-        let addr = socket.get_ref().0.peer_addr().unwrap();
+        addr: SocketAddr,
+        local_addr: SocketAddr,
+        socket: S,
+        tls_info: TlsInfo,
+        certfp: Option<String>,
+    ) -> ClientDuplex
+    where
+        S: AsyncRead + AsyncWrite + Send + Sync + 'static,
+    {
         let (socket_r, socket_w) = tokio::io::split(socket);
-        let sink = Box::pin(MessageSink::new(socket_w));
         let stream = Box::pin(MessageStream::new(BufReader::new(socket_r)));
-        Self::from_sink_and_stream(server_state, addr, stream, sink)
+        let info = ConnectionInfo {
+            transport: Transport::Tcp,
+            tls: Some(tls_info),
+        };
+        let mut duplex =
+            Self::from_sink_and_stream(server_state, addr, local_addr, info, stream, Box::new(socket_w));
+        duplex.client.certfp = certfp;
+        duplex
+    }
+
+    /// Creates a client without a socket, the embedder gets the other end of its connection
+    /// It can't be intercepted, so it counts as secure
+    pub(crate) fn from_virtual(
+        server_state: Arc<ServerState>,
+        identity: &VirtualIdentity,
+    ) -> (ClientDuplex, VirtualClient) {
+        let addr = next_virtual_addr();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let stream = Box::pin(UnboundedReceiverStream::new(receiver).map(Ok));
+        let (socket_w, embedder_end) = tokio::io::duplex(VIRTUAL_BUFFER_SIZE);
+        let info = ConnectionInfo {
+            transport: Transport::Virtual,
+            tls: None,
+        };
+        let mut duplex =
+            Self::from_sink_and_stream(server_state, addr, addr, info, stream, Box::new(socket_w));
+        duplex.client.is_secure = true;
+        duplex.client.virtual_host = Some(identity.host.clone());
+        if let ClientStatus::Unregistered(ref mut client_state) = duplex.client.status {
+            client_state.ident = Some(identity.username.clone());
+        }
+        (duplex, VirtualClient::new(addr, sender, embedder_end))
     }
 
     fn from_sink_and_stream(
         server_state: Arc<ServerState>,
         addr: SocketAddr,
+        local_addr: SocketAddr,
+        connection_info: ConnectionInfo,
         stream: Pin<Box<dyn Stream<Item = Result<Message, Error>> + Send>>,
-        sink: Pin<Box<dyn Sink<Message, Error = Error> + Send + Sync>>,
+        socket_w: Box<dyn AsyncWrite + Send + Unpin>,
     ) -> ClientDuplex {
+        let sendq = Arc::new(SendQueue::new(socket_w));
+        let disconnect_signal = Arc::new(DisconnectSignal::default());
+        let (outgoing, receiver) = mpsc::unbounded_channel();
+        let writer = Writer::new(sendq.clone(), receiver, addr);
+        let urgent = writer.urgent_sender();
+        let write_timeout = server_state.settings.write_timeout;
+        tokio::spawn(writer.run(disconnect_signal.clone(), write_timeout));
+        let shared = Arc::new(ClientShared {
+            outgoing,
+            urgent,
+            sendq,
+            disconnect_signal: disconnect_signal.clone(),
+            sendq_limit: server_state.settings.sendq_limit,
+            server_state: server_state.clone(),
+        });
         ClientDuplex {
             stream,
             client: Client {
-                sink: RwLock::new(sink),
+                shared,
                 server_state,
                 addr,
+                local_addr,
+                is_secure: connection_info.tls.is_some(),
+                connection_info,
                 status: ClientStatus::Unregistered(ClientUnregisteredState::new()),
+                casemapped_nick: None,
                 channels: RwLock::new(HashMap::new()),
+                account: None,
                 mode: Default::default(),
+                oper_privileges: Default::default(),
+                mute: None,
+                disconnect_signal,
+                webirc: None,
+                virtual_host: None,
+                certfp: None,
             },
         }
     }
 }
 
 pub struct Client {
-    sink: RwLock<Pin<Box<dyn Sink<Message, Error = Error> + Send + Sync>>>,
+    shared: Arc<ClientShared>,
     pub server_state: Arc<ServerState>,
     pub addr: SocketAddr,
+    /// Our address the client connected to
+    pub local_addr: SocketAddr,
+    /// Whether the client is connected over TLS, or through a web gateway which uses TLS
+    pub is_secure: bool,
+    /// How the client is connected to this server
+    pub connection_info: ConnectionInfo,
     pub status: ClientStatus,
+    /// The nick in the server's casemapping, which is the client's key in the users map
+    /// Kept in sync with the nick by set_nick
+    casemapped_nick: Option<String>,
     pub channels: RwLock<HashMap<String, Weak<RwLock<Channel>>>>,
+    /// Name of the account the user is logged in to, if any
+    pub account: Option<String>,
 
     pub mode: UserMode,
+    /// Privileges granted by the OPER command, only meaningful while the user is an operator
+    pub oper_privileges: OperPrivileges,
+    /// Set by the embedder to drop the client's messages, regardless of channel modes
+    pub mute: Option<Mute>,
+    /// Triggered to close the connection from outside of the client's task
+    pub disconnect_signal: Arc<DisconnectSignal>,
+    /// The end user's address given by a web gateway, which replaces the gateway's address
+    pub webirc: Option<WebircInfo>,
+    /// The host of a virtual client, which has no IP of its own
+    pub virtual_host: Option<String>,
+    /// SHA-256 fingerprint of the TLS client certificate, for embedders doing certificate-based auth
+    pub certfp: Option<String>,
 }
 
-impl Drop for Client {
-    fn drop(&mut self) {
-        (self.server_state.callbacks.on_client_disconnect)(&self.addr).ok();
+impl Client {
+    /// The host shown to other users
+    pub fn get_host(&self) -> String {
+        self.get_real_host()
+    }
 
-        match self.status {
-            ClientStatus::Unregistered(_) => (),
-            ClientStatus::Normal(ClientNormalState { ref nick, .. }) => {
-                block_on(Box::pin(self.broadcast(
-                    Message {
-                        tags: Vec::new(),
-                        source: Some(self.get_extended_prefix().unwrap()),
-                        command: "QUIT".to_owned(),
-                        params: vec!["Quit".to_owned()],
-                    },
-                    false,
-                )))
-                .ok();
-
-                block_on(self.server_state.users.write())
-                    .remove(&nick.to_ascii_uppercase())
-                    .expect("Dropped client was registered, but not in users list!");
-            }
-        };
+    /// The host the user actually connects from, even if another host is shown to other users
+    pub fn get_real_host(&self) -> String {
+        match (&self.webirc, &self.virtual_host) {
+            (Some(webirc), _) => webirc.hostname.clone(),
+            (None, Some(virtual_host)) => virtual_host.clone(),
+            (None, None) => self.addr.ip().to_string(),
+        }
+    }
 
-        block_on(self.server_state.clients.lock())
-            .remove(&self.addr.to_string())
-            .expect("Dropped client was not in client list!");
+    /// The IP of the user, which is not the IP of the connection if they use a web gateway
+    pub fn get_ip(&self) -> IpAddr {
+        match self.webirc {
+            Some(ref webirc) => webirc.ip,
+            None => self.addr.ip(),
+        }
     }
-}
 
-impl Client {
-    pub fn get_host(&self) -> String {
-        self.addr.ip().to_string()
+    /// Returns the user's operator privileges, or None if they're not an operator
+    pub fn oper_privileges(&self) -> Option<&OperPrivileges> {
+        Some(&self.oper_privileges).filter(|_| self.mode.is_oper)
     }
 
     pub fn get_nick(&self) -> Option<String> {
@@ -145,6 +501,22 @@ impl Client {
         }
     }
 
+    /// The nick in the server's casemapping, to compare nicks or look the user up without casemapping again
+    pub fn get_casemapped_nick(&self) -> Option<&str> {
+        self.casemapped_nick.as_deref()
+    }
+
+    /// Changes the nick, returns the previous one in the server's casemapping
+    /// Registered users must also be moved to their new key in the users map
+    pub(crate) fn set_nick(&mut self, nick: &str) -> Option<String> {
+        match self.status {
+            ClientStatus::Unregistered(ref mut state) => state.nick = Some(nick.to_owned()),
+            ClientStatus::Normal(ref mut state) => state.nick = nick.to_owned(),
+        };
+        let casemapped_nick = self.server_state.casemap(nick);
+        self.casemapped_nick.replace(casemapped_nick)
+    }
+
     pub fn get_username(&self) -> Option<String> {
         match self.status {
             ClientStatus::Unregistered(ref state) => state.username.clone(),
@@ -165,13 +537,72 @@ impl Client {
         Some(nick + "!" + &username + "@" + &self.get_host())
     }
 
+    /// Returns what ban masks are matched against for this user
+    pub async fn get_ban_target(&self) -> Option<BanTarget> {
+        // Channel keys are casemapped names, which is fine since masks are casemapped the same way
+        Some(BanTarget {
+            prefix: self.get_extended_prefix()?,
+            account: self.account.clone(),
+            channels: self.channels.read().await.keys().cloned().collect(),
+            casemapping: self.server_state.settings.casemapping,
+        })
+    }
+
     /// Sends an arbitrary message to the client
+    /// The message is handed to the client's writer task, so this doesn't wait on the socket
     pub async fn send(&self, msg: Message) -> Result<(), Error> {
-        let mut sink = self.sink.write().await;
-        sink.send(msg).await?;
+        self.shared.send(msg);
         Ok(())
     }
 
+    /// Returns a handle to send messages to this client without holding its lock
+    pub fn handle(&self) -> ClientHandle {
+        ClientHandle {
+            addr: self.addr,
+            shared: Arc::downgrade(&self.shared),
+        }
+    }
+
+    /// Returns a handle to the server, for instance to act on a channel from a hook
+    pub fn server(&self) -> ServerHandle {
+        ServerHandle::new(self.server_state.clone())
+    }
+
+    /// How long messages have been waiting for the client's socket to accept them
+    pub fn send_stalled_for(&self) -> Duration {
+        self.shared.sendq.stalled_for()
+    }
+
+    /// Sends a NOTICE from the server to the client
+    pub async fn send_notice(&self, text: &str) -> Result<(), Error> {
+        self.send(Message {
+            tags: Vec::new(),
+            source: Some(self.server_state.settings.server_name.clone()),
+            command: "NOTICE".into(),
+            params: smallvec![
+                self.get_nick().unwrap_or_else(|| "*".to_owned()),
+                text.to_owned(),
+            ],
+        })
+        .await
+    }
+
+    /// Sends a FAIL standard reply from the server, command is "*" if the failure isn't about a command
+    pub async fn send_fail(
+        &self,
+        command: &str,
+        code: &str,
+        description: &str,
+    ) -> Result<(), Error> {
+        self.send(Message {
+            tags: Vec::new(),
+            source: Some(self.server_state.settings.server_name.clone()),
+            command: "FAIL".into(),
+            params: smallvec![command.to_owned(), code.to_owned(), description.to_owned()],
+        })
+        .await
+    }
+
     /// Sends a series of messages in order to the client
     pub async fn send_all(&self, msgs: &[Message]) -> Result<(), Error> {
         for msg in msgs {
@@ -182,36 +613,41 @@ impl Client {
 
     /// Broadcasts a message to all users of all channels this user is in, and optionally to the user itself
     pub async fn broadcast(&self, message: Message, include_self: bool) -> Result<(), Error> {
-        let mut users_sent_to = HashSet::new();
+        let peers = self.channel_peers().await;
+        let line = message.to_shared_line();
         if include_self {
-            users_sent_to.insert(self.addr.to_string());
-            self.send(message.clone()).await?;
+            self.shared.send_line(line.clone());
+        }
+        for peer in peers {
+            peer.send_line(line.clone());
         }
+        Ok(())
+    }
 
-        let channels_guard = self.channels.read().await;
-        for channel_weak in channels_guard.values() {
-            let channel_lock = match channel_weak.upgrade() {
-                Some(channel) => channel,
-                None => continue,
-            };
-            let channel_guard = channel_lock.read().await;
+    /// Returns the users sharing a channel with this user, once each
+    /// Nothing stays locked after this returns, so sending to them can't hold up the channels
+    pub async fn channel_peers(&self) -> Vec<ClientHandle> {
+        let channels: Vec<_> = self
+            .channels
+            .read()
+            .await
+            .values()
+            .filter_map(Weak::upgrade)
+            .collect();
 
+        let mut seen = HashSet::new();
+        seen.insert(self.addr);
+        let mut peers = Vec::new();
+        for channel in channels {
+            let channel_guard = channel.read().await;
             let channel_users = channel_guard.users.read().await;
-            for (user_addr, weak_user) in channel_users.iter() {
-                if !users_sent_to.insert(user_addr.to_string()) {
-                    continue;
+            for member in channel_users.values() {
+                if seen.insert(member.handle.addr) {
+                    peers.push(member.handle.clone());
                 }
-
-                let user_lock = match weak_user.upgrade() {
-                    Some(user) => user,
-                    None => continue,
-                };
-                let user_guard = user_lock.read().await;
-                let _ = user_guard.send(message.clone()).await;
             }
         }
-
-        Ok(())
+        peers
     }
 
     /// Sends RPL_ISSUPPORT feature advertisment messages to the client
@@ -223,27 +659,46 @@ impl Client {
             ClientStatus::Normal(ClientNormalState { ref nick, .. }) => nick.clone(),
         };
         let state = self.server_state.clone();
+        let runtime_settings = state.runtime_settings();
 
-        // For now we don't even need to split it into multiple messages of 12 params each
-        let features = vec![
-            format!("CASEMAPPING=ascii"),
-            format!("CHANLIMIT=#:{}", state.settings.chan_limit),
-            format!("CHANMODES={}", CHANMODES),
-            format!("CHANNELLEN={}", state.settings.max_channel_length),
+        let mut features = vec![
+            format!("CASEMAPPING={}", state.settings.casemapping.name()),
+            format!("CHANLIMIT=#:{}", runtime_settings.chan_limit),
+            format!("CHANMODES={}", chanmodes_isupport()),
+            format!("CHANNELLEN={}", runtime_settings.max_channel_length),
             format!("CHANTYPES=#"),
+            format!("EXCEPTS=e"),
+            format!("EXTBAN={}", extban_isupport(&state.settings.extbans)),
+            format!("INVEX=I"),
             format!("NETWORK={}", state.settings.network_name),
-            format!("NICKLEN={}", state.settings.max_name_length),
-            format!("PREFIX"),
+            format!("NICKLEN={}", runtime_settings.max_name_length),
+            format!("PREFIX={}", prefix_isupport(&state.settings.member_ranks)),
             format!("SILENCE"), // No value means we don't support SILENCE
-            format!("TOPICLEN={}", state.settings.max_topic_length),
+            format!("TOPICLEN={}", runtime_settings.max_topic_length),
         ];
-        self.send(make_reply_msg(
-            &state,
-            &nick,
-            ReplyCode::RplIsSupport { features },
-        ))
-        .await?;
-        Ok(())
+        if state.settings.utf8_only {
+            features.push(format!("UTF8ONLY"));
+        }
+        for token in &state.settings.isupport_tokens {
+            match token.strip_prefix('-') {
+                Some(removed) => features.retain(|feature| isupport_name(feature) != removed),
+                None => match features
+                    .iter_mut()
+                    .find(|feature| isupport_name(feature) == isupport_name(token))
+                {
+                    Some(feature) => *feature = token.clone(),
+                    None => features.push(token.clone()),
+                },
+            }
+        }
+        let msgs: Vec<_> = features
+            .chunks(ISUPPORT_TOKENS_PER_LINE)
+            .map(|features| {
+                let features = features.to_vec();
+                make_reply_msg(&state, &nick, ReplyCode::RplIsSupport { features })
+            })
+            .collect();
+        self.send_all(&msgs).await
     }
 
     /// Sends RPL_LUSER* replies to the client
@@ -254,25 +709,22 @@ impl Client {
         };
         let state = self.server_state.clone();
 
-        let num_users;
+        let users = state.users.values();
+        let num_users = users.len();
         let mut num_invisibles = 0;
-        {
-            let users = state.users.read().await;
-            num_users = users.len();
-            for weak_user in users.values() {
-                if let Some(user) = weak_user.upgrade() {
-                    if user.read().await.mode.invisible {
-                        num_invisibles += 1;
-                    }
+        for weak_user in users {
+            if let Some(user) = weak_user.upgrade() {
+                if user.read().await.mode.invisible {
+                    num_invisibles += 1;
                 }
             }
         }
 
-        let num_channels = state.channels.lock().await.len();
+        let num_channels = state.channels.len();
         let max_users_seen = num_users;
         let num_ops = 0;
         let num_visibles = num_users - num_invisibles;
-        let num_unknowns = state.clients.lock().await.len() - num_users;
+        let num_unknowns = state.clients.len() - num_users;
         self.send_all(&[
             make_reply_msg(
                 &state,
@@ -315,13 +767,40 @@ impl Client {
             ClientStatus::Normal(ClientNormalState { ref nick, .. }) => nick.clone(),
         };
 
-        self.send(make_reply_msg(
-            &self.server_state,
+        let state = &self.server_state;
+        let motd = match state.runtime_settings().motd {
+            Some(ref motd) => motd.load().await,
+            None => None,
+        };
+        let motd = match motd {
+            Some(motd) => motd,
+            None => {
+                return self
+                    .send(make_reply_msg(state, &nick, ReplyCode::ErrNoMotd))
+                    .await
+            }
+        };
+
+        let empty_line = make_reply_msg(
+            state,
             &nick,
-            ReplyCode::ErrNoMotd,
-        ))
-        .await?;
-        Ok(())
+            ReplyCode::RplMotd {
+                line: String::new(),
+            },
+        );
+        let max_line_len = MAX_LENGTH - "\r\n".len() - empty_line.body_len();
+        let mut msgs = vec![make_reply_msg(state, &nick, ReplyCode::RplMotdStart)];
+        for line in wrap_motd(&motd, max_line_len) {
+            msgs.push(make_reply_msg(
+                state,
+                &nick,
+                ReplyCode::RplMotd {
+                    line: line.to_owned(),
+                },
+            ));
+        }
+        msgs.push(make_reply_msg(state, &nick, ReplyCode::RplEndOfMotd));
+        self.send_all(&msgs).await
     }
 
     /// Sends an ERROR message and closes down the connection
@@ -330,10 +809,10 @@ impl Client {
         self.send(Message {
             tags: Vec::new(),
             source: None,
-            command: "ERROR".to_owned(),
-            params: vec![format!(
+            command: "ERROR".into(),
+            params: smallvec![format!(
                 "Closing Link: {} ({})",
-                &self.addr.ip(),
+                self.get_ip(),
                 explanation
             )],
         })
@@ -342,17 +821,23 @@ impl Client {
         Err(Error::new(ErrorKind::Other, explanation))
     }
 
+    /// Closes the connection with an ERROR, like close_with_error but from any task
+    pub async fn disconnect(&self, explanation: &str) -> Result<(), Error> {
+        self.close_with_error(explanation).await.ok();
+        self.disconnect_signal.trigger(explanation);
+        Ok(())
+    }
+
     /// If the client is ready, try to go through the registration process
     /// Returns true if we still need to finish registration (it is possible to "register" twice)
     pub async fn try_begin_registration(&mut self) -> Result<bool, Error> {
-        let cur_nick: String;
         let registered_status = match self.status {
             ClientStatus::Unregistered(ClientUnregisteredState {
                 nick: Some(ref nick),
                 username: Some(ref username),
                 realname: Some(ref realname),
+                ..
             }) => {
-                cur_nick = nick.clone();
                 ClientStatus::Normal(ClientNormalState {
                     nick: nick.clone(),
                     username: username.clone(),
@@ -363,8 +848,8 @@ impl Client {
         };
 
         let state = self.server_state.clone();
-        let weak_self = match state.clients.lock().await.get(&self.addr.to_string()) {
-            Some(weak) => weak.clone(),
+        let weak_self = match state.clients.get(&self.addr) {
+            Some(weak) => weak,
             None => {
                 return Err(Error::new(
                     ErrorKind::Other,
@@ -374,18 +859,15 @@ impl Client {
         };
 
         {
-            let casemapped_nick = cur_nick.to_ascii_uppercase();
-            let mut users_map = state.users.write().await;
-            if users_map.contains_key(&casemapped_nick) {
+            let casemapped_nick = self.casemapped_nick.clone().expect("Registering without a nick");
+            if !state.users.insert_if_absent(casemapped_nick, weak_self) {
                 self.close_with_error("Overridden").await?;
                 unreachable!();
             }
-            let old_user = users_map.insert(casemapped_nick, weak_self);
-            debug_assert!(old_user.is_none());
             self.status = registered_status;
         }
 
-        match (state.callbacks.on_client_registering)(self) {
+        match state.hooks.on_client_registering(self).await {
             Ok(true) => (),
             Ok(false) => self.close_with_error("Rejected by server").await?,
             Err(e) => self.close_with_error(&e.to_string()).await?,
@@ -409,7 +891,20 @@ impl Client {
         self.send_lusers().await?;
         self.send_motd().await?;
 
-        let _ = (state.callbacks.on_client_registered)(self);
+        let notice = format!(
+            "Client connecting: {} ({}@{}) [{}]",
+            cur_nick,
+            self.get_username().unwrap(),
+            self.get_real_host(),
+            self.get_ip()
+        );
+        state.snotice(SnoCategory::Connects, &notice).await;
+
+        let _ = state.hooks.on_client_registered(self).await;
+        state.emit(|| ServerEvent::Registered {
+            addr: self.addr,
+            nick: cur_nick.clone(),
+        });
 
         Ok(())
     }
@@ -422,26 +917,18 @@ impl Client {
                 "Channels must start with a #",
             ));
         }
-        if self.channels.read().await.len() >= self.server_state.settings.chan_limit {
+        if self.channels.read().await.len() >= self.server_state.runtime_settings().chan_limit {
             return Err(Error::new(
                 ErrorKind::Other,
                 "Cannot join, too many channels",
             ));
         }
 
-        let channel_arc = {
-            let mut channels = self.server_state.channels.lock().await;
-            match channels.entry(chan_name.to_ascii_uppercase()) {
-                Entry::Occupied(entry) => entry.get().clone(),
-                Entry::Vacant(entry) => entry
-                    .insert(Arc::new(RwLock::new(Channel::new(chan_name.to_owned()))))
-                    .clone(),
-            }
-        };
+        let (channel_arc, _) = self.server_state.get_or_create_channel(chan_name).await;
 
         {
             let mut client_chans_guard = self.channels.write().await;
-            match client_chans_guard.entry(chan_name.to_ascii_uppercase()) {
+            match client_chans_guard.entry(self.server_state.casemap(chan_name)) {
                 Entry::Occupied(_) => return Ok(()),
                 Entry::Vacant(entry) => {
                     entry.insert(Arc::downgrade(&channel_arc));
@@ -449,14 +936,8 @@ impl Client {
             };
         }
 
-        let weak_self = match self
-            .server_state
-            .clients
-            .lock()
-            .await
-            .get(&self.addr.to_string())
-        {
-            Some(weak) => weak.clone(),
+        let weak_self = match self.server_state.clients.get(&self.addr) {
+            Some(weak) => weak,
             None => {
                 return Err(Error::new(
                     ErrorKind::Other,
@@ -467,7 +948,18 @@ impl Client {
 
         let channel_guard = channel_arc.read().await;
         let mut chan_users_guard = channel_guard.users.write().await;
-        chan_users_guard.insert(self.addr.to_string(), weak_self);
+        if !self.server_state.keep_channel(&channel_arc, chan_name) {
+            drop(chan_users_guard);
+            drop(channel_guard);
+            let mut client_chans_guard = self.channels.write().await;
+            client_chans_guard.remove(&self.server_state.casemap(chan_name));
+            return Err(Error::new(
+                ErrorKind::Interrupted,
+                "The channel was recreated while joining",
+            ));
+        }
+        chan_users_guard.insert(self.addr, ChannelMember::new(weak_self, self.handle()));
+        let fanout = channel_guard.subscribe();
         let chan_join_msgs = channel_guard
             .get_join_msgs(&self.server_state, &self.get_nick().unwrap())
             .await;
@@ -478,34 +970,57 @@ impl Client {
                 self.get_extended_prefix()
                     .expect("JOIN sent by user without a prefix!"),
             ),
-            command: "JOIN".to_owned(),
-            params: vec![channel_guard.name.to_owned()],
+            command: "JOIN".into(),
+            params: smallvec![channel_guard.name.to_owned()],
         };
 
-        let addr_string = self.addr.to_string();
-        for (chan_user_addr, chan_user_weak) in chan_users_guard.iter() {
-            if *chan_user_addr == addr_string {
+        let join_line = join_msg.to_shared_line();
+        for (chan_user_addr, chan_member) in chan_users_guard.iter() {
+            if *chan_user_addr == self.addr {
                 continue;
             }
-            let chan_user = match chan_user_weak.upgrade() {
-                Some(user) => user,
-                None => continue,
-            };
-            let chan_user_guard = chan_user.read().await;
-            chan_user_guard.send(join_msg.clone()).await?;
+            chan_member.handle.send_line(join_line.clone());
         }
         drop(chan_users_guard);
 
         self.send(join_msg).await?;
-        self.send_all(&chan_join_msgs).await
+        self.send_all(&chan_join_msgs).await?;
+        self.server_state.emit(|| ServerEvent::Joined {
+            nick: self.get_nick().unwrap(),
+            channel: channel_guard.name.clone(),
+        });
+        // Channel messages sent since joining come after the JOIN and NAMES replies
+        self.handle().subscribe(&channel_guard.name, fanout);
+        Ok(())
+    }
+
+    /// Removes the user from all its channels without telling their members, once its QUIT was sent
+    pub(crate) async fn leave_all_channels(&self) {
+        let channels: Vec<_> = self
+            .channels
+            .write()
+            .await
+            .drain()
+            .filter_map(|(_, channel)| channel.upgrade())
+            .collect();
+        for channel in channels {
+            let channel_guard = channel.read().await;
+            let mut channel_users = channel_guard.users.write().await;
+            channel_users.remove(&self.addr);
+            if channel_users.is_empty() && !channel_guard.mode.permanent {
+                self.server_state
+                    .remove_empty_channel(&channel, &channel_guard)
+                    .await;
+            }
+        }
     }
 
     /// Quits a channel, assuming the channel exists and the user is in it
-    pub async fn part(&self, channel_name: &str) -> Result<(), Error> {
+    pub async fn part(&self, channel_name: &str, reason: Option<&str>) -> Result<(), Error> {
         let channel = {
             let mut channels_guard = self.channels.write().await;
             channels_guard
-                .remove(&channel_name.to_ascii_uppercase())
+                .remove(&self.server_state.casemap(channel_name))
                 .and_then(|weak| weak.upgrade())
         };
         if channel.is_none() {
@@ -517,6 +1032,8 @@ impl Client {
         let channel = channel.unwrap();
 
         let channel_guard = channel.read().await;
+        let mut params: MessageParams = smallvec![channel_guard.name.to_owned()];
+        params.extend(reason.map(str::to_owned));
         let result = channel_guard
             .send(
                 Message {
@@ -525,8 +1042,8 @@ impl Client {
                         self.get_extended_prefix()
                             .expect("part called on a user without a prefix!"),
                     ),
-                    command: "PART".to_owned(),
-                    params: vec![channel_guard.name.to_owned()],
+                    command: "PART".into(),
+                    params,
                 },
                 None,
             )
@@ -535,12 +1052,30 @@ impl Client {
 
         let channel_guard = channel.read().await;
         let mut channel_users = channel_guard.users.write().await;
-        channel_users.remove(&self.addr.to_string());
+        if let Some(member) = channel_users.remove(&self.addr) {
+            member.handle.unsubscribe(&channel_guard);
+        }
 
-        if channel_users.len() == 0 {
-            let mut server_channels = self.server_state.channels.lock().await;
-            server_channels.remove(&channel_guard.name.to_ascii_uppercase());
+        if channel_users.is_empty() && !channel_guard.mode.permanent {
+            self.server_state
+                .remove_empty_channel(&channel, &channel_guard)
+                .await;
         }
+        drop(channel_users);
+        // Like for joins, the hook only gets a snapshot so that it can use a ChannelHandle
+        let channel_info = ChannelInfo::new(&channel_guard).await;
+        drop(channel_guard);
+
+        self.server_state
+            .hooks
+            .on_client_channel_part(self, &channel_info, reason)
+            .await
+            .ok();
+        self.server_state.emit(|| ServerEvent::Parted {
+            nick: self.get_nick().unwrap(),
+            channel: channel_info.name.clone(),
+            reason: reason.map(str::to_owned),
+        });
 
         result
     }
@@ -1,12 +1,15 @@
+use crate::callbacks::CallbackResult;
 use crate::client::{Client, ClientStatus};
 use crate::message::{make_reply_msg, Message, ReplyCode};
 use crate::server::ServerState;
+use async_trait::async_trait;
 use futures::Future;
 use lazy_static::lazy_static;
 use std::collections::HashMap;
 use std::io::Error;
 use std::pin::Pin;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::RwLock;
 
 macro_rules! pub_use_submodules {
@@ -18,9 +21,11 @@ macro_rules! pub_use_submodules {
     };
 }
 
-pub_use_submodules!(misc, identity, channels, userqueries);
+pub_use_submodules!(misc, identity, channels, userqueries, oper);
 
-enum CommandNamespace {
+/// Which clients can use a command
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CommandNamespace {
     /// Clients in any state can execute this command
     Any,
     /// Command can be used by normal users after registration
@@ -28,12 +33,59 @@ enum CommandNamespace {
 }
 
 type CommandHandlerFuture = Pin<Box<dyn Future<Output = Result<(), Error>> + Send>>;
+/// A command being handled, either a built-in or a custom one that borrows its handler
+pub type CommandFuture<'a> = Pin<Box<dyn Future<Output = Result<(), Error>> + Send + 'a>>;
 pub type CommandHandler =
     fn(Arc<ServerState>, Arc<RwLock<Client>>, Message) -> CommandHandlerFuture;
 
+/// A command added by the embedder with Server::register_command
+/// Closures taking the client and the message, and returning a future, implement this
+#[async_trait]
+pub trait CustomCommand: Send + Sync {
+    async fn handle(&self, client: Arc<RwLock<Client>>, msg: Message) -> Result<(), Error>;
+}
+
+#[async_trait]
+impl<F, Fut> CustomCommand for F
+where
+    F: Fn(Arc<RwLock<Client>>, Message) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<(), Error>> + Send,
+{
+    async fn handle(&self, client: Arc<RwLock<Client>>, msg: Message) -> Result<(), Error> {
+        self(client, msg).await
+    }
+}
+
+/// Runs around command handlers, to measure them, check extra permissions, or send extra replies
+/// Only commands that exist and that the client may use go through middlewares, in the order they were added
+#[async_trait]
+pub trait CommandMiddleware: Send + Sync {
+    /// Runs before the handler, return false to skip the command and the middlewares after this one
+    /// An error skips the command too
+    async fn before(&self, _client: &Client, _msg: &Message) -> CallbackResult<bool> {
+        Ok(true)
+    }
+
+    /// Runs once the handler returned, with how long it took and its result
+    /// The middlewares run in reverse order, and not at all if the command was skipped
+    async fn after(
+        &self,
+        _client: &Client,
+        _msg: &Message,
+        _elapsed: Duration,
+        _result: &Result<(), Error>,
+    ) {
+    }
+}
+
+pub struct RegisteredCommand {
+    pub namespace: CommandNamespace,
+    pub handler: Arc<dyn CustomCommand>,
+}
+
 pub struct Command {
     pub name: &'static str,
-    permissions: CommandNamespace,
+    pub permissions: CommandNamespace,
     pub handler: CommandHandler,
 }
 
@@ -61,6 +113,8 @@ declare_commands!(
         {ping, CommandNamespace::Any},
         {nick, CommandNamespace::Any},
         {user, CommandNamespace::Any},
+        {pass, CommandNamespace::Any},
+        {webirc, CommandNamespace::Any},
         {notice, CommandNamespace::Normal},
         {version, CommandNamespace::Normal},
         {lusers, CommandNamespace::Normal},
@@ -68,12 +122,27 @@ declare_commands!(
         {privmsg, CommandNamespace::Normal},
         {join, CommandNamespace::Normal},
         {part, CommandNamespace::Normal},
+        {kick, CommandNamespace::Normal},
+        {invite, CommandNamespace::Normal},
         {quit, CommandNamespace::Normal},
         {topic, CommandNamespace::Normal},
         {who, CommandNamespace::Normal},
         {whois, CommandNamespace::Normal},
         {mode, CommandNamespace::Normal},
         {names, CommandNamespace::Normal},
+        {list, CommandNamespace::Normal},
+        {oper, CommandNamespace::Normal},
+        {qline, CommandNamespace::Normal},
+        {unqline, CommandNamespace::Normal},
+        {cban, CommandNamespace::Normal},
+        {uncban, CommandNamespace::Normal},
+        {samode, CommandNamespace::Normal},
+        {sajoin, CommandNamespace::Normal},
+        {sapart, CommandNamespace::Normal},
+        {globops, CommandNamespace::Normal},
+        {shun, CommandNamespace::Normal},
+        {unshun, CommandNamespace::Normal},
+        {stats, CommandNamespace::Normal},
     ]
 );
 
@@ -100,8 +169,8 @@ pub async fn command_error(
     Ok(())
 }
 
-pub fn is_command_available(cmd: &Command, client: &Client) -> bool {
-    match cmd.permissions {
+pub fn is_command_available(namespace: CommandNamespace, client: &Client) -> bool {
+    match namespace {
         CommandNamespace::Any => true,
         CommandNamespace::Normal => matches!(client.status, ClientStatus::Normal(_)),
     }
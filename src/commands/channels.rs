@@ -1,14 +1,20 @@
 use crate::client::Client;
 use crate::server::ServerState;
-use crate::channel::{Channel, Topic};
-use crate::message::{Message, make_reply_msg, ReplyCode};
+use crate::channel::{Channel, ChannelMember, Topic};
+use crate::message::{check_text, Message, MessageParams, make_reply_msg, ReplyCode};
 use crate::errors::ChannelNotFoundError;
+use crate::events::ServerEvent;
 use crate::commands::command_error;
-use crate::mode::BaseMode;
+use crate::mode::{halfop_rank, rank_of_mode, AppliedModes, MemberMode};
+use crate::services::{is_topic_locked_for, registered_access, Service};
+use crate::snapshot::ChannelInfo;
 use chrono::Local;
-use std::io::Error;
+use smallvec::smallvec;
+use std::io::{Error, ErrorKind};
 use std::collections::hash_map::{Entry};
+use std::collections::VecDeque;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::RwLock;
 use std::error::Error as _;
 
@@ -20,33 +26,124 @@ pub async fn handle_join(state: Arc<ServerState>, client_lock: Arc<RwLock<Client
         None => return command_error(&state, &client, ReplyCode::ErrNeedMoreParams{cmd: "JOIN".to_owned()}).await,
     };
 
-    for chan_name in chanlist {
+    let mut keys = msg.params.get(1).map(|keys| keys.split(','));
+    let join_queue = chanlist
+        .map(|chan_name| (chan_name.to_owned(), keys.as_mut().and_then(|keys| keys.next()).map(str::to_owned), false))
+        .collect();
+    drop(client);
+
+    join_channels(&state, &client_lock, join_queue, false).await
+}
+
+/// Joins channels in order, each comes with its key and whether we were forwarded there
+/// Forced joins are operator overrides, they ignore channel modes and limits
+pub async fn join_channels(state: &ServerState, client_lock: &Arc<RwLock<Client>>,
+                           mut join_queue: VecDeque<(String, Option<String>, bool)>, force: bool) -> Result<(), Error> {
+    while let Some((chan_name, key, is_forwarded)) = join_queue.pop_front() {
+        let client = client_lock.read().await;
         if !chan_name.starts_with('#') {
-            command_error(&state, &client, ReplyCode::ErrNoSuchChannel{channel: chan_name.to_string()}).await?;
+            command_error(state, &client, ReplyCode::ErrNoSuchChannel{channel: chan_name.to_string()}).await?;
+            continue;
+        }
+        if state.forbidden_channels.read().await.is_forbidden(state.settings.casemapping, &chan_name) {
+            command_error(state, &client, ReplyCode::ErrBadChanName{channel: chan_name.to_string()}).await?;
             continue;
         }
 
-        let client = client_lock.read().await;
-        if client.channels.read().await.len() >= state.settings.chan_limit {
-            command_error(&state, &client, ReplyCode::ErrTooManyChannels{channel: chan_name.to_owned()}).await?;
+        if !force && client.channels.read().await.len() >= state.runtime_settings().chan_limit {
+            command_error(state, &client, ReplyCode::ErrTooManyChannels{channel: chan_name.to_owned()}).await?;
             break;
         }
 
-        let mut channels = state.channels.lock().await;
-        let channel_arc = match channels.entry(chan_name.to_ascii_uppercase()) {
-            Entry::Occupied(entry) => entry.get().clone(),
-            Entry::Vacant(entry) => {
-                if !state.settings.allow_channel_creation {
-                    command_error(&state, &client, ReplyCode::ErrNoSuchChannel{channel: chan_name.to_owned()}).await?;
+        let (channel_arc, is_creator) = match state.channels.get(&state.casemap(&chan_name)) {
+            Some(channel_arc) => (channel_arc, false),
+            None => {
+                if !force && !state.runtime_settings().allow_channel_creation {
+                    command_error(state, &client, ReplyCode::ErrNoSuchChannel{channel: chan_name.to_owned()}).await?;
                     continue;
                 }
-                entry.insert(Arc::new(RwLock::new(Channel::new(chan_name.to_owned())))).clone()
+                state.get_or_create_channel(&chan_name).await
             },
         };
 
+        if client.channels.read().await.contains_key(&state.casemap(&chan_name)) {
+            continue;
+        }
+
+        let client_prefix = client.get_extended_prefix().expect("JOIN sent by user without a prefix!");
+        if !force {
+            let ban_target = client.get_ban_target().await.unwrap();
+            let mut channel_guard = channel_arc.read().await;
+            let denial = {
+                let mode = &channel_guard.mode;
+                let num_users = channel_guard.users.read().await.len();
+                let recent_kicks = channel_guard.recent_kicks.read().await;
+                let channel = chan_name.to_owned();
+                if mode.is_banned(&state.settings.extbans, &ban_target) {
+                    Some(ReplyCode::ErrBannedFromChan{channel})
+                } else if let Some(remaining) = mode.kick_rejoin_delay.and_then(|delay| recent_kicks.remaining_delay(client.addr, Duration::from_secs(delay))) {
+                    Some(ReplyCode::ErrDelayRejoin{channel, seconds: remaining.as_secs() + 1})
+                } else if mode.oper_only && !client.mode.is_oper {
+                    Some(ReplyCode::ErrOperOnly{channel})
+                } else if mode.registered_only && client.account.is_none() {
+                    Some(ReplyCode::ErrNeedReggedNick{channel})
+                } else if mode.tls_only && !client.is_secure {
+                    Some(ReplyCode::ErrSecureOnlyChan{channel})
+                } else if mode.invite_only && !mode.is_invite_exempt(&state.settings.extbans, &ban_target)
+                        && !channel_guard.invites.read().await.contains(&client.addr) {
+                    Some(ReplyCode::ErrInviteOnlyChan{channel})
+                } else if mode.key.is_some() && mode.key != key {
+                    Some(ReplyCode::ErrBadChannelKey{channel})
+                } else if mode.user_limit.is_some_and(|limit| num_users >= limit) {
+                    Some(ReplyCode::ErrChannelIsFull{channel})
+                } else {
+                    None
+                }
+            };
+            let denial = match denial {
+                Some(denial) => Some(denial),
+                None => {
+                    // The hook can act on this channel through a ChannelHandle, so it gets a snapshot of the unlocked channel
+                    let channel_info = ChannelInfo::new(&channel_guard).await;
+                    drop(channel_guard);
+                    let veto = state.hooks.on_client_channel_join(&client, &channel_info).await;
+                    channel_guard = channel_arc.read().await;
+                    match veto {
+                        Ok(veto) => veto.map(|veto| veto.into_reply(chan_name.to_owned())),
+                        Err(e) => Some(ReplyCode::ErrCannotJoinChan{channel: chan_name.to_owned(), reason: e.to_string()}),
+                    }
+                },
+            };
+
+            if let Some(denial) = denial {
+                // Only a hook can refuse a channel this join created, which would otherwise stay around empty
+                let channel_users = channel_guard.users.read().await;
+                if is_creator && channel_users.is_empty() {
+                    state.remove_empty_channel(&channel_arc, &channel_guard).await;
+                }
+                drop(channel_users);
+                // Users who are banned, not invited, or find the channel full can be sent to the +f channel
+                let forward = match denial {
+                    ReplyCode::ErrBannedFromChan{..} | ReplyCode::ErrInviteOnlyChan{..} | ReplyCode::ErrChannelIsFull{..} => channel_guard.mode.forward.clone(),
+                    _ => None,
+                };
+                match forward.filter(|_| !is_forwarded) {
+                    Some(forward) => {
+                        client.send(make_reply_msg(state, &client.get_nick().unwrap(), ReplyCode::ErrLinkChannel{
+                            channel: chan_name.clone(),
+                            forward: forward.clone(),
+                        })).await?;
+                        join_queue.push_front((forward, None, true));
+                    },
+                    None => command_error(state, &client, denial).await?,
+                }
+                continue;
+            }
+        }
+
         {
             let mut client_chans_guard = client.channels.write().await;
-            match client_chans_guard.entry(chan_name.to_ascii_uppercase()) {
+            match client_chans_guard.entry(state.casemap(&chan_name)) {
                 Entry::Occupied(_) => continue,
                 Entry::Vacant(entry) => {
                     entry.insert(Arc::downgrade(&channel_arc));
@@ -56,29 +153,57 @@ pub async fn handle_join(state: Arc<ServerState>, client_lock: Arc<RwLock<Client
 
         let channel_guard = channel_arc.read().await;
         let client_nick = &client.get_nick().unwrap();
+        let access = registered_access(state, &client, &chan_name).await;
+        let access_rank = access.and_then(|access| rank_of_mode(&state.settings.member_ranks, access.mode() as u8));
 
         let mut chan_users_guard = channel_guard.users.write().await;
-        chan_users_guard.insert(client.addr.to_string(), Arc::downgrade(&client_lock));
+        if !state.keep_channel(&channel_arc, &chan_name) {
+            // The channel was emptied and created again while we were joining, so we join the new one
+            drop(chan_users_guard);
+            drop(channel_guard);
+            client.channels.write().await.remove(&state.casemap(&chan_name));
+            join_queue.push_front((chan_name, key, is_forwarded));
+            continue;
+        }
+        let mut member = ChannelMember::new(Arc::downgrade(client_lock), client.handle());
+        if is_creator {
+            // Whoever creates a channel gets the highest rank, otherwise nobody could ever manage it
+            member.mode.set_rank(0, true);
+        }
+        // Accounts on the access list of a registered channel get their rank back when they join
+        let access_given = access_rank.is_some_and(|rank| member.mode.set_rank(rank, true));
+        chan_users_guard.insert(client.addr, member);
+        // An invite is only good for one join
+        channel_guard.invites.write().await.remove(&client.addr);
+        let fanout = channel_guard.subscribe();
 
         let join_msg = Message {
             tags: Vec::new(),
-            source: Some(client.get_extended_prefix().expect("JOIN sent by user without a prefix!")),
-            command: "JOIN".to_owned(),
-            params: vec!(channel_guard.name.to_owned()),
+            source: Some(client_prefix),
+            command: "JOIN".into(),
+            params: smallvec!(channel_guard.name.to_owned()),
         };
 
-        for chan_user_weak in chan_users_guard.values() {
-            let chan_user = match chan_user_weak.upgrade() {
-                Some(user) => user,
-                None => continue,
-            };
-            let chan_user_guard = chan_user.read().await;
-            chan_user_guard.send(join_msg.clone()).await?;
+        let join_line = join_msg.to_shared_line();
+        for chan_member in chan_users_guard.values() {
+            chan_member.handle.send_line(join_line.clone());
         }
         drop(chan_users_guard);
 
-        let msgs = &channel_guard.get_join_msgs(&state, client_nick).await;
+        if let (true, Some(access)) = (access_given, access) {
+            channel_guard.send(Message {
+                tags: Vec::new(),
+                source: Some(Service::ChanServ.prefix(state)),
+                command: "MODE".into(),
+                params: smallvec!(channel_guard.name.to_owned(), format!("+{}", access.mode()), client_nick.to_owned()),
+            }, None).await?;
+        }
+
+        let msgs = &channel_guard.get_join_msgs(state, client_nick).await;
         client.send_all(msgs).await?;
+        state.emit(|| ServerEvent::Joined{nick: client_nick.clone(), channel: channel_guard.name.clone()});
+        // Channel messages sent since joining come after the JOIN and NAMES replies
+        client.handle().subscribe(&channel_guard.name, fanout);
     };
 
     Ok(())
@@ -92,12 +217,14 @@ pub async fn handle_part(state: Arc<ServerState>, client_lock: Arc<RwLock<Client
         None => return command_error(&state, &client, ReplyCode::ErrNeedMoreParams{cmd: "PART".to_owned()}).await,
     };
 
+    let reason = msg.params.get(1).map(String::as_str);
+
     let mut futs = Vec::new();
     for chan_name in chanlist {
         if !chan_name.starts_with('#') {
             command_error(&state, &client, ReplyCode::ErrNoSuchChannel{channel: chan_name.to_string()}).await?;
         } else {
-            futs.push(client.part(chan_name));
+            futs.push(client.part(chan_name, reason));
         }
     }
 
@@ -119,6 +246,39 @@ pub async fn handle_part(state: Arc<ServerState>, client_lock: Arc<RwLock<Client
     Ok(())
 }
 
+/// Checks a topic set by the embedder, which has to fit on one line and in the advertised TOPICLEN
+pub fn check_topic(state: &ServerState, text: &str) -> Result<(), Error> {
+    check_text(text)?;
+    if text.len() > state.runtime_settings().max_topic_length {
+        return Err(Error::new(ErrorKind::InvalidInput, "Topic is too long"));
+    }
+    Ok(())
+}
+
+/// Changes the topic of a channel and announces it from set_by, an empty text removes the topic
+pub async fn change_topic(state: &ServerState, channel_guard: &mut Channel, text: &str, set_by: String) -> Result<(), Error> {
+    // Registered channels remember their topic, to restore it after a restart
+    if let Some(registration) = state.channel_registrations.write().await.get_mut(&state.casemap(&channel_guard.name)) {
+        registration.topic = Some(text.to_owned()).filter(|text| !text.is_empty());
+    }
+
+    if text.is_empty() {
+        channel_guard.topic = None;
+    } else {
+        channel_guard.topic = Some(Topic {
+            text: text.to_owned(),
+            set_by_host: set_by.clone(),
+            set_at: Local::now(),
+        });
+    }
+    channel_guard.send(Message{
+        tags: Vec::new(),
+        source: Some(set_by),
+        command: "TOPIC".into(),
+        params: smallvec!(channel_guard.name.clone(), text.to_owned()),
+    }, None).await
+}
+
 pub async fn handle_topic(state: Arc<ServerState>, client: Arc<RwLock<Client>>, msg: Message) -> Result<(), Error> {
     let client = client.read().await;
     let target_chan = match msg.params.get(0) {
@@ -127,27 +287,23 @@ pub async fn handle_topic(state: Arc<ServerState>, client: Arc<RwLock<Client>>,
     };
     let topic_text = msg.params.get(1);
 
-    if let Some(channel_ref) = state.channels.lock().await.get(&target_chan.to_ascii_uppercase()) {
-        let channel_lock = channel_ref.clone();
+    if let Some(channel_lock) = state.channels.get(&state.casemap(target_chan)) {
         let mut channel_guard = channel_lock.write().await;
         let channel = channel_guard.name.clone();
 
         if let Some(text) = topic_text {
-            if text.is_empty() {
-                channel_guard.topic = None;
-            } else {
-                channel_guard.topic = Some(Topic {
-                    text: text.clone(),
-                    set_by_host: client.get_extended_prefix().unwrap(),
-                    set_at: Local::now(),
-                });
+            let member_mode = match channel_guard.get_member_mode(client.addr).await {
+                Some(member_mode) => member_mode,
+                None => return command_error(&state, &client, ReplyCode::ErrNotOnChannel{channel}).await,
+            };
+            if channel_guard.mode.topic_protected && !member_mode.is_at_least(halfop_rank(&state.settings.member_ranks)) {
+                return command_error(&state, &client, ReplyCode::ErrChanOPrivsNeeded{channel}).await;
             }
-            channel_guard.send(Message{
-                tags: Vec::new(),
-                source: Some(client.get_extended_prefix().expect("TOPIC change by user without a prefix!")),
-                command: "TOPIC".to_owned(),
-                params: vec!(channel, text.to_owned()),
-            }, None).await?;
+            if is_topic_locked_for(&state, &client, &channel).await {
+                return command_error(&state, &client, ReplyCode::ErrChanOPrivsNeeded{channel}).await;
+            }
+            let set_by = client.get_extended_prefix().expect("TOPIC change by user without a prefix!");
+            change_topic(&state, &mut channel_guard, text, set_by).await?;
         } else {
             let client_nick = client.get_nick().unwrap();
             if let Some(ref topic) = channel_guard.topic {
@@ -166,27 +322,157 @@ pub async fn handle_topic(state: Arc<ServerState>, client: Arc<RwLock<Client>>,
     Ok(())
 }
 
+/// Removes a member from a channel with a KICK from source, the caller removes the channel if it is left empty
+pub async fn kick_member(state: &ServerState, channel_guard: &Channel, target: &Client, source: String, reason: &str) -> Result<(), Error> {
+    channel_guard.send(Message {
+        tags: Vec::new(),
+        source: Some(source),
+        command: "KICK".into(),
+        params: smallvec!(channel_guard.name.clone(), target.get_nick().unwrap(), reason.to_owned()),
+    }, None).await?;
+
+    if let Some(member) = channel_guard.users.write().await.remove(&target.addr) {
+        member.handle.unsubscribe(channel_guard);
+    }
+    if let Some(delay) = channel_guard.mode.kick_rejoin_delay {
+        channel_guard.recent_kicks.write().await.record(target.addr, Duration::from_secs(delay));
+    }
+    target.channels.write().await.remove(&state.casemap(&channel_guard.name));
+    Ok(())
+}
+
+pub async fn handle_kick(state: Arc<ServerState>, client_lock: Arc<RwLock<Client>>, msg: Message) -> Result<(), Error> {
+    let client = client_lock.read().await;
+    let (chan_name, nicks) = match (msg.params.first(), msg.params.get(1)) {
+        (Some(chan_name), Some(nicks)) => (chan_name, nicks),
+        _ => return command_error(&state, &client, ReplyCode::ErrNeedMoreParams{cmd: "KICK".to_owned()}).await,
+    };
+    let reason = msg.params.get(2).cloned().unwrap_or_else(|| client.get_nick().unwrap());
+
+    let channel_lock = match state.channels.get(&state.casemap(chan_name)) {
+        Some(channel_lock) => channel_lock,
+        None => return command_error(&state, &client, ReplyCode::ErrNoSuchChannel{channel: chan_name.clone()}).await,
+    };
+    let channel_guard = channel_lock.read().await;
+    let kicker_mode = match channel_guard.get_member_mode(client.addr).await {
+        Some(member_mode) if member_mode.is_at_least(halfop_rank(&state.settings.member_ranks)) => member_mode,
+        Some(_) => return command_error(&state, &client, ReplyCode::ErrChanOPrivsNeeded{channel: channel_guard.name.clone()}).await,
+        None => return command_error(&state, &client, ReplyCode::ErrNotOnChannel{channel: channel_guard.name.clone()}).await,
+    };
+
+    for nick in nicks.split(',') {
+        let target_user = match state.find_user(nick) {
+            Some(target_user) => target_user,
+            None => {
+                command_error(&state, &client, ReplyCode::ErrNoSuchNick{nick: nick.to_owned()}).await?;
+                continue;
+            },
+        };
+        let target_guard = target_user.read().await;
+        let target_addr = target_guard.addr;
+        match channel_guard.get_member_mode(target_addr).await {
+            Some(target_mode) if target_mode.outranks(kicker_mode) => {
+                command_error(&state, &client, ReplyCode::ErrChanOPrivsNeeded{channel: channel_guard.name.clone()}).await?;
+                continue;
+            },
+            Some(_) => (),
+            None => {
+                command_error(&state, &client, ReplyCode::ErrUserNotInChannel{nick: nick.to_owned(), channel: channel_guard.name.clone()}).await?;
+                continue;
+            },
+        };
+
+        let kicker_prefix = client.get_extended_prefix().expect("KICK sent by user without a prefix!");
+        kick_member(&state, &channel_guard, &target_guard, kicker_prefix, &reason).await?;
+    }
+
+    if channel_guard.users.read().await.is_empty() && !channel_guard.mode.permanent {
+        state.remove_empty_channel(&channel_lock, &channel_guard).await;
+    }
+
+    Ok(())
+}
+
+pub async fn handle_invite(state: Arc<ServerState>, client_lock: Arc<RwLock<Client>>, msg: Message) -> Result<(), Error> {
+    let client = client_lock.read().await;
+    let (nick, chan_name) = match (msg.params.first(), msg.params.get(1)) {
+        (Some(nick), Some(chan_name)) => (nick, chan_name),
+        _ => return command_error(&state, &client, ReplyCode::ErrNeedMoreParams{cmd: "INVITE".to_owned()}).await,
+    };
+
+    let target_user = match state.find_user(nick) {
+        Some(target_user) => target_user,
+        None => return command_error(&state, &client, ReplyCode::ErrNoSuchNick{nick: nick.to_owned()}).await,
+    };
+    let channel_lock = match state.channels.get(&state.casemap(chan_name)) {
+        Some(channel_lock) => channel_lock,
+        None => return command_error(&state, &client, ReplyCode::ErrNoSuchChannel{channel: chan_name.clone()}).await,
+    };
+    let channel_guard = channel_lock.read().await;
+    let channel = channel_guard.name.clone();
+    match channel_guard.get_member_mode(client.addr).await {
+        // Anyone in the channel can invite, unless only operators can let people in
+        Some(member_mode) if channel_guard.mode.invite_only && !member_mode.is_at_least(halfop_rank(&state.settings.member_ranks)) => {
+            return command_error(&state, &client, ReplyCode::ErrChanOPrivsNeeded{channel}).await;
+        },
+        Some(_) => (),
+        None => return command_error(&state, &client, ReplyCode::ErrNotOnChannel{channel}).await,
+    };
+
+    let target_guard = target_user.read().await;
+    let target_nick = target_guard.get_nick().unwrap();
+    if channel_guard.users.read().await.contains_key(&target_guard.addr) {
+        return command_error(&state, &client, ReplyCode::ErrUserOnChannel{nick: target_nick, channel}).await;
+    }
+
+    channel_guard.invites.write().await.insert(target_guard.addr);
+    client.send(make_reply_msg(&state, &client.get_nick().unwrap(), ReplyCode::RplInviting{nick: target_nick.clone(), channel: channel.clone()})).await?;
+    target_guard.send(Message {
+        tags: Vec::new(),
+        source: Some(client.get_extended_prefix().expect("INVITE sent by user without a prefix!")),
+        command: "INVITE".into(),
+        params: smallvec!(target_nick, channel),
+    }).await
+}
+
+/// Asks the hooks whether to keep applied mode changes, a hook's error is sent back to the client
+async fn is_mode_change_accepted(state: &ServerState, client: &Client, target: &str, applied: &AppliedModes) -> Result<bool, Error> {
+    match state.hooks.on_mode_change(client, target, &applied.modestring, &applied.params).await {
+        Ok(accepted) => Ok(accepted),
+        Err(e) => {
+            client.send_fail("MODE", "MODE_REFUSED", &e.to_string()).await?;
+            Ok(false)
+        },
+    }
+}
+
 async fn handle_user_mode(state: Arc<ServerState>, client_lock: Arc<RwLock<Client>>,
-                          target: &str, modestring: Option<&String>) -> Result<(), Error> {
+                          target: &str, modestring: Option<&String>, mode_params: &[String]) -> Result<(), Error> {
     let mut client = client_lock.write().await;
     let client_nick = &client.get_nick().unwrap();
 
     if let Some(modestring) = modestring {
-        let applied = match client.mode.apply_modestring(modestring) {
-            Ok(applied) => applied,
-            Err((applied, _)) => {
-                command_error(&state, &client, ReplyCode::ErrUModeUnknownFlag).await?;
-                applied
-            }
-        };
+        let old_mode = client.mode.clone();
+        let (applied, unknown_modes) = client.mode.apply_user_modestring(modestring, mode_params);
+        if !unknown_modes.is_empty() {
+            command_error(&state, &client, ReplyCode::ErrUModeUnknownFlag).await?;
+        }
 
-        if !applied.is_empty() {
+        if !applied.is_empty() && !is_mode_change_accepted(&state, &client, target, &applied).await? {
+            client.mode = old_mode;
+        } else if !applied.is_empty() {
+            let snomask_changed = applied.modestring.contains('s');
+            let mut params: MessageParams = smallvec!(target.to_owned(), applied.modestring);
+            params.extend(applied.params);
             client.send(Message {
                 tags: Vec::new(),
                 source: Some(client_nick.to_owned()),
-                command: "MODE".to_owned(),
-                params: vec!(target.to_owned(), applied),
+                command: "MODE".into(),
+                params,
             }).await?;
+            if snomask_changed && !client.mode.snomask.is_empty() {
+                client.send(make_reply_msg(&state, client_nick, ReplyCode::RplSnomask { snomask: client.mode.snomask.to_string() })).await?;
+            }
         }
     } else {
         client.send(make_reply_msg(&state, &client_nick, ReplyCode::RplUModeIs { modestring: client.mode.to_string() })).await?;
@@ -194,36 +480,93 @@ async fn handle_user_mode(state: Arc<ServerState>, client_lock: Arc<RwLock<Clien
     Ok(())
 }
 
-async fn handle_channel_mode(state: Arc<ServerState>, client_lock: Arc<RwLock<Client>>,
+fn list_mode_msgs(state: &ServerState, client_nick: &str, channel: &Channel, list_mode: char) -> Vec<Message> {
+    let channel_name = channel.name.clone();
+    let (entries, end_msg) = match list_mode {
+        'b' => (&channel.mode.bans, make_reply_msg(state, client_nick, ReplyCode::RplEndOfBanList { channel: channel_name.clone() })),
+        'e' => (&channel.mode.ban_exceptions, make_reply_msg(state, client_nick, ReplyCode::RplEndOfExceptList { channel: channel_name.clone() })),
+        'I' => (&channel.mode.invite_exceptions, make_reply_msg(state, client_nick, ReplyCode::RplEndOfInviteList { channel: channel_name.clone() })),
+        'q' => (&channel.mode.quiets, make_reply_msg(state, client_nick, ReplyCode::RplEndOfQuietList { channel: channel_name.clone() })),
+        _ => return Vec::new(),
+    };
+
+    let mut msgs = Vec::new();
+    for entry in entries {
+        let mask = entry.mask.clone();
+        let set_by = entry.set_by.clone();
+        let set_at = entry.set_at;
+        let channel = channel_name.clone();
+        msgs.push(make_reply_msg(state, client_nick, match list_mode {
+            'b' => ReplyCode::RplBanList { channel, mask, set_by, set_at },
+            'e' => ReplyCode::RplExceptList { channel, mask, set_by, set_at },
+            'I' => ReplyCode::RplInviteList { channel, mask, set_by, set_at },
+            _ => ReplyCode::RplQuietList { channel, mask, set_by, set_at },
+        }));
+    }
+    msgs.push(end_msg);
+    msgs
+}
+
+/// Overrides let operators change modes with the highest rank, whether they are in the channel or not
+pub async fn handle_channel_mode(state: Arc<ServerState>, client_lock: Arc<RwLock<Client>>,
                           channel_lock: Arc<RwLock<Channel>>,
-                          target: &str, modestring: Option<&String>) -> Result<(), Error> {
+                          target: &str, modestring: Option<&String>, mode_params: &[String],
+                          is_override: bool) -> Result<(), Error> {
     let client = client_lock.read().await;
     let client_nick = &client.get_nick().unwrap();
     let mut channel = channel_lock.write().await;
 
     if let Some(modestring) = modestring {
-        // TODO: Implement channel permissions (PREFIX), and check if user is authorized to change channel modes
-
-        let applied = match channel.mode.apply_modestring(modestring) {
-            Ok(applied) => applied,
-            Err((applied, mode)) => {
-                command_error(&state, &client, ReplyCode::ErrUnknownMode{mode}).await?;
-                applied
-            }
+        let setter_mode = if is_override {
+            let mut highest_rank = MemberMode::default();
+            highest_rank.set_rank(0, true);
+            highest_rank
+        } else {
+            channel.get_member_mode(client.addr).await.unwrap_or_default()
         };
+        let set_by = client.get_extended_prefix().unwrap();
+        // Kept to undo the change if a hook refuses it
+        let old_mode = channel.mode.clone();
+        let old_member_modes: Vec<_> = channel.users.read().await.iter().map(|(&addr, member)| (addr, member.mode)).collect();
+        let change = channel.apply_modestring(modestring, mode_params, &set_by, setter_mode, &state.settings).await;
+        for &mode in &change.unknown_modes {
+            command_error(&state, &client, ReplyCode::ErrUnknownMode{mode}).await?;
+        }
+        if change.missing_privileges {
+            command_error(&state, &client, ReplyCode::ErrChanOPrivsNeeded{channel: channel.name.clone()}).await?;
+        }
+        for nick in change.missing_members {
+            command_error(&state, &client, ReplyCode::ErrUserNotInChannel{nick, channel: channel.name.clone()}).await?;
+        }
+
+        for &list_mode in &change.list_queries {
+            client.send_all(&list_mode_msgs(&state, client_nick, &channel, list_mode)).await?;
+        }
 
-        if !applied.is_empty() {
+        if !change.applied.is_empty() && !is_mode_change_accepted(&state, &client, target, &change.applied).await? {
+            channel.mode = old_mode;
+            let mut users = channel.users.write().await;
+            for (addr, mode) in old_member_modes {
+                if let Some(member) = users.get_mut(&addr) {
+                    member.mode = mode;
+                }
+            }
+        } else if !change.applied.is_empty() {
+            let mut params: MessageParams = smallvec!(target.to_owned(), change.applied.modestring);
+            params.extend(change.applied.params);
             channel.send(Message {
                 tags: Vec::new(),
-                source: Some(client.get_extended_prefix().unwrap()),
-                command: "MODE".to_owned(),
-                params: vec!(target.to_owned(), applied),
+                source: Some(if is_override { state.settings.server_name.clone() } else { set_by }),
+                command: "MODE".into(),
+                params,
             }, None).await?;
         }
     } else {
+        let is_member = channel.users.read().await.contains_key(&client.addr);
         client.send(make_reply_msg(&state, &client_nick, ReplyCode::RplChannelModeIs {
             channel: channel.name.clone(),
             modestring: channel.mode.to_string(),
+            mode_params: channel.mode.get_mode_params(is_member),
         })).await?;
         client.send(make_reply_msg(&state, &client_nick, ReplyCode::RplCreationTime {
             channel: channel.name.clone(),
@@ -242,19 +585,19 @@ pub async fn handle_mode(state: Arc<ServerState>, client_lock: Arc<RwLock<Client
         None => return command_error(&state, &client, ReplyCode::ErrNeedMoreParams{cmd: "MODE".to_owned()}).await,
     };
     let modestring = msg.params.get(1);
+    let mode_params = msg.params.get(2..).unwrap_or(&[]);
 
     if target.starts_with('#') {
-        if let Some(channel_ref) = state.channels.lock().await.get(&target.to_ascii_uppercase()) {
-            let channel_lock = channel_ref.clone();
+        if let Some(channel_lock) = state.channels.get(&state.casemap(target)) {
             drop(client);
-            handle_channel_mode(state.clone(), client_lock, channel_lock, target, modestring).await?;
+            handle_channel_mode(state.clone(), client_lock, channel_lock, target, modestring, mode_params, false).await?;
         } else {
             command_error(&state, &client, ReplyCode::ErrNoSuchChannel{channel: target.clone()}).await?;
         }
-    } else if target == client_nick {
+    } else if state.settings.casemapping.equals(target, client_nick) {
         drop(client);
-        handle_user_mode(state, client_lock, target, modestring).await?;
-    } else if state.users.read().await.contains_key(target) {
+        handle_user_mode(state, client_lock, target, modestring, mode_params).await?;
+    } else if state.users.contains_key(&state.casemap(target)) {
         command_error(&state, &client, ReplyCode::ErrUsersDontMatch).await?;
     } else {
         command_error(&state, &client, ReplyCode::ErrNoSuchNick{ nick: target.to_owned() }).await?;
@@ -271,14 +614,50 @@ pub async fn handle_names(state: Arc<ServerState>, client: Arc<RwLock<Client>>,
     };
 
     for target in targets.split(',') {
-        if let Some(channel_ref) = state.channels.lock().await.get(&target.to_ascii_uppercase()) {
-            let channel_lock = channel_ref.clone();
+        if let Some(channel_lock) = state.channels.get(&state.casemap(target)) {
             let channel = channel_lock.read().await;
 
-            client.send_all(&channel.get_names_msgs(&state, &client.get_nick().unwrap()).await).await?;
+            if channel.is_visible_to(client.addr).await {
+                client.send_all(&channel.get_names_msgs(&state, &client.get_nick().unwrap()).await).await?;
+            } else {
+                command_error(&state, &client, ReplyCode::RplEndOfNames { channel: target.to_owned() }).await?;
+            }
         } else {
             command_error(&state, &client, ReplyCode::RplEndOfNames { channel: target.to_owned() }).await?;
         }
     }
     Ok(())
 }
+
+pub async fn handle_list(state: Arc<ServerState>, client: Arc<RwLock<Client>>, msg: Message) -> Result<(), Error> {
+    let client = client.read().await;
+    let client_nick = &client.get_nick().unwrap();
+    let client_addr = client.addr;
+
+    let channel_locks: Vec<_> = match msg.params.first() {
+        Some(targets) => targets.split(',').filter_map(|target| state.channels.get(&state.casemap(target))).collect(),
+        None => state.channels.values(),
+    };
+
+    let mut msgs = vec!(make_reply_msg(&state, client_nick, ReplyCode::RplListStart));
+    for channel_lock in channel_locks {
+        let channel = channel_lock.read().await;
+        let is_member = channel.get_member_mode(client_addr).await.is_some();
+        if channel.mode.secret && !is_member {
+            continue;
+        }
+
+        // Like RFC 1459 says, private channels are listed without their topic
+        let topic = match channel.topic {
+            Some(ref topic) if is_member || !channel.mode.private => topic.text.clone(),
+            _ => String::new(),
+        };
+        msgs.push(make_reply_msg(&state, client_nick, ReplyCode::RplList {
+            channel: channel.name.clone(),
+            num_visible: channel.users.read().await.len(),
+            topic,
+        }));
+    }
+    msgs.push(make_reply_msg(&state, client_nick, ReplyCode::RplListEnd));
+    client.send_all(&msgs).await
+}
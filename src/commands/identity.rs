@@ -1,111 +1,198 @@
+use crate::account::{Account, AccountResult};
 use crate::client::{Client, ClientStatus};
 use crate::server::ServerState;
 use crate::message::{Message, make_reply_msg, ReplyCode};
 use crate::commands::command_error;
+use crate::events::ServerEvent;
+use crate::mode::operator_rank;
+use crate::nick_policy::{nick_skeleton, NickPolicy};
+use crate::services::{enforce_nick_registration, find_service};
+use crate::password::verify_password;
+use crate::snomask::SnoCategory;
+use crate::webirc::{is_valid_hostname, WebircInfo};
+use chrono::Local;
 use regex::Regex;
+use smallvec::smallvec;
 use std::io::Error;
+use std::net::IpAddr;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use lazy_static::lazy_static;
 
 lazy_static! {
-    static ref VALID_NICKNAME_REGEX: Regex = Regex::new(r"^[[:alpha:]\[\\\]\^_`\{\|\}][[:alnum:]\[\\\]\^_`\{\|\}\-]*$").unwrap();
     static ref BAD_USERNAME_CHARS_REGEX: Regex = Regex::new(r"[@\x00\x0D\x0A\x20]").unwrap();
 }
 
-fn is_valid_nick(max_len: usize, nick: &str) -> bool {
-    !nick.is_empty()
-        && nick.len() <= max_len
-        && VALID_NICKNAME_REGEX.is_match(nick)
-}
-
-fn make_valid_username(max_len: usize, username: &str) -> Option<String> {
+fn truncate_username(max_len: usize, username: &str) -> Option<String> {
     let mut username = username.to_owned();
-    username.truncate(max_len-1);
+    username.truncate(max_len);
     if let Some(mat) = BAD_USERNAME_CHARS_REGEX.find(&username).map(|mat| mat.start()) {
         username.truncate(mat);
     };
     if !username.is_empty() {
-        Some("~".to_owned()+&username)
+        Some(username)
     } else {
         None
     }
 }
 
+fn make_valid_username(max_len: usize, username: &str) -> Option<String> {
+    truncate_username(max_len-1, username).map(|username| "~".to_owned()+&username)
+}
+
+/// Whether a nick is used by someone else, or looks confusingly like it (with unicode nicks)
+/// The nicks of services are always taken
+pub async fn is_nick_taken(state: &ServerState, nick: &str) -> bool {
+    if find_service(state, nick).is_some() {
+        return true;
+    }
+    // A dead entry, like one left by a rename racing with a disconnect, doesn't keep the nick taken
+    if state.find_user(nick).is_some() {
+        return true;
+    }
+    let casemapped_nick = state.casemap(nick);
+    if state.settings.nick_policy == NickPolicy::Unicode {
+        let skeleton = nick_skeleton(&casemapped_nick);
+        return state.users.any(|user, _| nick_skeleton(user) == skeleton);
+    }
+    false
+}
+
+/// Returns the name of a +N channel that prevents this client from changing nick, if any
+/// Channel operators are not affected
+async fn find_nick_locked_channel(state: &ServerState, client: &Client) -> Option<String> {
+    let operator_rank = operator_rank(&state.settings.member_ranks);
+    for channel in client.channels.read().await.values() {
+        let channel = match channel.upgrade() {
+            Some(channel) => channel,
+            None => continue,
+        };
+        let channel = channel.read().await;
+        if !channel.mode.no_nick_change {
+            continue;
+        }
+        let member_mode = channel.get_member_mode(client.addr).await.unwrap_or_default();
+        if !member_mode.is_at_least(operator_rank) {
+            return Some(channel.name.clone());
+        }
+    }
+    None
+}
+
 pub async fn handle_nick(state: Arc<ServerState>, client_lock: Arc<RwLock<Client>>, msg: Message) -> Result<(), Error> {
+    {
+        let client = client_lock.read().await;
+        if let Some(channel) = find_nick_locked_channel(&state, &client).await {
+            return command_error(&state, &client, ReplyCode::ErrNoNickChange{channel}).await;
+        }
+    }
+
     let mut client = client_lock.write().await;
     let new_nick = match msg.params.get(0) {
         Some(nick) => nick,
         None => return command_error(&state, &client, ReplyCode::ErrNoNicknameGiven).await,
     };
-    if !is_valid_nick(state.settings.max_name_length, new_nick) {
-        let cur_nick = client.get_nick().unwrap_or_else(|| "*".to_owned());
-        return client.send(make_reply_msg(&state, &cur_nick, ReplyCode::ErrErroneusNickname{nick: new_nick.clone()})).await;
-    }
+    let forbidden_nicks = state.forbidden_nicks.read().await;
+    let valid_nick = state.settings.nick_policy.validate(state.runtime_settings().max_name_length, new_nick)
+        .filter(|nick| !forbidden_nicks.is_forbidden(state.settings.casemapping, nick));
+    drop(forbidden_nicks);
+    let new_nick = &match valid_nick {
+        Some(nick) => nick,
+        None => {
+            let cur_nick = client.get_nick().unwrap_or_else(|| "*".to_owned());
+            return client.send(make_reply_msg(&state, &cur_nick, ReplyCode::ErrErroneusNickname{nick: new_nick.clone()})).await;
+        },
+    };
 
-    if state.users.read().await.contains_key(&new_nick.to_ascii_uppercase()) {
+    if is_nick_taken(&state, new_nick).await {
         return command_error(&state, &client, ReplyCode::ErrNicknameInUse{nick: new_nick.clone()}).await;
     }
 
-    let old_extended_prefix = client.get_extended_prefix();
-    let old_nick = client.get_nick();
+    if let (ClientStatus::Normal(_), Some(cur_nick)) = (&client.status, client.get_nick()) {
+        let refusal = match state.hooks.on_nick_change(&client, &cur_nick, new_nick).await {
+            Ok(true) => None,
+            Ok(false) => Some(ReplyCode::ErrErroneusNickname{nick: new_nick.clone()}),
+            Err(e) => Some(ReplyCode::ErrNickRefused{nick: new_nick.clone(), reason: e.to_string()}),
+        };
+        if let Some(refusal) = refusal {
+            return command_error(&state, &client, refusal).await;
+        }
+    }
 
-    match client.status {
-        ClientStatus::Unregistered(ref mut state) => state.nick = Some(new_nick.clone()),
-        ClientStatus::Normal(ref mut state) => state.nick = new_nick.clone(),
-    };
+    let old_extended_prefix = client.get_extended_prefix();
+    let old_casemapped_nick = client.set_nick(new_nick);
 
-    if let ClientStatus::Unregistered{..} = client.status {
+    if let ClientStatus::Unregistered(ref client_state) = client.status {
+        let pass = client_state.password.clone();
         let should_finish = client.try_begin_registration().await?;
         drop(client);
         if should_finish {
-            client_lock.read().await.finish_registration().await?;
+            complete_registration(&state, &client_lock, pass).await?;
         }
         Ok(())
     } else {
         drop(client);
-        let client = client_lock.read().await;
+        announce_nick_change(&state, &*client_lock.read().await, &old_casemapped_nick.unwrap(), old_extended_prefix, new_nick).await?;
+        enforce_nick_registration(&state, &client_lock).await
+    }
+}
 
-        let mut users_map = state.users.write().await;
-        let old_user = users_map.remove(&old_nick.unwrap().to_ascii_uppercase());
-        users_map.insert(new_nick.to_ascii_uppercase(), old_user.unwrap());
+/// Updates the users list and tells everyone about a registered user's new nick
+async fn announce_nick_change(state: &ServerState, client: &Client, old_casemapped_nick: &str, old_extended_prefix: Option<String>, new_nick: &str) -> Result<(), Error> {
+    let old_user = state.users.remove(old_casemapped_nick);
+    state.users.insert(client.get_casemapped_nick().unwrap().to_owned(), old_user.unwrap());
 
-        client.broadcast(Message {
-            tags: Vec::new(),
-            source: old_extended_prefix,
-            command: "NICK".to_owned(),
-            params: vec!(new_nick.clone()),
-        }, true).await
+    let old_nick = old_extended_prefix.as_deref().and_then(|prefix| prefix.split('!').next()).unwrap_or_default().to_owned();
+    state.emit(|| ServerEvent::NickChanged{old_nick, new_nick: new_nick.to_owned()});
+
+    client.broadcast(Message {
+        tags: Vec::new(),
+        source: old_extended_prefix,
+        command: "NICK".into(),
+        params: smallvec!(new_nick.to_owned()),
+    }, true).await
+}
+
+/// Changes a registered user's nick without the checks of the NICK command
+/// The caller must make sure the new nick is valid and not taken
+pub async fn force_nick_change(state: &ServerState, client_lock: &RwLock<Client>, new_nick: &str) -> Result<(), Error> {
+    let mut client = client_lock.write().await;
+    if let ClientStatus::Unregistered(_) = client.status {
+        return Ok(());
     }
+    let old_nick = client.get_nick().unwrap();
+    state.hooks.on_nick_change(&client, &old_nick, new_nick).await.ok();
+    let old_extended_prefix = client.get_extended_prefix();
+    let old_casemapped_nick = client.set_nick(new_nick).unwrap();
+    drop(client);
+    announce_nick_change(state, &*client_lock.read().await, &old_casemapped_nick, old_extended_prefix, new_nick).await
 }
 
 pub async fn handle_user(state: Arc<ServerState>, client_lock: Arc<RwLock<Client>>, msg: Message) -> Result<(), Error> {
     let mut client = client_lock.write().await;
     let username = match msg.params.get(0) {
-        Some(username) => match make_valid_username(state.settings.max_name_length, username) {
+        Some(username) => match make_valid_username(state.runtime_settings().max_name_length, username) {
             Some(username) => username,
             None => {
-                let nick = client.get_nick().unwrap_or_else(|| "*".to_owned());
-                client.send(Message {
-                    tags: Vec::new(),
-                    source: Some(state.settings.server_name.clone()),
-                    command: "NOTICE".to_owned(),
-                    params: vec!(nick, "*** Your username is invalid. Please make sure that your username contains only alphanumeric characters.".to_owned()),
-                }).await?;
+                client.send_notice("*** Your username is invalid. Please make sure that your username contains only alphanumeric characters.").await?;
                 return client.close_with_error( "Invalid username").await;
             },
         },
-        None => return command_error(&state, &client, ReplyCode::ErrNeedMoreParams{cmd: msg.command}).await,
+        None => return command_error(&state, &client, ReplyCode::ErrNeedMoreParams{cmd: msg.command.into_owned()}).await,
     };
     let realname = match msg.params.get(3) {
         Some(realname) => realname,
-        None => return command_error(&state, &client, ReplyCode::ErrNeedMoreParams{cmd: msg.command}).await,
+        None => return command_error(&state, &client, ReplyCode::ErrNeedMoreParams{cmd: msg.command.into_owned()}).await,
     };
 
-    match client.status {
+    let pass = match client.status {
         ClientStatus::Unregistered(ref mut client_state) => {
-            client_state.username = Some(username.clone());
+            // A username confirmed by the client's identd is trusted, and doesn't get a ~
+            let ident = client_state.ident.as_deref()
+                .and_then(|ident| truncate_username(state.runtime_settings().max_name_length, ident));
+            client_state.username = Some(ident.unwrap_or(username));
             client_state.realname = Some(realname.clone());
+            client_state.password.clone()
         },
         _ => return command_error(&state, &client, ReplyCode::ErrAlreadyRegistered).await,
     };
@@ -113,17 +200,111 @@ pub async fn handle_user(state: Arc<ServerState>, client_lock: Arc<RwLock<Client
     let should_finish = client.try_begin_registration().await?;
     drop(client);
     if should_finish {
-        client_lock.read().await.finish_registration().await?;
+        complete_registration(&state, &client_lock, pass).await?;
+    }
+    Ok(())
+}
+
+pub async fn handle_pass(state: Arc<ServerState>, client_lock: Arc<RwLock<Client>>, msg: Message) -> Result<(), Error> {
+    let mut client = client_lock.write().await;
+    let password = match msg.params.first() {
+        Some(password) => password,
+        None => return command_error(&state, &client, ReplyCode::ErrNeedMoreParams{cmd: msg.command.into_owned()}).await,
+    };
+    match client.status {
+        ClientStatus::Unregistered(ref mut client_state) => client_state.password = Some(password.clone()),
+        _ => return command_error(&state, &client, ReplyCode::ErrAlreadyRegistered).await,
+    };
+    Ok(())
+}
+
+/// WEBIRC <password> <gateway> <hostname> <ip> [:options], sent by web gateways before registration
+pub async fn handle_webirc(state: Arc<ServerState>, client_lock: Arc<RwLock<Client>>, msg: Message) -> Result<(), Error> {
+    let client = client_lock.read().await;
+    if !matches!(client.status, ClientStatus::Unregistered(_)) {
+        return command_error(&state, &client, ReplyCode::ErrAlreadyRegistered).await;
+    }
+    let (password, hostname, ip) = match (msg.params.first(), msg.params.get(2), msg.params.get(3).and_then(|ip| ip.parse::<IpAddr>().ok())) {
+        (Some(password), Some(hostname), Some(ip)) => (password, hostname, ip),
+        _ => return client.close_with_error("Invalid WEBIRC parameters").await,
+    };
+    let gateway_ip = client.addr.ip();
+    drop(client);
+
+    let mut gateway = None;
+    for candidate in state.settings.webirc_gateways.iter().filter(|gateway| gateway.allows_ip(&gateway_ip)) {
+        if verify_password(password, &candidate.password_hash).await {
+            gateway = Some(candidate);
+            break;
+        }
     }
+    let mut client = client_lock.write().await;
+    let gateway = match gateway {
+        Some(gateway) => gateway,
+        None => return client.close_with_error("Invalid WEBIRC credentials").await,
+    };
+
+    // The gateway's identd says nothing about the end user
+    if let ClientStatus::Unregistered(ref mut client_state) = client.status {
+        client_state.ident = None;
+    }
+    let is_secure = msg.params.get(4).is_some_and(|options| options.split(' ').any(|option| option == "secure"));
+    client.is_secure |= is_secure;
+    client.webirc = Some(WebircInfo {
+        gateway: gateway.name.clone(),
+        ip,
+        hostname: if is_valid_hostname(hostname) { hostname.clone() } else { ip.to_string() },
+    });
     Ok(())
 }
 
+/// Logs a client in to an account if the password is right, and returns the account
+pub async fn log_in(state: &ServerState, client_lock: &RwLock<Client>, name: &str, password: &str) -> AccountResult<Option<Account>> {
+    let provider = &state.account_provider;
+    let mut account = match provider.verify_credentials(name, password).await? {
+        Some(account) => account,
+        None => return Ok(None),
+    };
+    account.last_seen = Local::now();
+    provider.update_account(&account).await?;
+    client_lock.write().await.account = Some(account.name.clone());
+    Ok(Some(account))
+}
+
+/// Logs in with the credentials given with PASS, as "account:password" or just the password of the nick's account
+async fn log_in_with_pass(state: &ServerState, client_lock: &RwLock<Client>, pass: &str) -> Result<(), Error> {
+    let nick = client_lock.read().await.get_nick().unwrap();
+    let (name, password) = pass.split_once(':').unwrap_or((&nick, pass));
+    let notice = match log_in(state, client_lock, name, password).await {
+        Ok(Some(account)) => format!("*** You are now logged in as {}", account.name),
+        Ok(None) => "*** Invalid account or password given with PASS".to_owned(),
+        Err(err) => {
+            state.snotice(SnoCategory::OperActions, &format!("Account provider error: {}", err)).await;
+            "*** Accounts are unavailable right now, you were not logged in".to_owned()
+        },
+    };
+    client_lock.read().await.send_notice(&notice).await
+}
+
+/// Welcomes a client that just sent NICK and USER, then logs them in if they gave credentials with PASS
+async fn complete_registration(state: &Arc<ServerState>, client_lock: &Arc<RwLock<Client>>, pass: Option<String>) -> Result<(), Error> {
+    client_lock.read().await.finish_registration().await?;
+    if let Some(pass) = pass {
+        log_in_with_pass(state, client_lock, &pass).await?;
+    }
+    enforce_nick_registration(state, client_lock).await
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::commands::COMMANDS_LIST;
     use std::collections::HashSet;
 
+    fn is_valid_nick(max_len: usize, nick: &str) -> bool {
+        NickPolicy::Ascii.validate(max_len, nick).is_some()
+    }
+
     fn is_valid_username(max_len: usize, username: &str) -> bool {
         match make_valid_username(max_len, username) {
             Some(fixed) => fixed == "~".to_owned()+username,
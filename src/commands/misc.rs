@@ -1,9 +1,15 @@
-use crate::client::{Client, ClientStatus};
+use crate::callbacks::PrivateMessageAction;
+use crate::client::{Client, ClientStatus, Mute};
 use crate::commands::command_error;
-use crate::message::{make_reply_msg, Message, ReplyCode};
+use crate::events::ServerEvent;
+use crate::formatting::{has_formatting, strip_formatting};
+use crate::mask::mask_matches;
+use crate::message::{ctcp_command, make_reply_msg, Message, ReplyCode};
 use crate::server::ServerState;
+use crate::services::{find_service, handle_service_message};
+use smallvec::smallvec;
 use std::io::{Error, ErrorKind};
-use std::sync::Arc;
+use std::sync::{Arc, Weak};
 use tokio::sync::RwLock;
 
 pub async fn handle_ping(
@@ -20,7 +26,7 @@ pub async fn handle_ping(
         .send(Message {
             tags: Vec::new(),
             source: Some(state.settings.server_name.clone()),
-            command: "PONG".to_owned(),
+            command: "PONG".into(),
             params: reply_params,
         })
         .await
@@ -121,11 +127,11 @@ pub async fn handle_privmsg(
 
 pub async fn handle_notice_or_privmsg(
     state: Arc<ServerState>,
-    client: Arc<RwLock<Client>>,
+    client_lock: Arc<RwLock<Client>>,
     msg: Message,
     is_notice: bool,
 ) -> Result<(), Error> {
-    let client = client.read().await;
+    let client = client_lock.read().await;
     let cmd_name = if is_notice {
         "NOTICE".to_owned()
     } else {
@@ -159,34 +165,138 @@ pub async fn handle_notice_or_privmsg(
         }
     };
 
-    if let Some(channel_ref) = state
-        .channels
-        .lock()
-        .await
-        .get(&target.to_ascii_uppercase())
-    {
-        let channel_lock = channel_ref.clone();
+    match client.mute {
+        Some(Mute::Silent) => return Ok(()),
+        Some(Mute::Notify) if is_notice => return Ok(()),
+        Some(Mute::Notify) => {
+            return client
+                .send_notice("*** You are muted, your message was not delivered")
+                .await
+        }
+        None => (),
+    }
+
+    if let Some(service) = find_service(&state, target) {
+        drop(client);
+        return if is_notice {
+            Ok(())
+        } else {
+            handle_service_message(&state, &client_lock, service, msg_text).await
+        };
+    }
+
+    if let Some(mask_target) = target.strip_prefix('$') {
+        let is_allowed = client
+            .oper_privileges()
+            .is_some_and(|privileges| privileges.global_messages);
+        if !is_allowed {
+            return command_error(&state, &client, ReplyCode::ErrNoPrivileges).await;
+        }
+        let prefix = client
+            .get_extended_prefix()
+            .expect("Message sent by user without a prefix!");
+        let msg = Message {
+            tags: Vec::new(),
+            source: Some(prefix),
+            command: cmd_name.into(),
+            params: smallvec![target.clone(), msg_text.to_owned()],
+        };
+        drop(client);
+        return send_to_mask(&state, &client_lock, mask_target, msg).await;
+    }
+
+    if let Some(channel_lock) = state.channels.get(&state.casemap(target)) {
         let channel_guard = channel_lock.read().await;
 
-        if channel_guard.mode.no_external_msgs {
-            let users = channel_guard.users.read().await;
-            if !users.contains_key(&client.addr.to_string()) {
+        let member_mode = channel_guard
+            .get_member_mode(client.addr)
+            .await;
+        if channel_guard.mode.no_external_msgs && member_mode.is_none() {
+            if !is_notice {
+                command_error(
+                    &state,
+                    &client,
+                    ReplyCode::ErrCannotSendToChan {
+                        channel: target.clone(),
+                        reason: "Cannot send to channel (+n is set)".to_string(),
+                    },
+                )
+                .await?;
+            }
+            return Ok(());
+        }
+
+        let prefix = client
+            .get_extended_prefix()
+            .expect("Message sent by user without a prefix!");
+        let ban_target = client.get_ban_target().await.unwrap();
+        let extbans = &state.settings.extbans;
+        let is_voiced = member_mode.is_some_and(|mode| mode.highest_rank().is_some());
+        if !is_voiced && channel_guard.mode.is_banned(extbans, &ban_target) {
+            if !is_notice {
+                command_error(
+                    &state,
+                    &client,
+                    ReplyCode::ErrCannotSendToChan {
+                        channel: target.clone(),
+                        reason: "Cannot send to channel (+b is set)".to_string(),
+                    },
+                )
+                .await?;
+            }
+            return Ok(());
+        }
+        if !is_voiced && channel_guard.mode.is_quieted(extbans, &ban_target) {
+            if !is_notice {
+                command_error(
+                    &state,
+                    &client,
+                    ReplyCode::ErrCannotSendToChan {
+                        channel: target.clone(),
+                        reason: "Cannot send to channel (+q is set)".to_string(),
+                    },
+                )
+                .await?;
+            }
+            return Ok(());
+        }
+
+        let is_ctcp_request = ctcp_command(msg_text).is_some_and(|command| command != "ACTION");
+        if channel_guard.mode.no_ctcp && is_ctcp_request {
+            if !is_notice {
+                command_error(
+                    &state,
+                    &client,
+                    ReplyCode::ErrCannotSendToChan {
+                        channel: target.clone(),
+                        reason: "Cannot send to channel (+C is set)".to_string(),
+                    },
+                )
+                .await?;
+            }
+            return Ok(());
+        }
+
+        let mut msg_text = msg_text.to_owned();
+        if channel_guard.mode.no_colors && has_formatting(&msg_text) {
+            if state.settings.reject_formatting {
                 if !is_notice {
                     command_error(
                         &state,
                         &client,
                         ReplyCode::ErrCannotSendToChan {
                             channel: target.clone(),
-                            reason: "Cannot send to channel (+n is set)".to_string(),
+                            reason: "Cannot send to channel (+c is set)".to_string(),
                         },
                     )
                     .await?;
                 }
                 return Ok(());
             }
+            msg_text = strip_formatting(&msg_text);
         }
 
-        match (state.callbacks.on_client_channel_message)(&client, &channel_guard, &msg) {
+        match state.hooks.on_client_channel_message(&client, &channel_guard, &msg).await {
             Ok(true) => (),
             Ok(false) => return Ok(()),
             Err(e) => {
@@ -206,27 +316,29 @@ pub async fn handle_notice_or_privmsg(
             }
         }
 
+        state.emit(|| ServerEvent::ChannelMessage {
+            nick: client.get_nick().unwrap(),
+            channel: channel_guard.name.clone(),
+            text: msg_text.clone(),
+            is_notice,
+        });
         channel_guard
             .send(
                 Message {
                     tags: Vec::new(),
-                    source: Some(
-                        client
-                            .get_extended_prefix()
-                            .expect("Message sent by user without a prefix!"),
-                    ),
-                    command: cmd_name.clone(),
-                    params: vec![channel_guard.name.to_owned(), msg_text.to_owned()],
+                    source: Some(prefix),
+                    command: cmd_name.clone().into(),
+                    params: smallvec![channel_guard.name.to_owned(), msg_text],
                 },
-                Some(client.addr.to_string()),
+                Some(client.addr),
             )
             .await
-    } else if target.to_ascii_uppercase()
-        == client
+    } else if state.settings.casemapping.equals(
+        target,
+        &client
             .get_nick()
-            .expect("Message sent by user without a nick!")
-            .to_ascii_uppercase()
-    {
+            .expect("Message sent by user without a nick!"),
+    ) {
         let nick = client.get_nick().unwrap();
         let prefix = Some(
             client
@@ -234,48 +346,45 @@ pub async fn handle_notice_or_privmsg(
                 .expect("Message sent by user without a prefix!"),
         );
         if is_notice {
-            Ok(())
-        } else {
-            client
-                .send(Message {
-                    tags: Vec::new(),
-                    source: prefix,
-                    command: cmd_name.clone(),
-                    params: vec![nick, msg_text.to_owned()],
-                })
-                .await
+            return Ok(());
         }
-    } else if let Some(target_user) = state.users.read().await.get(&target.to_ascii_uppercase()) {
-        let target_user = match target_user.upgrade() {
-            Some(target_user) => target_user,
-            None => {
-                return if is_notice {
-                    Ok(())
-                } else {
-                    command_error(
-                        &state,
-                        &client,
-                        ReplyCode::ErrNoSuchNick {
-                            nick: target.clone(),
-                        },
-                    )
-                    .await
-                }
-            }
+        let msg_text = match filter_private_message(&state, &client, &client, &msg).await? {
+            Some(msg_text) => msg_text,
+            None => return Ok(()),
         };
+        client
+            .send(Message {
+                tags: Vec::new(),
+                source: prefix,
+                command: cmd_name.clone().into(),
+                params: smallvec![nick, msg_text],
+            })
+            .await
+    } else if let Some(target_user) = state.find_user(target) {
         let target_user = target_user.read().await;
         let nick = target_user.get_nick().unwrap();
+        if target_user.mode.registered_only_pms && client.account.is_none() {
+            return if is_notice {
+                Ok(())
+            } else {
+                command_error(&state, &client, ReplyCode::ErrNoNonReg { nick }).await
+            };
+        }
         let prefix = Some(
             client
                 .get_extended_prefix()
                 .expect("Message sent by user without a prefix!"),
         );
+        let msg_text = match filter_private_message(&state, &client, &target_user, &msg).await? {
+            Some(msg_text) => msg_text,
+            None => return Ok(()),
+        };
         target_user
             .send(Message {
                 tags: Vec::new(),
                 source: prefix,
-                command: cmd_name.clone(),
-                params: vec![nick, msg_text.to_owned()],
+                command: cmd_name.clone().into(),
+                params: smallvec![nick, msg_text],
             })
             .await
     } else if is_notice {
@@ -292,6 +401,69 @@ pub async fn handle_notice_or_privmsg(
     }
 }
 
+/// Asks the hooks what to do with a message between users, returns the text to deliver or None to drop it
+/// A hook's error is sent back to the client, unless the message was a NOTICE
+async fn filter_private_message(
+    state: &ServerState,
+    client: &Client,
+    target: &Client,
+    msg: &Message,
+) -> Result<Option<String>, Error> {
+    let msg_text = &msg.params[1];
+    match state
+        .hooks
+        .on_client_private_message(client, target, msg)
+        .await
+    {
+        Ok(PrivateMessageAction::Deliver) => Ok(Some(msg_text.to_owned())),
+        Ok(PrivateMessageAction::Rewrite(msg_text)) => Ok(Some(msg_text)),
+        Ok(PrivateMessageAction::Drop) => Ok(None),
+        Err(_) if &*msg.command == "NOTICE" => Ok(None),
+        Err(e) => {
+            let reply = ReplyCode::ErrCannotSendToUser {
+                nick: target.get_nick().unwrap(),
+                reason: e.to_string(),
+            };
+            command_error(state, client, reply).await?;
+            Ok(None)
+        }
+    }
+}
+
+/// Sends a message to every other user matching a mask target without its leading '$'
+/// "$server.mask" matches users by server name, and "#host.mask" matches them by host
+async fn send_to_mask(
+    state: &ServerState,
+    sender: &Arc<RwLock<Client>>,
+    mask_target: &str,
+    msg: Message,
+) -> Result<(), Error> {
+    let users: Vec<_> = state
+        .users
+        .values()
+        .iter()
+        .filter_map(Weak::upgrade)
+        .filter(|user| !Arc::ptr_eq(user, sender))
+        .collect();
+    if let Some(server_mask) = mask_target.strip_prefix('$') {
+        // Every user is connected to this server, so they all match or none do
+        if !mask_matches(server_mask, &state.settings.server_name) {
+            return Ok(());
+        }
+        for user in users {
+            user.read().await.send(msg.clone()).await?;
+        }
+    } else if let Some(host_mask) = mask_target.strip_prefix('#') {
+        for user in users {
+            let user = user.read().await;
+            if mask_matches(host_mask, &user.get_host()) {
+                user.send(msg.clone()).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
 pub async fn handle_quit(
     _: Arc<ServerState>,
     client: Arc<RwLock<Client>>,
@@ -312,15 +484,15 @@ pub async fn handle_quit(
             Message {
                 tags: Vec::new(),
                 source: Some(client.get_extended_prefix().unwrap()),
-                command: "QUIT".to_owned(),
-                params: vec![reason.clone()],
+                command: "QUIT".into(),
+                params: smallvec![reason.clone()],
             },
             true,
         )
         .await?;
 
-    let mut channels = client.channels.write().await;
-    channels.clear();
+    // So the QUIT isn't sent again when the connection closes
+    client.leave_all_channels().await;
 
     // We return an "error" to signal the quit
     Err(Error::new(ErrorKind::Other, reason.clone()))
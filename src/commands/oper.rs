@@ -0,0 +1,542 @@
+use crate::client::Client;
+use crate::commands::{command_error, handle_channel_mode, join_channels};
+use crate::forbidden::ForbiddenNames;
+use crate::message::{make_reply_msg, Message, ReplyCode};
+use crate::oper::OperPrivileges;
+use crate::password::verify_password;
+use crate::server::ServerState;
+use crate::snomask::SnoCategory;
+use smallvec::smallvec;
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::sync::RwLock;
+
+pub async fn handle_oper(
+    state: Arc<ServerState>,
+    client_lock: Arc<RwLock<Client>>,
+    msg: Message,
+) -> Result<(), Error> {
+    let client = client_lock.read().await;
+    let (name, password) = match (msg.params.first(), msg.params.get(1)) {
+        (Some(name), Some(password)) => (name, password),
+        _ => {
+            return command_error(
+                &state,
+                &client,
+                ReplyCode::ErrNeedMoreParams {
+                    cmd: msg.command.into_owned(),
+                },
+            )
+            .await
+        }
+    };
+
+    let prefix = client.get_extended_prefix().unwrap();
+    let oper = state
+        .settings
+        .opers
+        .iter()
+        .find(|oper| &oper.name == name && oper.allows_prefix(&prefix));
+    let oper = match oper {
+        Some(oper) => oper,
+        None => return command_error(&state, &client, ReplyCode::ErrNoOperHost).await,
+    };
+    drop(client);
+
+    if !verify_password(password, &oper.password_hash).await {
+        let client = client_lock.read().await;
+        return command_error(&state, &client, ReplyCode::ErrPasswdMismatch).await;
+    }
+
+    let mut client = client_lock.write().await;
+    let nick = client.get_nick().unwrap();
+    client.mode.is_oper = true;
+    client.oper_privileges = oper.privileges;
+
+    client
+        .send(make_reply_msg(&state, &nick, ReplyCode::RplYoureOper))
+        .await?;
+    client
+        .send(Message {
+            tags: Vec::new(),
+            source: Some(nick.clone()),
+            command: "MODE".into(),
+            params: smallvec![nick, "+o".to_owned()],
+        })
+        .await?;
+    drop(client);
+
+    let notice = format!(
+        "{} is now an operator (using oper block {})",
+        prefix, oper.name
+    );
+    state.snotice(SnoCategory::OperActions, &notice).await;
+    state.audit(&prefix, "OPER", &oper.name).await;
+    Ok(())
+}
+
+/// Whether the client is an operator with a privilege
+fn has_privilege(client: &Client, privilege: fn(&OperPrivileges) -> bool) -> bool {
+    client.oper_privileges().is_some_and(privilege)
+}
+
+/// Sends a server notice to all operators
+pub async fn notify_opers(state: &ServerState, text: &str) -> Result<(), Error> {
+    let users: Vec<_> = state
+        .users
+        .values()
+        .iter()
+        .filter_map(Weak::upgrade)
+        .collect();
+    for user in users {
+        let user = user.read().await;
+        if user.mode.is_oper {
+            user.send_notice(text).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Adds or removes a mask from a list of forbidden names, for operators that can manage bans
+/// The kind of ban is used in the notices, like "Q-line"
+async fn edit_forbidden_names(
+    state: &ServerState,
+    client: &Client,
+    msg: Message,
+    names: &RwLock<ForbiddenNames>,
+    kind: &str,
+    add: bool,
+) -> Result<(), Error> {
+    if !has_privilege(client, |privileges| privileges.manage_bans) {
+        return command_error(state, client, ReplyCode::ErrNoPrivileges).await;
+    }
+    let mask = match msg.params.first() {
+        Some(mask) => mask,
+        None => {
+            return command_error(
+                state,
+                client,
+                ReplyCode::ErrNeedMoreParams {
+                    cmd: msg.command.into_owned(),
+                },
+            )
+            .await
+        }
+    };
+
+    let mut names = names.write().await;
+    let changed = if add {
+        names.add(mask)
+    } else {
+        names.remove(mask)
+    };
+    drop(names);
+    if changed {
+        let actor = client.get_extended_prefix().unwrap();
+        let command = msg.command.to_ascii_uppercase();
+        state.audit(&actor, &command, mask).await;
+        let action = if add { "added" } else { "removed" };
+        let notice = format!(
+            "{} {} {} for {}",
+            client.get_nick().unwrap(),
+            action,
+            kind,
+            mask
+        );
+        state.snotice(SnoCategory::OperActions, &notice).await;
+    }
+    let notice = match (add, changed) {
+        (true, true) => format!("*** Added {} for {}", kind, mask),
+        (true, false) => format!("*** {} for {} already exists", kind, mask),
+        (false, true) => format!("*** Removed {} for {}", kind, mask),
+        (false, false) => format!("*** No {} for {}", kind, mask),
+    };
+    client.send_notice(&notice).await
+}
+
+pub async fn handle_qline(
+    state: Arc<ServerState>,
+    client: Arc<RwLock<Client>>,
+    msg: Message,
+) -> Result<(), Error> {
+    let client = client.read().await;
+    edit_forbidden_names(&state, &client, msg, &state.forbidden_nicks, "Q-line", true).await
+}
+
+pub async fn handle_unqline(
+    state: Arc<ServerState>,
+    client: Arc<RwLock<Client>>,
+    msg: Message,
+) -> Result<(), Error> {
+    let client = client.read().await;
+    edit_forbidden_names(
+        &state,
+        &client,
+        msg,
+        &state.forbidden_nicks,
+        "Q-line",
+        false,
+    )
+    .await
+}
+
+pub async fn handle_cban(
+    state: Arc<ServerState>,
+    client: Arc<RwLock<Client>>,
+    msg: Message,
+) -> Result<(), Error> {
+    let client = client.read().await;
+    let channels = &state.forbidden_channels;
+    edit_forbidden_names(&state, &client, msg, channels, "channel ban", true).await
+}
+
+pub async fn handle_uncban(
+    state: Arc<ServerState>,
+    client: Arc<RwLock<Client>>,
+    msg: Message,
+) -> Result<(), Error> {
+    let client = client.read().await;
+    let channels = &state.forbidden_channels;
+    edit_forbidden_names(&state, &client, msg, channels, "channel ban", false).await
+}
+
+pub async fn handle_samode(
+    state: Arc<ServerState>,
+    client_lock: Arc<RwLock<Client>>,
+    msg: Message,
+) -> Result<(), Error> {
+    let client = client_lock.read().await;
+    if !has_privilege(&client, |privileges| privileges.override_channels) {
+        return command_error(&state, &client, ReplyCode::ErrNoPrivileges).await;
+    }
+    let (target, modestring) = match (msg.params.first(), msg.params.get(1)) {
+        (Some(target), Some(modestring)) => (target, modestring),
+        _ => {
+            return command_error(
+                &state,
+                &client,
+                ReplyCode::ErrNeedMoreParams {
+                    cmd: msg.command.into_owned(),
+                },
+            )
+            .await
+        }
+    };
+    let channel_lock = match state.channels.get(&state.casemap(target)) {
+        Some(channel) => channel,
+        None => {
+            return command_error(
+                &state,
+                &client,
+                ReplyCode::ErrNoSuchChannel {
+                    channel: target.clone(),
+                },
+            )
+            .await
+        }
+    };
+    let actor = client.get_extended_prefix().unwrap();
+    let audit_target = msg.params.join(" ");
+    let notice = format!(
+        "{} used SAMODE: {}",
+        client.get_nick().unwrap(),
+        audit_target
+    );
+    drop(client);
+
+    let mode_params = &msg.params[2..];
+    handle_channel_mode(
+        state.clone(),
+        client_lock,
+        channel_lock,
+        target,
+        Some(modestring),
+        mode_params,
+        true,
+    )
+    .await?;
+    state.snotice(SnoCategory::OperActions, &notice).await;
+    state.audit(&actor, "SAMODE", &audit_target).await;
+    Ok(())
+}
+
+pub async fn handle_sajoin(
+    state: Arc<ServerState>,
+    client: Arc<RwLock<Client>>,
+    msg: Message,
+) -> Result<(), Error> {
+    let client = client.read().await;
+    if !has_privilege(&client, |privileges| privileges.override_channels) {
+        return command_error(&state, &client, ReplyCode::ErrNoPrivileges).await;
+    }
+    let (nick, chanlist) = match (msg.params.first(), msg.params.get(1)) {
+        (Some(nick), Some(chanlist)) => (nick, chanlist),
+        _ => {
+            return command_error(
+                &state,
+                &client,
+                ReplyCode::ErrNeedMoreParams {
+                    cmd: msg.command.into_owned(),
+                },
+            )
+            .await
+        }
+    };
+    let user = match state.find_user(nick) {
+        Some(user) => user,
+        None => {
+            return command_error(
+                &state,
+                &client,
+                ReplyCode::ErrNoSuchNick { nick: nick.clone() },
+            )
+            .await
+        }
+    };
+    let actor = client.get_extended_prefix().unwrap();
+    let notice = format!(
+        "{} used SAJOIN to make {} join {}",
+        client.get_nick().unwrap(),
+        nick,
+        chanlist
+    );
+    drop(client);
+
+    let join_queue: VecDeque<_> = chanlist
+        .split(',')
+        .map(|chan_name| (chan_name.to_owned(), None, false))
+        .collect();
+    join_channels(&state, &user, join_queue, true).await?;
+    state.snotice(SnoCategory::OperActions, &notice).await;
+    let audit_target = format!("{} {}", nick, chanlist);
+    state.audit(&actor, "SAJOIN", &audit_target).await;
+    Ok(())
+}
+
+pub async fn handle_sapart(
+    state: Arc<ServerState>,
+    client: Arc<RwLock<Client>>,
+    msg: Message,
+) -> Result<(), Error> {
+    let client = client.read().await;
+    if !has_privilege(&client, |privileges| privileges.override_channels) {
+        return command_error(&state, &client, ReplyCode::ErrNoPrivileges).await;
+    }
+    let (nick, chanlist) = match (msg.params.first(), msg.params.get(1)) {
+        (Some(nick), Some(chanlist)) => (nick, chanlist),
+        _ => {
+            return command_error(
+                &state,
+                &client,
+                ReplyCode::ErrNeedMoreParams {
+                    cmd: msg.command.into_owned(),
+                },
+            )
+            .await
+        }
+    };
+    let reason = msg.params.get(2).map(String::as_str);
+    let user = match state.find_user(nick) {
+        Some(user) => user,
+        None => {
+            return command_error(
+                &state,
+                &client,
+                ReplyCode::ErrNoSuchNick { nick: nick.clone() },
+            )
+            .await
+        }
+    };
+
+    let user = user.read().await;
+    for chan_name in chanlist.split(',') {
+        match user.part(chan_name, reason).await {
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                command_error(
+                    &state,
+                    &client,
+                    ReplyCode::ErrUserNotInChannel {
+                        nick: nick.clone(),
+                        channel: chan_name.to_owned(),
+                    },
+                )
+                .await?
+            }
+            result => result?,
+        }
+    }
+    drop(user);
+
+    let actor = client.get_extended_prefix().unwrap();
+    let notice = format!(
+        "{} used SAPART to make {} part {}",
+        client.get_nick().unwrap(),
+        nick,
+        chanlist
+    );
+    drop(client);
+    state.snotice(SnoCategory::OperActions, &notice).await;
+    let audit_target = format!("{} {}", nick, chanlist);
+    state.audit(&actor, "SAPART", &audit_target).await;
+    Ok(())
+}
+
+pub async fn handle_globops(
+    state: Arc<ServerState>,
+    client: Arc<RwLock<Client>>,
+    msg: Message,
+) -> Result<(), Error> {
+    let client = client.read().await;
+    if !has_privilege(&client, |privileges| privileges.global_messages) {
+        return command_error(&state, &client, ReplyCode::ErrNoPrivileges).await;
+    }
+    let text = match msg.params.first() {
+        Some(text) if !text.is_empty() => text,
+        _ => {
+            return command_error(
+                &state,
+                &client,
+                ReplyCode::ErrNeedMoreParams {
+                    cmd: msg.command.into_owned(),
+                },
+            )
+            .await
+        }
+    };
+    let notice = format!(
+        "*** Global -- from {}: {}",
+        client.get_nick().unwrap(),
+        text
+    );
+    drop(client);
+
+    notify_opers(&state, &notice).await
+}
+
+pub async fn handle_shun(
+    state: Arc<ServerState>,
+    client: Arc<RwLock<Client>>,
+    msg: Message,
+) -> Result<(), Error> {
+    let client = client.read().await;
+    if !has_privilege(&client, |privileges| privileges.manage_bans) {
+        return command_error(&state, &client, ReplyCode::ErrNoPrivileges).await;
+    }
+    let (mask, duration) = match (msg.params.first(), msg.params.get(1)) {
+        (Some(mask), Some(duration)) => (mask, duration),
+        _ => {
+            return command_error(
+                &state,
+                &client,
+                ReplyCode::ErrNeedMoreParams {
+                    cmd: msg.command.into_owned(),
+                },
+            )
+            .await
+        }
+    };
+    let duration = match duration.parse() {
+        Ok(secs) if secs > 0 => Duration::from_secs(secs),
+        _ => {
+            let notice = format!("*** Invalid shun duration {}, expected seconds", duration);
+            return client.send_notice(&notice).await;
+        }
+    };
+    let reason = msg.params.get(2).map_or("No reason", String::as_str);
+
+    state.shuns.write().await.add(mask, duration, reason);
+    let notice = format!(
+        "{} added shun for {} for {} seconds ({})",
+        client.get_nick().unwrap(),
+        mask,
+        duration.as_secs(),
+        reason
+    );
+    state.snotice(SnoCategory::OperActions, &notice).await;
+    let actor = client.get_extended_prefix().unwrap();
+    let audit_target = format!("{} {} ({})", mask, duration.as_secs(), reason);
+    state.audit(&actor, "SHUN", &audit_target).await;
+    let notice = format!("*** Added shun for {}", mask);
+    client.send_notice(&notice).await
+}
+
+pub async fn handle_unshun(
+    state: Arc<ServerState>,
+    client: Arc<RwLock<Client>>,
+    msg: Message,
+) -> Result<(), Error> {
+    let client = client.read().await;
+    if !has_privilege(&client, |privileges| privileges.manage_bans) {
+        return command_error(&state, &client, ReplyCode::ErrNoPrivileges).await;
+    }
+    let mask = match msg.params.first() {
+        Some(mask) => mask,
+        None => {
+            return command_error(
+                &state,
+                &client,
+                ReplyCode::ErrNeedMoreParams {
+                    cmd: msg.command.into_owned(),
+                },
+            )
+            .await
+        }
+    };
+
+    if !state.shuns.write().await.remove(mask) {
+        let notice = format!("*** No shun for {}", mask);
+        return client.send_notice(&notice).await;
+    }
+    let notice = format!("{} removed shun for {}", client.get_nick().unwrap(), mask);
+    state.snotice(SnoCategory::OperActions, &notice).await;
+    let actor = client.get_extended_prefix().unwrap();
+    state.audit(&actor, "UNSHUN", mask).await;
+    let notice = format!("*** Removed shun for {}", mask);
+    client.send_notice(&notice).await
+}
+
+pub async fn handle_stats(
+    state: Arc<ServerState>,
+    client: Arc<RwLock<Client>>,
+    msg: Message,
+) -> Result<(), Error> {
+    let client = client.read().await;
+    let letter = match msg.params.first().and_then(|query| query.chars().next()) {
+        Some(letter) => letter,
+        None => {
+            return command_error(
+                &state,
+                &client,
+                ReplyCode::ErrNeedMoreParams {
+                    cmd: msg.command.into_owned(),
+                },
+            )
+            .await
+        }
+    };
+    let nick = client.get_nick().unwrap();
+
+    if letter == 'A' {
+        if client.oper_privileges().is_none() {
+            return command_error(&state, &client, ReplyCode::ErrNoPrivileges).await;
+        }
+        let entries: Vec<_> = state
+            .audit_log
+            .read()
+            .await
+            .entries()
+            .map(ToString::to_string)
+            .collect();
+        for entry in entries {
+            let reply = ReplyCode::RplStatsDebug {
+                letter,
+                text: entry,
+            };
+            client.send(make_reply_msg(&state, &nick, reply)).await?;
+        }
+    }
+    let reply = ReplyCode::RplEndOfStats { letter };
+    client.send(make_reply_msg(&state, &nick, reply)).await
+}
@@ -2,27 +2,34 @@ use crate::client::{Client};
 use crate::server::ServerState;
 use crate::message::{Message, make_reply_msg, ReplyCode};
 use crate::commands::command_error;
+use crate::mode::MemberMode;
 use std::io::{Error};
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use std::collections::{HashSet};
 
-fn who_reply_for_user(state: &ServerState, asker_nick: &str, chan_name: String, user: &Client) -> Message {
+fn who_reply_for_user(state: &ServerState, asker_nick: &str, chan_name: String, user: &Client, member_mode: MemberMode) -> Message {
+    // I believe H means Here, and G is Gone/Away
+    let mut status = "H".to_owned();
+    if let Some(prefix) = member_mode.prefix(&state.settings.member_ranks) {
+        status.push(prefix);
+    }
+
     make_reply_msg(&state, asker_nick, ReplyCode::RplWhoReply{
         channel: chan_name,
         user: user.get_username().unwrap(),
         host: user.get_host(),
         server: state.settings.server_name.clone(),
         nick: user.get_nick().unwrap(),
-        status: 'H', // I believe H means Here, and G is Gone/Away
+        status,
         hopcount: 0,
         realname: user.get_realname().unwrap(),
     })
 }
 
-fn user_matches_mask(user: &Client, mask: &str) -> bool {
+fn user_matches_mask(user: &Client, casemapped_mask: &str) -> bool {
     // TODO: Handle wildcards
-    user.get_nick().unwrap() == mask
+    user.get_casemapped_nick() == Some(casemapped_mask)
 }
 
 pub async fn handle_who(state: Arc<ServerState>, client: Arc<RwLock<Client>>, msg: Message) -> Result<(), Error> {
@@ -38,20 +45,28 @@ pub async fn handle_who(state: Arc<ServerState>, client: Arc<RwLock<Client>>, ms
     }
 
     let mut messages = Vec::new();
-    if let Some(channel_ref) = state.channels.lock().await.get(&mask.to_ascii_uppercase()) {
-        let channel_lock = channel_ref.clone();
+    if let Some(channel_lock) = state.channels.get(&state.casemap(mask)) {
         let channel_guard = channel_lock.read().await;
+        if !channel_guard.is_visible_to(client.addr).await {
+            return client.send(make_reply_msg(&state, &client.get_nick().unwrap(), ReplyCode::RplEndOfWho{mask: mask.to_owned()})).await;
+        }
         let channel_users_guard = channel_guard.users.read().await;
 
-        for weak_user in channel_users_guard.values() {
-            let user_lock = match weak_user.upgrade() {
+        let mut found_dead = false;
+        for member in channel_users_guard.values() {
+            let user_lock = match member.client.upgrade() {
                 Some(user) => user,
-                None => continue,
+                None => { found_dead = true; continue },
             };
             let user_guard = user_lock.read().await;
-            messages.push(who_reply_for_user(&state, &client.get_nick().unwrap(), channel_guard.name.clone(), &user_guard))
+            messages.push(who_reply_for_user(&state, &client.get_nick().unwrap(), channel_guard.name.clone(), &user_guard, member.mode))
+        }
+        drop(channel_users_guard);
+        if found_dead {
+            channel_guard.prune_dead_members().await;
         }
     } else {
+        let casemapped_mask = state.casemap(mask);
         let mut users_matched = HashSet::new();
         for channel_weak in client.channels.read().await.values() {
             let channel_lock = match channel_weak.upgrade() {
@@ -61,20 +76,20 @@ pub async fn handle_who(state: Arc<ServerState>, client: Arc<RwLock<Client>>, ms
             let channel_guard = channel_lock.read().await;
 
             let channel_users = channel_guard.users.read().await;
-            for (user_addr, weak_user) in channel_users.iter() {
-                if !users_matched.insert(user_addr.to_string()) {
+            for (user_addr, member) in channel_users.iter() {
+                if !users_matched.insert(*user_addr) {
                     continue
                 }
 
-                let user_lock = match weak_user.upgrade() {
+                let user_lock = match member.client.upgrade() {
                     Some(user) => user,
                     None => continue,
                 };
                 let user_guard = user_lock.read().await;
-                if !user_matches_mask(&user_guard, &mask) {
+                if !user_matches_mask(&user_guard, &casemapped_mask) {
                     continue
                 }
-                messages.push(who_reply_for_user(&state, &client.get_nick().unwrap(), channel_guard.name.clone(), &user_guard))
+                messages.push(who_reply_for_user(&state, &client.get_nick().unwrap(), channel_guard.name.clone(), &user_guard, member.mode))
             }
         }
     }
@@ -102,9 +117,10 @@ pub async fn handle_whois(state: Arc<ServerState>, client: Arc<RwLock<Client>>,
     // We only reply to WHOIS for the first nickmask. Why? That's just what Freenode seems to do...
     let mut users_matched = HashSet::new();
     if let Some(mask) = masks.split(',').next() {
-        let users_guard = state.users.read().await;
-        for (user_addr, weak_user) in users_guard.iter() {
-            if !users_matched.insert(user_addr.to_string()) {
+        let casemapped_mask = state.casemap(mask);
+        let users = state.users.entries();
+        for (user_addr, weak_user) in users.iter() {
+            if !users_matched.insert(user_addr) {
                 continue
             }
 
@@ -113,7 +129,7 @@ pub async fn handle_whois(state: Arc<ServerState>, client: Arc<RwLock<Client>>,
                 None => continue,
             };
             let user = user_lock.read().await;
-            if !user_matches_mask(&user, &mask) {
+            if !user_matches_mask(&user, &casemapped_mask) {
                 continue
             }
 
@@ -123,11 +139,47 @@ pub async fn handle_whois(state: Arc<ServerState>, client: Arc<RwLock<Client>>,
                 user: user.get_username().unwrap(),
                 realname: user.get_realname().unwrap(),
             })).await?;
+            let mut channel_names = Vec::new();
+            for channel_weak in user.channels.read().await.values() {
+                let channel_lock = match channel_weak.upgrade() {
+                    Some(channel) => channel,
+                    None => continue,
+                };
+                let channel = channel_lock.read().await;
+                if !channel.is_visible_to(client.addr).await {
+                    continue
+                }
+                match channel.get_member_mode(user.addr).await.and_then(|mode| mode.prefix(&state.settings.member_ranks)) {
+                    Some(prefix) => channel_names.push(prefix.to_string() + &channel.name),
+                    None => channel_names.push(channel.name.clone()),
+                }
+            }
+            if !channel_names.is_empty() {
+                let base_msg = make_reply_msg(&state, client_nick, ReplyCode::RplWhoisChannels{nick: user.get_nick().unwrap()});
+                client.send_all(&Message::split_trailing_args(base_msg, channel_names, " ")).await?;
+            }
             client.send(make_reply_msg(&state, &client_nick, ReplyCode::RplWhoisServer{
                 nick: user.get_nick().unwrap(),
                 server: state.settings.server_name.clone(),
                 server_info: state.settings.server_info.clone(),
             })).await?;
+            // Like the real host, the fingerprint could be used to track a user across nicks
+            if let Some(ref fingerprint) = user.certfp {
+                if user.addr == client.addr || client.oper_privileges().is_some() {
+                    client.send(make_reply_msg(&state, client_nick, ReplyCode::RplWhoisCertFp{
+                        nick: user.get_nick().unwrap(),
+                        fingerprint: fingerprint.clone(),
+                    })).await?;
+                }
+            }
+            if client.oper_privileges().is_some_and(|privileges| privileges.see_real_hosts) {
+                client.send(make_reply_msg(&state, client_nick, ReplyCode::RplWhoisActually{
+                    nick: user.get_nick().unwrap(),
+                    user: user.get_username().unwrap(),
+                    host: user.get_real_host(),
+                    ip: user.get_ip().to_string(),
+                })).await?;
+            }
             client.send(make_reply_msg(&state, &client_nick, ReplyCode::RplEndOfWhois{masks: masks.to_owned()})).await?;
             return Ok(());
         }
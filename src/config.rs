@@ -0,0 +1,308 @@
+//! Loads ServerSettings from a TOML file, with the "config" feature
+//!
+//! Every key is optional, those left out keep the value of ServerSettings::default:
+//!
+//! ```toml
+//! listen = ["0.0.0.0:6667", "[::]:6667"]
+//!
+//! [server]
+//! name = "irc.example.org"
+//! network = "ExampleNet"
+//! info = "An example server"
+//! motd = { file = "motd.txt" }   # or { text = "Welcome!" }
+//! casemapping = "rfc1459"        # ascii, rfc1459, strict-rfc1459 or rfc8265
+//! nick_policy = "ascii"          # ascii or unicode
+//!
+//! [limits]
+//! max_name_length = 16
+//! max_channel_length = 50
+//! max_topic_length = 390
+//! chan_limit = 120
+//! sendq_limit = 1048576
+//! throttle_max_connections = 10
+//! throttle_window_secs = 60
+//!
+//! [socket]
+//! tcp_nodelay = true
+//! listen_backlog = 1024
+//! keepalive = { idle_secs = 120, interval_secs = 30, retries = 4 }
+//!
+//! [[oper]]
+//! name = "admin"
+//! password_hash = "$argon2id$v=19$..."   # see hash_password
+//! hostmask = "*!*@127.0.0.1"
+//! privileges = ["see_real_hosts", "manage_bans", "override_channels", "global_messages"]  # or ["all"]
+//! ```
+
+use crate::casemapping::Casemapping;
+use crate::errors::ConfigError;
+use crate::motd::Motd;
+use crate::nick_policy::NickPolicy;
+use crate::oper::{OperBlock, OperPrivileges};
+use crate::password::is_valid_password_hash;
+use crate::settings::ServerSettings;
+use crate::socket_options::TcpKeepalive;
+use serde::de::{Deserializer, Error as _};
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    listen: Option<Vec<SocketAddr>>,
+    server: ServerSection,
+    limits: LimitsSection,
+    socket: SocketSection,
+    #[serde(rename = "oper")]
+    opers: Vec<OperSection>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ServerSection {
+    name: Option<String>,
+    network: Option<String>,
+    info: Option<String>,
+    motd: Option<MotdSection>,
+    #[serde(deserialize_with = "deserialize_casemapping")]
+    casemapping: Option<Casemapping>,
+    #[serde(deserialize_with = "deserialize_nick_policy")]
+    nick_policy: Option<NickPolicy>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct SocketSection {
+    tcp_nodelay: Option<bool>,
+    listen_backlog: Option<u32>,
+    keepalive: Option<KeepaliveSection>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeepaliveSection {
+    idle_secs: u64,
+    interval_secs: u64,
+    retries: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
+enum MotdSection {
+    Text(String),
+    File(PathBuf),
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct LimitsSection {
+    max_name_length: Option<usize>,
+    max_channel_length: Option<usize>,
+    max_topic_length: Option<usize>,
+    chan_limit: Option<usize>,
+    sendq_limit: Option<usize>,
+    throttle_max_connections: Option<usize>,
+    throttle_window_secs: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct OperSection {
+    name: String,
+    #[serde(deserialize_with = "deserialize_password_hash")]
+    password_hash: String,
+    hostmask: String,
+    #[serde(default, deserialize_with = "deserialize_privileges")]
+    privileges: OperPrivileges,
+}
+
+fn deserialize_casemapping<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Casemapping>, D::Error> {
+    let name = String::deserialize(deserializer)?;
+    let casemappings = [
+        Casemapping::Ascii,
+        Casemapping::Rfc1459,
+        Casemapping::StrictRfc1459,
+        Casemapping::Rfc8265,
+    ];
+    match casemappings
+        .iter()
+        .find(|casemapping| casemapping.name() == name)
+    {
+        Some(&casemapping) => Ok(Some(casemapping)),
+        None => Err(D::Error::custom(format!("unknown casemapping {:?}", name))),
+    }
+}
+
+fn deserialize_nick_policy<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<NickPolicy>, D::Error> {
+    match &*String::deserialize(deserializer)? {
+        "ascii" => Ok(Some(NickPolicy::Ascii)),
+        "unicode" => Ok(Some(NickPolicy::Unicode)),
+        name => Err(D::Error::custom(format!("unknown nick policy {:?}", name))),
+    }
+}
+
+/// Checked here rather than by ServerSettings::validate, so the error points at the hash
+fn deserialize_password_hash<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<String, D::Error> {
+    let password_hash = String::deserialize(deserializer)?;
+    if !is_valid_password_hash(&password_hash) {
+        return Err(D::Error::custom("invalid password hash, see hash_password"));
+    }
+    Ok(password_hash)
+}
+
+fn deserialize_privileges<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<OperPrivileges, D::Error> {
+    let mut privileges = OperPrivileges::default();
+    for name in Vec::<String>::deserialize(deserializer)? {
+        match &*name {
+            "all" => privileges = OperPrivileges::ALL,
+            "see_real_hosts" => privileges.see_real_hosts = true,
+            "manage_bans" => privileges.manage_bans = true,
+            "override_channels" => privileges.override_channels = true,
+            "global_messages" => privileges.global_messages = true,
+            _ => return Err(D::Error::custom(format!("unknown privilege {:?}", name))),
+        }
+    }
+    Ok(privileges)
+}
+
+impl ServerSettings {
+    /// Reads the settings from a TOML file, see the config module for its format
+    pub fn from_toml_file(path: impl AsRef<Path>) -> Result<ServerSettings, ConfigError> {
+        let text = std::fs::read_to_string(path).map_err(ConfigError::Io)?;
+        ServerSettings::from_toml(&text)
+    }
+
+    /// Parses settings in the format of from_toml_file, and validates them
+    pub fn from_toml(text: &str) -> Result<ServerSettings, ConfigError> {
+        let config: ConfigFile = toml::from_str(text).map_err(ConfigError::Parse)?;
+        let mut settings = ServerSettings::default();
+        if let Some(listen_addrs) = config.listen {
+            settings.listen_addrs = listen_addrs;
+        }
+
+        let server = config.server;
+        if let Some(name) = server.name {
+            settings.server_name = name;
+        }
+        if let Some(network) = server.network {
+            settings.network_name = network;
+        }
+        if let Some(info) = server.info {
+            settings.server_info = info;
+        }
+        settings.motd = server.motd.map(|motd| match motd {
+            MotdSection::Text(text) => Motd::Text(text),
+            MotdSection::File(path) => Motd::File(path),
+        });
+        if let Some(casemapping) = server.casemapping {
+            settings.casemapping = casemapping;
+        }
+        if let Some(nick_policy) = server.nick_policy {
+            settings.nick_policy = nick_policy;
+        }
+
+        let limits = config.limits;
+        let defaults = ServerSettings::default();
+        settings.max_name_length = limits.max_name_length.unwrap_or(defaults.max_name_length);
+        settings.max_channel_length = limits
+            .max_channel_length
+            .unwrap_or(defaults.max_channel_length);
+        settings.max_topic_length = limits.max_topic_length.unwrap_or(defaults.max_topic_length);
+        settings.chan_limit = limits.chan_limit.unwrap_or(defaults.chan_limit);
+        settings.sendq_limit = limits.sendq_limit.unwrap_or(defaults.sendq_limit);
+        settings.throttle_max_connections = limits
+            .throttle_max_connections
+            .unwrap_or(defaults.throttle_max_connections);
+        if let Some(secs) = limits.throttle_window_secs {
+            settings.throttle_window = Duration::from_secs(secs);
+        }
+
+        let socket = config.socket;
+        settings.tcp_nodelay = socket.tcp_nodelay.unwrap_or(defaults.tcp_nodelay);
+        settings.listen_backlog = socket.listen_backlog.unwrap_or(defaults.listen_backlog);
+        settings.tcp_keepalive = socket.keepalive.map(|keepalive| TcpKeepalive {
+            idle: Duration::from_secs(keepalive.idle_secs),
+            interval: Duration::from_secs(keepalive.interval_secs),
+            retries: keepalive.retries,
+        });
+
+        settings.opers = config
+            .opers
+            .into_iter()
+            .map(|oper| OperBlock {
+                name: oper.name,
+                password_hash: oper.password_hash,
+                hostmask: oper.hostmask,
+                privileges: oper.privileges,
+            })
+            .collect();
+
+        settings.validate().map_err(ConfigError::Settings)?;
+        Ok(settings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::SettingsError;
+
+    #[test]
+    fn parse_config() {
+        let text = r#"
+            listen = ["127.0.0.1:6667"]
+            [server]
+            name = "irc.example.org"
+            motd = { text = "Hi" }
+            casemapping = "rfc1459"
+            [limits]
+            max_topic_length = 300
+            throttle_window_secs = 10
+            [socket]
+            keepalive = { idle_secs = 60, interval_secs = 10, retries = 3 }
+        "#;
+        let settings = ServerSettings::from_toml(text).unwrap();
+        assert_eq!(settings.listen_addrs, ["127.0.0.1:6667".parse().unwrap()]);
+        assert_eq!(settings.server_name, "irc.example.org");
+        assert_eq!(settings.casemapping, Casemapping::Rfc1459);
+        assert_eq!(settings.max_topic_length, 300);
+        assert_eq!(settings.max_name_length, 16);
+        assert_eq!(settings.throttle_window, Duration::from_secs(10));
+        assert!(settings.tcp_nodelay);
+        let keepalive = settings.tcp_keepalive.unwrap();
+        assert_eq!(keepalive.idle, Duration::from_secs(60));
+        assert_eq!(keepalive.retries, 3);
+    }
+
+    #[test]
+    fn config_errors() {
+        let text = "listen = [\"127.0.0.1\"]";
+        let err = ServerSettings::from_toml(text).unwrap_err();
+        assert_eq!(err.span(), Some(10..21));
+
+        let text = "[server]\ncasemapping = \"utf8\"";
+        let err = ServerSettings::from_toml(text).unwrap_err();
+        assert_eq!(err.span(), Some(23..29));
+
+        let text = "[server]\nname = \"my server\"";
+        match ServerSettings::from_toml(text).unwrap_err() {
+            ConfigError::Settings(err) => assert_eq!(
+                err,
+                SettingsError::NameHasSpace {
+                    setting: "server_name"
+                }
+            ),
+            err => panic!("unexpected error {}", err),
+        }
+    }
+}
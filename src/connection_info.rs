@@ -0,0 +1,30 @@
+/// How a client is connected to the server, so embedders can apply policies like requiring TLS
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionInfo {
+    pub transport: Transport,
+    /// Set if the connection uses TLS
+    pub tls: Option<TlsInfo>,
+}
+
+/// The kind of stream IRC messages are sent over
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Transport {
+    /// A plain TCP stream of lines, with or without TLS
+    #[default]
+    Tcp,
+    /// A client run by the embedder in the same process, see ServerHandle::add_virtual_client
+    Virtual,
+}
+
+/// What the client and server agreed on during the TLS handshake
+/// Some TLS backends don't report everything, in which case fields are None
+#[derive(Clone, Debug, Default)]
+pub struct TlsInfo {
+    /// Like "TLSv1_3"
+    pub protocol: Option<String>,
+    /// Like "TLS13_AES_256_GCM_SHA384"
+    pub cipher: Option<String>,
+    /// The server name the client asked for (SNI)
+    pub sni: Option<String>,
+}
@@ -0,0 +1,149 @@
+use crate::client::Client;
+use crate::message::Message;
+use crate::server::{Server, ServerState};
+use futures::FutureExt;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::io::Error;
+use std::net::SocketAddr;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot, RwLock};
+
+/// Statistics of a dispatch worker, see ServerSettings::dispatch_workers
+#[derive(Clone, Debug, Default)]
+pub struct DispatchStats {
+    /// Messages waiting in the worker's queue
+    pub queued: usize,
+    /// Messages processed since the server started
+    pub processed: u64,
+    /// Time spent processing messages since the server started
+    pub busy_time: Duration,
+    /// How many times a client had to wait because the worker's queue was full
+    pub queue_full: u64,
+}
+
+/// A message waiting to be processed by a worker, the client's task waits for the result
+struct Job {
+    state: Arc<ServerState>,
+    client: Arc<RwLock<Client>>,
+    msg: Message,
+    done: oneshot::Sender<Result<(), Error>>,
+}
+
+struct Worker {
+    sender: mpsc::Sender<Job>,
+    /// Taken when the worker task starts
+    receiver: Mutex<Option<mpsc::Receiver<Job>>>,
+    processed: AtomicU64,
+    busy_nanos: AtomicU64,
+    queue_full: AtomicU64,
+}
+
+/// Processes the clients' messages on a fixed number of worker tasks instead of each client's own task
+/// Clients are partitioned by address, so a burst from the clients of one worker doesn't hold up the others
+pub struct Dispatcher {
+    workers: Box<[Arc<Worker>]>,
+    hasher: RandomState,
+}
+
+impl Dispatcher {
+    pub fn new(num_workers: usize, queue_size: usize) -> Dispatcher {
+        let workers = (0..num_workers)
+            .map(|_| {
+                let (sender, receiver) = mpsc::channel(queue_size);
+                Arc::new(Worker {
+                    sender,
+                    receiver: Mutex::new(Some(receiver)),
+                    processed: AtomicU64::new(0),
+                    busy_nanos: AtomicU64::new(0),
+                    queue_full: AtomicU64::new(0),
+                })
+            })
+            .collect();
+        Dispatcher {
+            workers,
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// Spawns the worker tasks, they run until the dispatcher is dropped
+    pub fn start(&self) {
+        for worker in self.workers.iter() {
+            let mut receiver = match worker.receiver.lock().unwrap().take() {
+                Some(receiver) => receiver,
+                None => continue,
+            };
+            let worker = Arc::downgrade(worker);
+            tokio::spawn(async move {
+                while let Some(job) = receiver.recv().await {
+                    let start = Instant::now();
+                    let processed = Server::process_message(job.state, job.client, job.msg);
+                    // A panic only fails this message, instead of stopping the worker of all its clients
+                    let result = AssertUnwindSafe(processed)
+                        .catch_unwind()
+                        .await
+                        .unwrap_or_else(|_| Err(Error::other("Internal error")));
+                    if let Some(worker) = worker.upgrade() {
+                        worker.processed.fetch_add(1, Ordering::Relaxed);
+                        let busy = start.elapsed().as_nanos() as u64;
+                        worker.busy_nanos.fetch_add(busy, Ordering::Relaxed);
+                    }
+                    job.done.send(result).ok();
+                }
+            });
+        }
+    }
+
+    /// Processes a message on the worker of the client, and waits until it is done
+    /// When the worker's queue is full the client waits, and stops reading from its socket meanwhile
+    pub async fn dispatch(
+        &self,
+        state: Arc<ServerState>,
+        client: Arc<RwLock<Client>>,
+        addr: SocketAddr,
+        msg: Message,
+    ) -> Result<(), Error> {
+        let worker = &self.workers[self.hasher.hash_one(addr) as usize % self.workers.len()];
+        let (done, result) = oneshot::channel();
+        let job = Job {
+            state,
+            client,
+            msg,
+            done,
+        };
+        let job = match worker.sender.try_send(job) {
+            Ok(()) => None,
+            Err(mpsc::error::TrySendError::Full(job)) => {
+                worker.queue_full.fetch_add(1, Ordering::Relaxed);
+                Some(job)
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                return Err(Error::other("Dispatch worker stopped"))
+            }
+        };
+        if let Some(job) = job {
+            if worker.sender.send(job).await.is_err() {
+                return Err(Error::other("Dispatch worker stopped"));
+            }
+        }
+        result
+            .await
+            .unwrap_or_else(|_| Err(Error::other("Dispatch worker stopped")))
+    }
+
+    /// The statistics of each worker
+    pub fn stats(&self) -> Vec<DispatchStats> {
+        self.workers
+            .iter()
+            .map(|worker| DispatchStats {
+                queued: worker.sender.max_capacity() - worker.sender.capacity(),
+                processed: worker.processed.load(Ordering::Relaxed),
+                busy_time: Duration::from_nanos(worker.busy_nanos.load(Ordering::Relaxed)),
+                queue_full: worker.queue_full.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,37 @@
+use crate::errors::SettingsError;
+use std::fmt::{Display, Error, Formatter};
+use std::ops::Range;
+
+/// Why settings could not be loaded from a config file, see ServerSettings::from_toml_file
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The file could not be read
+    Io(std::io::Error),
+    /// The file is not valid TOML, or doesn't follow the format of the settings
+    Parse(toml::de::Error),
+    /// The settings were read, but are not valid together
+    Settings(SettingsError),
+}
+
+impl ConfigError {
+    /// Byte range of the file where parsing failed, if known
+    pub fn span(&self) -> Option<Range<usize>> {
+        match self {
+            ConfigError::Parse(err) => err.span(),
+            _ => None,
+        }
+    }
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            ConfigError::Io(err) => write!(f, "Could not read the config file: {}", err),
+            // The TOML error already shows the line and column
+            ConfigError::Parse(err) => write!(f, "{}", err),
+            ConfigError::Settings(err) => write!(f, "Invalid settings: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
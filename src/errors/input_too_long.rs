@@ -0,0 +1,23 @@
+use crate::message::Message;
+use std::fmt::{Display, Error, Formatter};
+
+/// A client sent a line longer than the protocol allows
+#[derive(Debug)]
+pub struct InputTooLongError {
+    /// The message cut at the maximum length, if enough of it could be read
+    pub truncated: Option<Message>,
+}
+
+impl InputTooLongError {
+    pub fn new(truncated: Option<Message>) -> Self {
+        Self { truncated }
+    }
+}
+
+impl Display for InputTooLongError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        write!(f, "Input line was too long")
+    }
+}
+
+impl std::error::Error for InputTooLongError {}
@@ -0,0 +1,23 @@
+use crate::message::Message;
+use std::fmt::{Display, Error, Formatter};
+
+/// A client sent a line that is not valid UTF-8
+#[derive(Debug)]
+pub struct InvalidUtf8Error {
+    /// The message with its invalid bytes replaced by U+FFFD
+    pub lossy: Message,
+}
+
+impl InvalidUtf8Error {
+    pub fn new(lossy: Message) -> Self {
+        Self { lossy }
+    }
+}
+
+impl Display for InvalidUtf8Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        write!(f, "Input line was not valid UTF-8")
+    }
+}
+
+impl std::error::Error for InvalidUtf8Error {}
@@ -1,2 +1,16 @@
 mod channel_not_found;
+#[cfg(feature = "config")]
+mod config;
+mod input_too_long;
+mod invalid_utf8;
+mod parse;
+mod settings;
+mod setup;
 pub use channel_not_found::ChannelNotFoundError;
+#[cfg(feature = "config")]
+pub use config::ConfigError;
+pub use input_too_long::InputTooLongError;
+pub use invalid_utf8::InvalidUtf8Error;
+pub use parse::ParseError;
+pub use settings::SettingsError;
+pub use setup::SetupError;
@@ -0,0 +1,27 @@
+use std::fmt::{Display, Error, Formatter};
+
+/// Why a line is not a valid IRC message, see Message::parse
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// The line is empty or only has spaces
+    Empty,
+    /// The line has tags or a source, but no command
+    MissingCommand,
+    /// The command is neither made of letters nor a 3-digit numeric
+    InvalidCommand(String),
+    /// The line contains a NUL, CR or LF character
+    IllegalCharacter(char),
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            ParseError::Empty => write!(f, "Empty message"),
+            ParseError::MissingCommand => write!(f, "Message has no command"),
+            ParseError::InvalidCommand(command) => write!(f, "Invalid command {:?}", command),
+            ParseError::IllegalCharacter(c) => write!(f, "Illegal character {:?} in message", c),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
@@ -0,0 +1,79 @@
+use std::fmt::{Display, Error, Formatter};
+
+/// Why ServerSettings can't be used, see ServerSettings::validate
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SettingsError {
+    /// An address to listen on could not be parsed
+    InvalidListenAddress(String),
+    /// A length limit leaves too little room in messages, like max_topic_length
+    LengthLimitTooHigh {
+        setting: &'static str,
+        value: usize,
+        max: usize,
+    },
+    /// A name sent as a single word contains a space, like server_name
+    NameHasSpace { setting: &'static str },
+    /// The password_hash of an oper block or WEBIRC gateway is not a valid Argon2 PHC string
+    InvalidPasswordHash { setting: &'static str, name: String },
+    /// There are more member_ranks than channel members can hold
+    TooManyMemberRanks { count: usize, max: usize },
+    /// The member_ranks don't include channel operators (+o)
+    MissingOperatorRank,
+    /// A member rank uses the letter of a channel mode
+    RankModeConflict { mode: char },
+    /// Two member ranks use the same mode letter or prefix
+    DuplicateMemberRank { mode: char },
+    /// There are dispatch_workers, but dispatch_queue_size is 0
+    EmptyDispatchQueue,
+    /// A duration that must not be zero is, like sweep_interval
+    ZeroDuration { setting: &'static str },
+}
+
+impl Display for SettingsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            SettingsError::InvalidListenAddress(addr) => {
+                write!(f, "Invalid address to listen on {:?}", addr)
+            }
+            SettingsError::LengthLimitTooHigh {
+                setting,
+                value,
+                max,
+            } => write!(f, "{} is {}, it must be below {}", setting, value, max),
+            SettingsError::NameHasSpace { setting } => {
+                write!(f, "{} must not contain spaces", setting)
+            }
+            SettingsError::InvalidPasswordHash { setting, name } => {
+                write!(f, "Invalid password hash for {:?} in {}", name, setting)
+            }
+            SettingsError::TooManyMemberRanks { count, max } => {
+                write!(
+                    f,
+                    "{} member ranks given, at most {} are supported",
+                    count, max
+                )
+            }
+            SettingsError::MissingOperatorRank => {
+                write!(f, "member_ranks must include channel operators (+o)")
+            }
+            SettingsError::RankModeConflict { mode } => {
+                write!(f, "Member rank +{} conflicts with a channel mode", mode)
+            }
+            SettingsError::DuplicateMemberRank { mode } => {
+                write!(
+                    f,
+                    "Member rank +{} reuses the mode or prefix of another rank",
+                    mode
+                )
+            }
+            SettingsError::EmptyDispatchQueue => {
+                write!(f, "dispatch_queue_size must not be 0 with dispatch_workers")
+            }
+            SettingsError::ZeroDuration { setting } => {
+                write!(f, "{} must not be zero", setting)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SettingsError {}
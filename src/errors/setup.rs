@@ -0,0 +1,24 @@
+use std::fmt::{Display, Error, Formatter};
+
+/// Why a Server setter refused a change, see Server::register_command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SetupError {
+    /// The server was started or a ServerHandle was created, so its setup can't change anymore
+    AlreadyShared,
+    /// A built-in or registered command already has this name
+    CommandExists(String),
+}
+
+impl Display for SetupError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            SetupError::AlreadyShared => write!(
+                f,
+                "The server must be set up before calling handle() or start()"
+            ),
+            SetupError::CommandExists(name) => write!(f, "Command {} already exists", name),
+        }
+    }
+}
+
+impl std::error::Error for SetupError {}
@@ -0,0 +1,38 @@
+use std::net::SocketAddr;
+
+/// How many events a subscriber of Server::events can fall behind before it starts missing some
+pub const EVENT_QUEUE_SIZE: usize = 1024;
+
+/// Something that happened on the server, see Server::events
+/// Nicks and channel names are sent as they are displayed, not casemapped
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ServerEvent {
+    /// A client connected and was accepted by on_client_connect
+    ClientConnected { addr: SocketAddr },
+    /// A client completed registration
+    Registered { addr: SocketAddr, nick: String },
+    /// A user joined a channel
+    Joined { nick: String, channel: String },
+    /// A user left a channel with PART
+    Parted {
+        nick: String,
+        channel: String,
+        reason: Option<String>,
+    },
+    /// A user sent a PRIVMSG or NOTICE to a channel
+    ChannelMessage {
+        nick: String,
+        channel: String,
+        text: String,
+        is_notice: bool,
+    },
+    /// A user changed nick, or had it changed by the server
+    NickChanged { old_nick: String, new_nick: String },
+    /// A client disconnected, its nick is set if it had registered
+    Disconnected {
+        addr: SocketAddr,
+        nick: Option<String>,
+        reason: String,
+    },
+}
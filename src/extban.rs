@@ -0,0 +1,203 @@
+use crate::casemapping::Casemapping;
+use crate::mask::{mask_matches, normalize_mask};
+
+/// Character that starts an extended ban, like "~a:account"
+pub const EXTBAN_PREFIX: char = '~';
+
+/// Extban type letter that turns a ban into a mute, like "~q:nick!*@*"
+/// Muted users can still join the channel, but not talk in it
+pub const MUTE_EXTBAN: char = 'q';
+
+/// What ban masks are matched against
+#[derive(Clone, Debug, Default)]
+pub struct BanTarget {
+    /// The nick!user@host prefix of the user
+    pub prefix: String,
+    /// The account the user is logged in to, if any
+    pub account: Option<String>,
+    /// Names of the channels the user is in
+    pub channels: Vec<String>,
+    /// The server's casemapping, masks match names that are equivalent under it
+    pub casemapping: Casemapping,
+}
+
+impl BanTarget {
+    /// Whether a glob mask matches a name, compared with the server's casemapping like nicks are
+    pub fn mask_matches(&self, mask: &str, name: &str) -> bool {
+        let casemapping = self.casemapping;
+        mask_matches(&casemapping.to_upper(mask), &casemapping.to_upper(name))
+    }
+}
+
+/// A type of extended ban, matched by something else than the user's prefix
+#[derive(Clone, Copy, Debug)]
+pub struct ExtbanType {
+    pub letter: char,
+    /// Returns whether a user matches an extban with this parameter (the part after the ':')
+    /// The parameter is empty when the extban has none, like "~a"
+    pub matches: fn(&BanTarget, &str) -> bool,
+}
+
+/// Extban types we support out of the box
+pub const DEFAULT_EXTBANS: &[ExtbanType] = &[
+    // ~a matches any logged in user, ~a:mask matches users whose account matches the mask
+    ExtbanType {
+        letter: 'a',
+        matches: |target, param| match target.account {
+            Some(ref account) => param.is_empty() || target.mask_matches(param, account),
+            None => false,
+        },
+    },
+    // ~c:mask matches users in a channel matching the mask
+    ExtbanType {
+        letter: 'c',
+        matches: |target, param| {
+            !param.is_empty()
+                && target
+                    .channels
+                    .iter()
+                    .any(|channel| target.mask_matches(param, channel))
+        },
+    },
+];
+
+/// Splits an extban into its type letter and parameter, or returns None for regular masks
+pub fn parse_extban(mask: &str) -> Option<(char, &str)> {
+    let mut chars = mask.strip_prefix(EXTBAN_PREFIX)?.chars();
+    let letter = chars.next()?;
+    let rest = chars.as_str();
+    if rest.is_empty() {
+        Some((letter, rest))
+    } else {
+        Some((letter, rest.strip_prefix(':')?))
+    }
+}
+
+/// Value of the EXTBAN ISUPPORT token, like "~,acq"
+pub fn extban_isupport(extbans: &[ExtbanType]) -> String {
+    let mut letters: Vec<char> = extbans.iter().map(|extban| extban.letter).collect();
+    letters.push(MUTE_EXTBAN);
+    letters.sort_unstable();
+    format!(
+        "{},{}",
+        EXTBAN_PREFIX,
+        letters.into_iter().collect::<String>()
+    )
+}
+
+/// Normalizes a ban mask before it's added to a list, returns None if it is not a valid ban
+pub fn normalize_ban_mask(extbans: &[ExtbanType], mask: &str) -> Option<String> {
+    let (letter, param) = match parse_extban(mask) {
+        Some(extban) => extban,
+        None => return Some(normalize_mask(mask)),
+    };
+
+    if letter == MUTE_EXTBAN {
+        // The mute applies to whatever the inner ban matches, which can't itself be a mute
+        if param.is_empty() || parse_extban(param).is_some_and(|(inner, _)| inner == MUTE_EXTBAN) {
+            return None;
+        }
+        let inner = normalize_ban_mask(extbans, param)?;
+        Some(format!("{}{}:{}", EXTBAN_PREFIX, MUTE_EXTBAN, inner))
+    } else if extbans.iter().any(|extban| extban.letter == letter) {
+        Some(mask.to_owned())
+    } else {
+        None
+    }
+}
+
+/// Whether a ban mask matches a user, mutes are matched as the ban they contain
+pub fn ban_matches(extbans: &[ExtbanType], mask: &str, target: &BanTarget) -> bool {
+    match parse_extban(mask) {
+        None => target.mask_matches(mask, &target.prefix),
+        Some((MUTE_EXTBAN, param)) => ban_matches(extbans, param, target),
+        Some((letter, param)) => extbans
+            .iter()
+            .find(|extban| extban.letter == letter)
+            .is_some_and(|extban| (extban.matches)(target, param)),
+    }
+}
+
+/// Whether a ban mask is a mute, which doesn't prevent users from joining
+pub fn is_mute(mask: &str) -> bool {
+    parse_extban(mask).is_some_and(|(letter, _)| letter == MUTE_EXTBAN)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target() -> BanTarget {
+        BanTarget {
+            prefix: "nick!~user@host".to_owned(),
+            account: Some("Account".to_owned()),
+            channels: vec!["#chan".to_owned()],
+            casemapping: Casemapping::Rfc1459,
+        }
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(parse_extban("~a:account"), Some(('a', "account")));
+        assert_eq!(parse_extban("~a"), Some(('a', "")));
+        assert_eq!(parse_extban("~q:~c:#chan"), Some(('q', "~c:#chan")));
+        assert_eq!(parse_extban("~ab"), None);
+        assert_eq!(parse_extban("nick!*@*"), None);
+    }
+
+    #[test]
+    fn normalize() {
+        assert_eq!(
+            normalize_ban_mask(DEFAULT_EXTBANS, "nick").unwrap(),
+            "nick!*@*"
+        );
+        assert_eq!(
+            normalize_ban_mask(DEFAULT_EXTBANS, "~a:acc").unwrap(),
+            "~a:acc"
+        );
+        assert_eq!(
+            normalize_ban_mask(DEFAULT_EXTBANS, "~q:nick").unwrap(),
+            "~q:nick!*@*"
+        );
+        assert_eq!(
+            normalize_ban_mask(DEFAULT_EXTBANS, "~q:~a").unwrap(),
+            "~q:~a"
+        );
+        assert!(normalize_ban_mask(DEFAULT_EXTBANS, "~z:foo").is_none());
+        assert!(normalize_ban_mask(DEFAULT_EXTBANS, "~q:~q:foo").is_none());
+        assert!(normalize_ban_mask(DEFAULT_EXTBANS, "~q").is_none());
+    }
+
+    #[test]
+    fn matching() {
+        let target = target();
+        assert!(ban_matches(DEFAULT_EXTBANS, "*!*@host", &target));
+        assert!(ban_matches(DEFAULT_EXTBANS, "~a", &target));
+        assert!(ban_matches(DEFAULT_EXTBANS, "~a:acc*", &target));
+        assert!(!ban_matches(DEFAULT_EXTBANS, "~a:other", &target));
+        assert!(ban_matches(DEFAULT_EXTBANS, "~c:#CHAN", &target));
+        assert!(!ban_matches(DEFAULT_EXTBANS, "~c:#other", &target));
+        assert!(ban_matches(DEFAULT_EXTBANS, "~q:~a:account", &target));
+        assert!(!ban_matches(DEFAULT_EXTBANS, "~z:anything", &target));
+        let target = BanTarget {
+            prefix: "nick[away]!~user@host".to_owned(),
+            ..target
+        };
+        assert!(ban_matches(DEFAULT_EXTBANS, "nick{away}!*@*", &target));
+        assert!(ban_matches(DEFAULT_EXTBANS, "NICK{*}!*@*", &target));
+        assert!(!ban_matches(
+            DEFAULT_EXTBANS,
+            "~a",
+            &BanTarget {
+                account: None,
+                ..target
+            }
+        ));
+    }
+
+    #[test]
+    fn advertisement() {
+        assert_eq!(extban_isupport(DEFAULT_EXTBANS), "~,acq");
+        assert_eq!(extban_isupport(&[]), "~,q");
+    }
+}
@@ -0,0 +1,75 @@
+use crate::casemapping::Casemapping;
+use crate::mask::mask_matches;
+
+/// A list of glob masks of names nobody can use, like "NickServ" or "admin*"
+#[derive(Clone, Debug, Default)]
+pub struct ForbiddenNames {
+    masks: Vec<String>,
+}
+
+impl ForbiddenNames {
+    pub fn new(masks: &[String]) -> Self {
+        let mut names = Self::default();
+        for mask in masks {
+            names.add(mask);
+        }
+        names
+    }
+
+    pub fn masks(&self) -> &[String] {
+        &self.masks
+    }
+
+    /// Adds a mask, returns false if it was already in the list
+    pub fn add(&mut self, mask: &str) -> bool {
+        if self.masks.iter().any(|existing| existing == mask) {
+            return false;
+        }
+        self.masks.push(mask.to_owned());
+        true
+    }
+
+    /// Removes a mask, returns false if it wasn't in the list
+    pub fn remove(&mut self, mask: &str) -> bool {
+        let len = self.masks.len();
+        self.masks.retain(|existing| existing != mask);
+        self.masks.len() != len
+    }
+
+    /// Whether a name matches any of the masks, names are compared with the server's casemapping
+    pub fn is_forbidden(&self, casemapping: Casemapping, name: &str) -> bool {
+        let name = casemapping.to_upper(name);
+        self.masks
+            .iter()
+            .any(|mask| mask_matches(&casemapping.to_upper(mask), &name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matching() {
+        let names = ForbiddenNames::new(&["NickServ".to_owned(), "admin*".to_owned()]);
+        assert!(names.is_forbidden(Casemapping::Ascii, "nickserv"));
+        assert!(names.is_forbidden(Casemapping::Ascii, "Administrator"));
+        assert!(!names.is_forbidden(Casemapping::Ascii, "NickServ_"));
+        assert!(!names.is_forbidden(Casemapping::Ascii, "root"));
+
+        let names = ForbiddenNames::new(&["bad{nick}".to_owned()]);
+        assert!(!names.is_forbidden(Casemapping::Ascii, "BAD[NICK]"));
+        assert!(names.is_forbidden(Casemapping::Rfc1459, "BAD[NICK]"));
+    }
+
+    #[test]
+    fn add_and_remove() {
+        let mut names = ForbiddenNames::default();
+        assert!(names.add("root"));
+        assert!(!names.add("root"));
+        assert!(names.is_forbidden(Casemapping::Ascii, "root"));
+        assert!(names.remove("root"));
+        assert!(!names.remove("root"));
+        assert!(names.masks().is_empty());
+    }
+}
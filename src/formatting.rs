@@ -0,0 +1,98 @@
+use std::iter::Peekable;
+use std::str::Chars;
+
+// mIRC formatting codes
+const BOLD: char = '\x02';
+const COLOR: char = '\x03';
+const HEX_COLOR: char = '\x04';
+const RESET: char = '\x0F';
+const MONOSPACE: char = '\x11';
+const REVERSE: char = '\x16';
+const ITALIC: char = '\x1D';
+const STRIKETHROUGH: char = '\x1E';
+const UNDERLINE: char = '\x1F';
+
+fn is_formatting_code(c: char) -> bool {
+    matches!(
+        c,
+        BOLD | COLOR | HEX_COLOR | RESET | MONOSPACE | REVERSE | ITALIC | STRIKETHROUGH | UNDERLINE
+    )
+}
+
+/// Whether a message contains any color or formatting code
+pub fn has_formatting(text: &str) -> bool {
+    text.chars().any(is_formatting_code)
+}
+
+/// Skips up to max_len characters matching the predicate, returns how many were skipped
+fn skip_while(text: &mut Peekable<Chars>, max_len: usize, pred: fn(&char) -> bool) -> usize {
+    let mut skipped = 0;
+    while skipped < max_len && text.next_if(pred).is_some() {
+        skipped += 1;
+    }
+    skipped
+}
+
+/// Skips the "fg[,bg]" arguments of a color code, keeping the comma if no background follows
+fn skip_color_args(text: &mut Peekable<Chars>, max_len: usize, pred: fn(&char) -> bool) {
+    if skip_while(text, max_len, pred) == 0 {
+        return;
+    }
+    let mut lookahead = text.clone();
+    if lookahead.next() == Some(',') && lookahead.peek().is_some_and(pred) {
+        text.next();
+        skip_while(text, max_len, pred);
+    }
+}
+
+/// Removes all color and formatting codes from a message, including the arguments of color codes
+pub fn strip_formatting(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            COLOR => skip_color_args(&mut chars, 2, char::is_ascii_digit),
+            HEX_COLOR => skip_color_args(&mut chars, 6, char::is_ascii_hexdigit),
+            _ if is_formatting_code(c) => (),
+            _ => stripped.push(c),
+        }
+    }
+    stripped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detection() {
+        assert!(!has_formatting("plain text"));
+        assert!(!has_formatting("\x01ACTION waves\x01"));
+        assert!(has_formatting("\x02bold\x02"));
+        assert!(has_formatting("\x0304red"));
+    }
+
+    #[test]
+    fn strip_simple_codes() {
+        assert_eq!(strip_formatting("plain text"), "plain text");
+        assert_eq!(
+            strip_formatting("\x02bold\x0F \x1Fund\x1Der\x16"),
+            "bold under"
+        );
+        assert_eq!(
+            strip_formatting("\x01ACTION \x02waves\x01"),
+            "\x01ACTION waves\x01"
+        );
+    }
+
+    #[test]
+    fn strip_colors() {
+        assert_eq!(strip_formatting("\x034red"), "red");
+        assert_eq!(strip_formatting("\x0304,12red"), "red");
+        assert_eq!(strip_formatting("\x03123"), "3");
+        assert_eq!(strip_formatting("\x034,text"), ",text");
+        assert_eq!(strip_formatting("\x03,5text"), ",5text");
+        assert_eq!(strip_formatting("\x04FF0000,00ff00text"), "text");
+        assert_eq!(strip_formatting("a\x03b"), "ab");
+    }
+}
@@ -0,0 +1,36 @@
+use crate::server::ServerState;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+/// A snapshot of the server's health, for liveness and readiness checks
+#[derive(Clone, Debug)]
+pub struct Health {
+    /// Whether the server is listening and accepting new connections
+    pub accepting: bool,
+    /// Number of connected clients, including those that haven't completed registration
+    pub clients: usize,
+    /// Number of registered users
+    pub users: usize,
+    /// How long a task waited to be scheduled again, a large lag means the server is overloaded
+    pub event_loop_lag: Duration,
+}
+
+impl Health {
+    /// Whether the server accepts connections and its lag is under the limit
+    pub fn is_ready(&self, max_lag: Duration) -> bool {
+        self.accepting && self.event_loop_lag <= max_lag
+    }
+}
+
+pub async fn check_health(state: &ServerState) -> Health {
+    let start = Instant::now();
+    tokio::task::yield_now().await;
+    let event_loop_lag = start.elapsed();
+
+    Health {
+        accepting: state.accepting.load(Ordering::Relaxed),
+        clients: state.clients.len(),
+        users: state.users.len(),
+        event_loop_lag,
+    }
+}
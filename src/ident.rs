@@ -0,0 +1,93 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+/// Port of the ident service (RFC 1413)
+const IDENT_PORT: u16 = 113;
+/// Replies are supposed to fit in a single short line
+const MAX_REPLY_LEN: u64 = 1000;
+
+/// Asks the client's identd which user owns the connection from remote to local
+/// Returns None if the client doesn't run an identd, or it doesn't answer within the timeout
+pub async fn lookup_ident(
+    local: SocketAddr,
+    remote: SocketAddr,
+    max_duration: Duration,
+) -> Option<String> {
+    timeout(max_duration, query_identd(local, remote))
+        .await
+        .ok()
+        .flatten()
+}
+
+async fn query_identd(local: SocketAddr, remote: SocketAddr) -> Option<String> {
+    let mut socket = TcpStream::connect(SocketAddr::new(remote.ip(), IDENT_PORT))
+        .await
+        .ok()?;
+    let query = format!("{}, {}\r\n", remote.port(), local.port());
+    socket.write_all(query.as_bytes()).await.ok()?;
+
+    let mut reply = String::new();
+    BufReader::new(socket.take(MAX_REPLY_LEN))
+        .read_line(&mut reply)
+        .await
+        .ok()?;
+    parse_ident_reply(&reply, remote.port(), local.port())
+}
+
+/// Parses a reply like "6193, 23 : USERID : UNIX : stjohns", returns the user id
+fn parse_ident_reply(reply: &str, remote_port: u16, local_port: u16) -> Option<String> {
+    let mut fields = reply.trim_end_matches(['\r', '\n']).splitn(4, ':');
+    let (ports, reply_type, _os, user_id) = (
+        fields.next()?,
+        fields.next()?,
+        fields.next()?,
+        fields.next()?,
+    );
+
+    let (reply_remote, reply_local) = ports.split_once(',')?;
+    if reply_remote.trim().parse() != Ok(remote_port)
+        || reply_local.trim().parse() != Ok(local_port)
+        || reply_type.trim() != "USERID"
+    {
+        return None;
+    }
+    let user_id = user_id.trim();
+    if user_id.is_empty() {
+        None
+    } else {
+        Some(user_id.to_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_replies() {
+        assert_eq!(
+            parse_ident_reply("6193, 23 : USERID : UNIX : stjohns\r\n", 6193, 23),
+            Some("stjohns".to_owned())
+        );
+        assert_eq!(
+            parse_ident_reply("6193,23:USERID:OTHER,US-ASCII:user:name", 6193, 23),
+            Some("user:name".to_owned())
+        );
+        assert_eq!(
+            parse_ident_reply("6195, 23 : ERROR : NO-USER", 6195, 23),
+            None
+        );
+        assert_eq!(
+            parse_ident_reply("6193, 23 : USERID : UNIX : stjohns", 6194, 23),
+            None
+        );
+        assert_eq!(
+            parse_ident_reply("6193, 23 : USERID : UNIX :  ", 6193, 23),
+            None
+        );
+        assert_eq!(parse_ident_reply("garbage", 6193, 23), None);
+    }
+}
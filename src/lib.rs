@@ -1,19 +1,87 @@
-#![feature(backtrace)]
 #![allow(clippy::useless_format)]
 
+mod account;
+mod account_file;
+#[cfg(feature = "admin-api")]
+mod admin;
+mod audit;
 mod callbacks;
+mod casemapping;
 mod channel;
+mod channel_registration;
 mod client;
 mod commands;
+#[cfg(feature = "config")]
+mod config;
+mod connection_info;
+mod dispatch;
 mod errors;
+mod events;
+mod extban;
+mod forbidden;
+mod formatting;
+mod health;
+mod ident;
 mod message;
+mod mask;
 mod mode;
+mod motd;
+mod nick_policy;
+mod oper;
+mod password;
+mod sendq;
 mod server;
+mod server_handle;
+mod services;
 mod settings;
+mod sharded_map;
+mod snapshot;
+mod shun;
+mod snomask;
+mod socket_options;
+mod throttle;
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(any(feature = "tls", feature = "native-tls"))]
+mod tls_provider;
+mod virtual_client;
+mod webirc;
 
-pub use crate::callbacks::ServerCallbacks;
-pub use crate::channel::Channel;
-pub use crate::client::Client;
-pub use crate::message::Message;
+pub use crate::account::{Account, AccountProvider, AccountResult, MemoryAccountProvider};
+pub use crate::account_file::FileAccountProvider;
+#[cfg(feature = "admin-api")]
+pub use crate::admin::AdminApiSettings;
+pub use crate::audit::AuditEntry;
+pub use crate::callbacks::{
+    CallbackResult, DefaultHooks, JoinVeto, PrivateMessageAction, RawMessageAction, ServerHooks,
+};
+pub use crate::casemapping::Casemapping;
+pub use crate::channel::{Channel, ChannelHandle, Topic};
+pub use crate::channel_registration::{ChannelAccess, ChannelRegistration};
+pub use crate::client::{Client, ClientHandle, Mute};
+pub use crate::commands::{CommandMiddleware, CommandNamespace, CustomCommand};
+pub use crate::connection_info::{ConnectionInfo, TlsInfo, Transport};
+pub use crate::dispatch::DispatchStats;
+#[cfg(feature = "config")]
+pub use crate::errors::ConfigError;
+pub use crate::errors::{ParseError, SettingsError, SetupError};
+pub use crate::events::ServerEvent;
+pub use crate::extban::{BanTarget, ExtbanType, DEFAULT_EXTBANS};
+pub use crate::health::Health;
+pub use crate::message::{InvalidUtf8Policy, LongLinePolicy, Message};
+pub use crate::mode::{MemberRank, DEFAULT_MEMBER_RANKS, EXTENDED_MEMBER_RANKS};
+pub use crate::motd::Motd;
+pub use crate::nick_policy::NickPolicy;
+pub use crate::oper::{OperBlock, OperPrivileges};
+pub use crate::password::hash_password;
 pub use crate::server::Server;
-pub use crate::settings::ServerSettings;
+pub use crate::server_handle::{MessageTarget, RelayOptions, ServerHandle};
+pub use crate::settings::{RuntimeSettings, ServerSettings, ServerSettingsBuilder};
+pub use crate::snapshot::{ChannelInfo, ChannelMemberInfo, ClientInfo};
+pub use crate::snomask::{SnoCategory, Snomask};
+pub use crate::socket_options::TcpKeepalive;
+#[cfg(feature = "tls")]
+pub use crate::tls::{ClientCertCheck, ClientCertMode, SniCertResolver};
+pub use crate::virtual_client::{VirtualClient, VirtualIdentity};
+pub use crate::webirc::WebircGateway;
+pub use async_trait::async_trait;
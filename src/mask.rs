@@ -0,0 +1,79 @@
+/// Turns a partial mask like "nick" or "user@host" into a full "nick!user@host" mask
+pub fn normalize_mask(mask: &str) -> String {
+    match (mask.find('!'), mask.find('@')) {
+        (Some(_), Some(_)) => mask.to_owned(),
+        (Some(_), None) => mask.to_owned() + "@*",
+        (None, Some(_)) => "*!".to_owned() + mask,
+        (None, None) => mask.to_owned() + "!*@*",
+    }
+}
+
+/// Case-insensitive glob matching, where '*' matches any number of characters and '?' exactly one
+pub fn mask_matches(mask: &str, target: &str) -> bool {
+    let mask = mask.as_bytes();
+    let target = target.as_bytes();
+
+    let (mut m, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < target.len() {
+        if m < mask.len() && mask[m] == b'*' {
+            backtrack = Some((m, t));
+            m += 1;
+        } else if m < mask.len()
+            && (mask[m] == b'?' || mask[m].eq_ignore_ascii_case(&target[t]))
+        {
+            m += 1;
+            t += 1;
+        } else if let Some((star_m, star_t)) = backtrack {
+            m = star_m + 1;
+            t = star_t + 1;
+            backtrack = Some((star_m, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+
+    mask[m..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize() {
+        assert_eq!(normalize_mask("nick"), "nick!*@*");
+        assert_eq!(normalize_mask("nick!user"), "nick!user@*");
+        assert_eq!(normalize_mask("user@host"), "*!user@host");
+        assert_eq!(normalize_mask("nick!user@host"), "nick!user@host");
+        assert_eq!(normalize_mask("*!*@*"), "*!*@*");
+    }
+
+    #[test]
+    fn literal_matches() {
+        assert!(mask_matches("", ""));
+        assert!(mask_matches("abc", "abc"));
+        assert!(mask_matches("abc", "ABC"));
+        assert!(!mask_matches("abc", "abcd"));
+        assert!(!mask_matches("abcd", "abc"));
+        assert!(!mask_matches("", "abc"));
+    }
+
+    #[test]
+    fn wildcard_matches() {
+        assert!(mask_matches("*", ""));
+        assert!(mask_matches("*", "anything"));
+        assert!(mask_matches("a*", "abc"));
+        assert!(mask_matches("*c", "abc"));
+        assert!(mask_matches("a*c", "ac"));
+        assert!(mask_matches("a*c", "abbbc"));
+        assert!(!mask_matches("a*c", "abcd"));
+        assert!(mask_matches("a?c", "abc"));
+        assert!(!mask_matches("a?c", "ac"));
+        assert!(mask_matches("*!*@127.0.0.1", "nick!~user@127.0.0.1"));
+        assert!(mask_matches("nick!*@*", "NICK!~user@10.0.0.1"));
+        assert!(!mask_matches("nick!*@*", "nick2!~user@10.0.0.1"));
+        assert!(mask_matches("*a*b*", "xxaxxbxx"));
+        assert!(!mask_matches("*a*b*", "xxbxxaxx"));
+    }
+}
@@ -0,0 +1,25 @@
+/// Delimits CTCP messages, like "\x01VERSION\x01"
+pub const CTCP_DELIM: char = '\x01';
+
+/// Returns the command of a CTCP-framed message, like "ACTION", or None for regular messages
+/// Some clients omit the closing delimiter, so it is optional
+pub fn ctcp_command(text: &str) -> Option<&str> {
+    let body = text.strip_prefix(CTCP_DELIM)?;
+    let body = body.strip_suffix(CTCP_DELIM).unwrap_or(body);
+    body.split(' ').next().filter(|command| !command.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands() {
+        assert_eq!(ctcp_command("\x01VERSION\x01"), Some("VERSION"));
+        assert_eq!(ctcp_command("\x01ACTION waves\x01"), Some("ACTION"));
+        assert_eq!(ctcp_command("\x01ACTION waves"), Some("ACTION"));
+        assert_eq!(ctcp_command("\x01\x01"), None);
+        assert_eq!(ctcp_command("hello \x01VERSION\x01"), None);
+        assert_eq!(ctcp_command("hello"), None);
+    }
+}
@@ -1,6 +1,52 @@
+use crate::errors::ParseError;
+use bytes::{Bytes, BytesMut};
+use smallvec::SmallVec;
+use std::borrow::Cow;
+use std::fmt::{self, Write as _};
+use std::io::{Error, ErrorKind};
+
 /// Maximum length of a serialized message in bytes
 pub const MAX_LENGTH: usize = 512;
 
+/// Maximum length of the tags of a message in bytes, with the leading '@' and the trailing space
+/// This comes in addition to MAX_LENGTH
+pub const MAX_TAGS_LENGTH: usize = 8191;
+
+/// Maximum length of the tags a client sends, measured like MAX_TAGS_LENGTH
+/// The rest of MAX_TAGS_LENGTH is left for the tags the server adds
+pub const MAX_CLIENT_TAGS_LENGTH: usize = 4094;
+
+/// The parameters of a message, most messages have few enough to need no allocation besides the Strings
+pub type MessageParams = SmallVec<[String; 4]>;
+
+/// Commands common enough to be shared instead of allocated for each message parsed
+const INTERNED_COMMANDS: &[&str] = &[
+    "PRIVMSG", "NOTICE", "TAGMSG", "PING", "PONG", "JOIN", "PART", "MODE", "NICK", "QUIT", "TOPIC",
+    "KICK", "WHO", "WHOIS", "AWAY", "CAP",
+];
+
+/// Checks that a text from the embedder fits in a single parameter without starting a new line
+/// Long texts are truncated when sent, but a NUL, CR or LF would let the text inject its own messages
+pub fn check_text(text: &str) -> Result<(), Error> {
+    if text.contains(['\0', '\r', '\n']) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Text must not contain NUL, CR or LF",
+        ));
+    }
+    Ok(())
+}
+
+fn intern_command(command: &str) -> Cow<'static, str> {
+    match INTERNED_COMMANDS
+        .iter()
+        .find(|&&interned| interned == command)
+    {
+        Some(&interned) => Cow::Borrowed(interned),
+        None => Cow::Owned(command.to_owned()),
+    }
+}
+
 // A tag at the start of an IRC message
 #[derive(PartialEq, Debug, Clone)]
 pub struct MessageTag {
@@ -8,13 +54,70 @@ pub struct MessageTag {
     pub value: Option<String>,
 }
 
-impl ToString for MessageTag {
-    fn to_string(&self) -> String {
-        match self.value {
-            Some(ref value) => self.name.to_owned() + "=" + &value,
-            None => self.name.to_owned(),
+impl MessageTag {
+    fn write_to(&self, out: &mut impl fmt::Write) -> fmt::Result {
+        out.write_str(&self.name)?;
+        if let Some(ref value) = self.value {
+            out.write_char('=')?;
+            write_escaped_tag_value(out, value)?;
+        }
+        Ok(())
+    }
+
+    /// Length of the serialized tag, with its value escaped
+    fn len(&self) -> usize {
+        let mut counter = LenCounter(0);
+        self.write_to(&mut counter).unwrap();
+        counter.0
+    }
+}
+
+impl fmt::Display for MessageTag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write_to(f)
+    }
+}
+
+/// Escapes a tag value as IRCv3 requires, for the characters that would end the tag, the tags or the line
+fn write_escaped_tag_value(out: &mut impl fmt::Write, value: &str) -> fmt::Result {
+    let mut rest = value;
+    while let Some(i) = rest.find([';', ' ', '\\', '\r', '\n']) {
+        out.write_str(&rest[..i])?;
+        out.write_str(match rest.as_bytes()[i] {
+            b';' => "\\:",
+            b' ' => "\\s",
+            b'\\' => "\\\\",
+            b'\r' => "\\r",
+            _ => "\\n",
+        })?;
+        rest = &rest[i + 1..];
+    }
+    out.write_str(rest)
+}
+
+/// Reverts the escaping of a tag value
+/// An unknown escape stands for the character itself, and a lone '\' at the end is dropped
+fn unescape_tag_value(value: &str) -> String {
+    if !value.contains('\\') {
+        return value.to_owned();
+    }
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(':') => unescaped.push(';'),
+            Some('s') => unescaped.push(' '),
+            Some('r') => unescaped.push('\r'),
+            Some('n') => unescaped.push('\n'),
+            Some(c) => unescaped.push(c),
+            None => (),
         }
     }
+    unescaped
 }
 
 // One IRC message, delimited by \r\n, or \n
@@ -22,22 +125,34 @@ impl ToString for MessageTag {
 pub struct Message {
     pub tags: Vec<MessageTag>,
     pub source: Option<String>,
-    pub command: String,
-    pub params: Vec<String>,
+    pub command: Cow<'static, str>,
+    pub params: MessageParams,
 }
 
 impl Message {
     pub fn new(msg_line: &str) -> Message {
-        let (tags, msg_line) = Message::consume_tags(msg_line);
-        let (source, msg_line) = Message::consume_source(msg_line);
-        let (command, params) = Message::parse_command_params(msg_line);
+        MessageRef::parse(msg_line).to_message()
+    }
 
-        Message {
-            tags,
-            source,
-            command,
-            params,
+    /// Parses a line received from a client, without its line ending
+    /// Unlike new, which is for lines we trust, lines that aren't a valid message are an error
+    pub fn parse(msg_line: &str) -> Result<Message, ParseError> {
+        if let Some(c) = msg_line.chars().find(|&c| matches!(c, '\0' | '\r' | '\n')) {
+            return Err(ParseError::IllegalCharacter(c));
         }
+        let msg = MessageRef::parse(msg_line);
+        if msg.command.is_empty() {
+            return Err(if msg.tags.is_empty() && msg.source.is_none() {
+                ParseError::Empty
+            } else {
+                ParseError::MissingCommand
+            });
+        }
+        let is_numeric = msg.command.len() == 3 && msg.command.bytes().all(|b| b.is_ascii_digit());
+        if !is_numeric && !msg.command.bytes().all(|b| b.is_ascii_alphabetic()) {
+            return Err(ParseError::InvalidCommand(msg.command.to_owned()));
+        }
+        Ok(msg.to_message())
     }
 
     /// If a message may have a very long trailing parameter, split it into multiple messages
@@ -47,7 +162,7 @@ impl Message {
         separator: &str,
     ) -> Vec<Message> {
         let mut msgs = Vec::new();
-        let base_len = base_msg.to_line().len();
+        let base_len = base_msg.body_len();
 
         let max_param_len = MAX_LENGTH - base_len - 1;
         let mut next_trailing = String::new();
@@ -76,107 +191,230 @@ impl Message {
     }
 
     pub fn to_line(&self) -> String {
-        let mut line = if self.tags.is_empty() {
-            String::new()
-        } else {
-            "@".to_owned()
-                + &self
-                    .tags
-                    .iter()
-                    .map(|tag| tag.to_string())
-                    .collect::<Vec<_>>()
-                    .join(";")
-                + " "
-        };
+        let mut line = String::with_capacity(self.line_len());
+        self.write_to(&mut line).unwrap();
+        line
+    }
+
+    /// Serializes the message at the end of a buffer, like a connection's send buffer
+    pub fn to_bytes(&self, buf: &mut BytesMut) {
+        self.write_to(buf).unwrap();
+    }
+
+    /// Serializes the message with its line ending, to be shared by the connections it is relayed to
+    pub fn to_shared_line(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(self.line_len() + 2);
+        self.to_bytes(&mut buf);
+        buf.extend_from_slice(b"\r\n");
+        buf.freeze()
+    }
+
+    /// Length of the serialized message in bytes, without serializing it
+    pub fn line_len(&self) -> usize {
+        let mut counter = LenCounter(0);
+        self.write_to(&mut counter).unwrap();
+        counter.0
+    }
+
+    /// Length of the serialized message without its tags, which is what MAX_LENGTH applies to
+    pub fn body_len(&self) -> usize {
+        let mut counter = LenCounter(0);
+        self.write_tags(&mut counter).unwrap();
+        self.line_len() - counter.0
+    }
+
+    /// Serializes the message without its line ending
+    /// What follows the tags is cut at MAX_LENGTH, so clients never receive longer lines
+    pub fn write_to(&self, out: &mut impl fmt::Write) -> fmt::Result {
+        self.write_tags(out)?;
+        self.write_body(out)
+    }
 
+    /// Writes the tags that fit in MAX_TAGS_LENGTH, those that don't are left out whole
+    fn write_tags(&self, out: &mut impl fmt::Write) -> fmt::Result {
+        let mut remaining = MAX_TAGS_LENGTH - "@ ".len();
+        let mut written_any = false;
+        for tag in &self.tags {
+            let len = tag.len() + written_any as usize;
+            if len > remaining {
+                continue;
+            }
+            remaining -= len;
+            out.write_char(if written_any { ';' } else { '@' })?;
+            tag.write_to(out)?;
+            written_any = true;
+        }
+        // Only separates the tags from what follows, see the empty command case in write_body
+        if written_any && (self.source.is_some() || !self.command.is_empty()) {
+            out.write_char(' ')?;
+        }
+        Ok(())
+    }
+
+    fn write_body(&self, out: &mut impl fmt::Write) -> fmt::Result {
+        // The source counts in the length, so relaying a message can make it too long
+        let out = &mut Truncating {
+            out,
+            remaining: MAX_LENGTH - "\r\n".len(),
+        };
         if let Some(ref source) = self.source {
-            line = line + ":" + &source + " ";
+            out.write_char(':')?;
+            out.write_str(source)?;
+            if !self.command.is_empty() {
+                out.write_char(' ')?;
+            }
         }
 
         // Empty command are a special case to get clean roundtrips on messages like ":only-a-source"
         if self.command.is_empty() {
             debug_assert!(self.params.is_empty());
-            line.pop();
         } else {
-            line += &self.command;
+            out.write_str(&self.command)?;
 
             for (i, param) in self.params.iter().enumerate() {
                 if i == self.params.len() - 1
                     && (param.contains(' ') || param.contains(':') || param.is_empty())
                 {
-                    line = line + " :" + param;
+                    out.write_str(" :")?;
                 } else {
                     debug_assert!(!param.contains(' '));
-                    line = line + " " + param;
+                    out.write_char(' ')?;
                 }
+                out.write_str(param)?;
             }
         }
 
-        line
+        Ok(())
+    }
+}
+
+/// Counts the bytes written to it, to measure a message without allocating it
+struct LenCounter(usize);
+
+impl fmt::Write for LenCounter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0 += s.len();
+        Ok(())
+    }
+}
+
+/// Writes up to a number of bytes and drops the rest, cut at a character boundary
+struct Truncating<'a, W: fmt::Write> {
+    out: &'a mut W,
+    remaining: usize,
+}
+
+impl<W: fmt::Write> fmt::Write for Truncating<'_, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut len = s.len().min(self.remaining);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+        // Nothing is written after a cut, even if it would fit
+        self.remaining = if len < s.len() {
+            0
+        } else {
+            self.remaining - len
+        };
+        self.out.write_str(&s[..len])
+    }
+}
+
+/// A message parsed without copying, its parts borrow the line it was parsed from
+/// Tag values are left escaped, to_message unescapes them
+#[derive(PartialEq, Debug, Clone)]
+pub struct MessageRef<'a> {
+    pub tags: Vec<(&'a str, Option<&'a str>)>,
+    pub source: Option<&'a str>,
+    pub command: &'a str,
+    pub params: SmallVec<[&'a str; 4]>,
+}
+
+impl<'a> MessageRef<'a> {
+    pub fn parse(msg_line: &'a str) -> MessageRef<'a> {
+        let (tags, msg_line) = MessageRef::consume_tags(msg_line);
+        let (source, msg_line) = MessageRef::consume_source(msg_line);
+        let (command, params) = MessageRef::parse_command_params(msg_line);
+
+        MessageRef {
+            tags,
+            source,
+            command,
+            params,
+        }
+    }
+
+    /// Copies the parts of the message, for handlers that keep or modify it
+    pub fn to_message(&self) -> Message {
+        Message {
+            tags: self
+                .tags
+                .iter()
+                .map(|&(name, value)| MessageTag {
+                    name: name.to_owned(),
+                    value: value.map(unescape_tag_value),
+                })
+                .collect(),
+            source: self.source.map(str::to_owned),
+            command: intern_command(self.command),
+            params: self.params.iter().map(|&param| param.to_owned()).collect(),
+        }
+    }
+
+    /// Splits the first word off a line, and returns it with the rest of the line after the space
+    fn split_word(msg_line: &str) -> (&str, &str) {
+        match msg_line.find(' ') {
+            Some(next_space) => (&msg_line[..next_space], &msg_line[next_space + 1..]),
+            None => (msg_line, ""),
+        }
     }
 
-    fn consume_tags(msg_line: &str) -> (Vec<MessageTag>, &str) {
+    fn consume_tags(msg_line: &'a str) -> (Vec<(&'a str, Option<&'a str>)>, &'a str) {
         assert!(!msg_line.ends_with('\n'));
         let msg_line = msg_line.trim_start();
-        if msg_line.bytes().next() == Some(b'@') {
-            let (tags_word, next_msg_line) = if let Some(next_space) = msg_line.find(' ') {
-                (&msg_line[1..next_space], &msg_line[next_space..])
-            } else {
-                (&msg_line[1..], "")
-            };
-
-            let tags = tags_word
-                .split(';')
-                .map(|tag| {
-                    if let Some(equal) = tag.find('=') {
-                        MessageTag {
-                            name: tag[..equal].to_string(),
-                            value: Some(tag[equal + 1..].to_string()),
-                        }
-                    } else {
-                        MessageTag {
-                            name: tag.to_string(),
-                            value: None,
-                        }
-                    }
-                })
-                .collect();
-            (tags, next_msg_line)
-        } else {
-            (Vec::new(), msg_line)
+        match msg_line.strip_prefix('@') {
+            Some(msg_line) => {
+                let (tags_word, next_msg_line) = MessageRef::split_word(msg_line);
+                let tags = tags_word
+                    .split(';')
+                    .map(|tag| match tag.split_once('=') {
+                        Some((name, value)) => (name, Some(value)),
+                        None => (tag, None),
+                    })
+                    .collect();
+                (tags, next_msg_line)
+            }
+            None => (Vec::new(), msg_line),
         }
     }
 
-    fn consume_source(msg_line: &str) -> (Option<String>, &str) {
+    fn consume_source(msg_line: &'a str) -> (Option<&'a str>, &'a str) {
         let msg_line = msg_line.trim_start();
-        if msg_line.bytes().next() == Some(b':') {
-            match msg_line.find(' ') {
-                Some(next_space) => (
-                    Some(msg_line[1..next_space].to_string()),
-                    &msg_line[next_space..],
-                ),
-                None => (Some(msg_line[1..].to_string()), ""),
+        match msg_line.strip_prefix(':') {
+            Some(msg_line) => {
+                let (source, next_msg_line) = MessageRef::split_word(msg_line);
+                (Some(source), next_msg_line)
             }
-        } else {
-            (None, msg_line)
+            None => (None, msg_line),
         }
     }
 
-    fn parse_command_params(msg_line: &str) -> (String, Vec<String>) {
-        let words = &mut msg_line.trim_start().split(' ');
-        let command = words.next().unwrap_or("").to_string();
-        let mut params = Vec::new();
-        loop {
-            let param = match words.next() {
-                Some(word) => word,
-                None => return (command, params),
-            };
-            if param.bytes().next() == Some(b':') {
-                params.push(words.fold(param[1..].to_string(), |s, w| s + " " + w));
-            } else if !param.is_empty() {
-                params.push(param.to_string());
+    fn parse_command_params(msg_line: &'a str) -> (&'a str, SmallVec<[&'a str; 4]>) {
+        let (command, mut msg_line) = MessageRef::split_word(msg_line.trim_start());
+        let mut params = SmallVec::new();
+        while !msg_line.is_empty() {
+            // The trailing parameter is the rest of the line, spaces included
+            if let Some(trailing) = msg_line.strip_prefix(':') {
+                params.push(trailing);
+                break;
             }
+            let (param, next_msg_line) = MessageRef::split_word(msg_line);
+            if !param.is_empty() {
+                params.push(param);
+            }
+            msg_line = next_msg_line;
         }
+        (command, params)
     }
 }
 
@@ -204,7 +442,7 @@ mod tests {
         assert_eq!(parsed_msg.tags, tags);
         assert_eq!(parsed_msg.source, source.map(|s| s.to_string()));
         assert_eq!(parsed_msg.command, command.to_string());
-        assert_eq!(parsed_msg.params, params);
+        assert_eq!(parsed_msg.params.as_slice(), params);
         if msg_is_normalized {
             assert_eq!(&parsed_msg.to_line(), msg_line);
         }
@@ -212,6 +450,14 @@ mod tests {
 
     // Test vectors happily copied from https://github.com/grawity/code/blob/3d9e1c43ef07671eda92289240ef7570d4e86b21/lib/tests/irc-split.txt
 
+    #[test]
+    fn text_checks() {
+        assert!(check_text("Hello: world!").is_ok());
+        assert!(check_text("Hi\r\nQUIT").is_err());
+        assert!(check_text("Hi\nQUIT").is_err());
+        assert!(check_text("Hi\0").is_err());
+    }
+
     #[test]
     fn no_line_endings() {
         let base = "foo bar baz";
@@ -474,4 +720,93 @@ mod tests {
             &["bar", "baz asdf\t"],
         );
     }
+
+    #[test]
+    fn serialize_into_buffer() {
+        let mut buf = BytesMut::new();
+        for line in &["@a=b;c :nick!u@h PRIVMSG #chan :hi there", "@foo :bar", ":", ""] {
+            let msg = Message::new(line);
+            assert_eq!(msg.line_len(), line.len());
+            buf.clear();
+            msg.to_bytes(&mut buf);
+            assert_eq!(&buf[..], line.as_bytes());
+        }
+    }
+
+    #[test]
+    fn tag_escaping() {
+        let line = r"@a=semi\:space\sback\\cr\rlf\n;b=\x\;c=end\ :n PING";
+        let msg = Message::new(line);
+        let values: Vec<_> = msg.tags.iter().map(|tag| tag.value.as_deref()).collect();
+        assert_eq!(
+            values,
+            [Some("semi;space back\\cr\rlf\n"), Some("x"), Some("end")]
+        );
+        assert_eq!(msg.tags[0].to_string(), r"a=semi\:space\sback\\cr\rlf\n");
+
+        // Unknown escapes and a lone '\' at the end don't survive the round trip
+        let normalized = r"@a=semi\:space\sback\\cr\rlf\n;b=x;c=end :n PING";
+        assert_eq!(msg.to_line(), normalized);
+        assert_eq!(Message::new(normalized), msg);
+    }
+
+    #[test]
+    fn long_messages_are_cut() {
+        let tags = format!("@a={} ", "b".repeat(600));
+        let text = "é".repeat(MAX_LENGTH);
+        let msg = Message::new(&format!("{}:nick!user@host PRIVMSG #chan2 :{}", tags, text));
+        let line = msg.to_line();
+        assert!(line.starts_with(&tags));
+        // The cut falls in the middle of a character
+        assert_eq!(line.len() - tags.len(), MAX_LENGTH - 3);
+        assert_eq!(msg.line_len(), line.len());
+        assert_eq!(msg.to_shared_line().len(), line.len() + 2);
+    }
+
+    #[test]
+    fn long_tags_are_left_out() {
+        let long_tag = format!("a={}", "b".repeat(MAX_TAGS_LENGTH - "@a= ".len()));
+        let msg = Message::new(&format!("@x;{};y PING a", long_tag));
+        assert_eq!(msg.to_line(), "@x;y PING a");
+        let msg = Message::new(&format!("@{} PING a", long_tag));
+        assert_eq!(msg.to_line().len(), MAX_TAGS_LENGTH + "PING a".len());
+        assert_eq!(msg.body_len(), "PING a".len());
+
+        // The tags don't take room from the split parameters
+        let base = Message::new(&format!("@{} :server 353 nick = #chan", long_tag));
+        let params = vec!["n".repeat(100); 20];
+        let msgs = Message::split_trailing_args(base, params, " ");
+        assert!(msgs.len() < 10);
+        assert!(msgs.iter().all(|msg| msg.body_len() <= MAX_LENGTH - 2));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(Message::parse("PING a").unwrap(), Message::new("PING a"));
+        assert_eq!(Message::parse("  "), Err(ParseError::Empty));
+        assert_eq!(
+            Message::parse("@a=b :nick"),
+            Err(ParseError::MissingCommand)
+        );
+        assert_eq!(
+            Message::parse(":nick PR1VMSG #c :hi"),
+            Err(ParseError::InvalidCommand("PR1VMSG".to_owned()))
+        );
+        assert!(Message::parse(":server 001 nick :hi").is_ok());
+        assert_eq!(
+            Message::parse("PRIVMSG #c :a\nb"),
+            Err(ParseError::IllegalCharacter('\n'))
+        );
+    }
+
+    #[test]
+    fn parse_borrowed() {
+        let line = "@id=1;rx :nick!user@host PRIVMSG #chan :hello  world";
+        let msg = MessageRef::parse(line);
+        assert_eq!(msg.tags, [("id", Some("1")), ("rx", None)]);
+        assert_eq!(msg.source, Some("nick!user@host"));
+        assert_eq!(msg.command, "PRIVMSG");
+        assert_eq!(msg.params.as_slice(), ["#chan", "hello  world"]);
+        assert_eq!(msg.to_message(), Message::new(line));
+    }
 }
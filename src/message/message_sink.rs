@@ -1,21 +1,127 @@
 use crate::message::Message;
+use bytes::{Buf, Bytes, BytesMut};
 use futures::task::{Context, Poll};
-use futures::Sink;
-use std::io::Error;
+use futures::{ready, Sink};
+use smallvec::SmallVec;
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind, IoSlice};
+use std::time::{Duration, Instant};
 use tokio::io::AsyncWrite;
 use tokio::macros::support::Pin;
 
+/// How many chunks of the send buffer are passed to a single vectored write
+const MAX_WRITE_CHUNKS: usize = 64;
+
 // A Sink for sending IRC messages
 pub struct MessageSink<T: AsyncWrite + Unpin> {
     io: Pin<Box<T>>,
-    send_buffer: Vec<u8>,
+    /// Messages serialized for this connection, not yet moved to the chunks
+    send_buffer: BytesMut,
+    /// Data waiting to be written in order, lines shared with other connections are not copied
+    chunks: VecDeque<Bytes>,
+    /// How many chunks at the front urgent messages are queued behind: urgent ones, or one partly written
+    ahead: usize,
+    /// Whether data was written since the socket was last flushed
+    needs_flush: bool,
+    /// When the socket last accepted data, or when data was queued to an empty buffer
+    last_progress: Instant,
 }
 
 impl<T: AsyncWrite + Unpin> MessageSink<T> {
     pub fn new(io: T) -> MessageSink<T> {
         MessageSink {
             io: Box::pin(io),
-            send_buffer: Vec::new(),
+            send_buffer: BytesMut::new(),
+            chunks: VecDeque::new(),
+            ahead: 0,
+            needs_flush: false,
+            last_progress: Instant::now(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.send_buffer.is_empty() && self.chunks.is_empty()
+    }
+
+    /// How long queued data has been waiting without the socket accepting any of it
+    pub fn stalled_for(&self) -> Duration {
+        if self.is_empty() {
+            Duration::ZERO
+        } else {
+            self.last_progress.elapsed()
+        }
+    }
+
+    /// Adds a message to the send buffer without waiting for it to be written
+    pub fn queue(&mut self, item: &Message) {
+        if self.is_empty() {
+            self.last_progress = Instant::now();
+        }
+        item.to_bytes(&mut self.send_buffer);
+        self.send_buffer.extend_from_slice(b"\r\n");
+    }
+
+    /// Adds a line from Message::to_shared_line, so a message relayed to many clients is only serialized once
+    pub fn queue_line(&mut self, line: Bytes) {
+        if self.is_empty() {
+            self.last_progress = Instant::now();
+        }
+        self.split_send_buffer();
+        self.chunks.push_back(line);
+    }
+
+    /// Adds a message ahead of the queued ones, only after the line being written and earlier urgent messages
+    pub fn queue_urgent(&mut self, item: &Message) {
+        if self.is_empty() {
+            self.last_progress = Instant::now();
+        }
+        self.chunks.insert(self.ahead, item.to_shared_line());
+        self.ahead += 1;
+    }
+
+    /// Moves the messages serialized so far to the chunks, to keep them in order
+    fn split_send_buffer(&mut self) {
+        if !self.send_buffer.is_empty() {
+            self.chunks.push_back(self.send_buffer.split().freeze());
+        }
+    }
+
+    /// Without vectored writes each chunk would be written separately, and over TLS become its own record
+    /// The chunks urgent messages are queued behind are kept apart, so later ones can still go ahead of the rest
+    fn merge_chunks(&mut self) {
+        if self.chunks.len() <= self.ahead + 1 {
+            return;
+        }
+        let len = self.chunks.iter().skip(self.ahead).map(Bytes::len).sum();
+        let mut merged = BytesMut::with_capacity(len);
+        for chunk in self.chunks.drain(self.ahead..) {
+            merged.extend_from_slice(&chunk);
+        }
+        self.chunks.push_back(merged.freeze());
+    }
+
+    /// Drops the chunks the socket accepted
+    fn consume(&mut self, mut written: usize) {
+        while written > 0 {
+            let chunk = self.chunks.front_mut().unwrap();
+            if chunk.len() > written {
+                chunk.advance(written);
+                if self.ahead == 0 {
+                    // Urgent messages go after the line being written, not after the whole chunk
+                    match chunk.iter().position(|&b| b == b'\n') {
+                        Some(end) if end + 1 < chunk.len() => {
+                            let rest_of_line = chunk.split_to(end + 1);
+                            self.chunks.push_front(rest_of_line);
+                        }
+                        _ => (),
+                    }
+                    self.ahead = 1;
+                }
+                return;
+            }
+            written -= chunk.len();
+            self.chunks.pop_front();
+            self.ahead = self.ahead.saturating_sub(1);
         }
     }
 }
@@ -28,25 +134,44 @@ impl<T: AsyncWrite + Unpin> Sink<Message> for MessageSink<T> {
     }
 
     fn start_send(mut self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
-        self.send_buffer
-            .extend_from_slice(item.to_line().as_bytes());
-        self.send_buffer.push('\n' as u8);
+        self.queue(&item);
         Ok(())
     }
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         let this = Pin::into_inner(self);
+        this.split_send_buffer();
+        if !this.io.is_write_vectored() {
+            this.merge_chunks();
+        }
 
-        while !this.send_buffer.is_empty() {
-            match this.io.as_mut().poll_write(cx, &this.send_buffer) {
+        while !this.chunks.is_empty() {
+            let slices: SmallVec<[IoSlice; MAX_WRITE_CHUNKS]> = this
+                .chunks
+                .iter()
+                .take(MAX_WRITE_CHUNKS)
+                .map(|chunk| IoSlice::new(chunk))
+                .collect();
+            let written = this.io.as_mut().poll_write_vectored(cx, &slices);
+            drop(slices);
+            match written {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(ErrorKind::WriteZero.into())),
                 Poll::Ready(Ok(n)) => {
-                    this.send_buffer.drain(0..n);
+                    this.consume(n);
+                    this.needs_flush = true;
+                    this.last_progress = Instant::now();
                 }
                 Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
                 Poll::Pending => return Poll::Pending,
             }
         }
 
+        // Once everything queued is written, so a TLS stream sends its last record right away
+        if this.needs_flush {
+            ready!(this.io.as_mut().poll_flush(cx))?;
+            this.needs_flush = false;
+        }
+
         Poll::Ready(Ok(()))
     }
 
@@ -54,3 +179,42 @@ impl<T: AsyncWrite + Unpin> Sink<Message> for MessageSink<T> {
         self.poll_flush(cx)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::SinkExt;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn shared_lines_keep_their_order() {
+        let (client, mut server) = tokio::io::duplex(4096);
+        let mut sink = MessageSink::new(client);
+        sink.queue(&Message::new("PING :a"));
+        sink.queue_line(Message::new(":n PRIVMSG #c :hi").to_shared_line());
+        sink.queue(&Message::new("PING :b"));
+        sink.flush().await.unwrap();
+        drop(sink);
+
+        let mut received = String::new();
+        server.read_to_string(&mut received).await.unwrap();
+        assert_eq!(received, "PING a\r\n:n PRIVMSG #c hi\r\nPING b\r\n");
+    }
+
+    #[tokio::test]
+    async fn urgent_messages_skip_the_backlog() {
+        let (client, mut server) = tokio::io::duplex(4096);
+        let mut sink = MessageSink::new(client);
+        sink.queue(&Message::new("NOTICE n :backlog"));
+        sink.queue_line(Message::new("NOTICE n :relayed").to_shared_line());
+        sink.queue_urgent(&Message::new("PONG :a"));
+        sink.queue_urgent(&Message::new("PONG :b"));
+        sink.flush().await.unwrap();
+        drop(sink);
+
+        let mut received = String::new();
+        server.read_to_string(&mut received).await.unwrap();
+        let expected = "PONG a\r\nPONG b\r\nNOTICE n backlog\r\nNOTICE n relayed\r\n";
+        assert_eq!(received, expected);
+    }
+}
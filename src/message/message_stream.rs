@@ -1,30 +1,213 @@
 use futures::{ready, Stream};
-use std::io::Error;
-use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, Lines};
+use std::io::{Error, ErrorKind};
+use tokio::io::{AsyncBufRead, AsyncRead};
 
-use crate::message::Message;
+use crate::errors::{InputTooLongError, InvalidUtf8Error, ParseError};
+use crate::message::{Message, MAX_CLIENT_TAGS_LENGTH, MAX_LENGTH};
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
+/// Longest line we read, with the end of line, the rest of longer lines is skipped so they can't use unbounded memory
+pub const MAX_LINE_LENGTH: usize = MAX_CLIENT_TAGS_LENGTH + MAX_LENGTH;
+
+/// What happens to lines from clients that are longer than MAX_LENGTH not counting their tags,
+/// or whose tags are longer than MAX_CLIENT_TAGS_LENGTH
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LongLinePolicy {
+    /// The line is ignored, and the client gets ERR_INPUTTOOLONG
+    #[default]
+    Reject,
+    /// The line is cut at the maximum length and processed, as the protocol allows
+    /// Tags that are too long are dropped
+    Truncate,
+    /// The client is disconnected
+    Disconnect,
+}
+
+/// What happens to lines from clients that are not valid UTF-8
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InvalidUtf8Policy {
+    /// The line is ignored, and the client gets a FAIL INVALID_UTF8 standard reply
+    #[default]
+    Fail,
+    /// The invalid bytes are replaced by U+FFFD and the line is processed
+    Replace,
+}
+
 // A Stream for receiving IRC messages
+// Lines that are too long are returned as an InputTooLongError, the stream can still be read after that
+// The same goes for lines that are not valid UTF-8, which are returned as an InvalidUtf8Error,
+// and for lines that are not a valid message, which are returned as a ParseError
 #[must_use = "streams do nothing unless polled"]
 pub struct MessageStream<T: AsyncRead + AsyncBufRead + Unpin> {
-    lines: Lines<T>,
+    io: T,
+    /// The line being read, reused between messages
+    buf: Vec<u8>,
+    /// Whether the line being read is longer than MAX_LINE_LENGTH, only its start is kept
+    too_long: bool,
 }
 
 impl<T: AsyncRead + AsyncBufRead + Unpin> MessageStream<T> {
     pub fn new(io: T) -> MessageStream<T> {
-        MessageStream { lines: io.lines() }
+        MessageStream {
+            io,
+            buf: Vec::new(),
+            too_long: false,
+        }
+    }
+
+    /// Reads until the end of the line, returns false if the stream ended without any data
+    fn poll_read_line(&mut self, cx: &mut Context<'_>) -> Poll<Result<bool, Error>> {
+        loop {
+            let available = ready!(Pin::new(&mut self.io).poll_fill_buf(cx))?;
+            if available.is_empty() {
+                return Poll::Ready(Ok(!self.buf.is_empty()));
+            }
+            let end = available.iter().position(|&b| b == b'\n');
+            let line_part = &available[..end.unwrap_or(available.len())];
+            // The line ending counts in the length
+            let room = (MAX_LINE_LENGTH - 1).saturating_sub(self.buf.len());
+            if line_part.len() > room {
+                self.too_long = true;
+            }
+            self.buf
+                .extend_from_slice(&line_part[..line_part.len().min(room)]);
+            let consumed = line_part.len() + end.map_or(0, |_| 1);
+            Pin::new(&mut self.io).consume(consumed);
+
+            if end.is_some() {
+                return Poll::Ready(Ok(true));
+            }
+        }
+    }
+
+    /// Parses the line read, or returns an InputTooLongError with the line cut at the maximum length
+    fn parse_line(&mut self) -> Result<Message, Error> {
+        if self.buf.last() == Some(&b'\r') {
+            self.buf.pop();
+        }
+        let tags_len = match self.buf.first() {
+            Some(b'@') => self
+                .buf
+                .iter()
+                .position(|&b| b == b' ')
+                .map(|space| space + 1),
+            _ => Some(0),
+        };
+        // A line without a space after its tags is all tags
+        let tags_end = tags_len.unwrap_or(self.buf.len());
+        let tags_too_long = tags_end > MAX_CLIENT_TAGS_LENGTH;
+        let max_len = tags_end + MAX_LENGTH - "\r\n".len();
+        let too_long =
+            std::mem::take(&mut self.too_long) || tags_too_long || self.buf.len() > max_len;
+        if !too_long {
+            // The message is parsed in place, only its parts are copied out of the buffer
+            return match std::str::from_utf8(&self.buf) {
+                Ok(line) => Message::parse(line).map_err(invalid_message),
+                Err(_) => {
+                    let lossy = Message::parse(&String::from_utf8_lossy(&self.buf))
+                        .map_err(invalid_message)?;
+                    Err(Error::new(
+                        ErrorKind::InvalidData,
+                        InvalidUtf8Error::new(lossy),
+                    ))
+                }
+            };
+        }
+
+        // Without the end of the tags, there is nothing to truncate
+        let truncated = tags_len.and_then(|tags_len| {
+            let start = if tags_too_long { tags_len } else { 0 };
+            let line = &self.buf[start..max_len.min(self.buf.len())];
+            let line = match std::str::from_utf8(line) {
+                Ok(line) => line,
+                // The cut can fall in the middle of a character
+                Err(err) if err.error_len().is_none() => {
+                    std::str::from_utf8(&line[..err.valid_up_to()]).unwrap()
+                }
+                Err(_) => return None,
+            };
+            Message::parse(line).ok()
+        });
+        Err(Error::new(
+            ErrorKind::InvalidData,
+            InputTooLongError::new(truncated),
+        ))
     }
 }
 
+fn invalid_message(err: ParseError) -> Error {
+    Error::new(ErrorKind::InvalidData, err)
+}
+
 impl<T: AsyncRead + AsyncBufRead + Unpin> Stream for MessageStream<T> {
     type Item = Result<Message, Error>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         let this = Pin::into_inner(self);
-        let line = ready!(Pin::new(&mut this.lines).poll_next_line(cx))?;
-        let line = line.map(|s| Message::new(&s));
-        Poll::Ready(Ok(line).transpose())
+        if !ready!(this.poll_read_line(cx))? {
+            return Poll::Ready(None);
+        }
+        let msg = this.parse_line();
+        this.buf.clear();
+        Poll::Ready(Some(msg))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use tokio::io::BufReader;
+
+    fn truncated(err: Error) -> Option<Message> {
+        let inner = err.into_inner().unwrap();
+        inner.downcast::<InputTooLongError>().unwrap().truncated
+    }
+
+    #[tokio::test]
+    async fn long_lines_are_truncated() {
+        let text = "é".repeat(MAX_LINE_LENGTH);
+        let input = format!("PING a\r\n@a=b PRIVMSG #cc :{}\r\nPING b\r\n", text);
+        let mut stream = MessageStream::new(BufReader::with_capacity(64, input.as_bytes()));
+
+        assert_eq!(stream.next().await.unwrap().unwrap().params[0], "a");
+        let err = stream.next().await.unwrap().unwrap_err();
+        let msg = truncated(err).unwrap();
+        assert_eq!(msg.tags.len(), 1);
+        assert_eq!(
+            msg.params[1].len(),
+            MAX_LENGTH - "PRIVMSG #cc :\r\n".len() - 1
+        );
+        assert_eq!(stream.next().await.unwrap().unwrap().params[0], "b");
+        assert!(stream.next().await.is_none());
+
+        let input = format!("@{} PING a\r\n", "a".repeat(MAX_LINE_LENGTH));
+        let mut stream = MessageStream::new(BufReader::new(input.as_bytes()));
+        assert!(truncated(stream.next().await.unwrap().unwrap_err()).is_none());
+    }
+
+    #[tokio::test]
+    async fn invalid_utf8_is_replaced() {
+        let input = b"PRIVMSG #c :caf\xe9\r\nPING a\r\n";
+        let mut stream = MessageStream::new(BufReader::new(&input[..]));
+
+        let err = stream.next().await.unwrap().unwrap_err();
+        let inner = err.into_inner().unwrap();
+        let msg = inner.downcast::<InvalidUtf8Error>().unwrap().lossy;
+        assert_eq!(msg.params[1], "caf\u{FFFD}");
+        assert_eq!(stream.next().await.unwrap().unwrap().params[0], "a");
+    }
+
+    #[tokio::test]
+    async fn long_client_tags_are_dropped() {
+        let tags = format!("@a={}", "b".repeat(MAX_CLIENT_TAGS_LENGTH - "@a= ".len()));
+        let input = format!("{} PING a\r\n{}b PING b\r\n", tags, tags);
+        let mut stream = MessageStream::new(BufReader::new(input.as_bytes()));
+
+        assert_eq!(stream.next().await.unwrap().unwrap().tags.len(), 1);
+        let msg = truncated(stream.next().await.unwrap().unwrap_err()).unwrap();
+        assert!(msg.tags.is_empty());
+        assert_eq!(msg.params[0], "b");
     }
 }
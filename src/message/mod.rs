@@ -1,9 +1,11 @@
+mod ctcp;
 mod message_impl;
 mod message_sink;
 mod message_stream;
 mod reply_codes;
 
-pub use self::message_impl::{Message, MAX_LENGTH};
+pub use self::ctcp::ctcp_command;
+pub use self::message_impl::{check_text, Message, MessageParams, MAX_CLIENT_TAGS_LENGTH, MAX_LENGTH};
 pub use self::message_sink::MessageSink;
-pub use self::message_stream::MessageStream;
+pub use self::message_stream::{InvalidUtf8Policy, LongLinePolicy, MessageStream};
 pub use self::reply_codes::{make_reply_msg, ReplyCode};
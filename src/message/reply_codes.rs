@@ -1,4 +1,4 @@
-use crate::message::Message;
+use crate::message::{Message, MessageParams};
 use crate::server::ServerState;
 use chrono::{DateTime, Local};
 
@@ -11,10 +11,20 @@ pub enum ReplyCode {
     RplIsSupport {
         features: Vec<String>,
     },
+    RplSnomask {
+        snomask: String,
+    },
 
+    RplEndOfStats {
+        letter: char,
+    },
     RplUModeIs {
         modestring: String,
     },
+    RplStatsDebug {
+        letter: char,
+        text: String,
+    },
     RplLuserClient {
         num_visibles: usize,
         num_invisibles: usize,
@@ -57,9 +67,21 @@ pub enum ReplyCode {
     RplEndOfWhois {
         masks: String,
     },
+    /// This is a base reply, it does not include channels since they may not fit in a single message.
+    RplWhoisChannels {
+        nick: String,
+    },
+    RplListStart,
+    RplList {
+        channel: String,
+        num_visible: usize,
+        topic: String,
+    },
+    RplListEnd,
     RplChannelModeIs {
         channel: String,
         modestring: String,
+        mode_params: Vec<String>,
     },
     RplCreationTime {
         channel: String,
@@ -77,6 +99,40 @@ pub enum ReplyCode {
         who: String,
         time: DateTime<Local>,
     },
+    RplInviting {
+        nick: String,
+        channel: String,
+    },
+    /// Fingerprint of a user's TLS client certificate
+    RplWhoisCertFp {
+        nick: String,
+        fingerprint: String,
+    },
+    /// The real address of a user, only meant for operators
+    RplWhoisActually {
+        nick: String,
+        user: String,
+        host: String,
+        ip: String,
+    },
+    RplInviteList {
+        channel: String,
+        mask: String,
+        set_by: String,
+        set_at: DateTime<Local>,
+    },
+    RplEndOfInviteList {
+        channel: String,
+    },
+    RplExceptList {
+        channel: String,
+        mask: String,
+        set_by: String,
+        set_at: DateTime<Local>,
+    },
+    RplEndOfExceptList {
+        channel: String,
+    },
     RplVersion {
         comments: String,
     },
@@ -86,7 +142,7 @@ pub enum ReplyCode {
         host: String,
         server: String,
         nick: String,
-        status: char,
+        status: String,
         hopcount: u32,
         realname: String,
     },
@@ -98,6 +154,31 @@ pub enum ReplyCode {
     RplEndOfNames {
         channel: String,
     },
+    RplBanList {
+        channel: String,
+        mask: String,
+        set_by: String,
+        set_at: DateTime<Local>,
+    },
+    RplEndOfBanList {
+        channel: String,
+    },
+    RplMotd {
+        line: String,
+    },
+    RplMotdStart,
+    RplEndOfMotd,
+    RplYoureOper,
+
+    RplQuietList {
+        channel: String,
+        mask: String,
+        set_by: String,
+        set_at: DateTime<Local>,
+    },
+    RplEndOfQuietList {
+        channel: String,
+    },
 
     ErrNoSuchNick {
         nick: String,
@@ -119,6 +200,7 @@ pub enum ReplyCode {
         cmd: String,
     },
     ErrNoTextToSend,
+    ErrInputTooLong,
     ErrUnknownCommand {
         cmd: String,
     },
@@ -130,23 +212,88 @@ pub enum ReplyCode {
     ErrNicknameInUse {
         nick: String,
     },
+    ErrNickRefused {
+        nick: String,
+        reason: String,
+    },
+    ErrUserNotInChannel {
+        nick: String,
+        channel: String,
+    },
     ErrNotOnChannel {
         channel: String,
     },
+    ErrUserOnChannel {
+        nick: String,
+        channel: String,
+    },
+    ErrNoNickChange {
+        channel: String,
+    },
+    ErrNotRegistered,
     ErrNeedMoreParams {
         cmd: String,
     },
     ErrAlreadyRegistered,
+    ErrPasswdMismatch,
     ErrUnknownMode {
         mode: char,
     },
+    ErrLinkChannel {
+        channel: String,
+        forward: String,
+    },
+    ErrChannelIsFull {
+        channel: String,
+    },
+    ErrInviteOnlyChan {
+        channel: String,
+    },
+    ErrBannedFromChan {
+        channel: String,
+    },
+    ErrCannotJoinChan {
+        channel: String,
+        reason: String,
+    },
+    ErrBadChannelKey {
+        channel: String,
+    },
+    ErrBadChanName {
+        channel: String,
+    },
+    ErrNeedReggedNick {
+        channel: String,
+    },
+    ErrNoPrivileges,
+    ErrChanOPrivsNeeded {
+        channel: String,
+    },
+    ErrDelayRejoin {
+        channel: String,
+        seconds: u64,
+    },
+    ErrSecureOnlyChan {
+        channel: String,
+    },
+    ErrNoNonReg {
+        nick: String,
+    },
+    ErrCannotSendToUser {
+        nick: String,
+        reason: String,
+    },
+    ErrNoOperHost,
 
     ErrUModeUnknownFlag,
     ErrUsersDontMatch,
+    ErrOperOnly {
+        channel: String,
+    },
 }
 
 pub fn make_reply_msg(state: &ServerState, client_nick: &str, reply_type: ReplyCode) -> Message {
-    let (cmd_num, mut params, description) = match reply_type {
+    let (cmd_num, params, description) = match reply_type {
         ReplyCode::RplWelcome => (
             "001",
             vec![],
@@ -182,8 +329,17 @@ pub fn make_reply_msg(state: &ServerState, client_nick: &str, reply_type: ReplyC
             features,
             Some(format!("are supported by this server")),
         ),
+        ReplyCode::RplSnomask { snomask } => {
+            ("008", vec![snomask], Some(format!("Server notice mask")))
+        }
 
+        ReplyCode::RplEndOfStats { letter } => (
+            "219",
+            vec![letter.to_string()],
+            Some(format!("End of /STATS report")),
+        ),
         ReplyCode::RplUModeIs { modestring } => ("221", vec![], Some(modestring)),
+        ReplyCode::RplStatsDebug { letter, text } => ("249", vec![letter.to_string()], Some(text)),
         ReplyCode::RplLuserClient {
             num_visibles,
             num_invisibles,
@@ -257,10 +413,23 @@ pub fn make_reply_msg(state: &ServerState, client_nick: &str, reply_type: ReplyC
         ReplyCode::RplEndOfWhois { masks } => {
             ("318", vec![masks], Some(format!("End of /WHOIS list")))
         }
+        ReplyCode::RplWhoisChannels { nick } => ("319", vec![nick], None),
+        ReplyCode::RplListStart => ("321", vec![format!("Channel")], Some(format!("Users  Name"))),
+        ReplyCode::RplList {
+            channel,
+            num_visible,
+            topic,
+        } => ("322", vec![channel, num_visible.to_string()], Some(topic)),
+        ReplyCode::RplListEnd => ("323", vec![], Some(format!("End of /LIST"))),
         ReplyCode::RplChannelModeIs {
             channel,
             modestring,
-        } => ("324", vec![channel], Some(modestring)),
+            mode_params,
+        } => {
+            let mut params = vec![channel, modestring];
+            params.extend(mode_params);
+            ("324", params, None)
+        }
         ReplyCode::RplCreationTime { channel, timestamp } => {
             ("329", vec![channel], Some(format!("{}", timestamp)))
         }
@@ -273,6 +442,47 @@ pub fn make_reply_msg(state: &ServerState, client_nick: &str, reply_type: ReplyC
             vec![channel, who, time.timestamp().to_string()],
             None,
         ),
+        ReplyCode::RplInviting { nick, channel } => ("341", vec![nick, channel], None),
+        ReplyCode::RplWhoisCertFp { nick, fingerprint } => (
+            "276",
+            vec![nick],
+            Some(format!("has client certificate fingerprint {}", fingerprint)),
+        ),
+        ReplyCode::RplWhoisActually { nick, user, host, ip } => (
+            "338",
+            vec![nick, format!("{}@{}", user, host), ip],
+            Some(format!("Is actually using host")),
+        ),
+        ReplyCode::RplInviteList {
+            channel,
+            mask,
+            set_by,
+            set_at,
+        } => (
+            "346",
+            vec![channel, mask, set_by, set_at.timestamp().to_string()],
+            None,
+        ),
+        ReplyCode::RplEndOfInviteList { channel } => (
+            "347",
+            vec![channel],
+            Some(format!("End of channel invite exception list")),
+        ),
+        ReplyCode::RplExceptList {
+            channel,
+            mask,
+            set_by,
+            set_at,
+        } => (
+            "348",
+            vec![channel, mask, set_by, set_at.timestamp().to_string()],
+            None,
+        ),
+        ReplyCode::RplEndOfExceptList { channel } => (
+            "349",
+            vec![channel],
+            Some(format!("End of channel exception list")),
+        ),
         ReplyCode::RplVersion { comments } => (
             "351",
             vec![
@@ -292,7 +502,7 @@ pub fn make_reply_msg(state: &ServerState, client_nick: &str, reply_type: ReplyC
             realname,
         } => (
             "352",
-            vec![channel, user, host, server, nick, status.to_string()],
+            vec![channel, user, host, server, nick, status],
             Some(format!("{} {}", hopcount, realname)),
         ),
         ReplyCode::RplNameReply { symbol, channel } => {
@@ -301,6 +511,21 @@ pub fn make_reply_msg(state: &ServerState, client_nick: &str, reply_type: ReplyC
         ReplyCode::RplEndOfNames { channel } => {
             ("366", vec![channel], Some(format!("End of /NAMES list")))
         }
+        ReplyCode::RplBanList {
+            channel,
+            mask,
+            set_by,
+            set_at,
+        } => (
+            "367",
+            vec![channel, mask, set_by, set_at.timestamp().to_string()],
+            None,
+        ),
+        ReplyCode::RplEndOfBanList { channel } => (
+            "368",
+            vec![channel],
+            Some(format!("End of channel ban list")),
+        ),
 
         ReplyCode::ErrNoSuchNick { nick } => {
             ("401", vec![nick], Some(format!("No such nick/channel")))
@@ -321,6 +546,7 @@ pub fn make_reply_msg(state: &ServerState, client_nick: &str, reply_type: ReplyC
             ("411", vec![], Some(format!("No recipient given ({})", cmd)))
         }
         ReplyCode::ErrNoTextToSend => ("412", vec![], Some(format!("No text to send"))),
+        ReplyCode::ErrInputTooLong => ("417", vec![], Some(format!("Input line was too long"))),
         ReplyCode::ErrUnknownCommand { cmd } => {
             ("421", vec![cmd], Some(format!("Unknown command")))
         }
@@ -329,25 +555,151 @@ pub fn make_reply_msg(state: &ServerState, client_nick: &str, reply_type: ReplyC
         ReplyCode::ErrErroneusNickname { nick } => {
             ("432", vec![nick], Some(format!("Erroneous nickname")))
         }
+        ReplyCode::ErrNickRefused { nick, reason } => (
+            "432",
+            vec![nick],
+            Some(format!("Erroneous nickname ({})", reason)),
+        ),
         ReplyCode::ErrNicknameInUse { nick } => (
             "433",
             vec![nick],
             Some(format!("Nickname is already in use.")),
         ),
+        ReplyCode::ErrUserNotInChannel { nick, channel } => (
+            "441",
+            vec![nick, channel],
+            Some(format!("They aren't on that channel")),
+        ),
         ReplyCode::ErrNotOnChannel { channel } => (
             "442",
             vec![channel],
             Some(format!("You're not on that channel")),
         ),
+        ReplyCode::ErrUserOnChannel { nick, channel } => (
+            "443",
+            vec![nick, channel],
+            Some(format!("is already on channel")),
+        ),
+        ReplyCode::ErrNoNickChange { channel } => (
+            "447",
+            vec![],
+            Some(format!(
+                "Cannot change nickname while on {} (+N is set)",
+                channel
+            )),
+        ),
+        ReplyCode::ErrNotRegistered => ("451", vec![], Some(format!("You have not registered"))),
         ReplyCode::ErrNeedMoreParams { cmd } => {
             ("461", vec![cmd], Some(format!("Not enough parameters")))
         }
         ReplyCode::ErrAlreadyRegistered => ("462", vec![], Some(format!("You may not reregister"))),
+        ReplyCode::ErrPasswdMismatch => ("464", vec![], Some(format!("Password incorrect"))),
         ReplyCode::ErrUnknownMode { mode } => (
             "472",
             vec![mode.to_string()],
             Some(format!("is an unknown mode char to me")),
         ),
+        ReplyCode::ErrLinkChannel { channel, forward } => (
+            "470",
+            vec![channel, forward],
+            Some(format!("Forwarding to another channel")),
+        ),
+        ReplyCode::ErrChannelIsFull { channel } => (
+            "471",
+            vec![channel],
+            Some(format!("Cannot join channel (+l)")),
+        ),
+        ReplyCode::ErrInviteOnlyChan { channel } => (
+            "473",
+            vec![channel],
+            Some(format!("Cannot join channel (+i)")),
+        ),
+        ReplyCode::ErrBannedFromChan { channel } => (
+            "474",
+            vec![channel],
+            Some(format!("Cannot join channel (+b)")),
+        ),
+        ReplyCode::ErrCannotJoinChan { channel, reason } => (
+            "474",
+            vec![channel],
+            Some(format!("Cannot join channel ({})", reason)),
+        ),
+        ReplyCode::ErrBadChannelKey { channel } => (
+            "475",
+            vec![channel],
+            Some(format!("Cannot join channel (+k)")),
+        ),
+        ReplyCode::ErrBadChanName { channel } => (
+            "479",
+            vec![channel],
+            Some(format!("Cannot join channel (forbidden channel name)")),
+        ),
+        ReplyCode::ErrNeedReggedNick { channel } => (
+            "477",
+            vec![channel],
+            Some(format!(
+                "Cannot join channel (+R) - you need to be logged into your account"
+            )),
+        ),
+        ReplyCode::ErrNoPrivileges => (
+            "481",
+            vec![],
+            Some(format!("Permission Denied- You're not an IRC operator")),
+        ),
+        ReplyCode::ErrChanOPrivsNeeded { channel } => (
+            "482",
+            vec![channel],
+            Some(format!("You're not channel operator")),
+        ),
+        ReplyCode::ErrDelayRejoin { channel, seconds } => (
+            "495",
+            vec![channel],
+            Some(format!(
+                "You must wait {} seconds after being kicked to rejoin (+J)",
+                seconds
+            )),
+        ),
+        ReplyCode::ErrSecureOnlyChan { channel } => (
+            "489",
+            vec![channel],
+            Some(format!("Cannot join channel (+S)")),
+        ),
+        ReplyCode::ErrNoNonReg { nick } => (
+            "486",
+            vec![nick],
+            Some(format!("You must log in to your account to message this user")),
+        ),
+        ReplyCode::ErrCannotSendToUser { nick, reason } => ("531", vec![nick], Some(reason)),
+        ReplyCode::ErrNoOperHost => ("491", vec![], Some(format!("No O-lines for your host"))),
+        ReplyCode::RplMotd { line } => ("372", vec![], Some(format!("- {}", line))),
+        ReplyCode::RplMotdStart => (
+            "375",
+            vec![],
+            Some(format!("- {} Message of the day - ", state.settings.server_name)),
+        ),
+        ReplyCode::RplEndOfMotd => ("376", vec![], Some(format!("End of /MOTD command."))),
+        ReplyCode::RplYoureOper => ("381", vec![], Some(format!("You are now an IRC operator"))),
+        ReplyCode::RplQuietList {
+            channel,
+            mask,
+            set_by,
+            set_at,
+        } => (
+            "728",
+            vec![
+                channel,
+                "q".to_owned(),
+                mask,
+                set_by,
+                set_at.timestamp().to_string(),
+            ],
+            None,
+        ),
+        ReplyCode::RplEndOfQuietList { channel } => (
+            "729",
+            vec![channel, "q".to_owned()],
+            Some(format!("End of channel quiet list")),
+        ),
 
         ReplyCode::ErrUModeUnknownFlag => ("501", vec![], Some(format!("Unknown MODE flag"))),
         ReplyCode::ErrUsersDontMatch => (
@@ -355,16 +707,21 @@ pub fn make_reply_msg(state: &ServerState, client_nick: &str, reply_type: ReplyC
             vec![],
             Some(format!("Can't change mode for other users")),
         ),
+        ReplyCode::ErrOperOnly { channel } => (
+            "520",
+            vec![channel],
+            Some(format!("Cannot join channel (+O)")),
+        ),
     };
 
-    params.insert(0, client_nick.to_owned());
-    if let Some(description) = description {
-        params.push(description);
-    }
+    let mut msg_params = MessageParams::with_capacity(params.len() + 2);
+    msg_params.push(client_nick.to_owned());
+    msg_params.extend(params);
+    msg_params.extend(description);
     Message {
         tags: Vec::new(),
         source: Some(state.settings.server_name.clone()),
-        command: cmd_num.to_owned(),
-        params,
+        command: cmd_num.into(),
+        params: msg_params,
     }
 }
@@ -1,81 +1,153 @@
-pub trait BaseMode: ToString {
-    fn get_mode_bool(&mut self, mode: u8) -> Option<&mut bool>;
+use crate::casemapping::Casemapping;
+use crate::extban::{ban_matches, is_mute, BanTarget, ExtbanType};
+use crate::snomask::Snomask;
+use chrono::{DateTime, Local};
 
-    /// Return the applied modestring no matter what, but signals error on unknown modes
-    fn apply_modestring(&mut self, modestring: &str) -> Result<String, (String, char)> {
-        let mut applied_modestring = String::new();
-        if modestring.is_empty() {
-            return Ok(applied_modestring);
-        }
-
-        let mut had_unknown_mode = false;
-        let mut unknown_mode = '\0';
-
-        let mut positive = true;
-        let mut last_positive_applied = positive;
-        for &c in modestring.as_bytes() {
-            match c {
-                b'+' => positive = true,
-                b'-' => positive = false,
-                _ => {
-                    let result = self.apply_mode(
-                        c,
-                        positive,
-                        &mut last_positive_applied,
-                        &mut applied_modestring,
-                    );
-                    if result.is_err() {
-                        had_unknown_mode = true;
-                        unknown_mode = c as char;
-                    }
-                }
+/// How a mode takes parameters, following the categories of the CHANMODES ISUPPORT token
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ModeType {
+    /// Type A, a list of entries. Always takes a parameter, and lists the entries when given none
+    List,
+    /// Type B, always takes a parameter, like a channel key
+    AlwaysParam,
+    /// Type C, only takes a parameter when set, like a user limit
+    SetParam,
+    /// Type D, a flag that never takes a parameter
+    Flag,
+    /// A rank given to a channel member, which takes a nick as parameter (see PREFIX)
+    Membership,
+}
+
+/// A single change parsed from a modestring, with the parameter it consumed
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModeChange {
+    pub mode: u8,
+    pub positive: bool,
+    /// None for flags, and for list modes given without a parameter
+    pub param: Option<String>,
+}
+
+/// The changes parsed from a modestring, in order
+#[derive(Debug, Default, PartialEq)]
+pub struct ParsedModes {
+    pub changes: Vec<ModeChange>,
+    /// Mode characters we do not know about
+    pub unknown_modes: Vec<char>,
+}
+
+/// Splits a modestring into individual changes, consuming parameters positionally
+/// Changes that are missing their mandatory parameter are dropped
+pub fn parse_modestring(
+    modestring: &str,
+    params: &[String],
+    mode_type: impl Fn(u8) -> Option<ModeType>,
+) -> ParsedModes {
+    let mut parsed = ParsedModes::default();
+    let mut params = params.iter();
+
+    let mut positive = true;
+    for &c in modestring.as_bytes() {
+        let takes_param = match c {
+            b'+' => {
+                positive = true;
+                continue;
             }
-        }
+            b'-' => {
+                positive = false;
+                continue;
+            }
+            _ => match mode_type(c) {
+                Some(ModeType::List) | Some(ModeType::AlwaysParam) => true,
+                Some(ModeType::Membership) => true,
+                Some(ModeType::SetParam) => positive,
+                Some(ModeType::Flag) => false,
+                None => {
+                    parsed.unknown_modes.push(c as char);
+                    continue;
+                }
+            },
+        };
 
-        if had_unknown_mode {
-            Err((applied_modestring, unknown_mode))
+        let param = if takes_param {
+            match params.next() {
+                Some(param) => Some(param.to_owned()),
+                None if mode_type(c) == Some(ModeType::List) => None,
+                None => continue,
+            }
         } else {
-            Ok(applied_modestring)
-        }
+            None
+        };
+        parsed.changes.push(ModeChange {
+            mode: c,
+            positive,
+            param,
+        });
     }
 
-    /// Updates self and the modestring, if there was a change
-    fn apply_mode(
-        &mut self,
-        mode: u8,
-        positive: bool,
-        last_positive_applied: &mut bool,
-        modestring: &mut String,
-    ) -> Result<(), ()> {
-        let target = match self.get_mode_bool(mode) {
-            Some(target) => target,
-            None => return Err(()),
-        };
+    parsed
+}
 
-        if *target != positive {
-            *target = positive;
-            let positive_changed = modestring.is_empty() || positive != *last_positive_applied;
-            Self::append_mode(modestring, mode, positive_changed, positive);
-            *last_positive_applied = positive;
+/// Accumulates the changes that were applied into a modestring and its parameters, like "+o-b nick mask"
+#[derive(Debug, Default)]
+pub struct AppliedModes {
+    pub modestring: String,
+    pub params: Vec<String>,
+    last_positive: Option<bool>,
+}
+
+impl AppliedModes {
+    pub fn push(&mut self, mode: u8, positive: bool, param: Option<String>) {
+        if self.last_positive != Some(positive) {
+            self.modestring.push(if positive { '+' } else { '-' });
+            self.last_positive = Some(positive);
         }
+        self.modestring.push(mode as char);
+        self.params.extend(param);
+    }
 
-        Ok(())
+    pub fn is_empty(&self) -> bool {
+        self.modestring.is_empty()
+    }
+
+    /// Pushes a snomask change, which unsets +s when the new snomask is empty
+    fn push_snomask(&mut self, snomask: Snomask) {
+        if snomask.is_empty() {
+            self.push(b's', false, None);
+        } else {
+            self.push(b's', true, Some(snomask.to_string()));
+        }
     }
+}
+
+pub trait BaseMode: ToString {
+    /// Returns how a mode takes parameters, or None if we don't know this mode
+    fn mode_type(&self, mode: u8) -> Option<ModeType>;
 
-    fn append_mode(modestring: &mut String, mode: u8, positive_changed: bool, positive: bool) {
-        if positive_changed && positive {
-            modestring.push('+');
-        } else if positive_changed && !positive {
-            modestring.push('-');
+    fn get_mode_bool(&mut self, mode: u8) -> Option<&mut bool>;
+
+    /// Applies a single flag change, returns whether it changed anything
+    fn apply_flag(&mut self, mode: u8, positive: bool) -> bool {
+        match self.get_mode_bool(mode) {
+            Some(target) if *target != positive => {
+                *target = positive;
+                true
+            }
+            _ => false,
         }
-        modestring.push(mode as char);
     }
 }
 
+#[derive(Clone)]
 pub struct UserMode {
     pub invisible: bool,
     pub see_wallops: bool,
     pub is_bot: bool,
+    /// IRC operator status, which can't be set with MODE
+    pub is_oper: bool,
+    /// Only accept private messages from users logged in to an account
+    pub registered_only_pms: bool,
+    /// Server notices received by an operator, set with +s and a mask like "+cq"
+    pub snomask: Snomask,
 }
 
 impl Default for UserMode {
@@ -84,16 +156,81 @@ impl Default for UserMode {
             invisible: true,
             see_wallops: false,
             is_bot: false,
+            is_oper: false,
+            registered_only_pms: false,
+            snomask: Snomask::default(),
+        }
+    }
+}
+
+impl UserMode {
+    /// Applies a modestring, including +s which takes a snomask as parameter
+    /// Returns the applied changes and the unknown modes
+    pub fn apply_user_modestring(
+        &mut self,
+        modestring: &str,
+        params: &[String],
+    ) -> (AppliedModes, Vec<char>) {
+        let parsed = parse_modestring(modestring, params, |mode| self.mode_type(mode));
+
+        let mut applied = AppliedModes::default();
+        for change in parsed.changes {
+            if change.mode != b's' {
+                if self.apply_flag(change.mode, change.positive) {
+                    applied.push(change.mode, change.positive, None);
+                }
+                continue;
+            }
+
+            // Only operators can receive server notices
+            let old_snomask = self.snomask;
+            match change.param {
+                Some(ref mask) if self.is_oper => self.snomask.apply(mask),
+                _ => self.snomask = Snomask::default(),
+            }
+            if self.snomask != old_snomask {
+                applied.push_snomask(self.snomask);
+            }
+        }
+        if !self.is_oper && !self.snomask.is_empty() {
+            self.snomask = Snomask::default();
+            applied.push_snomask(self.snomask);
         }
+
+        (applied, parsed.unknown_modes)
     }
 }
 
 impl BaseMode for UserMode {
+    fn apply_flag(&mut self, mode: u8, positive: bool) -> bool {
+        // Users can drop their operator status, but only the server grants it
+        if mode == b'o' && positive {
+            return false;
+        }
+        match self.get_mode_bool(mode) {
+            Some(target) if *target != positive => {
+                *target = positive;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn mode_type(&self, mode: u8) -> Option<ModeType> {
+        match mode {
+            b'i' | b'w' | b'B' | b'o' | b'R' => Some(ModeType::Flag),
+            b's' => Some(ModeType::SetParam),
+            _ => None,
+        }
+    }
+
     fn get_mode_bool(&mut self, mode: u8) -> Option<&mut bool> {
         Some(match mode {
             b'i' => &mut self.invisible,
             b'w' => &mut self.see_wallops,
             b'B' => &mut self.is_bot,
+            b'o' => &mut self.is_oper,
+            b'R' => &mut self.registered_only_pms,
             _ => return None,
         })
     }
@@ -111,22 +248,143 @@ impl ToString for UserMode {
         if self.is_bot {
             modestring.push('B');
         }
+        if self.is_oper {
+            modestring.push('o');
+        }
+        if self.registered_only_pms {
+            modestring.push('R');
+        }
+        if !self.snomask.is_empty() {
+            modestring.push('s');
+        }
 
         modestring
     }
 }
 
-/// NOTE: Don't forget to update CHANMODES when adding a new mode!
-pub const CHANMODES: &str = ",,,n";
+/// All the channel modes we support, except member ranks. The CHANMODES token is built from this.
+pub const CHANNEL_MODES: &[(u8, ModeType)] = &[
+    (b'b', ModeType::List),
+    (b'e', ModeType::List),
+    (b'I', ModeType::List),
+    (b'q', ModeType::List),
+    (b'k', ModeType::AlwaysParam),
+    (b'f', ModeType::SetParam),
+    (b'J', ModeType::SetParam),
+    (b'l', ModeType::SetParam),
+    (b'c', ModeType::Flag),
+    (b'C', ModeType::Flag),
+    (b'i', ModeType::Flag),
+    (b'n', ModeType::Flag),
+    (b'N', ModeType::Flag),
+    (b'O', ModeType::Flag),
+    (b'p', ModeType::Flag),
+    (b'P', ModeType::Flag),
+    (b'R', ModeType::Flag),
+    (b'S', ModeType::Flag),
+    (b's', ModeType::Flag),
+    (b't', ModeType::Flag),
+];
+
+/// Value of the CHANMODES ISUPPORT token, like "b,k,l,nt"
+pub fn chanmodes_isupport() -> String {
+    let category = |mode_type| -> String {
+        CHANNEL_MODES
+            .iter()
+            .filter(|&&(_, t)| t == mode_type)
+            .map(|&(mode, _)| mode as char)
+            .collect()
+    };
+    [
+        category(ModeType::List),
+        category(ModeType::AlwaysParam),
+        category(ModeType::SetParam),
+        category(ModeType::Flag),
+    ]
+    .join(",")
+}
 
+/// An entry in one of a channel's list modes, like the ban list
+#[derive(Clone)]
+pub struct ListModeEntry {
+    pub mask: String,
+    pub set_by: String,
+    pub set_at: DateTime<Local>,
+}
+
+/// The outcome of applying a modestring and its parameters to a channel
+#[derive(Default)]
+pub struct ChannelModeChange {
+    /// The changes that were actually applied, with their parameters
+    pub applied: AppliedModes,
+    /// List modes that were given without a parameter, which requests their content
+    pub list_queries: Vec<char>,
+    /// Mode characters we do not know about
+    pub unknown_modes: Vec<char>,
+    /// Nicks targeted by a membership mode that are not in the channel
+    pub missing_members: Vec<String>,
+    /// Whether some changes were refused because the user does not have a high enough rank
+    pub missing_privileges: bool,
+}
+
+#[derive(Clone)]
 pub struct ChannelMode {
     pub no_external_msgs: bool,
+    pub topic_protected: bool,
+    pub secret: bool,
+    pub private: bool,
+    pub invite_only: bool,
+    /// Only clients connected over TLS can join
+    pub tls_only: bool,
+    /// Colors and formatting are stripped from messages
+    pub no_colors: bool,
+    /// CTCP requests other than ACTION are rejected
+    pub no_ctcp: bool,
+    /// Members below channel operators can't change their nick
+    pub no_nick_change: bool,
+    /// Only users logged in to an account can join
+    pub registered_only: bool,
+    /// Only IRC operators can join
+    pub oper_only: bool,
+    /// The channel is kept with its topic and modes when the last user leaves
+    pub permanent: bool,
+    pub key: Option<String>,
+    pub user_limit: Option<usize>,
+    /// Channel where users who can't join are sent instead
+    pub forward: Option<String>,
+    /// Seconds that kicked users have to wait before they can rejoin
+    pub kick_rejoin_delay: Option<u64>,
+    pub bans: Vec<ListModeEntry>,
+    /// Users matching an exception are not affected by bans and quiets
+    pub ban_exceptions: Vec<ListModeEntry>,
+    /// Users matching an invite exception can join an invite-only channel
+    pub invite_exceptions: Vec<ListModeEntry>,
+    pub quiets: Vec<ListModeEntry>,
 }
 
 impl Default for ChannelMode {
     fn default() -> Self {
         Self {
             no_external_msgs: true,
+            topic_protected: false,
+            secret: false,
+            private: false,
+            invite_only: false,
+            tls_only: false,
+            no_colors: false,
+            no_ctcp: false,
+            no_nick_change: false,
+            registered_only: false,
+            oper_only: false,
+            permanent: false,
+            key: None,
+            user_limit: None,
+            forward: None,
+            kick_rejoin_delay: None,
+            bans: Vec::new(),
+            ban_exceptions: Vec::new(),
+            invite_exceptions: Vec::new(),
+            quiets: Vec::new(),
         }
     }
 }
@@ -137,16 +395,565 @@ impl ToString for ChannelMode {
         if self.no_external_msgs {
             modestring.push('n');
         }
+        if self.topic_protected {
+            modestring.push('t');
+        }
+        if self.secret {
+            modestring.push('s');
+        }
+        if self.private {
+            modestring.push('p');
+        }
+        if self.invite_only {
+            modestring.push('i');
+        }
+        if self.tls_only {
+            modestring.push('S');
+        }
+        if self.no_colors {
+            modestring.push('c');
+        }
+        if self.no_ctcp {
+            modestring.push('C');
+        }
+        if self.no_nick_change {
+            modestring.push('N');
+        }
+        if self.registered_only {
+            modestring.push('R');
+        }
+        if self.oper_only {
+            modestring.push('O');
+        }
+        if self.permanent {
+            modestring.push('P');
+        }
+        if self.key.is_some() {
+            modestring.push('k');
+        }
+        if self.user_limit.is_some() {
+            modestring.push('l');
+        }
+        if self.forward.is_some() {
+            modestring.push('f');
+        }
+        if self.kick_rejoin_delay.is_some() {
+            modestring.push('J');
+        }
 
         modestring
     }
 }
 
 impl BaseMode for ChannelMode {
+    fn mode_type(&self, mode: u8) -> Option<ModeType> {
+        CHANNEL_MODES
+            .iter()
+            .find(|&&(known_mode, _)| known_mode == mode)
+            .map(|&(_, mode_type)| mode_type)
+    }
+
     fn get_mode_bool(&mut self, mode: u8) -> Option<&mut bool> {
         Some(match mode {
             b'n' => &mut self.no_external_msgs,
+            b't' => &mut self.topic_protected,
+            b's' => &mut self.secret,
+            b'p' => &mut self.private,
+            b'i' => &mut self.invite_only,
+            b'S' => &mut self.tls_only,
+            b'c' => &mut self.no_colors,
+            b'C' => &mut self.no_ctcp,
+            b'N' => &mut self.no_nick_change,
+            b'R' => &mut self.registered_only,
+            b'O' => &mut self.oper_only,
+            b'P' => &mut self.permanent,
+            _ => return None,
+        })
+    }
+}
+
+impl ChannelMode {
+    /// Parameters of the modes set on the channel, in the same order as in to_string()
+    /// The key is a secret for non-members, so it can be left out
+    pub fn get_mode_params(&self, include_key: bool) -> Vec<String> {
+        let mut params = Vec::new();
+        if let Some(ref key) = self.key {
+            params.push(if include_key {
+                key.clone()
+            } else {
+                "*".to_owned()
+            });
+        }
+        if let Some(limit) = self.user_limit {
+            params.push(limit.to_string());
+        }
+        if let Some(ref forward) = self.forward {
+            params.push(forward.clone());
+        }
+        if let Some(delay) = self.kick_rejoin_delay {
+            params.push(delay.to_string());
+        }
+        params
+    }
+
+    /// Applies a change of any type except membership modes and list queries
+    /// Returns None if nothing changed, otherwise the parameter to report with the applied mode
+    /// List entries are compared with the casemapping, so equivalent masks aren't added twice
+    pub fn apply_change(
+        &mut self,
+        change: &ModeChange,
+        set_by: &str,
+        casemapping: Casemapping,
+    ) -> Option<Option<String>> {
+        let ModeChange {
+            mode,
+            positive,
+            ref param,
+        } = *change;
+
+        if let Some(list) = self.get_mode_list(mode) {
+            let mask = param.as_ref()?;
+            let changed = if positive {
+                Self::add_list_entry(list, mask, set_by, casemapping)
+            } else {
+                Self::remove_list_entry(list, mask, casemapping)
+            };
+            return if changed {
+                Some(Some(mask.clone()))
+            } else {
+                None
+            };
+        }
+
+        match mode {
+            b'k' if positive => {
+                let key = param.as_ref()?;
+                if key.is_empty() || key.contains(',') || Some(key) == self.key.as_ref() {
+                    return None;
+                }
+                self.key = Some(key.clone());
+                Some(Some(key.clone()))
+            }
+            b'k' => self.key.take().map(|_| Some("*".to_owned())),
+            b'l' if positive => match param.as_ref()?.parse::<usize>() {
+                Ok(limit) if limit > 0 && self.user_limit != Some(limit) => {
+                    self.user_limit = Some(limit);
+                    Some(Some(limit.to_string()))
+                }
+                _ => None,
+            },
+            b'l' => self.user_limit.take().map(|_| None),
+            b'f' if positive => {
+                let forward = param.as_ref()?;
+                if !forward.starts_with('#')
+                    || forward.contains(',')
+                    || Some(forward) == self.forward.as_ref()
+                {
+                    return None;
+                }
+                self.forward = Some(forward.clone());
+                Some(Some(forward.clone()))
+            }
+            b'f' => self.forward.take().map(|_| None),
+            b'J' if positive => match param.as_ref()?.parse::<u64>() {
+                Ok(delay) if delay > 0 && self.kick_rejoin_delay != Some(delay) => {
+                    self.kick_rejoin_delay = Some(delay);
+                    Some(Some(delay.to_string()))
+                }
+                _ => None,
+            },
+            b'J' => self.kick_rejoin_delay.take().map(|_| None),
+            _ => {
+                if self.apply_flag(mode, positive) {
+                    Some(None)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    /// Secret and private channels are only visible to their members
+    pub fn is_hidden(&self) -> bool {
+        self.secret || self.private
+    }
+
+    fn list_matches(extbans: &[ExtbanType], list: &[ListModeEntry], target: &BanTarget) -> bool {
+        list.iter()
+            .any(|entry| ban_matches(extbans, &entry.mask, target))
+    }
+
+    /// Whether a user is banned from joining, and not exempted
+    pub fn is_banned(&self, extbans: &[ExtbanType], target: &BanTarget) -> bool {
+        self.bans
+            .iter()
+            .any(|ban| !is_mute(&ban.mask) && ban_matches(extbans, &ban.mask, target))
+            && !Self::list_matches(extbans, &self.ban_exceptions, target)
+    }
+
+    /// Whether a user is quieted or muted by a ban, and not exempted
+    pub fn is_quieted(&self, extbans: &[ExtbanType], target: &BanTarget) -> bool {
+        let is_muted = self
+            .bans
+            .iter()
+            .any(|ban| is_mute(&ban.mask) && ban_matches(extbans, &ban.mask, target));
+        (is_muted || Self::list_matches(extbans, &self.quiets, target))
+            && !Self::list_matches(extbans, &self.ban_exceptions, target)
+    }
+
+    /// Whether a user can join despite the channel being invite-only
+    pub fn is_invite_exempt(&self, extbans: &[ExtbanType], target: &BanTarget) -> bool {
+        Self::list_matches(extbans, &self.invite_exceptions, target)
+    }
+
+    pub fn get_mode_list(&mut self, mode: u8) -> Option<&mut Vec<ListModeEntry>> {
+        Some(match mode {
+            b'b' => &mut self.bans,
+            b'e' => &mut self.ban_exceptions,
+            b'I' => &mut self.invite_exceptions,
+            b'q' => &mut self.quiets,
             _ => return None,
         })
     }
+
+    /// Adds an entry to a list mode, returns false if an equivalent mask was already in the list
+    pub fn add_list_entry(
+        list: &mut Vec<ListModeEntry>,
+        mask: &str,
+        set_by: &str,
+        casemapping: Casemapping,
+    ) -> bool {
+        if list
+            .iter()
+            .any(|entry| casemapping.equals(&entry.mask, mask))
+        {
+            return false;
+        }
+        list.push(ListModeEntry {
+            mask: mask.to_owned(),
+            set_by: set_by.to_owned(),
+            set_at: Local::now(),
+        });
+        true
+    }
+
+    /// Removes an entry from a list mode, returns false if no equivalent mask was in the list
+    pub fn remove_list_entry(
+        list: &mut Vec<ListModeEntry>,
+        mask: &str,
+        casemapping: Casemapping,
+    ) -> bool {
+        let len_before = list.len();
+        list.retain(|entry| !casemapping.equals(&entry.mask, mask));
+        list.len() != len_before
+    }
+}
+
+/// A channel membership rank, set with a mode and shown as a prefix in NAMES, like op (+o, @)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MemberRank {
+    pub mode: char,
+    pub prefix: char,
+}
+
+impl MemberRank {
+    pub const fn new(mode: char, prefix: char) -> Self {
+        Self { mode, prefix }
+    }
+}
+
+/// Default ladder of membership ranks, from highest to lowest
+pub const DEFAULT_MEMBER_RANKS: &[MemberRank] = &[
+    MemberRank::new('o', '@'),
+    MemberRank::new('h', '%'),
+    MemberRank::new('v', '+'),
+];
+
+/// A ladder with all the supported ranks, including channel founder (~) and admin (&)
+/// Note that +q is already the quiet list mode, so founders use +Y
+pub const EXTENDED_MEMBER_RANKS: &[MemberRank] = &[
+    MemberRank::new('Y', '~'),
+    MemberRank::new('a', '&'),
+    MemberRank::new('o', '@'),
+    MemberRank::new('h', '%'),
+    MemberRank::new('v', '+'),
+];
+
+/// Returns the position in the ladder of the rank set by this mode, where 0 is the highest rank
+pub fn rank_of_mode(ranks: &[MemberRank], mode: u8) -> Option<usize> {
+    ranks.iter().position(|rank| rank.mode == mode as char)
+}
+
+/// Position of the channel operator rank, members ranked at least this high can manage the channel
+pub fn operator_rank(ranks: &[MemberRank]) -> usize {
+    rank_of_mode(ranks, b'o').expect("Channel operators must be one of the member ranks")
+}
+
+/// Position of the halfop rank, which can moderate but not manage the channel
+/// Falls back to the operator rank if halfops are not part of the ladder
+pub fn halfop_rank(ranks: &[MemberRank]) -> usize {
+    rank_of_mode(ranks, b'h').unwrap_or_else(|| operator_rank(ranks))
+}
+
+/// Value of the PREFIX ISUPPORT token, like "(ov)@+"
+pub fn prefix_isupport(ranks: &[MemberRank]) -> String {
+    let modes: String = ranks.iter().map(|rank| rank.mode).collect();
+    let prefixes: String = ranks.iter().map(|rank| rank.prefix).collect();
+    format!("({}){}", modes, prefixes)
+}
+
+/// Whether a member can give or take the rank at this position
+/// Ops and above can grant their own rank, lower ranks can only grant ranks strictly below theirs
+pub fn can_set_rank(ranks: &[MemberRank], setter: MemberMode, rank: usize) -> bool {
+    match setter.highest_rank() {
+        Some(highest) => highest < rank || (highest == rank && rank <= operator_rank(ranks)),
+        None => false,
+    }
+}
+
+/// Status of a user in a channel they are a member of, as a set of positions in the rank ladder
+#[derive(Default, Clone, Copy)]
+pub struct MemberMode {
+    ranks: u32,
+}
+
+impl MemberMode {
+    /// Maximum number of ranks a ladder can have
+    pub const MAX_RANKS: usize = 32;
+
+    pub fn has_rank(&self, rank: usize) -> bool {
+        self.ranks & (1 << rank) != 0
+    }
+
+    /// Gives or takes a rank, returns whether it changed
+    pub fn set_rank(&mut self, rank: usize, value: bool) -> bool {
+        let changed = self.has_rank(rank) != value;
+        if value {
+            self.ranks |= 1 << rank;
+        } else {
+            self.ranks &= !(1 << rank);
+        }
+        changed
+    }
+
+    /// Position of the highest rank this member has, if any
+    pub fn highest_rank(&self) -> Option<usize> {
+        if self.ranks == 0 {
+            None
+        } else {
+            Some(self.ranks.trailing_zeros() as usize)
+        }
+    }
+
+    /// Whether this member has this rank or a higher one
+    pub fn is_at_least(&self, rank: usize) -> bool {
+        self.highest_rank().is_some_and(|highest| highest <= rank)
+    }
+
+    /// Whether this member has a rank above all of the other member's ranks
+    pub fn outranks(&self, other: MemberMode) -> bool {
+        match (self.highest_rank(), other.highest_rank()) {
+            (Some(ours), Some(theirs)) => ours < theirs,
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    }
+
+    /// The prefix of the highest rank this member has, if any
+    pub fn prefix(&self, ranks: &[MemberRank]) -> Option<char> {
+        self.highest_rank().map(|rank| ranks[rank].prefix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extban::DEFAULT_EXTBANS;
+
+    fn member(ranks: &[usize]) -> MemberMode {
+        let mut mode = MemberMode::default();
+        for &rank in ranks {
+            mode.set_rank(rank, true);
+        }
+        mode
+    }
+
+    fn parse(modestring: &str, params: &[&str]) -> ParsedModes {
+        let params: Vec<String> = params.iter().map(|s| s.to_string()).collect();
+        let channel_mode = ChannelMode::default();
+        parse_modestring(modestring, &params, |mode| match mode {
+            b'o' | b'v' => Some(ModeType::Membership),
+            _ => channel_mode.mode_type(mode),
+        })
+    }
+
+    fn change(mode: u8, positive: bool, param: Option<&str>) -> ModeChange {
+        ModeChange {
+            mode,
+            positive,
+            param: param.map(|s| s.to_string()),
+        }
+    }
+
+    #[test]
+    fn chanmodes_advertisement() {
+        assert_eq!(chanmodes_isupport(), "beIq,k,fJl,cCinNOpPRSst");
+    }
+
+    #[test]
+    fn parse_consumes_params_positionally() {
+        let parsed = parse("+ob-l+kn", &["alice", "*!*@host", "secret"]);
+        assert_eq!(
+            parsed.changes,
+            vec![
+                change(b'o', true, Some("alice")),
+                change(b'b', true, Some("*!*@host")),
+                change(b'l', false, None),
+                change(b'k', true, Some("secret")),
+                change(b'n', true, None),
+            ]
+        );
+        assert!(parsed.unknown_modes.is_empty());
+    }
+
+    #[test]
+    fn parse_missing_params() {
+        let parsed = parse("+bkl", &[]);
+        assert_eq!(parsed.changes, vec![change(b'b', true, None)]);
+
+        let parsed = parse("-k+l", &["key"]);
+        assert_eq!(parsed.changes, vec![change(b'k', false, Some("key"))]);
+    }
+
+    #[test]
+    fn parse_unknown_modes() {
+        let parsed = parse("+nZ-Xt", &[]);
+        assert_eq!(
+            parsed.changes,
+            vec![change(b'n', true, None), change(b't', false, None)]
+        );
+        assert_eq!(parsed.unknown_modes, vec!['Z', 'X']);
+    }
+
+    #[test]
+    fn applied_modes_string() {
+        let mut applied = AppliedModes::default();
+        applied.push(b'o', true, Some("alice".to_owned()));
+        applied.push(b'v', true, Some("bob".to_owned()));
+        applied.push(b'l', false, None);
+        applied.push(b'k', true, Some("key".to_owned()));
+        assert_eq!(applied.modestring, "+ov-l+k");
+        assert_eq!(applied.params, vec!["alice", "bob", "key"]);
+    }
+
+    #[test]
+    fn user_modestring() {
+        let mut mode = UserMode::default();
+        let (applied, unknown_modes) = mode.apply_user_modestring("+iwB", &[]);
+        assert_eq!((applied.modestring.as_str(), unknown_modes), ("+wB", vec![]));
+        let (applied, unknown_modes) = mode.apply_user_modestring("-w+Z", &[]);
+        assert_eq!((applied.modestring.as_str(), unknown_modes), ("-w", vec!['Z']));
+        assert_eq!(mode.to_string(), "+iB");
+
+        assert!(mode.apply_user_modestring("+o", &[]).0.is_empty());
+        mode.is_oper = true;
+        assert_eq!(mode.apply_user_modestring("-o", &[]).0.modestring, "-o");
+    }
+
+    #[test]
+    fn snomask() {
+        let params = |params: &[&str]| params.iter().map(|&p| p.to_owned()).collect::<Vec<_>>();
+        let mut mode = UserMode::default();
+        let (applied, _) = mode.apply_user_modestring("+s", &params(&["+cq"]));
+        assert!(applied.is_empty());
+
+        mode.is_oper = true;
+        let (applied, _) = mode.apply_user_modestring("+ws", &params(&["+cq"]));
+        assert_eq!(applied.modestring, "+ws");
+        assert_eq!(applied.params, ["+cq"]);
+        assert_eq!(mode.to_string(), "+iwos");
+
+        let (applied, _) = mode.apply_user_modestring("+s", &params(&["-cq"]));
+        assert_eq!(applied.modestring, "-s");
+        mode.apply_user_modestring("+s", &params(&["k"]));
+        let (applied, _) = mode.apply_user_modestring("-o", &[]);
+        assert_eq!(applied.modestring, "-os");
+        assert!(mode.snomask.is_empty());
+    }
+
+    #[test]
+    fn ban_exceptions() {
+        let mut mode = ChannelMode::default();
+        let casemapping = Casemapping::Rfc1459;
+        ChannelMode::add_list_entry(&mut mode.bans, "*!*@host", "op", casemapping);
+        ChannelMode::add_list_entry(&mut mode.quiets, "*!*@other", "op", casemapping);
+        let exceptions = &mut mode.ban_exceptions;
+        ChannelMode::add_list_entry(exceptions, "friend[m]!*@*", "op", casemapping);
+        let added = ChannelMode::add_list_entry(exceptions, "FRIEND{M}!*@*", "op", casemapping);
+        assert!(!added);
+        let target = |prefix: &str| BanTarget {
+            prefix: prefix.to_owned(),
+            casemapping,
+            ..Default::default()
+        };
+        assert!(mode.is_banned(DEFAULT_EXTBANS, &target("nick!user@host")));
+        assert!(!mode.is_banned(DEFAULT_EXTBANS, &target("friend{m}!user@host")));
+        assert!(mode.is_quieted(DEFAULT_EXTBANS, &target("nick!user@other")));
+        assert!(!mode.is_quieted(DEFAULT_EXTBANS, &target("friend[m]!user@other")));
+    }
+
+    #[test]
+    fn mute_extban() {
+        let mut mode = ChannelMode::default();
+        ChannelMode::add_list_entry(&mut mode.bans, "~q:*!*@host", "op", Casemapping::Ascii);
+        let target = BanTarget {
+            prefix: "nick!user@host".to_owned(),
+            ..Default::default()
+        };
+        assert!(!mode.is_banned(DEFAULT_EXTBANS, &target));
+        assert!(mode.is_quieted(DEFAULT_EXTBANS, &target));
+    }
+
+    #[test]
+    fn prefix_advertisement() {
+        assert_eq!(prefix_isupport(DEFAULT_MEMBER_RANKS), "(ohv)@%+");
+        assert_eq!(prefix_isupport(EXTENDED_MEMBER_RANKS), "(Yaohv)~&@%+");
+    }
+
+    #[test]
+    fn member_prefix() {
+        let ranks = EXTENDED_MEMBER_RANKS;
+        assert_eq!(member(&[]).prefix(ranks), None);
+        assert_eq!(member(&[4]).prefix(ranks), Some('+'));
+        assert_eq!(member(&[2, 4]).prefix(ranks), Some('@'));
+        assert_eq!(member(&[0, 2]).prefix(ranks), Some('~'));
+    }
+
+    #[test]
+    fn rank_setting_rules() {
+        let ranks = EXTENDED_MEMBER_RANKS;
+        let (founder, admin, op, halfop, voice) = (0, 1, 2, 3, 4);
+
+        assert!(can_set_rank(ranks, member(&[founder]), founder));
+        assert!(can_set_rank(ranks, member(&[founder]), voice));
+        assert!(!can_set_rank(ranks, member(&[admin]), founder));
+        assert!(can_set_rank(ranks, member(&[admin]), admin));
+        assert!(can_set_rank(ranks, member(&[op]), op));
+        assert!(can_set_rank(ranks, member(&[op]), halfop));
+        assert!(!can_set_rank(ranks, member(&[op]), admin));
+        assert!(!can_set_rank(ranks, member(&[halfop]), halfop));
+        assert!(can_set_rank(ranks, member(&[halfop]), voice));
+        assert!(!can_set_rank(ranks, member(&[voice]), voice));
+        assert!(!can_set_rank(ranks, member(&[]), voice));
+    }
+
+    #[test]
+    fn ranks_comparison() {
+        assert!(member(&[0]).outranks(member(&[1])));
+        assert!(member(&[1]).outranks(member(&[])));
+        assert!(!member(&[1]).outranks(member(&[1])));
+        assert!(!member(&[]).outranks(member(&[])));
+        assert!(member(&[1, 3]).is_at_least(2));
+        assert!(!member(&[3]).is_at_least(2));
+    }
 }
@@ -0,0 +1,75 @@
+use std::path::PathBuf;
+
+/// Message of the day, sent to users when they connect and when they use the MOTD command
+#[derive(Clone, Debug)]
+pub enum Motd {
+    /// The text of the MOTD
+    Text(String),
+    /// A file containing the MOTD, read every time it's sent so that it can be edited while running
+    File(PathBuf),
+}
+
+impl Motd {
+    /// Returns the text of the MOTD, or None if the file couldn't be read
+    pub async fn load(&self) -> Option<String> {
+        match self {
+            Motd::Text(text) => Some(text.clone()),
+            Motd::File(path) => tokio::fs::read_to_string(path).await.ok(),
+        }
+    }
+}
+
+/// Splits a MOTD in lines of at most max_len bytes, breaking long lines at spaces if possible
+pub fn wrap_motd(text: &str, max_len: usize) -> Vec<&str> {
+    let mut lines = Vec::new();
+    for mut line in text.lines() {
+        while line.len() > max_len {
+            let mut split_at = max_len;
+            while !line.is_char_boundary(split_at) {
+                split_at -= 1;
+            }
+            let space = match line.as_bytes()[split_at] {
+                b' ' => Some(split_at),
+                _ => line[..split_at].rfind(' ').filter(|&space| space > 0),
+            };
+            let (head, tail) = match space {
+                Some(space) => (&line[..space], &line[space + 1..]),
+                None => line.split_at(split_at),
+            };
+            lines.push(head);
+            line = tail;
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_lines() {
+        assert_eq!(wrap_motd("Hello\n\nWorld\r\n", 10), ["Hello", "", "World"]);
+        assert!(wrap_motd("", 10).is_empty());
+    }
+
+    #[test]
+    fn wrap_at_spaces() {
+        assert_eq!(
+            wrap_motd("Welcome to the server", 10),
+            ["Welcome to", "the server"]
+        );
+        assert_eq!(wrap_motd("Welcome to", 10), ["Welcome to"]);
+        assert_eq!(wrap_motd("Welcometo the", 10), ["Welcometo", "the"]);
+    }
+
+    #[test]
+    fn wrap_long_words() {
+        assert_eq!(
+            wrap_motd("abcdefghijklmnopqrstuvwxyz", 10),
+            ["abcdefghij", "klmnopqrst", "uvwxyz"]
+        );
+        assert_eq!(wrap_motd("ééééé", 5), ["éé", "éé", "é"]);
+    }
+}
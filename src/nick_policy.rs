@@ -0,0 +1,106 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+use unicode_normalization::UnicodeNormalization;
+use unicode_security::{skeleton, GeneralSecurityProfile, MixedScript};
+
+lazy_static! {
+    static ref VALID_NICKNAME_REGEX: Regex =
+        Regex::new(r"^[[:alpha:]\[\\\]\^_`\{\|\}][[:alnum:]\[\\\]\^_`\{\|\}\-]*$").unwrap();
+}
+
+/// Which nicknames users are allowed to pick
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NickPolicy {
+    /// Traditional nicknames made of ASCII letters, digits and a few special characters
+    #[default]
+    Ascii,
+    /// UTF-8 nicknames in the spirit of PRECIS (RFC 8265), normalized to NFC
+    /// Nicks must use characters allowed in identifiers by UTS #39, and can't mix scripts
+    /// This is best used with the rfc8265 casemapping
+    Unicode,
+}
+
+/// Special characters allowed in nicks in addition to letters and digits
+fn is_special_char(c: char) -> bool {
+    matches!(c, '[' | '\\' | ']' | '^' | '_' | '`' | '{' | '|' | '}')
+}
+
+impl NickPolicy {
+    /// Validates a nickname, and returns it in the form the server will use
+    pub fn validate(self, max_len: usize, nick: &str) -> Option<String> {
+        match self {
+            NickPolicy::Ascii => Some(nick.to_owned()).filter(|nick| {
+                !nick.is_empty() && nick.len() <= max_len && VALID_NICKNAME_REGEX.is_match(nick)
+            }),
+            NickPolicy::Unicode => {
+                let nick: String = nick.nfc().collect();
+                let mut chars = nick.chars();
+                let first = chars.next()?;
+                let valid_first = first.is_alphabetic() || is_special_char(first);
+                let valid_rest = chars.all(|c| {
+                    c.is_ascii_alphanumeric()
+                        || is_special_char(c)
+                        || c == '-'
+                        || (!c.is_ascii() && c.identifier_allowed())
+                });
+                let valid = valid_first
+                    && valid_rest
+                    && nick.chars().count() <= max_len
+                    && nick.as_str().is_single_script();
+                Some(nick).filter(|_| valid)
+            }
+        }
+    }
+}
+
+/// Returns a form of the nick where confusable characters are replaced by the same prototype
+/// Two nicks that look alike have the same skeleton, like "paypal" and "раураl" with cyrillic letters
+pub fn nick_skeleton(nick: &str) -> String {
+    skeleton(nick).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ascii_nicks() {
+        let policy = NickPolicy::Ascii;
+        assert_eq!(
+            policy.validate(16, "nick[away]"),
+            Some("nick[away]".to_owned())
+        );
+        assert_eq!(policy.validate(16, "1nick"), None);
+        assert_eq!(policy.validate(4, "nick_"), None);
+        assert_eq!(policy.validate(16, "héllo"), None);
+        assert_eq!(policy.validate(16, ""), None);
+    }
+
+    #[test]
+    fn unicode_nicks() {
+        let policy = NickPolicy::Unicode;
+        assert_eq!(policy.validate(16, "héllo"), Some("héllo".to_owned()));
+        assert_eq!(policy.validate(16, "Дмитрий"), Some("Дмитрий".to_owned()));
+        assert_eq!(
+            policy.validate(16, "nick|away"),
+            Some("nick|away".to_owned())
+        );
+        // Decomposed characters are normalized
+        assert_eq!(
+            policy.validate(16, "he\u{301}llo"),
+            Some("héllo".to_owned())
+        );
+        assert_eq!(policy.validate(4, "héllo"), None);
+        assert_eq!(policy.validate(16, "1nick"), None);
+        assert_eq!(policy.validate(16, "nick name"), None);
+        assert_eq!(policy.validate(16, "nick\u{200B}"), None);
+        // Mixing latin and cyrillic letters
+        assert_eq!(policy.validate(16, "pаypal"), None);
+    }
+
+    #[test]
+    fn confusable_nicks() {
+        assert_eq!(nick_skeleton("paypal"), nick_skeleton("раураl"));
+        assert_ne!(nick_skeleton("paypal"), nick_skeleton("paypa1x"));
+    }
+}
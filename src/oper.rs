@@ -0,0 +1,43 @@
+use crate::mask::mask_matches;
+
+/// What an IRC operator is allowed to do, in addition to being an operator
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OperPrivileges {
+    /// See the real host of users in WHOIS
+    pub see_real_hosts: bool,
+    /// Manage server-wide bans, like forbidden nicks and channels
+    pub manage_bans: bool,
+    /// Force users and channels with commands like SAMODE or SAJOIN
+    pub override_channels: bool,
+    /// Send messages to all operators or all users
+    pub global_messages: bool,
+}
+
+impl OperPrivileges {
+    /// All privileges
+    pub const ALL: OperPrivileges = OperPrivileges {
+        see_real_hosts: true,
+        manage_bans: true,
+        override_channels: true,
+        global_messages: true,
+    };
+}
+
+/// Credentials of an IRC operator, checked by the OPER command
+#[derive(Clone, Debug)]
+pub struct OperBlock {
+    /// Name given to the OPER command
+    pub name: String,
+    /// Argon2 hash of the password in PHC string format, see hash_password
+    pub password_hash: String,
+    /// The nick!user@host mask users must match to use this block, like "*!*@127.0.0.1"
+    pub hostmask: String,
+    pub privileges: OperPrivileges,
+}
+
+impl OperBlock {
+    /// Whether a user with this nick!user@host prefix may use this block
+    pub fn allows_prefix(&self, prefix: &str) -> bool {
+        mask_matches(&self.hostmask, prefix)
+    }
+}
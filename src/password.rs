@@ -0,0 +1,56 @@
+use argon2::password_hash::SaltString;
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use rand_core::OsRng;
+
+/// Hashes a password with Argon2id and a random salt, returns the hash in PHC string format
+/// This is the format expected for oper passwords in the server settings
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("Failed to hash password")
+        .to_string()
+}
+
+/// Same as hash_password, but runs on a blocking thread so it doesn't stall the server
+pub async fn hash_password_async(password: &str) -> String {
+    let password = password.to_owned();
+    tokio::task::spawn_blocking(move || hash_password(&password))
+        .await
+        .expect("Failed to hash password")
+}
+
+/// Whether a PHC string is a password hash we know how to verify
+pub fn is_valid_password_hash(hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok()
+}
+
+/// Checks a password against a hash from hash_password, comparing the hashes in constant time
+/// Hashing is deliberately slow, so this runs on a blocking thread
+pub async fn verify_password(password: &str, hash: &str) -> bool {
+    let (password, hash) = (password.to_owned(), hash.to_owned());
+    tokio::task::spawn_blocking(move || match PasswordHash::new(&hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(_) => false,
+    })
+    .await
+    .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn hash_and_verify() {
+        let hash = hash_password("hunter2");
+        assert!(is_valid_password_hash(&hash));
+        assert!(!is_valid_password_hash("hunter2"));
+
+        assert!(verify_password("hunter2", &hash).await);
+        assert!(!verify_password("hunter3", &hash).await);
+        assert!(!verify_password("hunter2", "not a hash").await);
+    }
+}
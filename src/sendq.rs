@@ -0,0 +1,358 @@
+use crate::channel::ChannelMessage;
+use crate::client::DisconnectSignal;
+use crate::message::{Message, MessageSink};
+use bytes::Bytes;
+use futures::future::{self, Either};
+use futures::{select_biased, FutureExt, SinkExt, StreamExt};
+use std::collections::BTreeSet;
+use std::io::Error;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::AsyncWrite;
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::StreamMap;
+
+/// Numbers the messages sent to clients and channels, in the order they are sent
+/// A writer interleaves its client's messages and those of its channels in this order,
+/// so a user's channel message is never seen after the NICK or QUIT the user sent next
+struct Sequencer {
+    next_seq: u64,
+    /// Numbers taken by messages that are still being sent
+    in_flight: BTreeSet<u64>,
+}
+
+static SEQUENCER: Mutex<Sequencer> = Mutex::new(Sequencer {
+    next_seq: 0,
+    in_flight: BTreeSet::new(),
+});
+
+/// Releases a sequence number once its message is sent, even if sending panics
+struct InFlight(u64);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        SEQUENCER.lock().unwrap().in_flight.remove(&self.0);
+    }
+}
+
+/// Sends a message with the next sequence number, send must hand it to the writer or channel right away
+pub fn send_in_order<T>(send: impl FnOnce(u64) -> T) -> T {
+    let in_flight = {
+        let mut sequencer = SEQUENCER.lock().unwrap();
+        let seq = sequencer.next_seq;
+        sequencer.next_seq += 1;
+        sequencer.in_flight.insert(seq);
+        InFlight(seq)
+    };
+    send(in_flight.0)
+}
+
+/// The sequence number the next message will get
+pub fn current_seq() -> u64 {
+    SEQUENCER.lock().unwrap().next_seq
+}
+
+/// The messages numbered below this one were all handed to their writer or channel already
+/// A number taken by a message still being sent holds back the following ones
+fn sent_below() -> u64 {
+    let sequencer = SEQUENCER.lock().unwrap();
+    let first_in_flight = sequencer.in_flight.first().copied();
+    first_in_flight.unwrap_or(sequencer.next_seq)
+}
+
+/// What the other tasks send to a client's writer
+pub enum Outgoing {
+    /// A message with its sequence number, see send_in_order
+    Message(u64, Message),
+    /// A serialized message, shared by all the clients it is relayed to
+    Line(u64, Bytes),
+    /// Starts relaying the messages of a channel the client joined
+    Subscribe(String, broadcast::Receiver<ChannelMessage>),
+    /// Stops relaying a channel's messages, after those numbered below this sequence number
+    Unsubscribe(String, u64),
+}
+
+/// Whether a message goes ahead of the messages already queued for a client, see Writer::urgent_sender
+/// These keep the connection alive or end it, and don't depend on the order of the other messages
+/// Numerics are never urgent, not even errors: clients match them with the command they answer,
+/// so they stay in order with bursts like NAMES, WHO or WHOIS replies to earlier commands
+pub fn is_urgent(msg: &Message) -> bool {
+    matches!(&*msg.command, "PING" | "PONG" | "ERROR")
+}
+
+/// What the writer waits for when it has nothing left to write
+enum WriterEvent {
+    Urgent(Message),
+    Outgoing(Option<Outgoing>),
+    Channel(Option<(String, Result<ChannelMessage, BroadcastStreamRecvError>)>),
+}
+
+/// Messages waiting to be written to a client's socket, shared with the client's writer task
+pub struct SendQueue {
+    sink: Mutex<MessageSink<Box<dyn AsyncWrite + Send + Unpin>>>,
+    /// Size of the messages sent to the writer that are not yet written to the socket
+    /// Channel messages are not counted, a client too slow for those lags behind the channel instead
+    pub bytes: AtomicUsize,
+}
+
+impl SendQueue {
+    pub fn new(socket_w: Box<dyn AsyncWrite + Send + Unpin>) -> SendQueue {
+        SendQueue {
+            sink: Mutex::new(MessageSink::new(socket_w)),
+            bytes: AtomicUsize::new(0),
+        }
+    }
+
+    /// How long queued data has been waiting without the socket accepting any of it
+    pub fn stalled_for(&self) -> Duration {
+        self.sink.lock().unwrap().stalled_for()
+    }
+
+    /// Queues a message ahead of the others, returns its size
+    fn queue_urgent(&self, msg: &Message) -> usize {
+        self.sink.lock().unwrap().queue_urgent(msg);
+        msg.line_len() + 2
+    }
+
+    /// Writes the send buffer to the socket, unless the socket stops accepting data for too long
+    async fn flush(&self, write_timeout: Duration) -> Result<(), Error> {
+        loop {
+            let flushed = future::poll_fn(|cx| self.sink.lock().unwrap().poll_flush_unpin(cx));
+            match tokio::time::timeout(write_timeout, flushed).await {
+                Ok(result) => return result,
+                // A slow client that is still reading gets more time, a dead one is dropped
+                Err(_) => {
+                    if self.stalled_for() >= write_timeout {
+                        return Err(Error::other("Write timeout"));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A message received by the writer, waiting to be queued in the sink in sequence order
+enum Pending {
+    Message(Message),
+    Line(Bytes),
+    /// Channel messages are not counted in the SendQ
+    ChannelLine(Bytes),
+}
+
+/// Writes the messages sent to a client and those of its channels
+/// Senders only enqueue messages, so they never wait on another client's socket
+pub struct Writer {
+    sendq: Arc<SendQueue>,
+    receiver: mpsc::UnboundedReceiver<Outgoing>,
+    /// Messages written at the next line boundary, even while a backlog is being written
+    urgent: mpsc::UnboundedReceiver<Message>,
+    /// Keeps the urgent channel open until the writer stops, which is when the receiver closes
+    urgent_sender: mpsc::UnboundedSender<Message>,
+    channels: StreamMap<String, BroadcastStream<ChannelMessage>>,
+    /// Address of the client, to skip the channel messages it sent itself
+    addr: SocketAddr,
+    /// Size of the messages from the receiver queued in the sink since the last flush
+    queued_bytes: usize,
+    /// The messages received but not yet queued in the sink, with their sequence numbers
+    pending: Vec<(u64, Pending)>,
+}
+
+impl Writer {
+    pub fn new(
+        sendq: Arc<SendQueue>,
+        receiver: mpsc::UnboundedReceiver<Outgoing>,
+        addr: SocketAddr,
+    ) -> Writer {
+        let (urgent_sender, urgent) = mpsc::unbounded_channel();
+        Writer {
+            sendq,
+            receiver,
+            urgent,
+            urgent_sender,
+            channels: StreamMap::new(),
+            addr,
+            queued_bytes: 0,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Sends the messages that is_urgent picks, ahead of the messages already queued
+    pub fn urgent_sender(&self) -> mpsc::UnboundedSender<Message> {
+        self.urgent_sender.clone()
+    }
+
+    /// Runs until the client is dropped or writing fails, then triggers the disconnect signal
+    pub async fn run(mut self, disconnect_signal: Arc<DisconnectSignal>, write_timeout: Duration) {
+        if let Err(err) = self.write_all(write_timeout).await {
+            disconnect_signal.trigger(&err.to_string());
+        }
+    }
+
+    async fn write_all(&mut self, write_timeout: Duration) -> Result<(), Error> {
+        loop {
+            // Messages held back by the last write go out without waiting for new ones
+            if self.pending.is_empty() {
+                if !self.wait_event().await? {
+                    return Ok(());
+                }
+            } else {
+                // They wait for a message that is still being sent, which won't take long
+                tokio::task::yield_now().await;
+            }
+
+            // Read before receiving what is waiting, so the messages numbered below it are all received
+            let ready_below = sent_below();
+            // Everything that is already waiting goes out in the same write
+            while let Ok(msg) = self.urgent.try_recv() {
+                self.handle_urgent(msg);
+            }
+            while let Ok(outgoing) = self.receiver.try_recv() {
+                self.handle_outgoing(outgoing)?;
+            }
+            while let Some(Some((_, channel_msg))) = self.channels.next().now_or_never() {
+                self.handle_channel_msg(channel_msg)?;
+            }
+            self.queue_pending(ready_below);
+
+            self.flush(write_timeout).await?;
+            self.sendq
+                .bytes
+                .fetch_sub(self.queued_bytes, Ordering::Relaxed);
+            self.queued_bytes = 0;
+        }
+    }
+
+    /// Waits until there is something to write, returns false once the client is dropped
+    async fn wait_event(&mut self) -> Result<bool, Error> {
+        let channel_msg = if self.channels.is_empty() {
+            Either::Left(future::pending())
+        } else {
+            Either::Right(self.channels.next())
+        };
+        let next = select_biased! {
+            msg = self.urgent.recv().fuse() => WriterEvent::Urgent(msg.expect("The writer keeps a sender")),
+            outgoing = self.receiver.recv().fuse() => WriterEvent::Outgoing(outgoing),
+            channel_msg = channel_msg.fuse() => WriterEvent::Channel(channel_msg),
+        };
+        match next {
+            WriterEvent::Urgent(msg) => self.handle_urgent(msg),
+            WriterEvent::Outgoing(Some(outgoing)) => self.handle_outgoing(outgoing)?,
+            WriterEvent::Outgoing(None) => return Ok(false),
+            WriterEvent::Channel(Some((_, channel_msg))) => self.handle_channel_msg(channel_msg)?,
+            WriterEvent::Channel(None) => (),
+        }
+        Ok(true)
+    }
+
+    /// Writes everything queued, urgent messages sent meanwhile are queued ahead of what is left
+    async fn flush(&mut self, write_timeout: Duration) -> Result<(), Error> {
+        let sendq = self.sendq.clone();
+        let mut flush = Box::pin(sendq.flush(write_timeout));
+        loop {
+            let next = future::select(flush, Box::pin(self.urgent.recv())).await;
+            match next {
+                Either::Left((result, _)) => return result,
+                Either::Right((msg, unfinished)) => {
+                    // Only the fields not borrowed by the select can be used here
+                    let msg = msg.expect("The writer keeps a sender");
+                    self.queued_bytes += sendq.queue_urgent(&msg);
+                    flush = unfinished;
+                }
+            }
+        }
+    }
+
+    /// Queues the messages numbered below ready_below in the order they were sent, whether they came directly or from a channel
+    /// The others may come after a message that is still being sent, they wait for the next write
+    fn queue_pending(&mut self, ready_below: u64) {
+        self.pending.sort_by_key(|&(seq, _)| seq);
+        let ready = self.pending.partition_point(|&(seq, _)| seq < ready_below);
+        let mut sink = self.sendq.sink.lock().unwrap();
+        for (_, pending) in self.pending.drain(..ready) {
+            match pending {
+                Pending::Message(msg) => {
+                    self.queued_bytes += msg.line_len() + 2;
+                    sink.queue(&msg);
+                }
+                Pending::Line(line) => {
+                    self.queued_bytes += line.len();
+                    sink.queue_line(line);
+                }
+                Pending::ChannelLine(line) => sink.queue_line(line),
+            }
+        }
+    }
+
+    fn handle_urgent(&mut self, msg: Message) {
+        self.queued_bytes += self.sendq.queue_urgent(&msg);
+    }
+
+    fn handle_outgoing(&mut self, outgoing: Outgoing) -> Result<(), Error> {
+        match outgoing {
+            Outgoing::Message(seq, msg) => self.pending.push((seq, Pending::Message(msg))),
+            Outgoing::Line(seq, line) => self.pending.push((seq, Pending::Line(line))),
+            Outgoing::Subscribe(channel, receiver) => {
+                self.channels
+                    .insert(channel, BroadcastStream::new(receiver));
+            }
+            Outgoing::Unsubscribe(channel, until) => {
+                let mut stream = match self.channels.remove(&channel) {
+                    Some(stream) => stream,
+                    None => return Ok(()),
+                };
+                // The messages sent before the client left, like its PART, are still delivered
+                while let Some(Some(channel_msg)) = stream.next().now_or_never() {
+                    match channel_msg {
+                        Ok(channel_msg) if channel_msg.seq >= until => break,
+                        channel_msg => self.handle_channel_msg(channel_msg)?,
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_channel_msg(
+        &mut self,
+        channel_msg: Result<ChannelMessage, BroadcastStreamRecvError>,
+    ) -> Result<(), Error> {
+        // Messages were dropped, the client is too far behind the channel
+        let channel_msg = channel_msg.map_err(|_| Error::other("SendQ exceeded"))?;
+        if channel_msg.exclude.as_ref() != Some(&self.addr) {
+            self.pending
+                .push((channel_msg.seq, Pending::ChannelLine(channel_msg.line)));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn in_flight_messages_hold_back_later_ones() {
+        send_in_order(|seq| {
+            assert!(sent_below() <= seq);
+            let next = send_in_order(|next| {
+                assert!(sent_below() <= seq);
+                next
+            });
+            assert!(next > seq);
+            assert!(sent_below() <= seq);
+        });
+    }
+
+    #[test]
+    fn numerics_keep_their_place() {
+        assert!(is_urgent(&Message::new("PING :irc.example.org")));
+        let names = Message::new(":irc.example.org 353 alice = #chan :alice bob");
+        assert!(!is_urgent(&names));
+        let error = Message::new(":irc.example.org 401 alice bob :No such nick");
+        assert!(!is_urgent(&error));
+    }
+}
@@ -1,79 +1,573 @@
-use crate::callbacks::ServerCallbacks;
+use crate::account::{AccountProvider, MemoryAccountProvider};
+use crate::audit::{AuditEntry, AuditLog};
+use crate::callbacks::{RawMessageAction, ServerHooks};
 use crate::channel::Channel;
-use crate::client::{Client, ClientDuplex, ClientStatus};
-use crate::commands::{is_command_available, COMMANDS};
-use crate::message::{self, make_reply_msg, Message, ReplyCode};
-use crate::settings::ServerSettings;
+use crate::channel_registration::ChannelRegistration;
+use crate::client::{Client, ClientDuplex, ClientNormalState, ClientStatus, Mute};
+use crate::commands::{
+    is_command_available, CommandFuture, CommandMiddleware, CommandNamespace, CustomCommand,
+    RegisteredCommand, COMMANDS,
+};
+use crate::connection_info::{ConnectionInfo, Transport};
+use crate::dispatch::{DispatchStats, Dispatcher};
+use crate::errors::{InputTooLongError, InvalidUtf8Error, ParseError, SettingsError, SetupError};
+use crate::events::{ServerEvent, EVENT_QUEUE_SIZE};
+use crate::forbidden::ForbiddenNames;
+use crate::health::{check_health, Health};
+use crate::ident::lookup_ident;
+use crate::message::{make_reply_msg, InvalidUtf8Policy, LongLinePolicy, Message, ReplyCode};
+use crate::server_handle::ServerHandle;
+use crate::settings::{RuntimeSettings, ServerSettings};
+use crate::sharded_map::ShardedMap;
+use crate::shun::{ShunList, SHUN_EXEMPT_COMMANDS};
+use crate::snomask::SnoCategory;
+use crate::socket_options::configure_client_socket;
+use crate::throttle::ConnectionThrottle;
 
 use chrono::{DateTime, Local};
-use futures::StreamExt;
+use futures::future::{self, Either};
+use futures::{stream, Stream, StreamExt};
+use smallvec::smallvec;
+use socket2::{Domain, Socket, Type};
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::io::Error;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Weak};
+use std::time::Instant;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{Mutex, RwLock};
-use tokio_stream::wrappers::TcpListenerStream;
+use tokio::sync::{broadcast, Mutex, RwLock};
+use tokio_stream::wrappers::{BroadcastStream, TcpListenerStream};
 
+#[cfg(feature = "tls")]
+use crate::tls::{client_cert_verifier, SniCertResolver};
+#[cfg(any(feature = "tls", feature = "native-tls"))]
+use crate::tls_provider::TlsProvider;
+#[cfg(feature = "native-tls")]
+use tokio_native_tls::native_tls;
 #[cfg(feature = "tls")]
 use tokio_rustls::{rustls::ServerConfig, TlsAcceptor};
 
 pub struct ServerState {
     pub settings: ServerSettings,
-    pub callbacks: ServerCallbacks,
-    pub clients: Mutex<HashMap<String, Weak<RwLock<Client>>>>, // Peer addr -> Client
-    pub users: RwLock<HashMap<String, Weak<RwLock<Client>>>>,  // Nickname -> Registered Client
-    pub channels: Mutex<HashMap<String, Arc<RwLock<Channel>>>>, // Channel name -> Channel
+    /// Replaced as a whole when changed, so readers get a consistent snapshot
+    runtime_settings: std::sync::RwLock<Arc<RuntimeSettings>>,
+    pub hooks: Arc<dyn ServerHooks>,
+    pub clients: ShardedMap<SocketAddr, Weak<RwLock<Client>>>, // Peer addr -> Client
+    pub users: ShardedMap<String, Weak<RwLock<Client>>>,  // Nickname -> Registered Client
+    pub channels: ShardedMap<String, Arc<RwLock<Channel>>>, // Channel name -> Channel
+    pub connection_throttle: Mutex<ConnectionThrottle>,
+    pub forbidden_nicks: RwLock<ForbiddenNames>,
+    pub forbidden_channels: RwLock<ForbiddenNames>,
+    pub shuns: RwLock<ShunList>,
+    pub audit_log: RwLock<AuditLog>,
+    pub account_provider: Arc<dyn AccountProvider>,
+    /// Commands added by the embedder, by uppercase name
+    pub custom_commands: HashMap<String, RegisteredCommand>,
+    /// Sends the events of Server::events, see ServerState::emit
+    pub events: broadcast::Sender<ServerEvent>,
+    /// Middlewares in the order they were added, with the uppercase name of their command if they have one
+    pub middlewares: Vec<(Option<String>, Arc<dyn CommandMiddleware>)>,
+    pub channel_registrations: RwLock<HashMap<String, ChannelRegistration>>, // Channel name -> Registration
     pub creation_time: DateTime<Local>,
+    /// Whether the accept loop is running
+    pub accepting: AtomicBool,
+    /// Processes the clients' messages when ServerSettings::dispatch_workers isn't 0
+    pub dispatcher: Option<Dispatcher>,
 }
 
 impl ServerState {
-    pub fn new(settings: ServerSettings, callbacks: ServerCallbacks) -> Arc<ServerState> {
-        let msg_breathing_room = 96; // Pretty arbitrary, helps avoid running into MAX_LENGTH.
-        assert!(settings.max_name_length < message::MAX_LENGTH - msg_breathing_room);
-        assert!(settings.max_channel_length < message::MAX_LENGTH - msg_breathing_room);
-        assert!(settings.max_topic_length < message::MAX_LENGTH - msg_breathing_room);
-        assert!(!settings.server_name.contains(' '));
-        assert!(!settings.network_name.contains(' '));
+    /// The current values of the settings that can be changed while the server runs
+    pub fn runtime_settings(&self) -> Arc<RuntimeSettings> {
+        self.runtime_settings.read().unwrap().clone()
+    }
+
+    /// Returns the canonical form of a nick or channel name, used as key in our maps
+    pub fn casemap(&self, name: &str) -> String {
+        self.settings.casemapping.to_upper(name)
+    }
+
+    /// Sends an event to the subscribers of Server::events, it is only built if there are any
+    pub fn emit(&self, event: impl FnOnce() -> ServerEvent) {
+        if self.events.receiver_count() > 0 {
+            self.events.send(event()).ok();
+        }
+    }
+
+    /// Returns a channel, creating it if it doesn't exist, and whether it was created
+    /// A new channel is passed to the on_channel_created hook before anyone else can lock it
+    pub async fn get_or_create_channel(&self, name: &str) -> (Arc<RwLock<Channel>>, bool) {
+        let mut new_channel_guard = None;
+        let (channel, created) = self.channels.get_or_insert_with(self.casemap(name), || {
+            let channel = Arc::new(RwLock::new(Channel::new(name.to_owned())));
+            new_channel_guard = channel.clone().try_write_owned().ok();
+            channel
+        });
+        if let Some(mut channel_guard) = new_channel_guard {
+            self.hooks.on_channel_created(&mut channel_guard).await.ok();
+        }
+        (channel, created)
+    }
+
+    /// Removes a channel whose last user left, unless it was already replaced by a new channel
+    /// The caller holds the lock on the channel's users, so nobody is joining it meanwhile
+    pub async fn remove_empty_channel(
+        &self,
+        channel: &Arc<RwLock<Channel>>,
+        channel_guard: &Channel,
+    ) {
+        let removed = self
+            .channels
+            .remove_if(&self.casemap(&channel_guard.name), |registered| {
+                Arc::ptr_eq(registered, channel)
+            });
+        if removed.is_some() {
+            self.hooks.on_channel_destroyed(channel_guard).await.ok();
+        }
+    }
+
+    /// Returns a registered user by nick, a dead entry found on the way is removed
+    pub fn find_user(&self, nick: &str) -> Option<Arc<RwLock<Client>>> {
+        let casemapped_nick = self.casemap(nick);
+        let user = self.users.get(&casemapped_nick)?.upgrade();
+        if user.is_none() {
+            self.users
+                .remove_if(&casemapped_nick, |weak| weak.strong_count() == 0);
+        }
+        user
+    }
+
+    /// Removes the clients, users and channel members that are gone, which lookups otherwise only skip
+    /// Channels left empty are removed too, returns how many entries were removed
+    pub async fn sweep_dead_entries(&self) -> usize {
+        let mut removed = self.clients.retain(|_, client| client.strong_count() > 0);
+        removed += self.users.retain(|_, user| user.strong_count() > 0);
+        for channel in self.channels.values() {
+            let channel_guard = channel.read().await;
+            removed += channel_guard.prune_dead_members().await;
+            let channel_users = channel_guard.users.read().await;
+            if channel_users.is_empty() && !channel_guard.mode.permanent {
+                self.remove_empty_channel(&channel, &channel_guard).await;
+            }
+        }
+        removed
+    }
+
+    /// Checks that a channel being joined is still registered, once the joiner holds the lock on its users
+    /// If its last user left meanwhile it was removed, and is registered again
+    /// Returns false if a new channel with the same name was created meanwhile
+    pub fn keep_channel(&self, channel: &Arc<RwLock<Channel>>, name: &str) -> bool {
+        let (registered, _) = self
+            .channels
+            .get_or_insert_with(self.casemap(name), || channel.clone());
+        Arc::ptr_eq(&registered, channel)
+    }
+
+    /// Sends a server notice to the operators subscribed to its category
+    /// This locks every user, so the caller must not hold a write lock on any client
+    pub async fn snotice(&self, category: SnoCategory, text: &str) {
+        let users: Vec<_> = self
+            .users
+            .values()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .collect();
+        let notice = format!("*** {} -- {}", category.name(), text);
+        for user in users {
+            let user = user.read().await;
+            if user.mode.is_oper && user.mode.snomask.contains(category) {
+                // A broken connection to an operator shouldn't stop whoever triggered the notice
+                user.send_notice(&notice).await.ok();
+            }
+        }
+    }
+
+    /// Registers a channel, returns false if it was already registered
+    pub async fn register_channel(&self, registration: ChannelRegistration) -> bool {
+        let mut registrations = self.channel_registrations.write().await;
+        match registrations.entry(self.casemap(&registration.name)) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(registration);
+                true
+            }
+        }
+    }
+
+    /// Records an operator action in the audit log
+    pub async fn audit(&self, actor: &str, action: &str, target: &str) {
+        self.audit_log.write().await.record(actor, action, target);
+    }
+
+    /// The settings must have passed ServerSettings::validate
+    pub fn new(settings: ServerSettings, hooks: Arc<dyn ServerHooks>) -> Arc<ServerState> {
+        let mut channels = HashMap::new();
+        let mut channel_registrations = HashMap::new();
+        for registration in &settings.registered_channels {
+            let key = settings.casemapping.to_upper(&registration.name);
+            channels.insert(
+                key.clone(),
+                Arc::new(RwLock::new(Channel::from_registration(
+                    registration,
+                    &settings.server_name,
+                ))),
+            );
+            channel_registrations.insert(key, registration.clone());
+        }
+
+        let dispatcher = match settings.dispatch_workers {
+            0 => None,
+            workers => Some(Dispatcher::new(workers, settings.dispatch_queue_size)),
+        };
 
         Arc::new(ServerState {
+            forbidden_nicks: RwLock::new(ForbiddenNames::new(&settings.forbidden_nicks)),
+            forbidden_channels: RwLock::new(ForbiddenNames::new(&settings.forbidden_channels)),
+            shuns: RwLock::new(ShunList::default()),
+            audit_log: RwLock::new(AuditLog::new(settings.audit_log_size)),
+            account_provider: Arc::new(MemoryAccountProvider::new(settings.casemapping)),
+            custom_commands: HashMap::new(),
+            middlewares: Vec::new(),
+            events: broadcast::channel(EVENT_QUEUE_SIZE).0,
+            channel_registrations: RwLock::new(channel_registrations),
+            runtime_settings: std::sync::RwLock::new(Arc::new(settings.runtime())),
             settings,
-            callbacks,
+            hooks,
             creation_time: Local::now(),
-            clients: Mutex::new(HashMap::new()),
-            users: RwLock::new(HashMap::new()),
-            channels: Mutex::new(HashMap::new()),
+            accepting: AtomicBool::new(false),
+            dispatcher,
+            clients: ShardedMap::new(),
+            users: ShardedMap::new(),
+            channels: ShardedMap::from_map(channels),
+            connection_throttle: Mutex::new(ConnectionThrottle::default()),
         })
     }
 }
 
+/// Binds a listening socket, IPv6 sockets only accept IPv6 so that IPv4 can be bound on the same port
+fn bind_listener(addr: &SocketAddr, backlog: u32) -> Result<TcpListener, Error> {
+    let socket = Socket::new(Domain::for_address(*addr), Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.bind(&(*addr).into())?;
+    socket.listen(i32::try_from(backlog).unwrap_or(i32::MAX))?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
+
 pub struct Server {
     state: Arc<ServerState>,
 
-    #[cfg(feature = "tls")]
-    tls_acceptor: Option<TlsAcceptor>,
-    #[cfg(not(feature = "tls"))]
+    #[cfg(any(feature = "tls", feature = "native-tls"))]
+    tls_provider: Option<Box<dyn TlsProvider>>,
+    #[cfg(not(any(feature = "tls", feature = "native-tls")))]
     #[allow(dead_code)]
-    tls_acceptor: Option<()>,
+    tls_provider: Option<()>,
 }
 
 impl Server {
-    pub fn new(settings: ServerSettings, callbacks: ServerCallbacks) -> Server {
-        Server {
-            state: ServerState::new(settings, callbacks),
-            tls_acceptor: None,
-        }
+    /// Panics if the settings are invalid, see try_new
+    pub fn new(settings: ServerSettings, hooks: Arc<dyn ServerHooks>) -> Server {
+        Server::try_new(settings, hooks)
+            .unwrap_or_else(|err| panic!("Invalid server settings: {}", err))
+    }
+
+    /// Returns why the settings are invalid, instead of panicking like new
+    pub fn try_new(
+        settings: ServerSettings,
+        hooks: Arc<dyn ServerHooks>,
+    ) -> Result<Server, SettingsError> {
+        settings.validate()?;
+        Ok(Server {
+            state: ServerState::new(settings, hooks),
+            tls_provider: None,
+        })
     }
 
     #[cfg(feature = "tls")]
     /// Uses the provided TLS configuration for IRC connections
     pub fn use_tls(&mut self, tls_config: Arc<ServerConfig>) {
-        self.tls_acceptor = Some(TlsAcceptor::from(tls_config));
+        self.tls_provider = Some(Box::new(TlsAcceptor::from(tls_config)));
+    }
+
+    #[cfg(feature = "tls")]
+    /// Uses TLS with a certificate picked by SNI, the resolver can still be updated after the server starts
+    /// Client certificates are asked for according to the client_certs setting
+    pub fn use_tls_with_sni(&mut self, resolver: Arc<SniCertResolver>) {
+        let builder = ServerConfig::builder().with_safe_defaults();
+        let builder = match client_cert_verifier(&self.state.settings.client_certs) {
+            Some(verifier) => builder.with_client_cert_verifier(verifier),
+            None => builder.with_no_client_auth(),
+        };
+        let tls_config = builder.with_cert_resolver(resolver);
+        self.use_tls(Arc::new(tls_config));
+    }
+
+    #[cfg(feature = "native-tls")]
+    /// Uses the system's TLS library (OpenSSL on Linux) for IRC connections, instead of rustls
+    /// Client certificates are only used if the acceptor asks for them, the client_certs setting doesn't apply
+    pub fn use_native_tls(&mut self, acceptor: native_tls::TlsAcceptor) {
+        self.tls_provider = Some(Box::new(tokio_native_tls::TlsAcceptor::from(acceptor)));
+    }
+
+    /// Stores accounts with this provider instead of keeping them in memory
+    /// Fails once the server is shared, like register_command
+    pub fn set_account_provider(
+        &mut self,
+        provider: Arc<dyn AccountProvider>,
+    ) -> Result<(), SetupError> {
+        self.state_mut()?.account_provider = provider;
+        Ok(())
+    }
+
+    /// Adds a command handled by the embedder, for the clients its namespace allows
+    /// Fails if a built-in or registered command already has this name, or once the server is shared
+    /// Panics if the name isn't made of ASCII letters
+    pub fn register_command(
+        &mut self,
+        name: &str,
+        namespace: CommandNamespace,
+        handler: impl CustomCommand + 'static,
+    ) -> Result<(), SetupError> {
+        assert!(
+            !name.is_empty() && name.bytes().all(|c| c.is_ascii_alphabetic()),
+            "Command names must be ASCII letters"
+        );
+        let name = name.to_ascii_uppercase();
+        let state = self.state_mut()?;
+        if COMMANDS.contains_key(&*name) || state.custom_commands.contains_key(&name) {
+            return Err(SetupError::CommandExists(name));
+        }
+        let handler = Arc::new(handler);
+        let command = RegisteredCommand { namespace, handler };
+        state.custom_commands.insert(name, command);
+        Ok(())
+    }
+
+    /// Adds a middleware that runs around the handler of every command, see CommandMiddleware
+    /// Fails once the server is shared
+    pub fn add_middleware(
+        &mut self,
+        middleware: impl CommandMiddleware + 'static,
+    ) -> Result<(), SetupError> {
+        self.push_middleware(None, Arc::new(middleware))
+    }
+
+    /// Adds a middleware that only runs around one command, built-in or registered
+    /// Fails once the server is shared
+    pub fn add_command_middleware(
+        &mut self,
+        command: &str,
+        middleware: impl CommandMiddleware + 'static,
+    ) -> Result<(), SetupError> {
+        let command = command.to_ascii_uppercase();
+        self.push_middleware(Some(command), Arc::new(middleware))
+    }
+
+    fn push_middleware(
+        &mut self,
+        command: Option<String>,
+        middleware: Arc<dyn CommandMiddleware>,
+    ) -> Result<(), SetupError> {
+        self.state_mut()?.middlewares.push((command, middleware));
+        Ok(())
+    }
+
+    /// The state while nothing else holds it, it is shared once the server starts or has a handle
+    fn state_mut(&mut self) -> Result<&mut ServerState, SetupError> {
+        Arc::get_mut(&mut self.state).ok_or(SetupError::AlreadyShared)
+    }
+
+    /// Returns a handle to inspect the server from other tasks, even once it is started
+    /// The handle shares the server's state, so commands, middlewares and the account provider
+    /// must be set up before calling this, like before start
+    pub fn handle(&self) -> ServerHandle {
+        ServerHandle::new(self.state.clone())
+    }
+
+    /// Returns the events of the server from now on, for bots and bridges running in the same process
+    /// Subscribe before starting the server to see every event. A subscriber that falls behind by more
+    /// than EVENT_QUEUE_SIZE events misses the oldest ones
+    pub fn events(&self) -> impl Stream<Item = ServerEvent> {
+        BroadcastStream::new(self.state.events.subscribe())
+            .filter_map(|event| future::ready(event.ok()))
+    }
+
+    /// Creates a channel that stays open when empty, as if it had mode +P
+    /// If the channel already exists, it is made permanent
+    pub async fn add_permanent_channel(&self, name: &str) -> Arc<RwLock<Channel>> {
+        assert!(name.starts_with('#'));
+        let (channel, _) = self.state.get_or_create_channel(name).await;
+        channel.write().await.mode.permanent = true;
+        channel
+    }
+
+    /// Forbids nicks matching a glob mask, returns false if the mask was already forbidden
+    /// Users already using a matching nick keep it
+    pub async fn add_forbidden_nick(&self, mask: &str) -> bool {
+        self.state.forbidden_nicks.write().await.add(mask)
+    }
+
+    /// Allows nicks matching a mask again, returns false if the mask wasn't forbidden
+    pub async fn remove_forbidden_nick(&self, mask: &str) -> bool {
+        self.state.forbidden_nicks.write().await.remove(mask)
+    }
+
+    /// Forbids creating or joining channels matching a glob mask
+    /// Returns false if the mask was already forbidden. Users already in a matching channel stay there
+    pub async fn add_forbidden_channel(&self, mask: &str) -> bool {
+        self.state.forbidden_channels.write().await.add(mask)
+    }
+
+    /// Allows channels matching a mask again, returns false if the mask wasn't forbidden
+    pub async fn remove_forbidden_channel(&self, mask: &str) -> bool {
+        self.state.forbidden_channels.write().await.remove(mask)
+    }
+
+    /// Drops the PRIVMSGs and NOTICEs of a registered user, returns false if there's no such nick
+    pub async fn mute_user(&self, nick: &str, mute: Mute) -> bool {
+        self.set_user_mute(nick, Some(mute)).await
+    }
+
+    /// Lets a muted user send messages again, returns false if there's no such nick
+    pub async fn unmute_user(&self, nick: &str) -> bool {
+        self.set_user_mute(nick, None).await
+    }
+
+    async fn set_user_mute(&self, nick: &str, mute: Option<Mute>) -> bool {
+        let user = match self.state.find_user(nick) {
+            Some(user) => user,
+            None => return false,
+        };
+        user.write().await.mute = mute;
+        true
+    }
+
+    /// Returns the operator actions in the audit log, from oldest to newest
+    pub async fn audit_log(&self) -> Vec<AuditEntry> {
+        self.state
+            .audit_log
+            .read()
+            .await
+            .entries()
+            .cloned()
+            .collect()
+    }
+
+    /// Registers a channel as if with ChanServ, creating it if needed
+    /// Returns false if the channel was already registered
+    pub async fn register_channel(&self, registration: ChannelRegistration) -> bool {
+        self.add_permanent_channel(&registration.name).await;
+        self.state.register_channel(registration).await
+    }
+
+    /// Unregisters a channel, returns false if it wasn't registered
+    /// The channel is kept until it becomes empty
+    pub async fn unregister_channel(&self, name: &str) -> bool {
+        let key = self.state.casemap(name);
+        if self
+            .state
+            .channel_registrations
+            .write()
+            .await
+            .remove(&key)
+            .is_none()
+        {
+            return false;
+        }
+        if let Some(channel) = self.state.channels.get(&key) {
+            channel.write().await.mode.permanent = false;
+        }
+        true
+    }
+
+    /// Returns the registered channels, so they can be saved and passed back in the settings after a restart
+    pub async fn registered_channels(&self) -> Vec<ChannelRegistration> {
+        let registrations = self.state.channel_registrations.read().await;
+        registrations.values().cloned().collect()
+    }
+
+    /// Reports whether the server is accepting connections, its number of clients and its event loop lag
+    pub async fn health(&self) -> Health {
+        check_health(&self.state).await
+    }
+
+    /// Statistics of each dispatch worker, empty unless ServerSettings::dispatch_workers is set
+    pub fn dispatch_stats(&self) -> Vec<DispatchStats> {
+        match self.state.dispatcher {
+            Some(ref dispatcher) => dispatcher.stats(),
+            None => Vec::new(),
+        }
+    }
+
+    /// The current values of the settings that can be changed while the server runs
+    pub fn runtime_settings(&self) -> RuntimeSettings {
+        (*self.state.runtime_settings()).clone()
+    }
+
+    /// Changes settings while the server runs, nothing is changed if the new settings are invalid
+    /// New limits apply to the next commands, and are advertised to the clients that register afterwards
+    pub fn update_settings(
+        &self,
+        update: impl FnOnce(&mut RuntimeSettings),
+    ) -> Result<(), SettingsError> {
+        let mut runtime_settings = self.state.runtime_settings.write().unwrap();
+        let mut updated = (**runtime_settings).clone();
+        update(&mut updated);
+        updated.validate()?;
+        *runtime_settings = Arc::new(updated);
+        Ok(())
     }
 
     pub async fn start(&mut self) -> Result<(), Error> {
-        let listener = TcpListener::bind(&self.state.settings.listen_addr).await?;
-        let mut incoming = TcpListenerStream::new(listener);
+        let mut listeners = Vec::new();
+        for addr in &self.state.settings.listen_addrs {
+            let listener = bind_listener(addr, self.state.settings.listen_backlog)?;
+            listeners.push(TcpListenerStream::new(listener));
+        }
+        let mut incoming = stream::select_all(listeners);
 
+        #[cfg(feature = "admin-api")]
+        if let Some(admin_settings) = self.state.settings.admin_api.clone() {
+            let listener = TcpListener::bind(admin_settings.listen_addr).await?;
+            let state = self.state.clone();
+            let token = admin_settings.token;
+            tokio::spawn(crate::admin::run_admin_api(state, listener, token));
+        }
+
+        if let Some(ref dispatcher) = self.state.dispatcher {
+            dispatcher.start();
+        }
+
+        // The sweeper stops with the server, it only keeps a weak reference
+        let state = Arc::downgrade(&self.state);
+        let mut sweep_interval = tokio::time::interval(self.state.settings.sweep_interval);
+        sweep_interval.tick().await;
+        tokio::spawn(async move {
+            loop {
+                sweep_interval.tick().await;
+                match state.upgrade() {
+                    Some(state) => state.sweep_dead_entries().await,
+                    None => return,
+                };
+            }
+        });
+
+        self.state.accepting.store(true, Ordering::Relaxed);
+        let result = self.accept_connections(&mut incoming).await;
+        self.state.accepting.store(false, Ordering::Relaxed);
+        result
+    }
+
+    async fn accept_connections(
+        &self,
+        incoming: &mut (impl Stream<Item = Result<TcpStream, Error>> + Unpin),
+    ) -> Result<(), Error> {
         while let Some(socket) = incoming.next().await {
             let socket = socket?;
             let addr = match socket.peer_addr() {
@@ -83,6 +577,14 @@ impl Server {
                     continue;
                 }
             };
+            if !self.accept_connection_attempt(&addr).await {
+                let notice = format!("{} is reconnecting too fast", addr.ip());
+                self.state.snotice(SnoCategory::Flood, &notice).await;
+                continue;
+            }
+            if let Err(err) = configure_client_socket(&socket, &self.state.settings) {
+                println!("{}: Failed to set socket options: {}", addr, err);
+            }
             let client = match self.accept_client(socket).await {
                 Ok(c) => c,
                 Err(err) => {
@@ -97,84 +599,370 @@ impl Server {
         Ok(())
     }
 
-    #[cfg(not(feature = "tls"))]
+    /// Checks that the address isn't reconnecting too fast
+    async fn accept_connection_attempt(&self, addr: &SocketAddr) -> bool {
+        let settings = self.state.runtime_settings();
+        if settings.throttle_max_connections == 0 {
+            return true;
+        }
+        self.state
+            .connection_throttle
+            .lock()
+            .await
+            .register_attempt(
+                addr.ip(),
+                Instant::now(),
+                settings.throttle_window,
+                settings.throttle_max_connections,
+            )
+    }
+
+    #[cfg(not(any(feature = "tls", feature = "native-tls")))]
     async fn accept_client(&self, socket: TcpStream) -> Result<ClientDuplex, Error> {
         Ok(ClientDuplex::from_tcp_stream(self.state.clone(), socket))
     }
 
-    #[cfg(feature = "tls")]
+    #[cfg(any(feature = "tls", feature = "native-tls"))]
     async fn accept_client(&self, socket: TcpStream) -> Result<ClientDuplex, Error> {
-        let client = if self.tls_acceptor.is_some() {
-            let acceptor = self.tls_acceptor.clone().unwrap();
-            let tls_sock = acceptor.accept(socket).await?;
-
-            ClientDuplex::from_tls_stream(self.state.clone(), tls_sock)
-        } else {
-            ClientDuplex::from_tcp_stream(self.state.clone(), socket)
-        };
-        Ok(client)
+        match self.tls_provider {
+            Some(ref provider) => provider.accept(self.state.clone(), socket).await,
+            None => Ok(ClientDuplex::from_tcp_stream(self.state.clone(), socket)),
+        }
     }
 
-    async fn handle_client(
+    pub(crate) async fn handle_client(
         state: Arc<ServerState>,
         mut client_duplex: ClientDuplex,
     ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         let addr = client_duplex.client.addr;
+        let connection_info = client_duplex.client.connection_info.clone();
         println!("New client: {}", &addr);
         let client = Arc::new(RwLock::new(client_duplex.client));
         {
-            let old_client = state
-                .clients
-                .lock()
-                .await
-                .insert(addr.to_string(), Arc::downgrade(&client));
+            let old_client = state.clients.insert(addr, Arc::downgrade(&client));
             debug_assert!(old_client.is_none());
         }
-        match (state.callbacks.on_client_connect)(&addr) {
-            Ok(true) => (),
-            Ok(false) => return Ok(()),
-            Err(err) => return Err(err),
+        let result =
+            Server::serve_client(&state, &client, &mut client_duplex.stream, &connection_info)
+                .await;
+        let reason = match result {
+            Ok(()) => "Connection closed".to_owned(),
+            Err(ref err) => err.to_string(),
         };
-
-        while let Some(msg) = client_duplex.stream.next().await {
-            let msg = msg?;
-            Server::process_message(state.clone(), client.clone(), msg).await?;
+        let client_guard = client.read().await;
+        let notice = match client_guard.status {
+            ClientStatus::Unregistered(_) => None,
+            ClientStatus::Normal(ClientNormalState { ref nick, .. }) => Some(format!(
+                "Client exiting: {} ({}@{}) [{}]",
+                nick,
+                client_guard.get_username().unwrap(),
+                client_guard.get_real_host(),
+                reason
+            )),
+        };
+        drop(client_guard);
+        if let Some(notice) = notice {
+            state.snotice(SnoCategory::Quits, &notice).await;
         }
+        Server::remove_client(&state, &client, &reason).await;
+        result?;
 
         println!("Client {} disconnected", &addr);
         Ok(())
     }
 
-    async fn process_message(
+    /// Runs the client's connection until it closes
+    async fn serve_client(
+        state: &Arc<ServerState>,
+        client: &Arc<RwLock<Client>>,
+        stream: &mut Pin<Box<dyn Stream<Item = Result<Message, Error>> + Send>>,
+        connection_info: &ConnectionInfo,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let addr = client.read().await.addr;
+        if !state
+            .hooks
+            .on_client_connect(&addr, connection_info)
+            .await?
+        {
+            return Ok(());
+        }
+        state.emit(|| ServerEvent::ClientConnected { addr });
+        // Virtual clients have no identd to ask
+        if state.settings.ident_lookup && connection_info.transport != Transport::Virtual {
+            Server::lookup_client_ident(state, client).await?;
+        }
+        Ok(Server::process_messages(state, client, stream).await?)
+    }
+
+    /// Removes a client whose connection closed, and sends a QUIT to the users sharing a channel with it
+    async fn remove_client(state: &ServerState, client_lock: &RwLock<Client>, reason: &str) {
+        let client = client_lock.read().await;
+        state.hooks.on_client_disconnect(&client.addr).await.ok();
+        // Only registered users are in the users map
+        let casemapped_nick = match client.status {
+            ClientStatus::Unregistered(_) => None,
+            ClientStatus::Normal(_) => client.get_casemapped_nick().map(str::to_owned),
+        };
+        state.emit(|| ServerEvent::Disconnected {
+            addr: client.addr,
+            nick: casemapped_nick.as_ref().and_then(|_| client.get_nick()),
+            reason: reason.to_owned(),
+        });
+        if casemapped_nick.is_some() {
+            let quit = Message {
+                tags: Vec::new(),
+                source: Some(client.get_extended_prefix().unwrap()),
+                command: "QUIT".into(),
+                params: smallvec![reason.to_owned()],
+            };
+            client.broadcast(quit, false).await.ok();
+        }
+        client.leave_all_channels().await;
+        let addr = client.addr;
+        drop(client);
+
+        // A nick change can run meanwhile, so the entries of both nicks are removed only if they are ours
+        // An entry it adds afterwards points to a client that is gone, and is swept later
+        if let Some(casemapped_nick) = casemapped_nick {
+            let client = client_lock.read().await;
+            let current_nick = client.get_casemapped_nick().map(str::to_owned);
+            for nick in std::iter::once(casemapped_nick).chain(current_nick) {
+                state
+                    .users
+                    .remove_if(&nick, |user| std::ptr::eq(user.as_ptr(), client_lock));
+            }
+        }
+        state
+            .clients
+            .remove(&addr)
+            .expect("Disconnected client was not in client list!");
+    }
+
+    async fn process_messages(
+        state: &Arc<ServerState>,
+        client: &Arc<RwLock<Client>>,
+        stream: &mut Pin<Box<dyn Stream<Item = Result<Message, Error>> + Send>>,
+    ) -> Result<(), Error> {
+        let (addr, disconnect_signal) = {
+            let client = client.read().await;
+            (client.addr, client.disconnect_signal.clone())
+        };
+        loop {
+            let disconnected = Box::pin(disconnect_signal.wait());
+            let msg = match future::select(stream.next(), disconnected).await {
+                Either::Left((Some(Ok(msg)), _)) => msg,
+                Either::Left((Some(Err(err)), _)) => {
+                    match Server::handle_bad_line(state, client, err).await? {
+                        Some(msg) => msg,
+                        None => continue,
+                    }
+                }
+                Either::Left((None, _)) => return Ok(()),
+                Either::Right((reason, _)) => return Err(Error::other(reason)),
+            };
+            match state.dispatcher {
+                Some(ref dispatcher) => {
+                    dispatcher
+                        .dispatch(state.clone(), client.clone(), addr, msg)
+                        .await?
+                }
+                None => Server::process_message(state.clone(), client.clone(), msg).await?,
+            }
+        }
+    }
+
+    /// Applies ServerSettings::long_lines and invalid_utf8 to a line that was rejected when read,
+    /// returns the message to process if any. Lines that aren't a valid message are skipped,
+    /// and other read errors are returned as is
+    async fn handle_bad_line(
+        state: &ServerState,
+        client: &RwLock<Client>,
+        err: Error,
+    ) -> Result<Option<Message>, Error> {
+        if let Some(parse_err) = err
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<ParseError>())
+        {
+            // Registered clients are told about a bad command like about an unknown one
+            let client = client.read().await;
+            if let (ParseError::InvalidCommand(cmd), Some(nick)) = (parse_err, client.get_nick()) {
+                let reply = ReplyCode::ErrUnknownCommand { cmd: cmd.clone() };
+                client.send(make_reply_msg(state, &nick, reply)).await?;
+            }
+            return Ok(None);
+        }
+
+        if let Some(invalid) = err
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<InvalidUtf8Error>())
+        {
+            if state.settings.invalid_utf8 == InvalidUtf8Policy::Replace {
+                return Ok(Some(invalid.lossy.clone()));
+            }
+            let command = match &*invalid.lossy.command {
+                "" => "*",
+                command => command,
+            };
+            let description =
+                "Message rejected, your IRC software MUST use UTF-8 encoding on this network";
+            let client = client.read().await;
+            client
+                .send_fail(command, "INVALID_UTF8", description)
+                .await?;
+            return Ok(None);
+        }
+
+        let truncated = match err
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<InputTooLongError>())
+        {
+            Some(too_long) => too_long.truncated.clone(),
+            None => return Err(err),
+        };
+        match (state.settings.long_lines, truncated) {
+            (LongLinePolicy::Truncate, Some(truncated)) => Ok(Some(truncated)),
+            (LongLinePolicy::Disconnect, _) => Err(err),
+            _ => {
+                let client = client.read().await;
+                let nick = client.get_nick().unwrap_or_else(|| "*".to_owned());
+                client
+                    .send(make_reply_msg(state, &nick, ReplyCode::ErrInputTooLong))
+                    .await?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Runs before we process the client's first message, so registration waits for the answer
+    async fn lookup_client_ident(
+        state: &ServerState,
+        client: &RwLock<Client>,
+    ) -> Result<(), Error> {
+        let (local_addr, addr) = {
+            let client = client.read().await;
+            client.send_notice("*** Checking Ident").await?;
+            (client.local_addr, client.addr)
+        };
+
+        let ident = lookup_ident(local_addr, addr, state.settings.ident_timeout).await;
+
+        let mut client = client.write().await;
+        let reply = if ident.is_some() {
+            "*** Got Ident response"
+        } else {
+            "*** No Ident response"
+        };
+        client.send_notice(reply).await?;
+        if let ClientStatus::Unregistered(ref mut client_state) = client.status {
+            client_state.ident = ident;
+        }
+        Ok(())
+    }
+
+    async fn reply_not_registered(state: &ServerState, client: &Client) -> Result<(), Error> {
+        let nick = client.get_nick().unwrap_or_else(|| "*".to_owned());
+        client
+            .send(make_reply_msg(state, &nick, ReplyCode::ErrNotRegistered))
+            .await
+    }
+
+    pub(crate) async fn process_message(
         state: Arc<ServerState>,
         client_lock: Arc<RwLock<Client>>,
         msg: Message,
     ) -> Result<(), Error> {
-        if let Some(command) = COMMANDS.get(&msg.command.to_ascii_uppercase() as &str) {
-            if is_command_available(&command, &*client_lock.read().await) {
-                (command.handler)(state.clone(), client_lock.clone(), msg).await?;
+        let action = {
+            let client = client_lock.read().await;
+            state.hooks.on_raw_message(&client, &msg).await
+        };
+        let msg = match action {
+            Ok(RawMessageAction::Pass) => msg,
+            Ok(RawMessageAction::Rewrite(msg)) => msg,
+            Ok(RawMessageAction::Consume) | Err(_) => return Ok(()),
+        };
+
+        let command_name = msg.command.to_ascii_uppercase();
+        if !SHUN_EXEMPT_COMMANDS.contains(&command_name.as_str()) {
+            let prefix = client_lock.read().await.get_extended_prefix();
+            let shuns = state.shuns.read().await;
+            if prefix.is_some_and(|prefix| shuns.is_shunned(&prefix)) {
+                return Ok(());
             }
+        }
+
+        let builtin_command = COMMANDS.get(&command_name as &str);
+        let custom_command = state.custom_commands.get(&command_name);
+        let namespace = match (builtin_command, custom_command) {
+            (Some(command), _) => command.permissions,
+            (None, Some(command)) => command.namespace,
+            (None, None) => return Server::reply_unknown_command(&state, &client_lock, &msg).await,
+        };
+        {
+            let client = client_lock.read().await;
+            if !is_command_available(namespace, &client) {
+                return Server::reply_not_registered(&state, &client).await;
+            }
+        }
+
+        let middlewares: Vec<_> = state
+            .middlewares
+            .iter()
+            .filter(|(command, _)| command.as_ref().is_none_or(|name| *name == command_name))
+            .map(|(_, middleware)| middleware.clone())
+            .collect();
+        let msg_copy = if middlewares.is_empty() {
+            None
         } else {
-            // We need two blocks to end the client nick's borrow before the send. Thanks, borrowck.
+            Some(msg.clone())
+        };
+        for middleware in &middlewares {
             let client = client_lock.read().await;
-            let maybe_nick = match client.status {
-                ClientStatus::Normal(ref client_status) => Some(client_status.nick.clone()),
-                _ => None,
-            };
+            if !middleware.before(&client, &msg).await.unwrap_or(false) {
+                return Ok(());
+            }
+        }
 
-            if let Some(nick) = maybe_nick {
-                client
-                    .send(make_reply_msg(
-                        &state,
-                        &nick,
-                        ReplyCode::ErrUnknownCommand {
-                            cmd: msg.command.clone(),
-                        },
-                    ))
-                    .await?;
+        let start = Instant::now();
+        let handled: CommandFuture = match (builtin_command, custom_command) {
+            (Some(command), _) => (command.handler)(state.clone(), client_lock.clone(), msg),
+            (None, Some(command)) => command.handler.handle(client_lock.clone(), msg),
+            (None, None) => unreachable!(),
+        };
+        let result = handled.await;
+
+        if let Some(msg) = msg_copy {
+            let elapsed = start.elapsed();
+            let client = client_lock.read().await;
+            for middleware in middlewares.iter().rev() {
+                middleware.after(&client, &msg, elapsed, &result).await;
             }
+        }
+        result
+    }
+
+    async fn reply_unknown_command(
+        state: &ServerState,
+        client_lock: &RwLock<Client>,
+        msg: &Message,
+    ) -> Result<(), Error> {
+        // We need two blocks to end the client nick's borrow before the send. Thanks, borrowck.
+        let client = client_lock.read().await;
+        let maybe_nick = match client.status {
+            ClientStatus::Normal(ref client_status) => Some(client_status.nick.clone()),
+            _ => None,
         };
 
+        if let Some(nick) = maybe_nick {
+            client
+                .send(make_reply_msg(
+                    state,
+                    &nick,
+                    ReplyCode::ErrUnknownCommand {
+                        cmd: msg.command.to_string(),
+                    },
+                ))
+                .await?;
+        }
         Ok(())
     }
 }
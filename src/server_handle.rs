@@ -0,0 +1,366 @@
+use crate::channel::ChannelHandle;
+use crate::client::{ClientDuplex, ClientHandle};
+use crate::commands::is_nick_taken;
+use crate::errors::ChannelNotFoundError;
+use crate::extban::BanTarget;
+use crate::formatting::{has_formatting, strip_formatting};
+use crate::message::{check_text, ctcp_command, Message};
+use crate::server::{Server, ServerState};
+use crate::snapshot::{ChannelInfo, ClientInfo};
+use crate::virtual_client::{VirtualClient, VirtualIdentity};
+use smallvec::smallvec;
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::{Arc, Weak};
+
+/// Who a message sent with ServerHandle::send_notice or send_privmsg goes to
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MessageTarget {
+    /// A registered user, by nick
+    User(String),
+    /// A channel, by name
+    Channel(String),
+    /// Every registered user
+    AllUsers,
+}
+
+/// How a message relayed with ServerHandle::relay_to_channel is checked, the default is the strictest
+#[derive(Clone, Debug, Default)]
+pub struct RelayOptions {
+    /// Sends a NOTICE instead of a PRIVMSG
+    pub is_notice: bool,
+    /// Accepts any source that doesn't break the line, instead of only a nick!user@host with a valid nick
+    /// This also allows the nick of a user connected to the server, which other users can't tell apart
+    pub relaxed_source: bool,
+    /// Delivers the message even if the source matches a ban or quiet of the channel
+    pub ignore_bans: bool,
+    /// Delivers the message even if the channel's +C or +c forbid it
+    pub ignore_modes: bool,
+}
+
+/// Whether a message source can be sent as is, it must not break the line it is in
+fn is_valid_source(source: &str) -> bool {
+    !source.is_empty() && !source.starts_with(':') && !source.contains([' ', '\0', '\r', '\n'])
+}
+
+/// A cloneable handle to a server, for the embedder's tasks running next to Server::start
+/// The snapshots it returns are owned, no lock of the server is held once they are returned
+#[derive(Clone)]
+pub struct ServerHandle {
+    state: Arc<ServerState>,
+}
+
+impl ServerHandle {
+    pub(crate) fn new(state: Arc<ServerState>) -> ServerHandle {
+        ServerHandle { state }
+    }
+
+    /// Connects a client that the embedder runs in the same process, like a services bot
+    /// Returns once the client is registered, or with the reason it couldn't register, like a nick in use
+    /// The messages it received until then are not kept
+    pub async fn add_virtual_client(
+        &self,
+        identity: VirtualIdentity,
+    ) -> Result<VirtualClient, Error> {
+        // Unregistered clients don't get a reply when their nick is taken, so it's checked first
+        if is_nick_taken(&self.state, &identity.nick).await {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                "Nickname is already in use",
+            ));
+        }
+        let (duplex, mut client) = ClientDuplex::from_virtual(self.state.clone(), &identity);
+        client.send(Message {
+            tags: Vec::new(),
+            source: None,
+            command: "NICK".into(),
+            params: smallvec![identity.nick],
+        })?;
+        client.send(Message {
+            tags: Vec::new(),
+            source: None,
+            command: "USER".into(),
+            params: smallvec![
+                identity.username,
+                "0".to_owned(),
+                "*".to_owned(),
+                identity.realname
+            ],
+        })?;
+        tokio::spawn(Server::handle_client(self.state.clone(), duplex));
+
+        while let Some(msg) = client.recv().await {
+            let is_error_reply = msg.command.len() == 3 && msg.command.starts_with(['4', '5']);
+            if msg.command == "001" {
+                return Ok(client);
+            } else if is_error_reply || msg.command == "ERROR" {
+                let reason = msg.params.last().cloned().unwrap_or_default();
+                return Err(Error::new(ErrorKind::PermissionDenied, reason));
+            }
+        }
+        Err(Error::new(
+            ErrorKind::ConnectionAborted,
+            "Virtual client disconnected",
+        ))
+    }
+
+    /// Sends a NOTICE, from the server unless a source like "Announcer!bot@services" is given
+    pub async fn send_notice(
+        &self,
+        target: MessageTarget,
+        source: Option<&str>,
+        text: &str,
+    ) -> Result<(), Error> {
+        self.send_text("NOTICE", target, source, text).await
+    }
+
+    /// Sends a PRIVMSG, from the server unless a source like "Announcer!bot@services" is given
+    pub async fn send_privmsg(
+        &self,
+        target: MessageTarget,
+        source: Option<&str>,
+        text: &str,
+    ) -> Result<(), Error> {
+        self.send_text("PRIVMSG", target, source, text).await
+    }
+
+    async fn send_text(
+        &self,
+        command: &'static str,
+        target: MessageTarget,
+        source: Option<&str>,
+        text: &str,
+    ) -> Result<(), Error> {
+        let source = source.unwrap_or(&self.state.settings.server_name);
+        check_text(text)?;
+        if !is_valid_source(source) {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid source"));
+        }
+        let make_msg = |target: String| Message {
+            tags: Vec::new(),
+            source: Some(source.to_owned()),
+            command: command.into(),
+            params: smallvec![target, text.to_owned()],
+        };
+
+        match target {
+            MessageTarget::User(nick) => {
+                let user = self.state.find_user(&nick);
+                let user = user.ok_or_else(|| Error::new(ErrorKind::NotFound, "No such nick"))?;
+                let user = user.read().await;
+                user.send(make_msg(user.get_nick().unwrap())).await
+            }
+            MessageTarget::Channel(name) => {
+                let channel = self.state.channels.get(&self.state.casemap(&name));
+                let channel = channel.ok_or_else(|| {
+                    Error::new(ErrorKind::NotFound, ChannelNotFoundError::new(name))
+                })?;
+                let channel = channel.read().await;
+                channel.send(make_msg(channel.name.clone()), None).await
+            }
+            MessageTarget::AllUsers => {
+                let users: Vec<_> = self
+                    .state
+                    .users
+                    .values()
+                    .iter()
+                    .filter_map(Weak::upgrade)
+                    .collect();
+                for user in users {
+                    let user = user.read().await;
+                    user.send(make_msg(user.get_nick().unwrap())).await?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Sends a message to a channel from a user of another network, like a bridge relaying a Matrix user
+    /// The source is a prefix like `alice[m]!alice@matrix.example.org`, the user doesn't need to exist on the server
+    /// A message refused by the channel's bans or modes returns a PermissionDenied error with the reason
+    /// +n doesn't apply, since the message is sent by the server itself
+    pub async fn relay_to_channel(
+        &self,
+        channel: &str,
+        source: &str,
+        text: &str,
+        options: &RelayOptions,
+    ) -> Result<(), Error> {
+        let valid_source = if options.relaxed_source {
+            is_valid_source(source)
+        } else {
+            self.is_valid_relay_prefix(source).await
+        };
+        check_text(text)?;
+        if !valid_source {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid source"));
+        }
+
+        let channel_lock = self.state.channels.get(&self.state.casemap(channel));
+        let channel_lock = channel_lock.ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                ChannelNotFoundError::new(channel.to_owned()),
+            )
+        })?;
+        let channel = channel_lock.read().await;
+        let refuse = |mode: char| {
+            let reason = format!("Cannot send to channel (+{} is set)", mode);
+            Err(Error::new(ErrorKind::PermissionDenied, reason))
+        };
+
+        if !options.ignore_bans {
+            let ban_target = BanTarget {
+                prefix: source.to_owned(),
+                account: None,
+                channels: Vec::new(),
+                casemapping: self.state.settings.casemapping,
+            };
+            let extbans = &self.state.settings.extbans;
+            if channel.mode.is_banned(extbans, &ban_target) {
+                return refuse('b');
+            }
+            if channel.mode.is_quieted(extbans, &ban_target) {
+                return refuse('q');
+            }
+        }
+
+        let mut text = text.to_owned();
+        if !options.ignore_modes {
+            let is_ctcp_request = ctcp_command(&text).is_some_and(|command| command != "ACTION");
+            if channel.mode.no_ctcp && is_ctcp_request {
+                return refuse('C');
+            }
+            if channel.mode.no_colors && has_formatting(&text) {
+                if self.state.settings.reject_formatting {
+                    return refuse('c');
+                }
+                text = strip_formatting(&text);
+            }
+        }
+
+        let command = if options.is_notice {
+            "NOTICE"
+        } else {
+            "PRIVMSG"
+        };
+        let msg = Message {
+            tags: Vec::new(),
+            source: Some(source.to_owned()),
+            command: command.into(),
+            params: smallvec![channel.name.clone(), text],
+        };
+        channel.send(msg, None).await
+    }
+
+    /// Whether a relayed message's source is a nick!user@host that can't be mistaken for a local user
+    async fn is_valid_relay_prefix(&self, source: &str) -> bool {
+        let (nick, user_host) = match source.split_once('!') {
+            Some(parts) => parts,
+            None => return false,
+        };
+        let (user, host) = match user_host.split_once('@') {
+            Some(parts) => parts,
+            None => return false,
+        };
+        let max_len = self.state.runtime_settings().max_name_length;
+        let valid_nick = self.state.settings.nick_policy.validate(max_len, nick);
+        valid_nick.as_deref() == Some(nick)
+            && !user.is_empty()
+            && !host.is_empty()
+            && !user_host.contains(['!', ' ', '\0', '\r', '\n'])
+            && !host.contains('@')
+            && !is_nick_taken(&self.state, nick).await
+    }
+
+    /// Every connected client, including those that haven't completed registration
+    pub async fn clients(&self) -> Vec<ClientInfo> {
+        let clients: Vec<_> = self
+            .state
+            .clients
+            .values()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .collect();
+        let mut infos = Vec::with_capacity(clients.len());
+        for client in clients {
+            infos.push(ClientInfo::new(&*client.read().await).await);
+        }
+        infos
+    }
+
+    /// The clients that completed registration
+    pub async fn users(&self) -> Vec<ClientInfo> {
+        let users: Vec<_> = self
+            .state
+            .users
+            .values()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .collect();
+        let mut infos = Vec::with_capacity(users.len());
+        for user in users {
+            infos.push(ClientInfo::new(&*user.read().await).await);
+        }
+        infos
+    }
+
+    /// A connected client by its address
+    pub async fn client(&self, addr: SocketAddr) -> Option<ClientInfo> {
+        let client = self.state.clients.get(&addr)?.upgrade()?;
+        let client = client.read().await;
+        Some(ClientInfo::new(&client).await)
+    }
+
+    /// A registered user by nick
+    pub async fn user(&self, nick: &str) -> Option<ClientInfo> {
+        let user = self.state.find_user(nick)?;
+        let user = user.read().await;
+        Some(ClientInfo::new(&user).await)
+    }
+
+    /// A handle to act on a registered user, found by nick
+    pub async fn find_user(&self, nick: &str) -> Option<ClientHandle> {
+        let user = self.state.find_user(nick)?;
+        let handle = user.read().await.handle();
+        Some(handle)
+    }
+
+    /// Closes the connection of a user found by nick, or of any client found by address like "1.2.3.4:5678"
+    /// The client gets an ERROR with the reason, then its task sends the QUIT and removes it like any other quit
+    pub async fn disconnect(&self, nick_or_addr: &str, reason: &str) -> Result<(), Error> {
+        let client = match nick_or_addr.parse::<SocketAddr>() {
+            Ok(addr) => self
+                .state
+                .clients
+                .get(&addr)
+                .and_then(|weak| weak.upgrade()),
+            Err(_) => self.state.find_user(nick_or_addr),
+        };
+        let client = client.ok_or_else(|| Error::new(ErrorKind::NotFound, "No such client"))?;
+        let client = client.read().await;
+        client.disconnect(reason).await
+    }
+
+    pub async fn channels(&self) -> Vec<ChannelInfo> {
+        let channels = self.state.channels.values();
+        let mut infos = Vec::with_capacity(channels.len());
+        for channel in channels {
+            infos.push(ChannelInfo::new(&*channel.read().await).await);
+        }
+        infos
+    }
+
+    pub async fn channel(&self, name: &str) -> Option<ChannelInfo> {
+        let channel = self.state.channels.get(&self.state.casemap(name))?;
+        let channel = channel.read().await;
+        Some(ChannelInfo::new(&channel).await)
+    }
+
+    /// A handle to act on a channel, found by name
+    pub async fn find_channel(&self, name: &str) -> Option<ChannelHandle> {
+        let channel = self.state.channels.get(&self.state.casemap(name))?;
+        let name = channel.read().await.name.clone();
+        Some(ChannelHandle::new(self.state.clone(), &channel, name))
+    }
+}
@@ -0,0 +1,78 @@
+use crate::client::Client;
+use crate::message::Message;
+use crate::server::ServerState;
+use smallvec::smallvec;
+use std::io::Error;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+mod chanserv;
+mod nickserv;
+
+pub use self::chanserv::{is_topic_locked_for, registered_access};
+pub use self::nickserv::enforce_nick_registration;
+
+/// The built-in services, pseudo-users that registered users can message
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Service {
+    NickServ,
+    ChanServ,
+}
+
+impl Service {
+    pub const ALL: &'static [Service] = &[Service::NickServ, Service::ChanServ];
+
+    pub fn nick(self) -> &'static str {
+        match self {
+            Service::NickServ => "NickServ",
+            Service::ChanServ => "ChanServ",
+        }
+    }
+
+    /// The prefix of messages sent by the service
+    pub fn prefix(self, state: &ServerState) -> String {
+        format!("{0}!{0}@{1}", self.nick(), state.settings.server_name)
+    }
+}
+
+/// Returns the service using this nick, if services are enabled
+pub fn find_service(state: &ServerState, nick: &str) -> Option<Service> {
+    if !state.settings.services {
+        return None;
+    }
+    let nick = state.casemap(nick);
+    Service::ALL
+        .iter()
+        .copied()
+        .find(|service| state.casemap(service.nick()) == nick)
+}
+
+/// Handles a PRIVMSG sent to a service
+pub async fn handle_service_message(
+    state: &Arc<ServerState>,
+    client: &Arc<RwLock<Client>>,
+    service: Service,
+    text: &str,
+) -> Result<(), Error> {
+    match service {
+        Service::NickServ => nickserv::handle_nickserv(state, client, text).await,
+        Service::ChanServ => chanserv::handle_chanserv(state, client, text).await,
+    }
+}
+
+/// Sends a NOTICE from a service to a user
+async fn service_reply(
+    state: &ServerState,
+    client: &Client,
+    service: Service,
+    text: &str,
+) -> Result<(), Error> {
+    client
+        .send(Message {
+            tags: Vec::new(),
+            source: Some(service.prefix(state)),
+            command: "NOTICE".into(),
+            params: smallvec![client.get_nick().unwrap(), text.to_owned()],
+        })
+        .await
+}
@@ -0,0 +1,266 @@
+use crate::channel_registration::{ChannelAccess, ChannelRegistration};
+use crate::client::Client;
+use crate::mode::operator_rank;
+use crate::server::ServerState;
+use crate::services::{service_reply, Service};
+use std::io::Error;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+const HELP: &[&str] = &[
+    "ChanServ lets you register channels you operate, so you keep them.",
+    "REGISTER <#channel>  Registers a channel to your account",
+    "DROP <#channel>  Unregisters a channel",
+    "ACCESS <#channel> LIST  Lists the accounts that get a rank on join",
+    "ACCESS <#channel> ADD <account> <OP|VOICE>  Gives a rank to an account",
+    "ACCESS <#channel> DEL <account>  Removes an account from the access list",
+    "TOPICLOCK <#channel> <ON|OFF>  Only lets ops in the access list change the topic",
+    "INFO <#channel>  Shows information about a registered channel",
+];
+
+pub async fn handle_chanserv(
+    state: &Arc<ServerState>,
+    client: &Arc<RwLock<Client>>,
+    text: &str,
+) -> Result<(), Error> {
+    let client = client.read().await;
+    let mut args = text.split_whitespace();
+    let command = args.next().unwrap_or_default().to_ascii_uppercase();
+    let args: Vec<_> = args.collect();
+    match command.as_str() {
+        "REGISTER" => register(state, &client, &args).await,
+        "DROP" => drop_channel(state, &client, &args).await,
+        "ACCESS" => access(state, &client, &args).await,
+        "TOPICLOCK" => topic_lock(state, &client, &args).await,
+        "INFO" => info(state, &client, &args).await,
+        "HELP" | "" => {
+            for line in HELP {
+                reply(state, &client, line).await?;
+            }
+            Ok(())
+        }
+        _ => {
+            let text = format!("Unknown command {}, try HELP", command);
+            reply(state, &client, &text).await
+        }
+    }
+}
+
+async fn reply(state: &ServerState, client: &Client, text: &str) -> Result<(), Error> {
+    service_reply(state, client, Service::ChanServ, text).await
+}
+
+/// Whether the client is a channel operator of a channel
+async fn is_channel_operator(state: &ServerState, client: &Client, chan_name: &str) -> bool {
+    let channel = match state.channels.get(&state.casemap(chan_name)) {
+        Some(channel) => channel,
+        None => return false,
+    };
+    let channel = channel.read().await;
+    let member_mode = channel.get_member_mode(client.addr).await;
+    member_mode.is_some_and(|mode| mode.is_at_least(operator_rank(&state.settings.member_ranks)))
+}
+
+/// Whether the client is the founder of a registered channel, or an operator
+fn can_manage(state: &ServerState, client: &Client, registration: &ChannelRegistration) -> bool {
+    let is_founder = client
+        .account
+        .as_deref()
+        .is_some_and(|account| registration.is_founder(state.settings.casemapping, account));
+    is_founder || client.mode.is_oper
+}
+
+async fn register(state: &ServerState, client: &Client, args: &[&str]) -> Result<(), Error> {
+    let chan_name = match args {
+        [chan_name] => chan_name,
+        _ => return reply(state, client, "Syntax: REGISTER <#channel>").await,
+    };
+    let account = match client.account {
+        Some(ref account) => account,
+        None => return reply(state, client, "You must be logged in to register a channel").await,
+    };
+    if !is_channel_operator(state, client, chan_name).await {
+        let text = format!("You must be a channel operator of {}", chan_name);
+        return reply(state, client, &text).await;
+    }
+
+    let channel = state.channels.get(&state.casemap(chan_name));
+    let mut registration = ChannelRegistration::new(chan_name, account);
+    if let Some(channel) = channel {
+        let mut channel = channel.write().await;
+        registration.name = channel.name.clone();
+        registration.topic = channel.topic.as_ref().map(|topic| topic.text.clone());
+        // Registered channels stay open when empty, so that they keep their topic and modes
+        channel.mode.permanent = true;
+    }
+    let text = format!("{} is now registered to {}", registration.name, account);
+    if !state.register_channel(registration).await {
+        let text = format!("{} is already registered", chan_name);
+        return reply(state, client, &text).await;
+    }
+    reply(state, client, &text).await
+}
+
+async fn drop_channel(state: &ServerState, client: &Client, args: &[&str]) -> Result<(), Error> {
+    let chan_name = match args {
+        [chan_name] => chan_name,
+        _ => return reply(state, client, "Syntax: DROP <#channel>").await,
+    };
+    let mut registrations = state.channel_registrations.write().await;
+    let key = state.casemap(chan_name);
+    match registrations.get(&key) {
+        Some(registration) if can_manage(state, client, registration) => (),
+        Some(_) => return reply(state, client, "Only the founder can drop the channel").await,
+        None => {
+            let text = format!("{} is not registered", chan_name);
+            return reply(state, client, &text).await;
+        }
+    }
+    registrations.remove(&key);
+    drop(registrations);
+
+    if let Some(channel) = state.channels.get(&key) {
+        channel.write().await.mode.permanent = false;
+    }
+    let text = format!("{} is no longer registered", chan_name);
+    reply(state, client, &text).await
+}
+
+async fn access(state: &ServerState, client: &Client, args: &[&str]) -> Result<(), Error> {
+    let (chan_name, command, args) = match args {
+        [chan_name, command, args @ ..] => (chan_name, command.to_ascii_uppercase(), args),
+        _ => return reply(state, client, "Syntax: ACCESS <#channel> <LIST|ADD|DEL>").await,
+    };
+    let casemapping = state.settings.casemapping;
+    let mut registrations = state.channel_registrations.write().await;
+    let registration = match registrations.get_mut(&state.casemap(chan_name)) {
+        Some(registration) => registration,
+        None => {
+            let text = format!("{} is not registered", chan_name);
+            return reply(state, client, &text).await;
+        }
+    };
+
+    if command == "LIST" {
+        let mut lines = vec![format!("{} FOUNDER", registration.founder)];
+        for (account, access) in &registration.access {
+            lines.push(format!("{} {}", account, access.name()));
+        }
+        drop(registrations);
+        for line in lines {
+            reply(state, client, &line).await?;
+        }
+        return Ok(());
+    }
+    if !can_manage(state, client, registration) {
+        return reply(state, client, "Only the founder can change the access list").await;
+    }
+    let text = match (command.as_str(), args) {
+        ("ADD", [account, access]) => match ChannelAccess::from_name(access) {
+            Some(access) => {
+                registration.set_access(casemapping, account, access);
+                format!(
+                    "{} now has {} access to {}",
+                    account,
+                    access.name(),
+                    chan_name
+                )
+            }
+            None => "Access must be OP or VOICE".to_owned(),
+        },
+        ("DEL", [account]) => {
+            if registration.remove_access(casemapping, account) {
+                format!(
+                    "{} was removed from the access list of {}",
+                    account, chan_name
+                )
+            } else {
+                format!("{} is not on the access list of {}", account, chan_name)
+            }
+        }
+        _ => "Syntax: ACCESS <#channel> <ADD <account> <OP|VOICE>|DEL <account>>".to_owned(),
+    };
+    drop(registrations);
+    reply(state, client, &text).await
+}
+
+async fn topic_lock(state: &ServerState, client: &Client, args: &[&str]) -> Result<(), Error> {
+    let (chan_name, enable) = match args {
+        [chan_name, value] if value.eq_ignore_ascii_case("ON") => (chan_name, true),
+        [chan_name, value] if value.eq_ignore_ascii_case("OFF") => (chan_name, false),
+        _ => return reply(state, client, "Syntax: TOPICLOCK <#channel> <ON|OFF>").await,
+    };
+    let mut registrations = state.channel_registrations.write().await;
+    let text = match registrations.get_mut(&state.casemap(chan_name)) {
+        Some(registration) if can_manage(state, client, registration) => {
+            registration.topic_lock = enable;
+            let value = if enable { "on" } else { "off" };
+            format!("Topic lock is now {} for {}", value, chan_name)
+        }
+        Some(_) => "Only the founder can change the topic lock".to_owned(),
+        None => format!("{} is not registered", chan_name),
+    };
+    drop(registrations);
+    reply(state, client, &text).await
+}
+
+async fn info(state: &ServerState, client: &Client, args: &[&str]) -> Result<(), Error> {
+    let chan_name = match args {
+        [chan_name] => chan_name,
+        _ => return reply(state, client, "Syntax: INFO <#channel>").await,
+    };
+    let registration = state
+        .channel_registrations
+        .read()
+        .await
+        .get(&state.casemap(chan_name))
+        .cloned();
+    let registration = match registration {
+        Some(registration) => registration,
+        None => {
+            let text = format!("{} is not registered", chan_name);
+            return reply(state, client, &text).await;
+        }
+    };
+    let topic_lock = if registration.topic_lock { "on" } else { "off" };
+    let lines = [
+        format!("Information on {}:", registration.name),
+        format!("Founder: {}", registration.founder),
+        format!(
+            "Registered: {}",
+            registration.registered.format("%Y-%m-%d %H:%M:%S")
+        ),
+        format!("Topic lock: {}", topic_lock),
+    ];
+    for line in &lines {
+        reply(state, client, line).await?;
+    }
+    Ok(())
+}
+
+/// Returns the access a client has to a registered channel, based on the account they're logged in to
+pub async fn registered_access(
+    state: &ServerState,
+    client: &Client,
+    chan_name: &str,
+) -> Option<ChannelAccess> {
+    let account = client.account.as_deref()?;
+    let registrations = state.channel_registrations.read().await;
+    let registration = registrations.get(&state.casemap(chan_name))?;
+    registration.access_of(state.settings.casemapping, account)
+}
+
+/// Whether the topic lock of a registered channel prevents this client from changing the topic
+pub async fn is_topic_locked_for(state: &ServerState, client: &Client, chan_name: &str) -> bool {
+    let registrations = state.channel_registrations.read().await;
+    match registrations.get(&state.casemap(chan_name)) {
+        Some(registration) if registration.topic_lock => {
+            let access = client
+                .account
+                .as_deref()
+                .and_then(|account| registration.access_of(state.settings.casemapping, account));
+            access != Some(ChannelAccess::Op)
+        }
+        _ => false,
+    }
+}
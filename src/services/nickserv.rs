@@ -0,0 +1,265 @@
+use crate::client::Client;
+use crate::commands::{force_nick_change, is_nick_taken, log_in};
+use crate::server::ServerState;
+use crate::services::{service_reply, Service};
+use crate::snomask::SnoCategory;
+use chrono::Local;
+use rand_core::{OsRng, RngCore};
+use std::io::Error;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+const HELP: &[&str] = &[
+    "NickServ lets you register your nick, so nobody else can use it.",
+    "REGISTER <password> [email]  Registers your current nick",
+    "IDENTIFY [account] <password>  Logs you in to an account",
+    "LOGOUT  Logs you out of your account",
+    "INFO [account]  Shows information about an account",
+    "SET EMAIL <email>  Changes the email of your account",
+];
+
+pub async fn handle_nickserv(
+    state: &Arc<ServerState>,
+    client: &Arc<RwLock<Client>>,
+    text: &str,
+) -> Result<(), Error> {
+    let mut args = text.split_whitespace();
+    let command = args.next().unwrap_or_default().to_ascii_uppercase();
+    let args: Vec<_> = args.collect();
+    match command.as_str() {
+        "REGISTER" => register(state, client, &args).await,
+        "IDENTIFY" => identify(state, client, &args).await,
+        "LOGOUT" => logout(state, client).await,
+        "INFO" => info(state, client, &args).await,
+        "SET" => set(state, client, &args).await,
+        "HELP" | "" => {
+            let client = client.read().await;
+            for line in HELP {
+                reply(state, &client, line).await?;
+            }
+            Ok(())
+        }
+        _ => {
+            let client = client.read().await;
+            let text = format!("Unknown command {}, try HELP", command);
+            reply(state, &client, &text).await
+        }
+    }
+}
+
+async fn reply(state: &ServerState, client: &Client, text: &str) -> Result<(), Error> {
+    service_reply(state, client, Service::NickServ, text).await
+}
+
+/// Tells the user their request failed because the account provider returned an error
+async fn reply_provider_error(
+    state: &ServerState,
+    client: &Client,
+    err: Box<dyn std::error::Error + Send + Sync>,
+) -> Result<(), Error> {
+    let notice = format!("Account provider error: {}", err);
+    state.snotice(SnoCategory::OperActions, &notice).await;
+    reply(
+        state,
+        client,
+        "Accounts are unavailable right now, try again later",
+    )
+    .await
+}
+
+async fn register(
+    state: &ServerState,
+    client_lock: &RwLock<Client>,
+    args: &[&str],
+) -> Result<(), Error> {
+    let client = client_lock.read().await;
+    let (password, email) = match args {
+        [password] => (password, None),
+        [password, email] if email.contains('@') => (password, Some(*email)),
+        _ => return reply(state, &client, "Syntax: REGISTER <password> [email]").await,
+    };
+    if client.account.is_some() {
+        return reply(state, &client, "You are already logged in").await;
+    }
+    let nick = client.get_nick().unwrap();
+    drop(client);
+
+    let provider = &state.account_provider;
+    let result = provider.create_account(&nick, password, email).await;
+    let mut client = client_lock.write().await;
+    let account = match result {
+        Ok(Some(account)) => account,
+        Ok(None) => {
+            let text = format!("{} is already registered", nick);
+            return reply(state, &client, &text).await;
+        }
+        Err(err) => return reply_provider_error(state, &client, err).await,
+    };
+    client.account = Some(account.name);
+    let text = format!("{} is now registered, and you are logged in", nick);
+    reply(state, &client, &text).await
+}
+
+async fn identify(
+    state: &ServerState,
+    client_lock: &RwLock<Client>,
+    args: &[&str],
+) -> Result<(), Error> {
+    let client = client_lock.read().await;
+    let (name, password) = match args {
+        [password] => (client.get_nick().unwrap(), password),
+        [name, password] => (name.to_string(), password),
+        _ => return reply(state, &client, "Syntax: IDENTIFY [account] <password>").await,
+    };
+    drop(client);
+
+    let result = log_in(state, client_lock, &name, password).await;
+    let client = client_lock.read().await;
+    match result {
+        Ok(Some(account)) => {
+            let text = format!("You are now logged in as {}", account.name);
+            reply(state, &client, &text).await
+        }
+        Ok(None) => reply(state, &client, "Invalid account or password").await,
+        Err(err) => reply_provider_error(state, &client, err).await,
+    }
+}
+
+async fn logout(state: &ServerState, client_lock: &RwLock<Client>) -> Result<(), Error> {
+    let mut client = client_lock.write().await;
+    let name = match client.account.take() {
+        Some(name) => name,
+        None => return reply(state, &client, "You are not logged in").await,
+    };
+    let provider = &state.account_provider;
+    if let Ok(Some(mut account)) = provider.get_account(&name).await {
+        account.last_seen = Local::now();
+        provider.update_account(&account).await.ok();
+    }
+    reply(state, &client, "You are now logged out").await
+}
+
+async fn info(state: &ServerState, client: &RwLock<Client>, args: &[&str]) -> Result<(), Error> {
+    let client = client.read().await;
+    let name = match args.first() {
+        Some(name) => name.to_string(),
+        None => client.get_nick().unwrap(),
+    };
+    let account = match state.account_provider.get_account(&name).await {
+        Ok(Some(account)) => account,
+        Ok(None) => {
+            let text = format!("{} is not registered", name);
+            return reply(state, &client, &text).await;
+        }
+        Err(err) => return reply_provider_error(state, &client, err).await,
+    };
+
+    let time_format = "%Y-%m-%d %H:%M:%S";
+    let lines = [
+        format!("Information on {}:", account.name),
+        format!("Registered: {}", account.registered.format(time_format)),
+        format!("Last seen: {}", account.last_seen.format(time_format)),
+    ];
+    for line in &lines {
+        reply(state, &client, line).await?;
+    }
+    // Emails are private, only the owner and operators can see them
+    let is_owner = client.account.as_deref() == Some(&account.name);
+    if let Some(email) = account.email.filter(|_| is_owner || client.mode.is_oper) {
+        reply(state, &client, &format!("Email: {}", email)).await?;
+    }
+    Ok(())
+}
+
+async fn set(state: &ServerState, client: &RwLock<Client>, args: &[&str]) -> Result<(), Error> {
+    let client = client.read().await;
+    let email = match args {
+        [setting, email] if setting.eq_ignore_ascii_case("EMAIL") && email.contains('@') => email,
+        _ => return reply(state, &client, "Syntax: SET EMAIL <email>").await,
+    };
+    let name = match client.account {
+        Some(ref name) => name,
+        None => return reply(state, &client, "You are not logged in").await,
+    };
+    let provider = &state.account_provider;
+    let result = match provider.get_account(name).await {
+        Ok(Some(mut account)) => {
+            account.email = Some(email.to_string());
+            provider.update_account(&account).await
+        }
+        Ok(None) => Ok(()),
+        Err(err) => Err(err),
+    };
+    match result {
+        Ok(()) => reply(state, &client, "Your email was changed").await,
+        Err(err) => reply_provider_error(state, &client, err).await,
+    }
+}
+
+/// Whether the client is logged in to the account registered for their current nick
+fn is_identified_for_nick(state: &ServerState, client: &Client) -> bool {
+    match (&client.account, client.get_nick()) {
+        (Some(account), Some(nick)) => state.casemap(account) == state.casemap(&nick),
+        _ => false,
+    }
+}
+
+/// Warns a user that took a registered nick without logging in to its account
+/// If they don't identify in time, they are renamed to a guest nick
+pub async fn enforce_nick_registration(
+    state: &Arc<ServerState>,
+    client_lock: &Arc<RwLock<Client>>,
+) -> Result<(), Error> {
+    if !state.settings.services {
+        return Ok(());
+    }
+    let client = client_lock.read().await;
+    let nick = client.get_nick().unwrap();
+    // If accounts are unavailable, nobody can identify, so it's better not to enforce anything
+    let account = state.account_provider.get_account(&nick).await;
+    let is_registered = matches!(account, Ok(Some(_)));
+    if !is_registered || is_identified_for_nick(state, &client) {
+        return Ok(());
+    }
+    let delay = state.settings.nick_enforce_delay;
+    let text = format!(
+        "This nick is registered. If it's yours, use IDENTIFY <password> within {} seconds, \
+         or your nick will be changed",
+        delay.as_secs()
+    );
+    reply(state, &client, &text).await?;
+    drop(client);
+
+    let state = state.clone();
+    let client_weak = Arc::downgrade(client_lock);
+    tokio::spawn(async move {
+        tokio::time::sleep(delay).await;
+        let client_lock = match client_weak.upgrade() {
+            Some(client_lock) => client_lock,
+            None => return,
+        };
+        let client = client_lock.read().await;
+        let nick_unchanged = client.get_casemapped_nick() == Some(&state.casemap(&nick));
+        if !nick_unchanged || is_identified_for_nick(&state, &client) {
+            return;
+        }
+        drop(client);
+        if let Some(guest_nick) = find_guest_nick(&state).await {
+            force_nick_change(&state, &client_lock, &guest_nick)
+                .await
+                .ok();
+        }
+    });
+    Ok(())
+}
+
+/// Picks a free nick like "Guest12345"
+async fn find_guest_nick(state: &ServerState) -> Option<String> {
+    for _ in 0..10 {
+        let nick = format!("Guest{:05}", OsRng.next_u32() % 100_000);
+        if !is_nick_taken(state, &nick).await {
+            return Some(nick);
+        }
+    }
+    None
+}
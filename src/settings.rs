@@ -1,32 +1,420 @@
+#[cfg(feature = "admin-api")]
+use crate::admin::AdminApiSettings;
+use crate::casemapping::Casemapping;
+use crate::channel_registration::ChannelRegistration;
+use crate::errors::SettingsError;
+use crate::extban::{ExtbanType, DEFAULT_EXTBANS};
+use crate::message::{InvalidUtf8Policy, LongLinePolicy, MAX_LENGTH};
+use crate::mode::{MemberMode, MemberRank, CHANNEL_MODES, DEFAULT_MEMBER_RANKS};
+use crate::motd::Motd;
+use crate::nick_policy::NickPolicy;
+use crate::oper::OperBlock;
+use crate::socket_options::TcpKeepalive;
+use crate::password::is_valid_password_hash;
+#[cfg(feature = "tls")]
+use crate::tls::ClientCertMode;
+use crate::webirc::WebircGateway;
 use std::net::SocketAddr;
+use std::time::Duration;
 
+/// Fields marked "runtime" can be changed while the server runs, see Server::update_settings
+/// The values here are the initial ones
 #[derive(Clone, Debug)]
 pub struct ServerSettings {
-    /// Network address/port to listen on
-    pub listen_addr: SocketAddr,
+    /// Network addresses/ports to listen on, like `0.0.0.0:6667` and `[::]:6667` for both IPv4 and IPv6
+    pub listen_addrs: Vec<SocketAddr>,
     /// Advertised network name for this server
     pub network_name: String,
     /// Name the server will use to identify itself
     pub server_name: String,
     /// Description of this server
     pub server_info: String,
-    /// Maximum length of nicknames and usernames
+    /// Maximum length of nicknames and usernames (runtime)
     /// Note that the madatory leading "~" in usernames counts towards this limit
     pub max_name_length: usize,
-    /// Maximum length of a channel name
+    /// Maximum length of a channel name (runtime)
     pub max_channel_length: usize,
-    /// Maximum length of a channel topic
+    /// Maximum length of a channel topic (runtime)
     pub max_topic_length: usize,
-    /// Maximum number of #channels a client may join
+    /// Maximum number of #channels a client may join (runtime)
     pub chan_limit: usize,
-    /// Whether regular users can create channels
+    /// How nicks and channel names are compared
+    pub casemapping: Casemapping,
+    /// Which nicknames are allowed
+    pub nick_policy: NickPolicy,
+    /// What happens to lines from clients longer than 512 bytes, not counting their tags
+    pub long_lines: LongLinePolicy,
+    /// Advertises the UTF8ONLY ISUPPORT token, which tells clients to only send UTF-8
+    pub utf8_only: bool,
+    /// Extra ISUPPORT tokens to advertise, like "WHOX" or "MONITOR=100"
+    /// A token named like one of the server's replaces it, and "-NAME" removes the server's NAME token
+    pub isupport_tokens: Vec<String>,
+    /// What happens to lines from clients that are not valid UTF-8
+    pub invalid_utf8: InvalidUtf8Policy,
+    /// How many times a single IP can connect within throttle_window, 0 disables throttling (runtime)
+    /// Further connections are refused until the IP slows down
+    pub throttle_max_connections: usize,
+    /// Time window used for throttle_max_connections (runtime)
+    pub throttle_window: Duration,
+    /// Whether to query the client's ident server (RFC 1413) before completing registration
+    /// Clients whose identd answers get its username, others get their USER username prefixed with ~
+    pub ident_lookup: bool,
+    /// How long to wait for the ident server to answer before giving up
+    pub ident_timeout: Duration,
+    /// Whether regular users can create channels (runtime)
     pub allow_channel_creation: bool,
+    /// Channel membership ranks from highest to lowest, must include channel operators (+o)
+    /// Ranks at or above +o manage the channel, halfops (+h) can moderate it
+    /// The user who creates a channel is given the highest rank
+    pub member_ranks: Vec<MemberRank>,
+    /// Types of extended bans, like "~a:account", that can be put in ban and exception lists
+    /// Embedders can add their own types here. The ~q mute modifier is always available
+    pub extbans: Vec<ExtbanType>,
+    /// Whether messages with colors or formatting are rejected from +c channels
+    /// By default their formatting codes are stripped, and the message goes through
+    pub reject_formatting: bool,
+    /// Message of the day, if any (runtime)
+    pub motd: Option<Motd>,
+    /// Credentials that users can give to the OPER command to become IRC operators
+    pub opers: Vec<OperBlock>,
+    /// Glob masks of nicks nobody can use (Q-lines), like "NickServ" or "admin*"
+    /// Operators can change the list at runtime with QLINE and UNQLINE
+    pub forbidden_nicks: Vec<String>,
+    /// Glob masks of channels that can't be created or joined, like "#warez*"
+    /// Operators can change the list at runtime with CBAN and UNCBAN
+    pub forbidden_channels: Vec<String>,
+    /// How many operator actions are kept in the audit log, see STATS A
+    pub audit_log_size: usize,
+    /// Enables the built-in NickServ, which lets users register their nick
+    /// External services packages like Atheme or Anope can't be linked instead, there are no server links.
+    /// Their accounts can be used with an AccountProvider, and bots can be added as virtual clients
+    pub services: bool,
+    /// How long users of a registered nick have to identify before being renamed
+    pub nick_enforce_delay: Duration,
+    /// Channels registered with ChanServ, which are re-created when the server starts
+    /// The current registrations can be saved with Server::registered_channels
+    pub registered_channels: Vec<ChannelRegistration>,
+    /// Web gateways allowed to pass on the real address of their users with WEBIRC
+    pub webirc_gateways: Vec<WebircGateway>,
+    /// Whether client sockets send small writes right away (TCP_NODELAY), instead of waiting to fill packets
+    pub tcp_nodelay: bool,
+    /// Enables TCP keepalive on client sockets, to drop dead connections the PINGs haven't caught yet
+    pub tcp_keepalive: Option<TcpKeepalive>,
+    /// How many connections can wait to be accepted by each listener
+    pub listen_backlog: u32,
+    /// Maximum bytes waiting to be sent to a client, clients that don't read fast enough are disconnected
+    /// Channel messages are not counted, clients are also disconnected if they fall too far behind a channel
+    pub sendq_limit: usize,
+    /// Clients whose socket doesn't accept any of their queued messages for this long are disconnected
+    pub write_timeout: Duration,
+    /// How often the entries of clients and channel members that are gone are swept from the server's maps
+    /// Lookups also remove the dead entries they come across. It must not be zero
+    pub sweep_interval: Duration,
+    /// Processes the clients' messages on this many worker tasks, clients are split between them by address
+    /// For very large servers, so a burst of messages from some clients can't hold up everyone's commands
+    /// With 0, each client's messages are processed on its own task
+    pub dispatch_workers: usize,
+    /// How many messages can wait for each dispatch worker, more clients wait and stop being read meanwhile
+    pub dispatch_queue_size: usize,
+    /// Serves an HTTP admin API on a separate address, to manage the server without an IRC client
+    #[cfg(feature = "admin-api")]
+    pub admin_api: Option<AdminApiSettings>,
+    /// Whether TLS clients are asked for a certificate, and which ones are accepted
+    /// This applies to Server::use_tls_with_sni, a custom TLS configuration has its own verifier
+    #[cfg(feature = "tls")]
+    pub client_certs: ClientCertMode,
+}
+
+/// Length limits must leave room for the rest of the messages they appear in
+fn check_length_limit(setting: &'static str, value: usize) -> Result<(), SettingsError> {
+    let msg_breathing_room = 96; // Pretty arbitrary, helps avoid running into MAX_LENGTH.
+    let max = MAX_LENGTH - msg_breathing_room;
+    if value >= max {
+        return Err(SettingsError::LengthLimitTooHigh {
+            setting,
+            value,
+            max,
+        });
+    }
+    Ok(())
+}
+
+/// Names that are sent as a single word of a message
+fn check_name(setting: &'static str, name: &str) -> Result<(), SettingsError> {
+    if name.contains(' ') {
+        return Err(SettingsError::NameHasSpace { setting });
+    }
+    Ok(())
+}
+
+/// Durations used as a period or timeout, which make no sense at zero
+fn check_duration(setting: &'static str, value: Duration) -> Result<(), SettingsError> {
+    if value.is_zero() {
+        return Err(SettingsError::ZeroDuration { setting });
+    }
+    Ok(())
+}
+
+/// The settings that can be changed while the server runs, see ServerSettings for what they do
+#[derive(Clone, Debug)]
+pub struct RuntimeSettings {
+    pub max_name_length: usize,
+    pub max_channel_length: usize,
+    pub max_topic_length: usize,
+    pub chan_limit: usize,
+    pub throttle_max_connections: usize,
+    pub throttle_window: Duration,
+    pub allow_channel_creation: bool,
+    pub motd: Option<Motd>,
+}
+
+impl RuntimeSettings {
+    pub fn validate(&self) -> Result<(), SettingsError> {
+        check_length_limit("max_name_length", self.max_name_length)?;
+        check_length_limit("max_channel_length", self.max_channel_length)?;
+        check_length_limit("max_topic_length", self.max_topic_length)
+    }
+}
+
+impl ServerSettings {
+    /// The initial values of the settings that can be changed while the server runs
+    pub fn runtime(&self) -> RuntimeSettings {
+        RuntimeSettings {
+            max_name_length: self.max_name_length,
+            max_channel_length: self.max_channel_length,
+            max_topic_length: self.max_topic_length,
+            chan_limit: self.chan_limit,
+            throttle_max_connections: self.throttle_max_connections,
+            throttle_window: self.throttle_window,
+            allow_channel_creation: self.allow_channel_creation,
+            motd: self.motd.clone(),
+        }
+    }
+
+    /// Builds settings starting from the defaults, an alternative to the struct update syntax
+    pub fn builder() -> ServerSettingsBuilder {
+        ServerSettingsBuilder::default()
+    }
+
+    /// Checks the settings, Server::try_new returns this error instead of starting with invalid settings
+    pub fn validate(&self) -> Result<(), SettingsError> {
+        self.runtime().validate()?;
+        check_name("server_name", &self.server_name)?;
+        check_name("network_name", &self.network_name)?;
+        check_duration("write_timeout", self.write_timeout)?;
+        check_duration("sweep_interval", self.sweep_interval)?;
+        if self.dispatch_workers > 0 && self.dispatch_queue_size == 0 {
+            return Err(SettingsError::EmptyDispatchQueue);
+        }
+
+        let password_hashes = self
+            .opers
+            .iter()
+            .map(|oper| ("opers", &oper.name, &oper.password_hash))
+            .chain(
+                self.webirc_gateways
+                    .iter()
+                    .map(|gateway| ("webirc_gateways", &gateway.name, &gateway.password_hash)),
+            );
+        for (setting, name, password_hash) in password_hashes {
+            if !is_valid_password_hash(password_hash) {
+                let name = name.clone();
+                return Err(SettingsError::InvalidPasswordHash { setting, name });
+            }
+        }
+
+        if self.member_ranks.len() > MemberMode::MAX_RANKS {
+            return Err(SettingsError::TooManyMemberRanks {
+                count: self.member_ranks.len(),
+                max: MemberMode::MAX_RANKS,
+            });
+        }
+        if !self.member_ranks.iter().any(|rank| rank.mode == 'o') {
+            return Err(SettingsError::MissingOperatorRank);
+        }
+        for (i, rank) in self.member_ranks.iter().enumerate() {
+            let mode = rank.mode;
+            if CHANNEL_MODES
+                .iter()
+                .any(|&(chan_mode, _)| chan_mode as char == mode)
+            {
+                return Err(SettingsError::RankModeConflict { mode });
+            }
+            if self.member_ranks[..i]
+                .iter()
+                .any(|other| other.mode == rank.mode || other.prefix == rank.prefix)
+            {
+                return Err(SettingsError::DuplicateMemberRank { mode });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Builds ServerSettings, see ServerSettings::builder
+/// Each setter checks its own value, and build checks the settings as a whole
+/// An invalid value is not set, and build returns the first error
+#[derive(Default)]
+pub struct ServerSettingsBuilder {
+    settings: ServerSettings,
+    /// The addresses given to listen, which replace the default ones
+    listen_addrs: Vec<SocketAddr>,
+    error: Option<SettingsError>,
+}
+
+impl ServerSettingsBuilder {
+    /// Records the first error, returns whether the value is valid
+    fn check(&mut self, result: Result<(), SettingsError>) -> bool {
+        match result {
+            Ok(()) => true,
+            Err(err) => {
+                self.error.get_or_insert(err);
+                false
+            }
+        }
+    }
+
+    /// Listens on an address like "0.0.0.0:6667", can be called for each address to listen on
+    pub fn listen(mut self, addr: &str) -> Self {
+        match addr.parse() {
+            Ok(addr) => self.listen_addrs.push(addr),
+            Err(_) => {
+                self.check(Err(SettingsError::InvalidListenAddress(addr.to_owned())));
+            }
+        }
+        self
+    }
+
+    /// The name the server identifies itself with
+    pub fn name(mut self, name: &str) -> Self {
+        if self.check(check_name("server_name", name)) {
+            self.settings.server_name = name.to_owned();
+        }
+        self
+    }
+
+    pub fn info(mut self, info: &str) -> Self {
+        self.settings.server_info = info.to_owned();
+        self
+    }
+
+    pub fn network(mut self, name: &str) -> Self {
+        if self.check(check_name("network_name", name)) {
+            self.settings.network_name = name.to_owned();
+        }
+        self
+    }
+
+    pub fn max_name_length(mut self, len: usize) -> Self {
+        if self.check(check_length_limit("max_name_length", len)) {
+            self.settings.max_name_length = len;
+        }
+        self
+    }
+
+    pub fn max_channel_length(mut self, len: usize) -> Self {
+        if self.check(check_length_limit("max_channel_length", len)) {
+            self.settings.max_channel_length = len;
+        }
+        self
+    }
+
+    pub fn max_topic_length(mut self, len: usize) -> Self {
+        if self.check(check_length_limit("max_topic_length", len)) {
+            self.settings.max_topic_length = len;
+        }
+        self
+    }
+
+    pub fn chan_limit(mut self, limit: usize) -> Self {
+        self.settings.chan_limit = limit;
+        self
+    }
+
+    pub fn casemapping(mut self, casemapping: Casemapping) -> Self {
+        self.settings.casemapping = casemapping;
+        self
+    }
+
+    pub fn nick_policy(mut self, nick_policy: NickPolicy) -> Self {
+        self.settings.nick_policy = nick_policy;
+        self
+    }
+
+    pub fn motd(mut self, motd: Motd) -> Self {
+        self.settings.motd = Some(motd);
+        self
+    }
+
+    /// Adds an oper block, its password hash is checked right away
+    pub fn oper(mut self, oper: OperBlock) -> Self {
+        if !is_valid_password_hash(&oper.password_hash) {
+            let name = oper.name.clone();
+            self.check(Err(SettingsError::InvalidPasswordHash {
+                setting: "opers",
+                name,
+            }));
+        }
+        self.settings.opers.push(oper);
+        self
+    }
+
+    /// Adds a WEBIRC gateway, its password hash is checked right away
+    pub fn webirc_gateway(mut self, gateway: WebircGateway) -> Self {
+        if !is_valid_password_hash(&gateway.password_hash) {
+            let name = gateway.name.clone();
+            self.check(Err(SettingsError::InvalidPasswordHash {
+                setting: "webirc_gateways",
+                name,
+            }));
+        }
+        self.settings.webirc_gateways.push(gateway);
+        self
+    }
+
+    pub fn member_ranks(mut self, member_ranks: Vec<MemberRank>) -> Self {
+        self.settings.member_ranks = member_ranks;
+        self
+    }
+
+    /// Processes messages on worker tasks, see ServerSettings::dispatch_workers
+    pub fn dispatch_workers(mut self, workers: usize, queue_size: usize) -> Self {
+        self.settings.dispatch_workers = workers;
+        self.settings.dispatch_queue_size = queue_size;
+        self
+    }
+
+    /// How often dead entries are swept, see ServerSettings::sweep_interval
+    pub fn sweep_interval(mut self, interval: Duration) -> Self {
+        if self.check(check_duration("sweep_interval", interval)) {
+            self.settings.sweep_interval = interval;
+        }
+        self
+    }
+
+    /// Changes any other setting, build still checks them
+    pub fn with(mut self, configure: impl FnOnce(&mut ServerSettings)) -> Self {
+        configure(&mut self.settings);
+        self
+    }
+
+    /// Returns the settings, or the first error found
+    pub fn build(mut self) -> Result<ServerSettings, SettingsError> {
+        if let Some(err) = self.error {
+            return Err(err);
+        }
+        if !self.listen_addrs.is_empty() {
+            self.settings.listen_addrs = self.listen_addrs;
+        }
+        self.settings.validate()?;
+        Ok(self.settings)
+    }
 }
 
 impl Default for ServerSettings {
     fn default() -> Self {
         ServerSettings {
-            listen_addr: "0.0.0.0:6667".parse().unwrap(),
+            listen_addrs: vec!["0.0.0.0:6667".parse().unwrap()],
             server_name: "rirc-server".to_owned(),
             server_info: "Default server".to_owned(),
             network_name: "rIRC".to_owned(),
@@ -34,7 +422,111 @@ impl Default for ServerSettings {
             max_channel_length: 50,
             max_topic_length: 390,
             chan_limit: 120,
+            casemapping: Casemapping::Ascii,
+            nick_policy: NickPolicy::Ascii,
+            long_lines: LongLinePolicy::Reject,
+            utf8_only: false,
+            isupport_tokens: Vec::new(),
+            invalid_utf8: InvalidUtf8Policy::Fail,
+            throttle_max_connections: 10,
+            throttle_window: Duration::from_secs(60),
+            ident_lookup: false,
+            ident_timeout: Duration::from_secs(5),
             allow_channel_creation: true,
+            member_ranks: DEFAULT_MEMBER_RANKS.to_vec(),
+            extbans: DEFAULT_EXTBANS.to_vec(),
+            reject_formatting: false,
+            motd: None,
+            opers: Vec::new(),
+            forbidden_nicks: Vec::new(),
+            forbidden_channels: Vec::new(),
+            audit_log_size: 1000,
+            services: false,
+            nick_enforce_delay: Duration::from_secs(60),
+            registered_channels: Vec::new(),
+            webirc_gateways: Vec::new(),
+            tcp_nodelay: true,
+            tcp_keepalive: None,
+            listen_backlog: 1024,
+            sendq_limit: 1024 * 1024,
+            write_timeout: Duration::from_secs(60),
+            sweep_interval: Duration::from_secs(300),
+            dispatch_workers: 0,
+            dispatch_queue_size: 1024,
+            #[cfg(feature = "admin-api")]
+            admin_api: None,
+            #[cfg(feature = "tls")]
+            client_certs: ClientCertMode::default(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate() {
+        assert_eq!(ServerSettings::default().validate(), Ok(()));
+        let settings = ServerSettings {
+            server_name: "my server".to_owned(),
+            ..Default::default()
+        };
+        let err = SettingsError::NameHasSpace {
+            setting: "server_name",
+        };
+        assert_eq!(settings.validate(), Err(err));
+        let settings = ServerSettings {
+            member_ranks: vec![MemberRank::new('o', '@'), MemberRank::new('v', '@')],
+            ..Default::default()
+        };
+        let err = SettingsError::DuplicateMemberRank { mode: 'v' };
+        assert_eq!(settings.validate(), Err(err));
+        let settings = ServerSettings {
+            write_timeout: Duration::ZERO,
+            ..Default::default()
+        };
+        let err = SettingsError::ZeroDuration {
+            setting: "write_timeout",
+        };
+        assert_eq!(settings.validate(), Err(err));
+        let settings = ServerSettings {
+            sweep_interval: Duration::ZERO,
+            ..Default::default()
+        };
+        let err = SettingsError::ZeroDuration {
+            setting: "sweep_interval",
+        };
+        assert_eq!(settings.validate(), Err(err));
+    }
+
+    #[test]
+    fn builder() {
+        let settings = ServerSettings::builder()
+            .listen("127.0.0.1:6667")
+            .listen("[::1]:6667")
+            .name("irc.example.org")
+            .max_topic_length(300)
+            .build()
+            .unwrap();
+        assert_eq!(settings.listen_addrs.len(), 2);
+        assert_eq!(settings.server_name, "irc.example.org");
+        assert_eq!(settings.max_topic_length, 300);
+
+        let err = ServerSettings::builder()
+            .listen("localhost")
+            .name("irc example")
+            .build();
+        let expected = SettingsError::InvalidListenAddress("localhost".to_owned());
+        assert_eq!(err.unwrap_err(), expected);
+        let err = ServerSettings::builder().dispatch_workers(4, 0).build();
+        assert_eq!(err.unwrap_err(), SettingsError::EmptyDispatchQueue);
+        let err = ServerSettings::builder()
+            .sweep_interval(Duration::ZERO)
+            .build();
+        let expected = SettingsError::ZeroDuration {
+            setting: "sweep_interval",
+        };
+        assert_eq!(err.unwrap_err(), expected);
+    }
+}
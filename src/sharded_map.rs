@@ -0,0 +1,190 @@
+use std::borrow::Borrow;
+use std::collections::hash_map::{Entry, RandomState};
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+use std::sync::RwLock;
+
+/// How many shards a map is split in, a power of two
+const SHARDS: usize = 32;
+
+/// A map split in shards with their own lock, so tasks using different keys don't wait on each other
+/// The locks are only held inside the methods, values are cloned out, so no lock is ever held across an await
+pub struct ShardedMap<K, V> {
+    shards: Box<[RwLock<HashMap<K, V>>]>,
+    hasher: RandomState,
+}
+
+impl<K: Eq + Hash, V: Clone> ShardedMap<K, V> {
+    pub fn new() -> Self {
+        Self::from_map(HashMap::new())
+    }
+
+    pub fn from_map(map: HashMap<K, V>) -> Self {
+        let sharded = ShardedMap {
+            shards: (0..SHARDS).map(|_| RwLock::new(HashMap::new())).collect(),
+            hasher: RandomState::new(),
+        };
+        for (key, value) in map {
+            sharded.insert(key, value);
+        }
+        sharded
+    }
+
+    fn shard<Q: Hash + ?Sized>(&self, key: &Q) -> &RwLock<HashMap<K, V>> {
+        let hash = self.hasher.hash_one(key) as usize;
+        &self.shards[hash & (SHARDS - 1)]
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.shard(key).read().unwrap().get(key).cloned()
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.shard(key).read().unwrap().contains_key(key)
+    }
+
+    /// Inserts a value, returns the one it replaced
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        self.shard(&key).write().unwrap().insert(key, value)
+    }
+
+    /// Inserts a value only if the key is free, returns false if it was taken
+    pub fn insert_if_absent(&self, key: K, value: V) -> bool {
+        match self.shard(&key).write().unwrap().entry(key) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(value);
+                true
+            }
+        }
+    }
+
+    /// Returns the value of a key, inserting one first if there was none
+    /// Also returns whether the value was inserted
+    pub fn get_or_insert_with(&self, key: K, make_value: impl FnOnce() -> V) -> (V, bool) {
+        match self.shard(&key).write().unwrap().entry(key) {
+            Entry::Occupied(entry) => (entry.get().clone(), false),
+            Entry::Vacant(entry) => (entry.insert(make_value()).clone(), true),
+        }
+    }
+
+    pub fn remove<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.shard(key).write().unwrap().remove(key)
+    }
+
+    /// Removes a key only if its value passes the check, for example if it is still the one we looked up
+    pub fn remove_if<Q>(&self, key: &Q, check: impl FnOnce(&V) -> bool) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        let mut shard = self.shard(key).write().unwrap();
+        match shard.get(key) {
+            Some(value) if check(value) => shard.remove(key),
+            _ => None,
+        }
+    }
+
+    /// Counts the entries, the shards are counted one after the other so this is only a snapshot
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.read().unwrap().len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards
+            .iter()
+            .all(|shard| shard.read().unwrap().is_empty())
+    }
+
+    /// A snapshot of the values, which can be used across awaits
+    pub fn values(&self) -> Vec<V> {
+        let mut values = Vec::new();
+        for shard in self.shards.iter() {
+            values.extend(shard.read().unwrap().values().cloned());
+        }
+        values
+    }
+
+    /// Keeps only the entries that pass the check, returns how many were removed
+    pub fn retain(&self, mut check: impl FnMut(&K, &mut V) -> bool) -> usize {
+        let mut removed = 0;
+        for shard in self.shards.iter() {
+            let mut shard = shard.write().unwrap();
+            let len = shard.len();
+            shard.retain(|key, value| check(key, value));
+            removed += len - shard.len();
+        }
+        removed
+    }
+
+    /// Whether any entry passes the check, the shards are locked one at a time
+    pub fn any(&self, mut check: impl FnMut(&K, &V) -> bool) -> bool {
+        self.shards.iter().any(|shard| {
+            let shard = shard.read().unwrap();
+            shard.iter().any(|(key, value)| check(key, value))
+        })
+    }
+
+    /// A snapshot of the entries, which can be used across awaits
+    pub fn entries(&self) -> Vec<(K, V)>
+    where
+        K: Clone,
+    {
+        let mut entries = Vec::new();
+        for shard in self.shards.iter() {
+            let shard = shard.read().unwrap();
+            entries.extend(shard.iter().map(|(key, value)| (key.clone(), value.clone())));
+        }
+        entries
+    }
+}
+
+impl<K: Eq + Hash, V: Clone> Default for ShardedMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sharded_map() {
+        let map = ShardedMap::new();
+        for i in 0..100 {
+            assert!(map.insert_if_absent(i.to_string(), i));
+        }
+        assert!(!map.insert_if_absent("42".to_owned(), 0));
+        assert_eq!(map.get("42"), Some(42));
+        assert_eq!(map.len(), 100);
+        assert_eq!(map.get_or_insert_with("7".to_owned(), || 0), (7, false));
+        assert_eq!(map.get_or_insert_with("a".to_owned(), || 0), (0, true));
+
+        assert_eq!(map.remove_if("7", |&value| value == 0), None);
+        assert_eq!(map.remove_if("7", |&value| value == 7), Some(7));
+        assert!(!map.contains_key("7"));
+        assert!(map.any(|key, _| key == "a"));
+        assert_eq!(map.retain(|_, &mut value| value != 0), 2);
+        assert!(!map.contains_key("a"));
+        let mut values = map.values();
+        values.sort_unstable();
+        assert_eq!(values.len(), 98);
+        assert_eq!(values[..2], [1, 2]);
+    }
+}
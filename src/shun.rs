@@ -0,0 +1,83 @@
+use crate::mask::{mask_matches, normalize_mask};
+use std::time::{Duration, Instant};
+
+/// Commands a shunned client can still use, so they don't notice anything is wrong
+pub const SHUN_EXEMPT_COMMANDS: &[&str] = &["PING", "PONG", "QUIT"];
+
+#[derive(Clone, Debug)]
+pub struct Shun {
+    /// A full "nick!user@host" glob mask
+    pub mask: String,
+    pub reason: String,
+    pub expires: Instant,
+}
+
+/// Users matching a shun have their commands silently ignored until it expires
+#[derive(Clone, Debug, Default)]
+pub struct ShunList {
+    shuns: Vec<Shun>,
+}
+
+impl ShunList {
+    /// Returns the shuns that haven't expired yet
+    pub fn active(&self) -> impl Iterator<Item = &Shun> {
+        let now = Instant::now();
+        self.shuns.iter().filter(move |shun| shun.expires > now)
+    }
+
+    /// Shuns a mask for a duration, replacing any existing shun on the same mask
+    pub fn add(&mut self, mask: &str, duration: Duration, reason: &str) {
+        let now = Instant::now();
+        let mask = normalize_mask(mask);
+        self.shuns
+            .retain(|shun| shun.expires > now && shun.mask != mask);
+        self.shuns.push(Shun {
+            mask,
+            reason: reason.to_owned(),
+            expires: now + duration,
+        });
+    }
+
+    /// Removes a shun, returns false if the mask wasn't shunned
+    pub fn remove(&mut self, mask: &str) -> bool {
+        let mask = normalize_mask(mask);
+        let was_shunned = self.active().any(|shun| shun.mask == mask);
+        let now = Instant::now();
+        self.shuns
+            .retain(|shun| shun.expires > now && shun.mask != mask);
+        was_shunned
+    }
+
+    /// Whether a user's "nick!user@host" prefix matches an active shun
+    pub fn is_shunned(&self, prefix: &str) -> bool {
+        self.active().any(|shun| mask_matches(&shun.mask, prefix))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_and_remove() {
+        let mut shuns = ShunList::default();
+        shuns.add("*@10.0.0.*", Duration::from_secs(60), "Spam");
+        assert!(shuns.is_shunned("nick!user@10.0.0.3"));
+        assert!(!shuns.is_shunned("nick!user@10.0.1.3"));
+
+        shuns.add("*!*@10.0.0.*", Duration::from_secs(120), "More spam");
+        assert_eq!(shuns.active().count(), 1);
+        assert!(!shuns.remove("other"));
+        assert!(shuns.remove("*@10.0.0.*"));
+        assert!(!shuns.is_shunned("nick!user@10.0.0.3"));
+    }
+
+    #[test]
+    fn expiration() {
+        let mut shuns = ShunList::default();
+        shuns.add("nick", Duration::ZERO, "Gone already");
+        assert!(!shuns.is_shunned("nick!user@host"));
+        assert_eq!(shuns.active().count(), 0);
+        assert!(!shuns.remove("nick"));
+    }
+}
@@ -0,0 +1,100 @@
+use crate::channel::{Channel, Topic};
+use crate::client::{Client, ClientStatus};
+use crate::connection_info::ConnectionInfo;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Weak;
+
+/// A connected client as it was when the snapshot was taken, see ServerHandle::clients
+#[derive(Clone, Debug)]
+pub struct ClientInfo {
+    pub addr: SocketAddr,
+    /// Unset until the client sends a NICK
+    pub nick: Option<String>,
+    pub username: Option<String>,
+    pub realname: Option<String>,
+    /// The host the user actually connects from, even if another host is shown to other users
+    pub host: String,
+    pub ip: IpAddr,
+    pub account: Option<String>,
+    /// Whether the client completed registration
+    pub registered: bool,
+    pub is_oper: bool,
+    pub is_secure: bool,
+    /// The user modes, like "+iw"
+    pub modes: String,
+    pub connection_info: ConnectionInfo,
+    /// SHA-256 fingerprint of the TLS client certificate
+    pub certfp: Option<String>,
+    /// Names of the channels the user is in
+    pub channels: Vec<String>,
+}
+
+impl ClientInfo {
+    pub async fn new(client: &Client) -> ClientInfo {
+        let channels: Vec<_> = client
+            .channels
+            .read()
+            .await
+            .values()
+            .filter_map(Weak::upgrade)
+            .collect();
+        let mut channel_names = Vec::with_capacity(channels.len());
+        for channel in channels {
+            channel_names.push(channel.read().await.name.clone());
+        }
+
+        ClientInfo {
+            addr: client.addr,
+            nick: client.get_nick(),
+            username: client.get_username(),
+            realname: client.get_realname(),
+            host: client.get_real_host(),
+            ip: client.get_ip(),
+            account: client.account.clone(),
+            registered: matches!(client.status, ClientStatus::Normal(_)),
+            is_oper: client.mode.is_oper,
+            is_secure: client.is_secure,
+            modes: client.mode.to_string(),
+            connection_info: client.connection_info.clone(),
+            certfp: client.certfp.clone(),
+            channels: channel_names,
+        }
+    }
+}
+
+/// A channel as it was when the snapshot was taken, see ServerHandle::channels
+#[derive(Clone, Debug)]
+pub struct ChannelInfo {
+    /// Includes the # character
+    pub name: String,
+    pub topic: Option<Topic>,
+    /// The channel modes, like "+nt"
+    pub modes: String,
+    /// Parameters of the modes, in the same order, including the key
+    pub mode_params: Vec<String>,
+    pub member_count: usize,
+    /// When the channel was created, in seconds since the Unix epoch
+    pub creation_timestamp: u64,
+}
+
+impl ChannelInfo {
+    pub async fn new(channel: &Channel) -> ChannelInfo {
+        ChannelInfo {
+            name: channel.name.clone(),
+            topic: channel.topic.clone(),
+            modes: channel.mode.to_string(),
+            mode_params: channel.mode.get_mode_params(true),
+            member_count: channel.users.read().await.len(),
+            creation_timestamp: channel.creation_timestamp,
+        }
+    }
+}
+
+/// A member of a channel as it was when the snapshot was taken, see ChannelHandle::members
+#[derive(Clone, Debug)]
+pub struct ChannelMemberInfo {
+    pub nick: String,
+    pub addr: SocketAddr,
+    /// Prefix of the member's highest rank, like '@' for operators
+    pub prefix: Option<char>,
+}
@@ -0,0 +1,139 @@
+use std::fmt;
+
+/// A category of server notices that operators can subscribe to with user mode +s
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SnoCategory {
+    /// Users completing registration
+    Connects,
+    /// Registered users disconnecting
+    Quits,
+    /// Users forcibly removed from the server
+    Kills,
+    /// Operators logging in and using their privileges
+    OperActions,
+    /// Clients flooding the server, like reconnecting too fast
+    Flood,
+}
+
+impl SnoCategory {
+    pub const ALL: &'static [SnoCategory] = &[
+        SnoCategory::Connects,
+        SnoCategory::Quits,
+        SnoCategory::Kills,
+        SnoCategory::OperActions,
+        SnoCategory::Flood,
+    ];
+
+    /// The letter of this category in a snomask
+    pub fn letter(self) -> char {
+        match self {
+            SnoCategory::Connects => 'c',
+            SnoCategory::Quits => 'q',
+            SnoCategory::Kills => 'k',
+            SnoCategory::OperActions => 'o',
+            SnoCategory::Flood => 'f',
+        }
+    }
+
+    /// Shown at the start of notices, like "*** Connect -- "
+    pub fn name(self) -> &'static str {
+        match self {
+            SnoCategory::Connects => "Connect",
+            SnoCategory::Quits => "Quit",
+            SnoCategory::Kills => "Kill",
+            SnoCategory::OperActions => "Oper",
+            SnoCategory::Flood => "Flood",
+        }
+    }
+
+    fn from_letter(letter: char) -> Option<SnoCategory> {
+        SnoCategory::ALL
+            .iter()
+            .copied()
+            .find(|category| category.letter() == letter)
+    }
+
+    fn bit(self) -> u8 {
+        1 << SnoCategory::ALL.iter().position(|&c| c == self).unwrap()
+    }
+}
+
+/// The server notice categories an operator is subscribed to
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Snomask {
+    categories: u8,
+}
+
+impl Snomask {
+    pub fn is_empty(self) -> bool {
+        self.categories == 0
+    }
+
+    pub fn contains(self, category: SnoCategory) -> bool {
+        self.categories & category.bit() != 0
+    }
+
+    pub fn set(&mut self, category: SnoCategory, value: bool) {
+        if value {
+            self.categories |= category.bit();
+        } else {
+            self.categories &= !category.bit();
+        }
+    }
+
+    /// Applies changes like "+cq-k", a mask without sign like "cq" adds categories
+    /// "*" stands for all categories. Unknown letters are ignored
+    pub fn apply(&mut self, changes: &str) {
+        let mut positive = true;
+        for c in changes.chars() {
+            match c {
+                '+' => positive = true,
+                '-' => positive = false,
+                '*' => {
+                    for &category in SnoCategory::ALL {
+                        self.set(category, positive);
+                    }
+                }
+                _ => {
+                    if let Some(category) = SnoCategory::from_letter(c) {
+                        self.set(category, positive);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Snomask {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("+")?;
+        for &category in SnoCategory::ALL {
+            if self.contains(category) {
+                write!(f, "{}", category.letter())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apply_changes() {
+        let mut snomask = Snomask::default();
+        assert!(snomask.is_empty());
+        snomask.apply("cq");
+        assert!(snomask.contains(SnoCategory::Connects));
+        assert!(!snomask.contains(SnoCategory::Kills));
+        assert_eq!(snomask.to_string(), "+cq");
+
+        snomask.apply("+k-c+Z");
+        assert_eq!(snomask.to_string(), "+qk");
+        snomask.apply("*");
+        assert_eq!(snomask.to_string(), "+cqkof");
+        snomask.apply("-*");
+        assert!(snomask.is_empty());
+    }
+}
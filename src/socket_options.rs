@@ -0,0 +1,46 @@
+use crate::settings::ServerSettings;
+use socket2::SockRef;
+use std::io::Error;
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+/// TCP keepalive probes, to notice clients whose connection died without being closed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TcpKeepalive {
+    /// How long a connection stays idle before probes are sent
+    pub idle: Duration,
+    /// Time between probes, only used on Linux, Android, macOS and the BSDs
+    pub interval: Duration,
+    /// How many unanswered probes drop the connection, only used where interval is
+    pub retries: u32,
+}
+
+impl Default for TcpKeepalive {
+    fn default() -> Self {
+        TcpKeepalive {
+            idle: Duration::from_secs(120),
+            interval: Duration::from_secs(30),
+            retries: 4,
+        }
+    }
+}
+
+/// Applies the TCP settings to a client's socket, right after it's accepted
+pub fn configure_client_socket(socket: &TcpStream, settings: &ServerSettings) -> Result<(), Error> {
+    socket.set_nodelay(settings.tcp_nodelay)?;
+    if let Some(keepalive) = settings.tcp_keepalive {
+        let params = socket2::TcpKeepalive::new().with_time(keepalive.idle);
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "macos",
+            target_os = "freebsd",
+            target_os = "netbsd"
+        ))]
+        let params = params
+            .with_interval(keepalive.interval)
+            .with_retries(keepalive.retries);
+        SockRef::from(socket).set_tcp_keepalive(&params)?;
+    }
+    Ok(())
+}
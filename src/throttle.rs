@@ -0,0 +1,71 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// Remembers recent connection attempts per IP, to reject clients stuck in a reconnect loop
+#[derive(Default)]
+pub struct ConnectionThrottle {
+    attempts: HashMap<IpAddr, VecDeque<Instant>>,
+}
+
+impl ConnectionThrottle {
+    /// Records a connection attempt, and returns whether it should be accepted
+    /// Attempts are rejected when an IP already made max_attempts in the window, rejected attempts count too
+    pub fn register_attempt(
+        &mut self,
+        ip: IpAddr,
+        now: Instant,
+        window: Duration,
+        max_attempts: usize,
+    ) -> bool {
+        let is_recent = |attempt: &Instant| now.saturating_duration_since(*attempt) < window;
+        self.attempts
+            .retain(|_, attempts| attempts.back().is_some_and(is_recent));
+
+        let attempts = self.attempts.entry(ip).or_default();
+        while attempts.front().is_some_and(|attempt| !is_recent(attempt)) {
+            attempts.pop_front();
+        }
+        let accepted = attempts.len() < max_attempts;
+        // No need to remember more attempts than the limit
+        if attempts.len() >= max_attempts {
+            attempts.pop_front();
+        }
+        attempts.push_back(now);
+        accepted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throttling() {
+        let mut throttle = ConnectionThrottle::default();
+        let ip = "10.0.0.1".parse().unwrap();
+        let other_ip = "10.0.0.2".parse().unwrap();
+        let window = Duration::from_secs(10);
+        let start = Instant::now();
+
+        for i in 0..3 {
+            assert!(throttle.register_attempt(ip, start + Duration::from_secs(i), window, 3));
+        }
+        assert!(!throttle.register_attempt(ip, start + Duration::from_secs(3), window, 3));
+        assert!(throttle.register_attempt(other_ip, start + Duration::from_secs(3), window, 3));
+
+        // Rejected attempts count, so a loop stays throttled until it slows down
+        assert!(!throttle.register_attempt(ip, start + Duration::from_secs(5), window, 3));
+        assert!(throttle.register_attempt(ip, start + Duration::from_secs(30), window, 3));
+    }
+
+    #[test]
+    fn forgets_old_attempts() {
+        let mut throttle = ConnectionThrottle::default();
+        let window = Duration::from_secs(10);
+        let start = Instant::now();
+        throttle.register_attempt("10.0.0.1".parse().unwrap(), start, window, 3);
+        throttle.register_attempt("10.0.0.2".parse().unwrap(), start + window, window, 3);
+        assert_eq!(throttle.attempts.len(), 1);
+    }
+}
@@ -0,0 +1,212 @@
+use crate::tls_provider::cert_fingerprint;
+use rustls::server::{
+    AllowAnyAuthenticatedClient, ClientCertVerified, ClientCertVerifier, ClientHello,
+    ResolvesServerCert,
+};
+use rustls::sign::{any_supported_type, CertifiedKey};
+use rustls::{Certificate, DistinguishedNames, PrivateKey, RootCertStore};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+/// Picks the certificate matching the server name the client asked for (SNI)
+/// Certificates can be added and removed while the server runs, for example to renew them
+#[derive(Default)]
+pub struct SniCertResolver {
+    /// Lowercase server name -> Certificate, names can be wildcards like "*.example.org"
+    certs: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+    /// Used when the client doesn't send SNI or no certificate matches
+    default: RwLock<Option<Arc<CertifiedKey>>>,
+}
+
+fn certified_key(certs: Vec<Certificate>, key: &PrivateKey) -> Result<Arc<CertifiedKey>, Error> {
+    let key = any_supported_type(key)
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, "Unsupported private key type"))?;
+    Ok(Arc::new(CertifiedKey::new(certs, key)))
+}
+
+impl SniCertResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds or replaces the certificate chain for a server name, like "irc.example.org" or "*.example.org"
+    pub fn add(&self, name: &str, certs: Vec<Certificate>, key: &PrivateKey) -> Result<(), Error> {
+        let cert = certified_key(certs, key)?;
+        self.certs
+            .write()
+            .unwrap()
+            .insert(name.to_ascii_lowercase(), cert);
+        Ok(())
+    }
+
+    /// Removes the certificate of a server name, returns false if there was none
+    pub fn remove(&self, name: &str) -> bool {
+        let mut certs = self.certs.write().unwrap();
+        certs.remove(&name.to_ascii_lowercase()).is_some()
+    }
+
+    /// Sets the certificate used for clients without SNI, or asking for an unknown name
+    /// Without a default certificate, these clients are refused
+    pub fn set_default(&self, certs: Vec<Certificate>, key: &PrivateKey) -> Result<(), Error> {
+        *self.default.write().unwrap() = Some(certified_key(certs, key)?);
+        Ok(())
+    }
+
+    /// Finds the certificate for a server name, an exact match is preferred over a wildcard
+    fn find(&self, name: &str) -> Option<Arc<CertifiedKey>> {
+        let name = name.to_ascii_lowercase();
+        let certs = self.certs.read().unwrap();
+        if let Some(cert) = certs.get(&name) {
+            return Some(cert.clone());
+        }
+        let (_, parent) = name.split_once('.')?;
+        certs.get(&format!("*.{}", parent)).cloned()
+    }
+}
+
+impl ResolvesServerCert for SniCertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        client_hello
+            .server_name()
+            .and_then(|name| self.find(name))
+            .or_else(|| self.default.read().unwrap().clone())
+    }
+}
+
+/// How client certificates are checked, see ClientCertMode
+#[derive(Clone)]
+pub enum ClientCertCheck {
+    /// Any certificate is accepted, IRC clients usually send self-signed certificates
+    AcceptAny,
+    /// Certificates must be signed by one of these certificate authorities
+    Ca(RootCertStore),
+    /// Only certificates with one of these SHA-256 fingerprints are accepted, in hex with or without colons
+    Fingerprints(Vec<String>),
+    /// Certificates are checked by the embedder's own verifier
+    Custom(Arc<dyn ClientCertVerifier>),
+}
+
+impl fmt::Debug for ClientCertCheck {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientCertCheck::AcceptAny => f.write_str("AcceptAny"),
+            ClientCertCheck::Ca(roots) => f.debug_tuple("Ca").field(roots).finish(),
+            ClientCertCheck::Fingerprints(fingerprints) => {
+                f.debug_tuple("Fingerprints").field(fingerprints).finish()
+            }
+            ClientCertCheck::Custom(_) => f.write_str("Custom"),
+        }
+    }
+}
+
+/// Whether TLS clients are asked for a certificate
+#[derive(Clone, Debug)]
+pub enum ClientCertMode {
+    /// Clients are not asked for a certificate
+    Disabled,
+    /// Clients are asked for a certificate, but can still connect without one
+    Request(ClientCertCheck),
+    /// Clients without an accepted certificate are refused, for private networks
+    Require(ClientCertCheck),
+}
+
+impl Default for ClientCertMode {
+    fn default() -> Self {
+        ClientCertMode::Request(ClientCertCheck::AcceptAny)
+    }
+}
+
+/// Normalizes a fingerprint like "5F:E4:A6:..." to lowercase hex without colons
+fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint.replace(':', "").to_ascii_lowercase()
+}
+
+struct ClientCertChecker {
+    mandatory: bool,
+    check: ClientCertCheck,
+    /// Verifies the chain of trust for CA checks
+    inner: Option<Arc<dyn ClientCertVerifier>>,
+}
+
+impl ClientCertVerifier for ClientCertChecker {
+    fn client_auth_mandatory(&self) -> Option<bool> {
+        Some(self.mandatory)
+    }
+
+    fn client_auth_root_subjects(&self) -> Option<DistinguishedNames> {
+        match self.inner {
+            Some(ref inner) => inner.client_auth_root_subjects(),
+            None => Some(Vec::new()),
+        }
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        now: SystemTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        if let Some(ref inner) = self.inner {
+            return inner.verify_client_cert(end_entity, intermediates, now);
+        }
+        match self.check {
+            ClientCertCheck::Fingerprints(ref fingerprints) => {
+                let fingerprint = cert_fingerprint(&end_entity.0);
+                if fingerprints
+                    .iter()
+                    .any(|allowed| normalize_fingerprint(allowed) == fingerprint)
+                {
+                    Ok(ClientCertVerified::assertion())
+                } else {
+                    Err(rustls::Error::InvalidCertificateData(
+                        "Fingerprint not allowed".to_owned(),
+                    ))
+                }
+            }
+            // The handshake still checks that the client owns the certificate's key
+            _ => Ok(ClientCertVerified::assertion()),
+        }
+    }
+}
+
+/// Makes the rustls verifier for a client certificate mode, or None if clients aren't asked for one
+pub fn client_cert_verifier(mode: &ClientCertMode) -> Option<Arc<dyn ClientCertVerifier>> {
+    let (mandatory, check) = match mode {
+        ClientCertMode::Disabled => return None,
+        ClientCertMode::Request(check) => (false, check),
+        ClientCertMode::Require(check) => (true, check),
+    };
+    let inner = match check {
+        ClientCertCheck::Ca(roots) => Some(AllowAnyAuthenticatedClient::new(roots.clone())),
+        ClientCertCheck::Custom(verifier) => Some(verifier.clone()),
+        _ => None,
+    };
+    Some(Arc::new(ClientCertChecker {
+        mandatory,
+        check: check.clone(),
+        inner,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fingerprint_allow_list() {
+        let cert = Certificate(b"abc".to_vec());
+        let allowed = "BA:78:16:BF:8F:01:CF:EA:41:41:40:DE:5D:AE:22:23:B0:03:61:A3:96:17:7A:9C:B4:10:FF:61:F2:00:15:AD";
+        let check = ClientCertCheck::Fingerprints(vec![allowed.to_owned()]);
+        let verifier = client_cert_verifier(&ClientCertMode::Require(check)).unwrap();
+        assert_eq!(verifier.client_auth_mandatory(), Some(true));
+        let now = SystemTime::now();
+        assert!(verifier.verify_client_cert(&cert, &[], now).is_ok());
+        let other = Certificate(b"abcd".to_vec());
+        assert!(verifier.verify_client_cert(&other, &[], now).is_err());
+
+        assert!(client_cert_verifier(&ClientCertMode::Disabled).is_none());
+    }
+}
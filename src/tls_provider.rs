@@ -0,0 +1,108 @@
+use crate::client::ClientDuplex;
+use crate::connection_info::TlsInfo;
+use crate::server::ServerState;
+use async_trait::async_trait;
+use sha2::{Digest, Sha256};
+use std::io::Error;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+
+/// A TLS backend, which completes the handshake of new connections
+#[async_trait]
+pub(crate) trait TlsProvider: Send + Sync {
+    async fn accept(
+        &self,
+        server_state: Arc<ServerState>,
+        socket: TcpStream,
+    ) -> Result<ClientDuplex, Error>;
+}
+
+/// The SHA-256 fingerprint of a DER certificate in lowercase hex, as shown in WHOIS
+pub fn cert_fingerprint(der: &[u8]) -> String {
+    Sha256::digest(der)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(feature = "tls")]
+#[async_trait]
+impl TlsProvider for tokio_rustls::TlsAcceptor {
+    async fn accept(
+        &self,
+        server_state: Arc<ServerState>,
+        socket: TcpStream,
+    ) -> Result<ClientDuplex, Error> {
+        let (addr, local_addr) = (socket.peer_addr()?, socket.local_addr()?);
+        let tls_sock = tokio_rustls::TlsAcceptor::accept(self, socket).await?;
+        let certfp = tls_sock
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(<[_]>::first)
+            .map(|cert| cert_fingerprint(&cert.0));
+        let connection = tls_sock.get_ref().1;
+        let tls_info = TlsInfo {
+            protocol: connection
+                .protocol_version()
+                .map(|version| format!("{:?}", version)),
+            cipher: connection
+                .negotiated_cipher_suite()
+                .map(|suite| format!("{:?}", suite.suite())),
+            sni: connection.sni_hostname().map(str::to_owned),
+        };
+        Ok(ClientDuplex::from_secure_stream(
+            server_state,
+            addr,
+            local_addr,
+            tls_sock,
+            tls_info,
+            certfp,
+        ))
+    }
+}
+
+#[cfg(feature = "native-tls")]
+#[async_trait]
+impl TlsProvider for tokio_native_tls::TlsAcceptor {
+    async fn accept(
+        &self,
+        server_state: Arc<ServerState>,
+        socket: TcpStream,
+    ) -> Result<ClientDuplex, Error> {
+        let (addr, local_addr) = (socket.peer_addr()?, socket.local_addr()?);
+        let tls_sock = tokio_native_tls::TlsAcceptor::accept(self, socket)
+            .await
+            .map_err(Error::other)?;
+        let peer_cert = tls_sock
+            .get_ref()
+            .peer_certificate()
+            .map_err(Error::other)?;
+        let certfp = match peer_cert {
+            Some(cert) => Some(cert_fingerprint(&cert.to_der().map_err(Error::other)?)),
+            None => None,
+        };
+        // native-tls doesn't tell which protocol, cipher or server name were negotiated
+        Ok(ClientDuplex::from_secure_stream(
+            server_state,
+            addr,
+            local_addr,
+            tls_sock,
+            TlsInfo::default(),
+            certfp,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fingerprint() {
+        assert_eq!(
+            cert_fingerprint(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
@@ -0,0 +1,102 @@
+use crate::message::{Message, MessageStream};
+use futures::task::{Context, Poll};
+use futures::{Stream, StreamExt};
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{BufReader, DuplexStream};
+use tokio::sync::mpsc;
+
+/// How much of the server's messages can wait for a virtual client to read them
+/// Past this the client is as slow as a socket that doesn't accept data, and ends up disconnected
+pub const VIRTUAL_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Virtual clients get addresses in the discard-only prefix 100::/64, which can't belong to a real client
+pub fn next_virtual_addr() -> SocketAddr {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let segments = [
+        (id >> 48) as u16,
+        (id >> 32) as u16,
+        (id >> 16) as u16,
+        id as u16,
+    ];
+    let [a, b, c, d] = segments;
+    SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0x100, 0, 0, 0, a, b, c, d)), 0)
+}
+
+/// Who a virtual client registers as, see ServerHandle::add_virtual_client
+#[derive(Clone, Debug)]
+pub struct VirtualIdentity {
+    pub nick: String,
+    /// Used without the ~ of unverified usernames
+    pub username: String,
+    pub realname: String,
+    /// Shown in the client's prefix instead of an IP, like "services.example.org"
+    pub host: String,
+}
+
+/// A client without a socket, run by the embedder like a bot, see ServerHandle::add_virtual_client
+/// It is seen in channels, NAMES and WHO like any other user, and quits when this is dropped
+/// The messages the server sends it are read as a Stream, or with recv
+pub struct VirtualClient {
+    addr: SocketAddr,
+    sender: mpsc::UnboundedSender<Message>,
+    receiver: MessageStream<BufReader<DuplexStream>>,
+}
+
+impl VirtualClient {
+    pub(crate) fn new(
+        addr: SocketAddr,
+        sender: mpsc::UnboundedSender<Message>,
+        connection: DuplexStream,
+    ) -> VirtualClient {
+        VirtualClient {
+            addr,
+            sender,
+            receiver: MessageStream::new(BufReader::new(connection)),
+        }
+    }
+
+    /// The address of the client in the server, see next_virtual_addr
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Sends a message as the client, like a JOIN or a PRIVMSG
+    pub fn send(&self, msg: Message) -> Result<(), Error> {
+        self.sender
+            .send(msg)
+            .map_err(|_| Error::new(ErrorKind::NotConnected, "Virtual client disconnected"))
+    }
+
+    /// Sends messages as the client from other tasks, the client stays connected until this is dropped too
+    pub fn sender(&self) -> mpsc::UnboundedSender<Message> {
+        self.sender.clone()
+    }
+
+    /// Waits for the next message the server sends to the client, returns None once it is disconnected
+    pub async fn recv(&mut self) -> Option<Message> {
+        self.next().await
+    }
+}
+
+impl Stream for VirtualClient {
+    type Item = Message;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Message>> {
+        loop {
+            match self.receiver.poll_next_unpin(cx) {
+                // The server only sends valid lines, an error means the connection is closing
+                Poll::Ready(Some(Ok(msg))) => return Poll::Ready(Some(msg)),
+                Poll::Ready(Some(Err(err))) if err.kind() != ErrorKind::InvalidData => {
+                    return Poll::Ready(None)
+                }
+                Poll::Ready(Some(Err(_))) => continue,
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
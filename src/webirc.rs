@@ -0,0 +1,66 @@
+use crate::mask::mask_matches;
+use std::net::IpAddr;
+
+/// A trusted web gateway, which can use WEBIRC to pass on the real address of its users
+#[derive(Clone, Debug)]
+pub struct WebircGateway {
+    /// Shown in server notices, like "KiwiIRC"
+    pub name: String,
+    /// Argon2 hash of the WEBIRC password in PHC string format, see hash_password
+    pub password_hash: String,
+    /// Glob masks of the IPs the gateway connects from, like "192.0.2.*"
+    pub hosts: Vec<String>,
+}
+
+impl WebircGateway {
+    /// Whether the gateway may connect from this IP
+    pub fn allows_ip(&self, ip: &IpAddr) -> bool {
+        let ip = ip.to_string();
+        self.hosts.iter().any(|mask| mask_matches(mask, &ip))
+    }
+}
+
+/// The end user behind a web gateway
+#[derive(Clone, Debug)]
+pub struct WebircInfo {
+    /// Name of the gateway's block in the settings
+    pub gateway: String,
+    pub ip: IpAddr,
+    pub hostname: String,
+}
+
+/// Whether a hostname sent by a gateway can be shown in prefixes
+pub fn is_valid_hostname(hostname: &str) -> bool {
+    !hostname.is_empty()
+        && !hostname.starts_with(['.', '-', ':'])
+        && hostname
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-.:".contains(c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gateway_hosts() {
+        let gateway = WebircGateway {
+            name: "kiwi".to_owned(),
+            password_hash: String::new(),
+            hosts: vec!["192.0.2.*".to_owned(), "2001:db8::1".to_owned()],
+        };
+        assert!(gateway.allows_ip(&"192.0.2.10".parse().unwrap()));
+        assert!(gateway.allows_ip(&"2001:db8::1".parse().unwrap()));
+        assert!(!gateway.allows_ip(&"198.51.100.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn hostnames() {
+        assert!(is_valid_hostname("user.example.com"));
+        assert!(is_valid_hostname("2001:db8::1"));
+        assert!(!is_valid_hostname(""));
+        assert!(!is_valid_hostname(":evil"));
+        assert!(!is_valid_hostname("a b"));
+        assert!(!is_valid_hostname("nick!user@host"));
+    }
+}
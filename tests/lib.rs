@@ -1,15 +1,381 @@
 extern crate rirc_server;
 
-use rirc_server::{Server, ServerCallbacks, ServerSettings};
+use rirc_server::{
+    async_trait, CallbackResult, ChannelInfo, Client, CommandNamespace, ConnectionInfo,
+    DefaultHooks, JoinVeto, Message, MessageTarget, RelayOptions, Server, ServerHandle,
+    ServerHooks, ServerSettings, SetupError, VirtualClient, VirtualIdentity,
+};
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::sync::RwLock;
 
 #[test]
 fn can_instantiate_server() {
     let _ = Server::new(
         ServerSettings {
-            listen_addr: "0.0.0.0:6667".parse().unwrap(),
+            listen_addrs: vec!["0.0.0.0:6667".parse().unwrap()],
             server_name: "test-server".to_owned(),
             ..Default::default()
         },
-        ServerCallbacks::default(),
+        Arc::new(DefaultHooks),
     );
 }
+
+#[test]
+fn can_update_settings() {
+    let server = Server::new(ServerSettings::default(), Arc::new(DefaultHooks));
+    server
+        .update_settings(|settings| settings.chan_limit = 10)
+        .unwrap();
+    assert_eq!(server.runtime_settings().chan_limit, 10);
+
+    let result = server.update_settings(|settings| {
+        settings.chan_limit = 20;
+        settings.max_topic_length = 1000;
+    });
+    assert!(result.is_err());
+    assert_eq!(server.runtime_settings().chan_limit, 10);
+}
+
+/// Refuses insecure connections and counts them, the other hooks keep their default
+#[derive(Default)]
+struct RequireTls {
+    refused: AtomicUsize,
+}
+
+#[async_trait]
+impl ServerHooks for RequireTls {
+    async fn on_client_connect(
+        &self,
+        _addr: &SocketAddr,
+        connection_info: &ConnectionInfo,
+    ) -> CallbackResult<bool> {
+        if connection_info.tls.is_none() {
+            self.refused.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(connection_info.tls.is_some())
+    }
+}
+
+#[tokio::test]
+async fn hooks_can_keep_state() {
+    let hooks = Arc::new(RequireTls::default());
+    let _server = Server::new(ServerSettings::default(), hooks.clone());
+
+    let addr = "127.0.0.1:1234".parse().unwrap();
+    let accepted = hooks
+        .on_client_connect(&addr, &ConnectionInfo::default())
+        .await;
+    assert!(!accepted.unwrap());
+    assert!(hooks.on_client_disconnect(&addr).await.is_ok());
+    assert_eq!(hooks.refused.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn can_register_commands() {
+    let mut server = Server::new(ServerSettings::default(), Arc::new(DefaultHooks));
+    let hello = |client: Arc<RwLock<Client>>, _msg: Message| async move {
+        client.read().await.send_notice("Hello!").await
+    };
+    assert!(server
+        .register_command("hello", CommandNamespace::Normal, hello)
+        .is_ok());
+    let exists = server.register_command("HELLO", CommandNamespace::Any, hello);
+    assert_eq!(exists, Err(SetupError::CommandExists("HELLO".to_owned())));
+    let exists = server.register_command("privmsg", CommandNamespace::Normal, hello);
+    assert_eq!(exists, Err(SetupError::CommandExists("PRIVMSG".to_owned())));
+}
+
+#[test]
+fn setup_fails_once_server_is_shared() {
+    let mut server = Server::new(ServerSettings::default(), Arc::new(DefaultHooks));
+    let _handle = server.handle();
+    let hello = |client: Arc<RwLock<Client>>, _msg: Message| async move {
+        client.read().await.send_notice("Hello!").await
+    };
+    let registered = server.register_command("hello", CommandNamespace::Normal, hello);
+    assert_eq!(registered, Err(SetupError::AlreadyShared));
+}
+
+#[tokio::test]
+async fn can_inspect_server() {
+    let server = Server::new(ServerSettings::default(), Arc::new(DefaultHooks));
+    let handle = server.handle();
+    server.add_permanent_channel("#Lobby").await;
+
+    assert!(handle.clients().await.is_empty());
+    assert!(handle.user("nobody").await.is_none());
+    let channels = handle.channels().await;
+    assert_eq!(channels.len(), 1);
+    assert_eq!(channels[0].name, "#Lobby");
+    assert_eq!(channels[0].member_count, 0);
+    let channel = handle.channel("#lobby").await.unwrap();
+    assert!(channel.modes.contains('P'));
+}
+
+#[tokio::test]
+async fn checks_injected_messages() {
+    let server = Server::new(ServerSettings::default(), Arc::new(DefaultHooks));
+    let handle = server.handle();
+    server.add_permanent_channel("#news").await;
+
+    let to_channel = MessageTarget::Channel("#news".to_owned());
+    let sent = handle.send_notice(to_channel.clone(), None, "Hi").await;
+    assert!(sent.is_ok());
+    let bad_source = handle.send_privmsg(to_channel, Some("a b"), "Hi").await;
+    assert_eq!(bad_source.unwrap_err().kind(), ErrorKind::InvalidInput);
+    let to_user = MessageTarget::User("nobody".to_owned());
+    let no_user = handle.send_notice(to_user, None, "Hi").await;
+    assert_eq!(no_user.unwrap_err().kind(), ErrorKind::NotFound);
+}
+
+#[tokio::test]
+async fn channel_handle_checks_texts() {
+    let settings = ServerSettings::builder()
+        .max_topic_length(10)
+        .build()
+        .unwrap();
+    let server = Server::new(settings, Arc::new(DefaultHooks));
+    server.add_permanent_channel("#news").await;
+    let channel = server.handle().find_channel("#news").await.unwrap();
+
+    assert!(channel.set_topic("Hello").await.is_ok());
+    let too_long = channel.set_topic("Hello world!").await;
+    assert_eq!(too_long.unwrap_err().kind(), ErrorKind::InvalidInput);
+    let injected = channel.set_topic("Hi\r\nQUIT").await;
+    assert_eq!(injected.unwrap_err().kind(), ErrorKind::InvalidInput);
+    let injected = channel.send_notice("Hi\r\nQUIT").await;
+    assert_eq!(injected.unwrap_err().kind(), ErrorKind::InvalidInput);
+    assert_eq!(channel.topic().await.unwrap().text, "Hello");
+}
+
+fn bot_identity(nick: &str) -> VirtualIdentity {
+    VirtualIdentity {
+        nick: nick.to_owned(),
+        username: "bot".to_owned(),
+        realname: "Test bot".to_owned(),
+        host: "bots.test".to_owned(),
+    }
+}
+
+/// Skips messages until one with one of the commands
+async fn next_reply(client: &mut VirtualClient, commands: &[&str]) -> Message {
+    loop {
+        let msg = client.recv().await.unwrap();
+        if commands.contains(&&*msg.command) {
+            return msg;
+        }
+    }
+}
+
+#[tokio::test]
+async fn virtual_clients_can_talk() {
+    let server = Server::new(ServerSettings::default(), Arc::new(DefaultHooks));
+    let handle = server.handle();
+    let identity = bot_identity("alice");
+    let mut alice = handle.add_virtual_client(identity).await.unwrap();
+    let bob = handle
+        .add_virtual_client(bot_identity("bob"))
+        .await
+        .unwrap();
+    let taken = handle.add_virtual_client(bot_identity("Bob")).await;
+    assert_eq!(taken.err().unwrap().kind(), ErrorKind::AlreadyExists);
+
+    alice.send(Message::new("JOIN #bots")).unwrap();
+    while alice.recv().await.unwrap().command != "366" {}
+    bob.send(Message::new("JOIN #bots")).unwrap();
+    bob.send(Message::new("PRIVMSG #bots :Hi alice")).unwrap();
+    let privmsg = loop {
+        let msg = alice.recv().await.unwrap();
+        if msg.command == "PRIVMSG" {
+            break msg;
+        }
+    };
+    assert_eq!(privmsg.source.as_deref(), Some("bob!bot@bots.test"));
+    assert_eq!(privmsg.params[1], "Hi alice");
+    assert_eq!(handle.channel("#bots").await.unwrap().member_count, 2);
+}
+
+#[tokio::test]
+async fn relays_messages_with_custom_source() {
+    let server = Server::new(ServerSettings::default(), Arc::new(DefaultHooks));
+    let handle = server.handle();
+    let identity = bot_identity("alice");
+    let mut alice = handle.add_virtual_client(identity).await.unwrap();
+    alice.send(Message::new("JOIN #bridge")).unwrap();
+    while alice.recv().await.unwrap().command != "366" {}
+
+    let options = RelayOptions::default();
+    let source = "carol[m]!carol@matrix.test";
+    handle
+        .relay_to_channel("#bridge", source, "Hi from Matrix", &options)
+        .await
+        .unwrap();
+    let msg = alice.recv().await.unwrap();
+    assert_eq!(msg.source.as_deref(), Some(source));
+    assert_eq!(msg.params[1], "Hi from Matrix");
+
+    let spoofed = handle
+        .relay_to_channel("#bridge", "alice!a@b", "Hi", &options)
+        .await;
+    assert_eq!(spoofed.err().unwrap().kind(), ErrorKind::InvalidInput);
+
+    let channel = handle.find_channel("#bridge").await.unwrap();
+    channel
+        .set_mode("+b", &["carol*!*@*".to_owned()])
+        .await
+        .unwrap();
+    let banned = handle
+        .relay_to_channel("#bridge", source, "Hi", &options)
+        .await;
+    assert_eq!(banned.err().unwrap().kind(), ErrorKind::PermissionDenied);
+    let options = RelayOptions {
+        ignore_bans: true,
+        ..Default::default()
+    };
+    handle
+        .relay_to_channel("#bridge", source, "Hi", &options)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn can_force_nick_change() {
+    let server = Server::new(ServerSettings::default(), Arc::new(DefaultHooks));
+    let handle = server.handle();
+    let mut alice = handle
+        .add_virtual_client(bot_identity("alice"))
+        .await
+        .unwrap();
+    let _bob = handle
+        .add_virtual_client(bot_identity("bob"))
+        .await
+        .unwrap();
+    let user = handle.find_user("alice").await.unwrap();
+
+    let taken = user.change_nick("Bob").await;
+    assert_eq!(taken.err().unwrap().kind(), ErrorKind::AlreadyExists);
+    let invalid = user.change_nick("#alice").await;
+    assert_eq!(invalid.err().unwrap().kind(), ErrorKind::InvalidInput);
+
+    user.change_nick("Guest123").await.unwrap();
+    let nick = loop {
+        let msg = alice.recv().await.unwrap();
+        if msg.command == "NICK" {
+            break msg;
+        }
+    };
+    assert_eq!(nick.source.as_deref(), Some("alice!bot@bots.test"));
+    assert_eq!(nick.params[0], "Guest123");
+    assert!(handle.user("alice").await.is_none());
+    assert!(handle.find_user("guest123").await.is_some());
+}
+
+#[tokio::test]
+async fn invite_lets_users_join_invite_only_channels() {
+    let server = Server::new(ServerSettings::default(), Arc::new(DefaultHooks));
+    let handle = server.handle();
+    let mut alice = handle
+        .add_virtual_client(bot_identity("alice"))
+        .await
+        .unwrap();
+    let mut bob = handle
+        .add_virtual_client(bot_identity("bob"))
+        .await
+        .unwrap();
+
+    alice.send(Message::new("JOIN #priv")).unwrap();
+    alice.send(Message::new("MODE #priv +i")).unwrap();
+    next_reply(&mut alice, &["MODE"]).await;
+    bob.send(Message::new("JOIN #priv")).unwrap();
+    assert_eq!(next_reply(&mut bob, &["JOIN", "473"]).await.command, "473");
+    bob.send(Message::new("INVITE bob #priv")).unwrap();
+    assert_eq!(next_reply(&mut bob, &["341", "442"]).await.command, "442");
+
+    alice.send(Message::new("INVITE bob #priv")).unwrap();
+    assert_eq!(next_reply(&mut alice, &["341"]).await.params[1], "bob");
+    let invite = next_reply(&mut bob, &["INVITE"]).await;
+    assert_eq!(invite.source.as_deref(), Some("alice!bot@bots.test"));
+    bob.send(Message::new("JOIN #priv")).unwrap();
+    assert_eq!(next_reply(&mut bob, &["JOIN", "473"]).await.command, "JOIN");
+    alice.send(Message::new("INVITE bob #priv")).unwrap();
+    assert_eq!(next_reply(&mut alice, &["341", "443"]).await.command, "443");
+}
+
+/// Sets the topic of the channels users join, through a handle on its own server
+#[derive(Default)]
+struct WelcomeTopic {
+    handle: OnceLock<ServerHandle>,
+}
+
+#[async_trait]
+impl ServerHooks for WelcomeTopic {
+    async fn on_client_channel_join(
+        &self,
+        client: &Client,
+        channel: &ChannelInfo,
+    ) -> CallbackResult<Option<JoinVeto>> {
+        let handle = self.handle.get().unwrap();
+        let channel = handle.find_channel(&channel.name).await.unwrap();
+        let topic = format!("Welcome {}", client.get_nick().unwrap());
+        channel.set_topic(&topic).await?;
+        Ok(None)
+    }
+}
+
+#[tokio::test]
+async fn join_hooks_can_use_channel_handles() {
+    let hooks = Arc::new(WelcomeTopic::default());
+    let server = Server::new(ServerSettings::default(), hooks.clone());
+    let handle = server.handle();
+    hooks.handle.set(handle.clone()).ok();
+    let mut alice = handle
+        .add_virtual_client(bot_identity("alice"))
+        .await
+        .unwrap();
+
+    alice.send(Message::new("JOIN #welcome")).unwrap();
+    let topic = next_reply(&mut alice, &["332"]);
+    let topic = tokio::time::timeout(Duration::from_secs(5), topic).await;
+    let topic = topic.expect("The join hook deadlocked");
+    assert_eq!(topic.params[2], "Welcome alice");
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn channel_messages_stay_ordered_with_nick_changes() {
+    let server = Server::new(ServerSettings::default(), Arc::new(DefaultHooks));
+    let handle = server.handle();
+    let alice = handle
+        .add_virtual_client(bot_identity("alice"))
+        .await
+        .unwrap();
+    let mut bob = handle
+        .add_virtual_client(bot_identity("bob"))
+        .await
+        .unwrap();
+    alice.send(Message::new("JOIN #chat")).unwrap();
+    bob.send(Message::new("JOIN #chat")).unwrap();
+    next_reply(&mut bob, &["366"]).await;
+
+    for i in 0..100 {
+        alice
+            .send(Message::new(&format!("PRIVMSG #chat :Message {}", i)))
+            .unwrap();
+        alice
+            .send(Message::new(&format!("NICK alice{}", i)))
+            .unwrap();
+    }
+    let mut nick = "alice".to_owned();
+    for i in 0..100 {
+        let privmsg = next_reply(&mut bob, &["PRIVMSG", "NICK"]).await;
+        assert_eq!(privmsg.command, "PRIVMSG");
+        assert_eq!(privmsg.source.unwrap(), format!("{}!bot@bots.test", nick));
+        assert_eq!(privmsg.params[1], format!("Message {}", i));
+        let nick_change = next_reply(&mut bob, &["PRIVMSG", "NICK"]).await;
+        assert_eq!(nick_change.command, "NICK");
+        nick = nick_change.params[0].clone();
+    }
+}